@@ -0,0 +1,146 @@
+use crate::errors::CliError;
+use rdkv::memkv::export::{self, ExportRecord, ImportMode, ImportReport, RecordWriter};
+use rdkv::memkv::{KvError, MemKvPage, Value, KV_PAGE_SIZE};
+use rdkv_client::Client;
+use std::io::Write;
+use std::path::Path;
+
+/// Where the commands run, either directly on a page file or against a running server
+pub enum Backend {
+    // The server must not have the page open at the same time
+    Local(Box<MemKvPage>),
+    Remote(Client),
+}
+
+impl Backend {
+    pub fn open_page(path: &Path) -> Result<Backend, CliError> {
+        // Pages would be created by `MemKvPage::new`, which hides typos in the path
+        if !path.exists() {
+            return Err(CliError::usage(&format!(
+                "page file {:?} does not exist",
+                path
+            )));
+        }
+        return Ok(Backend::Local(Box::new(MemKvPage::new(
+            path,
+            KV_PAGE_SIZE,
+        )?)));
+    }
+
+    pub fn connect(address: &str) -> Backend {
+        return Backend::Remote(Client::connect(address));
+    }
+
+    pub async fn get(self: &Self, key: &str) -> Result<Option<Value>, CliError> {
+        return match self {
+            Backend::Local(page) => match page.get(key) {
+                Ok(value) => Ok(Some(value)),
+                Err(KvError::KeyDoesNotExist { .. }) => Ok(None),
+                Err(error) => Err(error.into()),
+            },
+            Backend::Remote(client) => Ok(client.get(key).await?),
+        };
+    }
+
+    pub async fn put(self: &mut Self, key: &str, value: Value) -> Result<(), CliError> {
+        match self {
+            Backend::Local(page) => {
+                page.upsert(key, value)?;
+                page.sync_all()?;
+            }
+            Backend::Remote(client) => client.put(key, value).await?,
+        }
+        return Ok(());
+    }
+
+    /// Returns whether the key existed
+    pub async fn delete(self: &mut Self, key: &str) -> Result<bool, CliError> {
+        return match self {
+            Backend::Local(page) => match page.delete(key) {
+                Ok(()) => {
+                    page.sync_all()?;
+                    Ok(true)
+                }
+                Err(KvError::KeyDoesNotExist { .. }) => Ok(false),
+                Err(error) => Err(error.into()),
+            },
+            Backend::Remote(client) => Ok(client.delete(key).await?),
+        };
+    }
+
+    pub async fn scan(self: &Self, prefix: &str) -> Result<Vec<(String, Value)>, CliError> {
+        return match self {
+            Backend::Local(page) => {
+                let mut entries = page.scan(prefix)?;
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                Ok(entries)
+            }
+            Backend::Remote(client) => Ok(client.scan(prefix).await?),
+        };
+    }
+
+    /// Returns the number of exported entries. Servers don't report ttls, so remote exports
+    /// have none.
+    pub async fn export<W: Write>(
+        self: &Self,
+        prefix: &str,
+        writer: &mut RecordWriter<W>,
+    ) -> Result<usize, CliError> {
+        return match self {
+            Backend::Local(page) => Ok(export::export_engine(page.as_ref(), prefix, writer)?),
+            Backend::Remote(client) => {
+                let entries = client.scan(prefix).await?;
+                for (key, value) in entries.iter() {
+                    writer.write(&ExportRecord {
+                        key: key.clone(),
+                        value: value.clone(),
+                        ttl: None,
+                    })?;
+                }
+                writer.flush()?;
+                Ok(entries.len())
+            }
+        };
+    }
+
+    pub async fn import(
+        self: &mut Self,
+        records: impl Iterator<Item = Result<ExportRecord, KvError>>,
+        mode: ImportMode,
+    ) -> Result<ImportReport, CliError> {
+        let client = match self {
+            Backend::Local(page) => {
+                return Ok(export::import_engine(page.as_mut(), records, mode)?)
+            }
+            Backend::Remote(client) => client,
+        };
+        let mut report = ImportReport::default();
+        for record in records {
+            let record = record?;
+            if record.ttl.is_some() {
+                return Err(CliError::LocalOnly {
+                    command: format!("importing the ttl of {:?}", record.key),
+                });
+            }
+            if mode != ImportMode::Overwrite && client.get(&record.key).await?.is_some() {
+                if mode == ImportMode::Fail {
+                    return Err(KvError::KeyAlreadyExists { key: record.key }.into());
+                }
+                report.skipped += 1;
+                continue;
+            }
+            client.put(&record.key, record.value).await?;
+            report.imported += 1;
+        }
+        return Ok(report);
+    }
+
+    pub fn get_page(self: &mut Self, command: &str) -> Result<&mut MemKvPage, CliError> {
+        return match self {
+            Backend::Local(page) => Ok(page),
+            Backend::Remote(_) => Err(CliError::LocalOnly {
+                command: String::from(command),
+            }),
+        };
+    }
+}
@@ -0,0 +1,405 @@
+use crate::backend::Backend;
+use crate::errors::CliError;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rdkv::memkv::export::{ExportFormat, ImportMode, RecordReader, RecordWriter};
+use rdkv::memkv::{StorageEngine, Value};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+pub const HELP: &str = "\
+get <key>                  print the value of a key
+put <key> <value> [--type string|integer|json|blob]
+                           write a value, blobs are base64 encoded
+del <key>                  delete a key
+scan [prefix]              list the entries starting with prefix
+export <file> [prefix] [--format jsonl|csv]
+                           write the entries starting with prefix to a file
+import <file> [--format jsonl|csv] [--mode overwrite|skip|fail]
+                           write the entries of an export, existing keys are
+                           overwritten unless another mode is given
+stats                      key count and size of a local page
+defrag                     reclaim deleted entries of a local page
+help                       show this help
+exit                       leave the shell";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: Value,
+    },
+    Delete {
+        key: String,
+    },
+    Scan {
+        prefix: String,
+    },
+    Export {
+        path: PathBuf,
+        prefix: String,
+        format: ExportFormat,
+    },
+    Import {
+        path: PathBuf,
+        format: ExportFormat,
+        mode: ImportMode,
+    },
+    Stats,
+    Defrag,
+    Help,
+    Exit,
+}
+
+/// Parses a line of the shell, arguments are split like in a posix shell so keys and values
+/// with spaces can be quoted. Returns `None` for empty lines.
+pub fn parse_line(line: &str) -> Result<Option<Command>, CliError> {
+    let arguments = match shlex::split(line) {
+        Some(arguments) => arguments,
+        None => return Err(CliError::usage("unbalanced quotes")),
+    };
+    if arguments.is_empty() {
+        return Ok(None);
+    }
+    return Ok(Some(parse_command(&arguments)?));
+}
+
+pub fn parse_command(arguments: &[String]) -> Result<Command, CliError> {
+    let name = arguments[0].to_lowercase();
+    let mut data_type = String::from("string");
+    let mut format = None;
+    let mut mode = String::from("overwrite");
+    let mut positional = vec![];
+    let mut iterator = arguments[1..].iter();
+    while let Some(argument) = iterator.next() {
+        let (option, value) = match argument.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => {
+                (option, Some(String::from(value)))
+            }
+            _ => (argument.as_str(), None),
+        };
+        let target = match option {
+            "--type" => &mut data_type,
+            "--format" => format.insert(String::new()),
+            "--mode" => &mut mode,
+            _ => {
+                positional.push(argument.clone());
+                continue;
+            }
+        };
+        *target = match value.or_else(|| iterator.next().cloned()) {
+            Some(value) => value,
+            None => return Err(CliError::usage(&format!("{} needs a value", option))),
+        };
+    }
+
+    let command = match (name.as_str(), positional.as_slice()) {
+        ("get", [key]) => Command::Get { key: key.clone() },
+        ("put", [key, value]) => Command::Put {
+            key: key.clone(),
+            value: parse_value(&data_type, value)?,
+        },
+        ("del" | "delete", [key]) => Command::Delete { key: key.clone() },
+        ("scan", []) => Command::Scan {
+            prefix: String::new(),
+        },
+        ("scan", [prefix]) => Command::Scan {
+            prefix: prefix.clone(),
+        },
+        ("export", [path, rest @ ..]) if rest.len() <= 1 => Command::Export {
+            path: PathBuf::from(path),
+            prefix: rest.first().cloned().unwrap_or_default(),
+            format: parse_format(format.as_deref(), path)?,
+        },
+        ("import", [path]) => Command::Import {
+            path: PathBuf::from(path),
+            format: parse_format(format.as_deref(), path)?,
+            mode: mode.parse()?,
+        },
+        ("stats", []) => Command::Stats,
+        ("defrag", []) => Command::Defrag,
+        ("help", _) => Command::Help,
+        ("exit" | "quit", []) => Command::Exit,
+        (
+            "get" | "put" | "del" | "delete" | "scan" | "export" | "import" | "stats" | "defrag"
+            | "exit" | "quit",
+            _,
+        ) => {
+            return Err(CliError::usage(&format!(
+                "wrong number of arguments for {}, see help",
+                name
+            )))
+        }
+        _ => {
+            return Err(CliError::usage(&format!(
+                "unknown command {:?}, see help",
+                name
+            )))
+        }
+    };
+    return Ok(command);
+}
+
+// Without --format the file extension decides, json lines are the default
+fn parse_format(format: Option<&str>, path: &str) -> Result<ExportFormat, CliError> {
+    return match format {
+        Some(format) => Ok(format.parse()?),
+        None => Ok(ExportFormat::from_path(Path::new(path)).unwrap_or(ExportFormat::JsonLines)),
+    };
+}
+
+fn parse_value(data_type: &str, text: &str) -> Result<Value, CliError> {
+    return match data_type.to_lowercase().as_str() {
+        "string" => Ok(Value::String(String::from(text))),
+        "integer" => match text.parse() {
+            Ok(number) => Ok(Value::Integer(number)),
+            Err(_) => Err(CliError::usage("integers must be unsigned 64 bit numbers")),
+        },
+        "json" => Ok(Value::json_from_str(text)?),
+        "blob" => match BASE64.decode(text) {
+            Ok(data) => Ok(Value::Blob(data)),
+            Err(_) => Err(CliError::usage("blobs must be base64 encoded")),
+        },
+        _ => Err(CliError::usage(&format!(
+            "unknown type {:?}, use string, integer, json or blob",
+            data_type
+        ))),
+    };
+}
+
+/// Formats values like `redis-cli`, the data type is named unless it's a string
+pub fn format_value(value: &Value) -> String {
+    return match value {
+        Value::String(text) => format!("{:?}", text),
+        Value::Integer(number) => format!("(integer) {}", number),
+        Value::Json(document) => format!("(json) {}", document),
+        Value::Blob(data) => format!("(blob) {}", BASE64.encode(data)),
+        Value::Typed(codec, data) => format!("(typed {:?}) {}", codec, BASE64.encode(data)),
+    };
+}
+
+/// Runs a command and returns its output, `Exit` is handled by the shell
+pub async fn execute(backend: &mut Backend, command: Command) -> Result<String, CliError> {
+    let output = match command {
+        Command::Get { key } => match backend.get(&key).await? {
+            Some(value) => format_value(&value),
+            None => String::from("(nil)"),
+        },
+        Command::Put { key, value } => {
+            backend.put(&key, value).await?;
+            String::from("OK")
+        }
+        Command::Delete { key } => match backend.delete(&key).await? {
+            true => String::from("(integer) 1"),
+            false => String::from("(integer) 0"),
+        },
+        Command::Scan { prefix } => {
+            let entries = backend.scan(&prefix).await?;
+            if entries.is_empty() {
+                String::from("(empty)")
+            } else {
+                entries
+                    .iter()
+                    .map(|(key, value)| format!("{:?} = {}", key, format_value(value)))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }
+        }
+        Command::Export {
+            path,
+            prefix,
+            format,
+        } => {
+            let mut writer = RecordWriter::new(BufWriter::new(File::create(&path)?), format)?;
+            let count = backend.export(&prefix, &mut writer).await?;
+            format!("exported {} entries to {}", count, path.display())
+        }
+        Command::Import { path, format, mode } => {
+            let reader = RecordReader::new(BufReader::new(File::open(&path)?), format)?;
+            let report = backend.import(reader, mode).await?;
+            format!(
+                "imported {} entries, skipped {}",
+                report.imported, report.skipped
+            )
+        }
+        Command::Stats => {
+            let page = backend.get_page("stats")?;
+            let stats = StorageEngine::stats(page)?;
+            format!(
+                "keys: {}\nused bytes: {}\npage size: {}",
+                stats.key_count,
+                stats.size,
+                page.get_page_size()
+            )
+        }
+        Command::Defrag => {
+            let page = backend.get_page("defrag")?;
+            let used_size = page.get_used_size();
+            page.defrag()?;
+            page.sync_all()?;
+            format!(
+                "reclaimed {} bytes",
+                used_size.saturating_sub(page.get_used_size())
+            )
+        }
+        Command::Help => String::from(HELP),
+        Command::Exit => String::new(),
+    };
+    return Ok(output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, parse_line, Command};
+    use crate::backend::Backend;
+    use crate::errors::CliError;
+    use rdkv::memkv::export::{ExportFormat, ImportMode};
+    use rdkv::memkv::mem_kv_page::remove_page_files;
+    use rdkv::memkv::{MemKvPage, Value, KV_PAGE_SIZE};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    // Removes the page files when created and dropped, declare it before the page
+    struct PageFilesGuard {
+        path: PathBuf,
+    }
+
+    impl PageFilesGuard {
+        fn new(path: &Path) -> Result<PageFilesGuard, rdkv::memkv::KvError> {
+            remove_page_files(path)?;
+            return Ok(PageFilesGuard {
+                path: PathBuf::from(path),
+            });
+        }
+    }
+
+    impl Drop for PageFilesGuard {
+        fn drop(&mut self) {
+            if let Err(error) = remove_page_files(&self.path) {
+                eprintln!("Failed to remove page {:?}: {}", self.path, error);
+            }
+        }
+    }
+
+    async fn run(backend: &mut Backend, line: &str) -> Result<String, CliError> {
+        let command = parse_line(line)?.unwrap();
+        return execute(backend, command).await;
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("  ").unwrap(), None);
+        assert_eq!(
+            parse_line("put 'user 1' \"peter pan\"").unwrap(),
+            Some(Command::Put {
+                key: String::from("user 1"),
+                value: Value::String(String::from("peter pan")),
+            })
+        );
+        assert_eq!(
+            parse_line("PUT --type integer a 7").unwrap(),
+            Some(Command::Put {
+                key: String::from("a"),
+                value: Value::Integer(7),
+            })
+        );
+        assert_eq!(
+            parse_line("put a AAE= --type=blob").unwrap(),
+            Some(Command::Put {
+                key: String::from("a"),
+                value: Value::Blob(vec![0, 1]),
+            })
+        );
+        assert!(parse_line("put a -1 --type integer").is_err());
+        assert!(parse_line("put a {} --type float").is_err());
+        assert!(parse_line("get").is_err());
+        assert!(parse_line("get 'a").is_err());
+        assert!(parse_line("flush").is_err());
+        assert_eq!(
+            parse_line("export dump.csv user: --format jsonl").unwrap(),
+            Some(Command::Export {
+                path: PathBuf::from("dump.csv"),
+                prefix: String::from("user:"),
+                format: ExportFormat::JsonLines,
+            })
+        );
+        assert_eq!(
+            parse_line("import dump.csv").unwrap(),
+            Some(Command::Import {
+                path: PathBuf::from("dump.csv"),
+                format: ExportFormat::Csv,
+                mode: ImportMode::Overwrite,
+            })
+        );
+        assert!(parse_line("import dump --mode replace").is_err());
+        assert!(parse_line("import dump --format").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_backend() {
+        const PAGE: &str = "test_cli_page";
+        let _page_files = PageFilesGuard::new(Path::new(PAGE)).unwrap();
+        assert!(Backend::open_page(Path::new(PAGE)).is_err());
+        drop(MemKvPage::new(Path::new(PAGE), KV_PAGE_SIZE).unwrap());
+        let mut backend = Backend::open_page(Path::new(PAGE)).unwrap();
+
+        assert_eq!(
+            run(&mut backend, "put a 1 --type integer").await.unwrap(),
+            "OK"
+        );
+        assert_eq!(
+            run(&mut backend, "put b '{\"x\": 1}' --type json")
+                .await
+                .unwrap(),
+            "OK"
+        );
+        assert_eq!(run(&mut backend, "get a").await.unwrap(), "(integer) 1");
+        assert_eq!(run(&mut backend, "get c").await.unwrap(), "(nil)");
+        assert_eq!(
+            run(&mut backend, "scan").await.unwrap(),
+            "\"a\" = (integer) 1\n\"b\" = (json) {\"x\":1}"
+        );
+        assert_eq!(run(&mut backend, "del a").await.unwrap(), "(integer) 1");
+        assert_eq!(run(&mut backend, "del a").await.unwrap(), "(integer) 0");
+        assert_eq!(
+            run(&mut backend, "export test_cli_export.csv")
+                .await
+                .unwrap(),
+            "exported 1 entries to test_cli_export.csv"
+        );
+        assert_eq!(
+            run(&mut backend, "import test_cli_export.csv --mode=skip")
+                .await
+                .unwrap(),
+            "imported 0 entries, skipped 1"
+        );
+        assert!(run(&mut backend, "import test_cli_export.csv --mode fail")
+            .await
+            .is_err());
+        fs::remove_file("test_cli_export.csv").unwrap();
+        assert!(run(&mut backend, "stats")
+            .await
+            .unwrap()
+            .starts_with("keys: 1\n"));
+        assert!(run(&mut backend, "defrag")
+            .await
+            .unwrap()
+            .starts_with("reclaimed"));
+        assert_eq!(
+            run(&mut backend, "get b").await.unwrap(),
+            "(json) {\"x\":1}"
+        );
+        drop(backend);
+
+        // Stats and defrag need the page file
+        let mut backend = Backend::connect("127.0.0.1:1");
+        assert!(matches!(
+            run(&mut backend, "defrag").await,
+            Err(CliError::LocalOnly { .. })
+        ));
+    }
+}
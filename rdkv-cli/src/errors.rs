@@ -0,0 +1,66 @@
+use rdkv::memkv::KvError;
+use rdkv_client::ClientError;
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage { message: String },
+    // The protocol has no request for the command, so it needs the page file
+    LocalOnly { command: String },
+    Storage(KvError),
+    Client(ClientError),
+    Io(io::Error),
+}
+
+impl CliError {
+    pub fn usage(message: &str) -> CliError {
+        return CliError::Usage {
+            message: String::from(message),
+        };
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage { message } => write!(f, "{}", message),
+            CliError::LocalOnly { command } => {
+                write!(f, "{} only works on a local page file, use --page", command)
+            }
+            CliError::Storage(error) => write!(f, "{}", error),
+            CliError::Client(error) => write!(f, "{}", error),
+            CliError::Io(error) => write!(f, "io error: {}", error),
+        }
+    }
+}
+
+impl error::Error for CliError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        return match self {
+            CliError::Storage(error) => Some(error),
+            CliError::Client(error) => Some(error),
+            CliError::Io(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<KvError> for CliError {
+    fn from(error: KvError) -> Self {
+        return CliError::Storage(error);
+    }
+}
+
+impl From<ClientError> for CliError {
+    fn from(error: ClientError) -> Self {
+        return CliError::Client(error);
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        return CliError::Io(error);
+    }
+}
@@ -0,0 +1,111 @@
+// Explicit returns and typed self parameters like in the server crate
+#![allow(clippy::needless_return, clippy::needless_arbitrary_self_type)]
+
+mod backend;
+mod commands;
+mod errors;
+
+use backend::Backend;
+use commands::Command;
+use errors::CliError;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7070";
+const USAGE: &str = "usage: rdkv-cli [--page <path> | --server <address>] [command...]";
+const HISTORY_FILE: &str = ".rdkv_history";
+
+fn open_backend(arguments: &mut Vec<String>) -> Result<Backend, CliError> {
+    let target = match arguments.first().map(|argument| argument.as_str()) {
+        Some("--page") | Some("--server") if arguments.len() < 2 => {
+            return Err(CliError::usage(USAGE))
+        }
+        Some("--page") | Some("--server") => {
+            let target = arguments.drain(..2).collect::<Vec<String>>();
+            Some((target[0].clone(), target[1].clone()))
+        }
+        Some("--help") | Some("-h") => return Err(CliError::usage(USAGE)),
+        _ => None,
+    };
+    return match target {
+        Some((option, path)) if option == "--page" => Backend::open_page(Path::new(&path)),
+        Some((_, address)) => Ok(Backend::connect(&address)),
+        None => Ok(Backend::connect(DEFAULT_ADDRESS)),
+    };
+}
+
+fn get_history_path() -> Option<PathBuf> {
+    return env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE));
+}
+
+async fn run_shell(backend: &mut Backend) -> Result<(), ReadlineError> {
+    let mut editor = DefaultEditor::new()?;
+    let history_path = get_history_path();
+    if let Some(path) = &history_path {
+        // There is no history before the first session
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let line = match editor.readline("rdkv> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        let command = match commands::parse_line(&line) {
+            Ok(Some(Command::Exit)) => break,
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(error) => {
+                eprintln!("(error) {}", error);
+                continue;
+            }
+        };
+        match commands::execute(backend, command).await {
+            Ok(output) => println!("{}", output),
+            Err(error) => eprintln!("(error) {}", error),
+        }
+    }
+    if let Some(path) = &history_path {
+        editor.save_history(path)?;
+    }
+    return Ok(());
+}
+
+#[tokio::main]
+async fn main() {
+    let mut arguments: Vec<String> = env::args().skip(1).collect();
+    let mut backend = match open_backend(&mut arguments) {
+        Ok(backend) => backend,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+    };
+
+    // Commands on the command line run once without the shell
+    if !arguments.is_empty() {
+        let result = match commands::parse_command(&arguments) {
+            Ok(command) => commands::execute(&mut backend, command).await,
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(output) => println!("{}", output),
+            Err(error) => {
+                eprintln!("(error) {}", error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Err(error) = run_shell(&mut backend).await {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::errors::ClientError;
+use crate::pool::ConnectionPool;
+use crate::routing::Router;
+use log::warn;
+use rdkv::memkv::{Codec, Value};
+use rdkv::server::binary_protocol::{ErrorKind, Request, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+
+pub struct ClientConfig {
+    // Keys are partitioned over all nodes, see `Router`
+    pub nodes: Vec<String>,
+    // Per node
+    pub max_connections: usize,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub max_retries: u32,
+    // The backoff doubles with every retry up to `max_backoff`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(nodes: &[&str]) -> ClientConfig {
+        return ClientConfig {
+            nodes: nodes.iter().map(|node| String::from(*node)).collect(),
+            max_connections: 16,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        };
+    }
+}
+
+/// Async client for the binary protocol. Clients are cheap to share between tasks, every
+/// request borrows a pooled connection to the node owning its key.
+pub struct Client {
+    router: Router,
+    pools: Vec<ConnectionPool>,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Result<Client, ClientError> {
+        if config.nodes.is_empty() {
+            return Err(ClientError::NoNodes);
+        }
+        let pools = config
+            .nodes
+            .iter()
+            .map(|node| {
+                ConnectionPool::new(
+                    node,
+                    config.max_connections,
+                    config.connect_timeout,
+                    config.request_timeout,
+                )
+            })
+            .collect();
+        return Ok(Client {
+            router: Router::new(config.nodes),
+            pools,
+            max_retries: config.max_retries,
+            initial_backoff: config.initial_backoff,
+            max_backoff: config.max_backoff,
+        });
+    }
+
+    /// Client for a single node with the default settings
+    pub fn connect(address: &str) -> Client {
+        return Client::new(ClientConfig::new(&[address])).expect("one node is configured");
+    }
+
+    /// Returns `None` if the key does not exist
+    pub async fn get(self: &Self, key: &str) -> Result<Option<Value>, ClientError> {
+        let request = Request::Get {
+            key: String::from(key),
+        };
+        return match self.send(self.router.route(key), &request, true).await {
+            Ok(Response::Value(value)) => Ok(Some(value)),
+            Ok(_) => Err(ClientError::UnexpectedResponse),
+            Err(ClientError::Server {
+                kind: ErrorKind::NotFound,
+                ..
+            }) => Ok(None),
+            Err(error) => Err(error),
+        };
+    }
+
+    pub async fn get_typed<T: DeserializeOwned>(
+        self: &Self,
+        key: &str,
+    ) -> Result<Option<T>, ClientError> {
+        return match self.get(key).await? {
+            Some(Value::Typed(codec, data)) => Ok(Some(codec.decode(&data)?)),
+            Some(Value::Blob(data)) => Ok(Some(Codec::Json.decode(&data)?)),
+            Some(_) => Err(ClientError::UnexpectedResponse),
+            None => Ok(None),
+        };
+    }
+
+    pub async fn put(self: &Self, key: &str, value: Value) -> Result<(), ClientError> {
+        let request = Request::Put {
+            key: String::from(key),
+            value,
+        };
+        return self.expect_done(key, &request, true).await;
+    }
+
+    /// Stores the value as json encoded typed value
+    pub async fn put_typed<T: Serialize>(
+        self: &Self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), ClientError> {
+        let data = Codec::Json.encode(value)?;
+        return self.put(key, Value::Typed(Codec::Json, data)).await;
+    }
+
+    /// Returns whether the key existed. Deletes are not retried once they may have reached
+    /// the server, a retry of a delete that was applied would report the key as missing.
+    pub async fn delete(self: &Self, key: &str) -> Result<bool, ClientError> {
+        let request = Request::Delete {
+            key: String::from(key),
+        };
+        return match self.expect_done(key, &request, false).await {
+            Ok(()) => Ok(true),
+            Err(ClientError::Server {
+                kind: ErrorKind::NotFound,
+                ..
+            }) => Ok(false),
+            Err(error) => Err(error),
+        };
+    }
+
+    /// Collects the matching entries of every node, sorted by key
+    pub async fn scan(self: &Self, prefix: &str) -> Result<Vec<(String, Value)>, ClientError> {
+        let request = Request::Scan {
+            prefix: String::from(prefix),
+        };
+        let responses = futures::future::try_join_all(
+            (0..self.pools.len()).map(|node| self.send(node, &request, true)),
+        )
+        .await?;
+        let mut entries = vec![];
+        for response in responses {
+            match response {
+                Response::Entries(node_entries) => entries.extend(node_entries),
+                _ => return Err(ClientError::UnexpectedResponse),
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        return Ok(entries);
+    }
+
+    /// Writes `value` only if the current value equals `expected`, or if the key does not
+    /// exist for `None`. Returns whether the value was written.
+    pub async fn cas(
+        self: &Self,
+        key: &str,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, ClientError> {
+        let request = Request::Cas {
+            key: String::from(key),
+            expected,
+            value,
+        };
+        return match self.expect_done(key, &request, false).await {
+            Ok(()) => Ok(true),
+            Err(ClientError::Server {
+                kind: ErrorKind::Conflict,
+                ..
+            }) => Ok(false),
+            Err(error) => Err(error),
+        };
+    }
+
+    async fn expect_done(
+        self: &Self,
+        key: &str,
+        request: &Request,
+        is_idempotent: bool,
+    ) -> Result<(), ClientError> {
+        return match self
+            .send(self.router.route(key), request, is_idempotent)
+            .await?
+        {
+            Response::Done => Ok(()),
+            _ => Err(ClientError::UnexpectedResponse),
+        };
+    }
+
+    async fn send(
+        self: &Self,
+        node: usize,
+        request: &Request,
+        is_idempotent: bool,
+    ) -> Result<Response, ClientError> {
+        let pool = &self.pools[node];
+        let payload = request.encode()?;
+        let mut attempt = 0;
+        loop {
+            let error = match pool.send(&payload).await {
+                Ok(Response::Error(kind, message)) => {
+                    return Err(ClientError::Server { kind, message })
+                }
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+            if attempt >= self.max_retries || !error.is_retryable(is_idempotent) {
+                return Err(error);
+            }
+            let backoff = self.get_backoff(attempt);
+            warn!(
+                "Request to {} failed, retrying in {:?}: {}",
+                pool.address(),
+                backoff,
+                error
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    // Exponential backoff with full jitter, so clients that failed together retry apart
+    fn get_backoff(self: &Self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff);
+        return ceiling.mul_f64(rand::random::<f64>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, ClientConfig};
+    use crate::errors::ClientError;
+    use rdkv::memkv::{MemoryEngine, StorageEngine, Value};
+    use rdkv::server::{Protocol, TcpServer};
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    async fn start_server() -> String {
+        let engine: Box<dyn StorageEngine> = Box::new(MemoryEngine::new());
+        let engine = Arc::new(parking_lot::Mutex::new(engine));
+        let server = TcpServer::bind("127.0.0.1:0", Protocol::Binary, engine)
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        tokio::spawn(server.run());
+        return address;
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let nodes = [start_server().await, start_server().await];
+        let nodes: Vec<&str> = nodes.iter().map(|node| node.as_str()).collect();
+        let client = Client::new(ClientConfig::new(&nodes)).unwrap();
+
+        for i in 0..20 {
+            client
+                .put(&format!("key-{:02}", i), Value::Integer(i))
+                .await
+                .unwrap();
+        }
+        assert_eq!(client.get("key-07").await.unwrap(), Some(Value::Integer(7)));
+        assert_eq!(client.get("missing").await.unwrap(), None);
+        let entries = client.scan("key-").await.unwrap();
+        assert_eq!(entries.len(), 20);
+        assert_eq!(entries[19], (String::from("key-19"), Value::Integer(19)));
+        // Both nodes own part of the keys
+        for node in nodes {
+            let entries = Client::connect(node).scan("key-").await.unwrap();
+            assert!(!entries.is_empty() && entries.len() < 20);
+        }
+
+        assert!(client.delete("key-07").await.unwrap());
+        assert!(!client.delete("key-07").await.unwrap());
+        assert!(client.cas("key-07", None, Value::Integer(1)).await.unwrap());
+        assert!(!client.cas("key-07", None, Value::Integer(2)).await.unwrap());
+        assert!(client
+            .cas("key-07", Some(Value::Integer(1)), Value::Integer(2))
+            .await
+            .unwrap());
+
+        let user = User {
+            name: String::from("peter"),
+            age: 42,
+        };
+        client.put_typed("user", &user).await.unwrap();
+        assert_eq!(client.get_typed::<User>("user").await.unwrap(), Some(user));
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_timeouts() {
+        // Nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut config = ClientConfig::new(&[&address]);
+        config.initial_backoff = Duration::from_millis(1);
+        let client = Client::new(config).unwrap();
+        assert!(matches!(
+            client.get("a").await,
+            Err(ClientError::Connect { .. })
+        ));
+
+        // A server that never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let mut config = ClientConfig::new(&[&address]);
+        config.request_timeout = Duration::from_millis(50);
+        config.initial_backoff = Duration::from_millis(1);
+        let client = Client::new(config).unwrap();
+        assert!(matches!(client.get("a").await, Err(ClientError::Timeout)));
+        // Without retries the delete fails after a single timeout
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            client.delete("a").await,
+            Err(ClientError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(150));
+
+        assert!(matches!(
+            Client::new(ClientConfig::new(&[])),
+            Err(ClientError::NoNodes)
+        ));
+    }
+}
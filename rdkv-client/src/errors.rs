@@ -0,0 +1,88 @@
+use rdkv::memkv::KvError;
+use rdkv::server::binary_protocol::ErrorKind;
+use rdkv::server::errors::ProtocolError;
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ClientError {
+    NoNodes,
+    // Nothing was sent yet, so every request can be retried after these
+    Connect { address: String, error: io::Error },
+    Timeout,
+    ConnectionClosed,
+    // The server handled the request and answered with an error
+    Server { kind: ErrorKind, message: String },
+    UnexpectedResponse,
+    Value(KvError),
+    Protocol(ProtocolError),
+    Io(io::Error),
+}
+
+impl ClientError {
+    // Requests which are not idempotent might have been applied before a timeout or a closed
+    // connection, so they are only retried if they never left the client
+    pub(crate) fn is_retryable(self: &Self, is_idempotent: bool) -> bool {
+        return match self {
+            ClientError::Connect { .. } => true,
+            ClientError::Timeout | ClientError::ConnectionClosed | ClientError::Io(_) => {
+                is_idempotent
+            }
+            _ => false,
+        };
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::NoNodes => write!(f, "no nodes configured"),
+            ClientError::Connect { address, error } => {
+                write!(f, "failed to connect to {}: {}", address, error)
+            }
+            ClientError::Timeout => write!(f, "request timed out"),
+            ClientError::ConnectionClosed => write!(f, "connection closed by server"),
+            ClientError::Server { kind, message } => {
+                write!(f, "server error ({:?}): {}", kind, message)
+            }
+            ClientError::UnexpectedResponse => write!(f, "unexpected response from server"),
+            ClientError::Value(error) => write!(f, "invalid value: {}", error),
+            ClientError::Protocol(error) => write!(f, "protocol error: {}", error),
+            ClientError::Io(error) => write!(f, "io error: {}", error),
+        }
+    }
+}
+
+impl error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        return match self {
+            ClientError::Connect { error, .. } => Some(error),
+            ClientError::Value(error) => Some(error),
+            ClientError::Protocol(error) => Some(error),
+            ClientError::Io(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<KvError> for ClientError {
+    fn from(error: KvError) -> Self {
+        return ClientError::Value(error);
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(error: ProtocolError) -> Self {
+        return match error {
+            ProtocolError::Io(error) => ClientError::Io(error),
+            error => ClientError::Protocol(error),
+        };
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        return ClientError::Io(error);
+    }
+}
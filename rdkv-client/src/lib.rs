@@ -0,0 +1,10 @@
+// Explicit returns and typed self parameters like in the server crate
+#![allow(clippy::needless_return, clippy::needless_arbitrary_self_type)]
+
+pub mod client;
+pub mod errors;
+pub mod pool;
+pub mod routing;
+pub use client::{Client, ClientConfig};
+pub use errors::ClientError;
+pub use rdkv::memkv::Value;
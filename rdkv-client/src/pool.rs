@@ -0,0 +1,97 @@
+use crate::errors::ClientError;
+use parking_lot::Mutex;
+use rdkv::server::binary_protocol::{read_frame, write_frame, Response};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    async fn connect(address: &str, connect_timeout: Duration) -> Result<Connection, ClientError> {
+        let stream = match timeout(connect_timeout, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => {
+                return Err(ClientError::Connect {
+                    address: String::from(address),
+                    error,
+                })
+            }
+            Err(elapsed) => {
+                return Err(ClientError::Connect {
+                    address: String::from(address),
+                    error: elapsed.into(),
+                })
+            }
+        };
+        stream.set_nodelay(true)?;
+        return Ok(Connection {
+            stream: BufStream::new(stream),
+        });
+    }
+
+    async fn send(self: &mut Self, payload: &[u8]) -> Result<Response, ClientError> {
+        write_frame(&mut self.stream, payload).await?;
+        self.stream.flush().await?;
+        return match read_frame(&mut self.stream).await? {
+            Some(payload) => Ok(Response::decode(&payload)?),
+            None => Err(ClientError::ConnectionClosed),
+        };
+    }
+}
+
+/// Connections to a single node. Idle connections are reused and at most `max_connections`
+/// requests are in flight at the same time, others wait for a free connection.
+pub struct ConnectionPool {
+    address: String,
+    idle: Mutex<Vec<Connection>>,
+    permits: Semaphore,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(
+        address: &str,
+        max_connections: usize,
+        connect_timeout: Duration,
+        request_timeout: Duration,
+    ) -> ConnectionPool {
+        return ConnectionPool {
+            address: String::from(address),
+            idle: Mutex::new(vec![]),
+            permits: Semaphore::new(max_connections.max(1)),
+            connect_timeout,
+            request_timeout,
+        };
+    }
+
+    pub fn address(self: &Self) -> &str {
+        return &self.address;
+    }
+
+    pub async fn send(self: &Self, payload: &[u8]) -> Result<Response, ClientError> {
+        // The semaphore is never closed
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| ClientError::ConnectionClosed)?;
+        let idle = self.idle.lock().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => Connection::connect(&self.address, self.connect_timeout).await?,
+        };
+        let response = match timeout(self.request_timeout, connection.send(payload)).await {
+            Ok(response) => response?,
+            Err(_) => return Err(ClientError::Timeout),
+        };
+        // Connections which failed are dropped, their stream might be out of sync
+        self.idle.lock().push(connection);
+        return Ok(response);
+    }
+}
@@ -0,0 +1,69 @@
+// FNV-1a parameters, unlike the std hasher the hash is the same for every client build
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Assigns every key to one node with rendezvous hashing, so adding or removing a node only
+/// moves the keys owned by that node
+pub struct Router {
+    nodes: Vec<String>,
+}
+
+impl Router {
+    pub fn new(nodes: Vec<String>) -> Router {
+        return Router { nodes };
+    }
+
+    pub fn nodes(self: &Self) -> &[String] {
+        return &self.nodes;
+    }
+
+    /// Returns the index of the node owning `key`
+    pub fn route(self: &Self, key: &str) -> usize {
+        return (0..self.nodes.len())
+            .max_by_key(|index| hash(&self.nodes[*index], key))
+            .unwrap_or(0);
+    }
+}
+
+fn hash(node: &str, key: &str) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    // The separator keeps ("ab", "c") and ("a", "bc") apart
+    for byte in node.bytes().chain([0xff]).chain(key.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    // Node addresses often differ in a single byte, the finalizer of murmur3 spreads that
+    // difference over the whole hash
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    return hash;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+
+    #[test]
+    fn test_route() {
+        let nodes: Vec<String> = (0..4).map(|i| format!("10.0.0.{}:7070", i)).collect();
+        let router = Router::new(nodes.clone());
+        let keys: Vec<String> = (0..1000).map(|i| format!("key-{}", i)).collect();
+        let routes: Vec<usize> = keys.iter().map(|key| router.route(key)).collect();
+        for node in 0..4 {
+            let count = routes.iter().filter(|route| **route == node).count();
+            assert!(count > 150, "node {} owns only {} keys", node, count);
+        }
+
+        // Removing a node only moves its own keys
+        let router = Router::new(nodes[..3].to_vec());
+        for (key, route) in keys.iter().zip(routes) {
+            if route != 3 {
+                assert_eq!(router.route(key), route);
+            }
+        }
+        assert_eq!(Router::new(vec![]).route("a"), 0);
+    }
+}
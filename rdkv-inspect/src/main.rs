@@ -0,0 +1,174 @@
+// Explicit returns and typed self parameters like in the server crate
+#![allow(clippy::needless_return, clippy::needless_arbitrary_self_type)]
+
+use rdkv::memkv::page_inspector::{self, EntryInspection, PageInspection};
+use rdkv::memkv::{Keyring, KvError};
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "usage: rdkv-inspect <page> [--keyfile <path>] [--salvage <new page>]";
+
+struct Options {
+    page: PathBuf,
+    keyfile: Option<PathBuf>,
+    salvage: Option<PathBuf>,
+}
+
+fn parse_options(arguments: &[String]) -> Option<Options> {
+    let mut page = None;
+    let mut keyfile = None;
+    let mut salvage = None;
+    let mut iterator = arguments.iter();
+    while let Some(argument) = iterator.next() {
+        match argument.as_str() {
+            "--keyfile" => keyfile = Some(PathBuf::from(iterator.next()?)),
+            "--salvage" => salvage = Some(PathBuf::from(iterator.next()?)),
+            "--help" | "-h" => return None,
+            _ if page.is_none() && !argument.starts_with("--") => {
+                page = Some(PathBuf::from(argument))
+            }
+            _ => return None,
+        }
+    }
+    return Some(Options {
+        page: page?,
+        keyfile,
+        salvage,
+    });
+}
+
+fn format_flags(flags: u8) -> String {
+    let names = [
+        (0x1, "deleted"),
+        (0x2, "lz4"),
+        (0x4, "zstd"),
+        (0x8, "encrypted"),
+        (0x10, "overflow"),
+        (0x20, "checksum"),
+    ];
+    let set: Vec<&str> = names
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0x0)
+        .map(|(_, name)| *name)
+        .collect();
+    if set.is_empty() {
+        return String::from("-");
+    }
+    return set.join(",");
+}
+
+fn format_entry(entry: &EntryInspection) -> String {
+    let (key, status) = match &entry.content {
+        Ok((key, _)) => (format!("{:?}", key), String::from("ok")),
+        Err(error) => (String::from("?"), format!("error: {}", error)),
+    };
+    return format!(
+        "{:>10}  {:<8} {:<18} {:>8} {:>10}  {}  {}",
+        entry.offset,
+        format!("{:?}", entry.data_type),
+        format_flags(entry.flags),
+        entry.key_size,
+        entry.value_size,
+        key,
+        status
+    );
+}
+
+fn print_inspection(inspection: &PageInspection) {
+    println!("page: {}", inspection.path.display());
+    println!("file size: {}", inspection.file_size);
+    if inspection.header_page_size == inspection.file_size {
+        println!("header page size: {}", inspection.header_page_size);
+    } else {
+        println!(
+            "header page size: {} (does not match the file size)",
+            inspection.header_page_size
+        );
+    }
+    let checksums = inspection
+        .entries
+        .iter()
+        .filter(|entry| entry.has_checksum())
+        .count();
+    println!(
+        "checksums: {} of {} entries, entries without one are checked by decoding",
+        checksums,
+        inspection.entries.len()
+    );
+    println!();
+    println!(
+        "{:>10}  {:<8} {:<18} {:>8} {:>10}  key  status",
+        "offset", "type", "flags", "key size", "value size"
+    );
+    for entry in &inspection.entries {
+        println!("{}", format_entry(entry));
+    }
+    for (offset, length) in &inspection.corrupted_ranges {
+        println!(
+            "corrupted: {} bytes at offset {} without a readable entry",
+            length, offset
+        );
+    }
+
+    let fragmentation = inspection.get_fragmentation();
+    let unreadable = inspection
+        .entries
+        .iter()
+        .filter(|entry| entry.content.is_err())
+        .count();
+    println!();
+    println!("entries: {}", inspection.entries.len());
+    println!("unreadable entries: {}", unreadable);
+    println!("deleted entries: {}", fragmentation.deleted_entries);
+    println!("deleted bytes: {}", fragmentation.deleted_bytes);
+    println!("used bytes: {}", fragmentation.used_bytes);
+    println!("free bytes: {}", fragmentation.free_bytes);
+    println!("fragmentation: {:.1}%", fragmentation.get_ratio() * 100.0);
+}
+
+fn run(options: &Options) -> Result<bool, KvError> {
+    let keyring = match &options.keyfile {
+        Some(path) => Some(Keyring::from_keyfile(path)?),
+        None => None,
+    };
+    let inspection = page_inspector::inspect_page(&options.page, keyring.as_ref())?;
+    print_inspection(&inspection);
+    let is_damaged = !inspection.corrupted_ranges.is_empty()
+        || inspection
+            .entries
+            .iter()
+            .any(|entry| entry.content.is_err());
+
+    if let Some(target) = &options.salvage {
+        let report = page_inspector::salvage_page(&inspection, target, keyring)?;
+        println!();
+        println!(
+            "salvaged {} entries into {}, {} live entries were lost",
+            report.salvaged_entries,
+            target.display(),
+            report.lost_entries
+        );
+    }
+    return Ok(is_damaged);
+}
+
+fn main() {
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&arguments) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    match run(&options) {
+        // Damaged pages exit with 1 so scripts can check pages in bulk
+        Ok(true) => process::exit(1),
+        Ok(false) => {}
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+// Explicit returns, typed self parameters and plain `new` constructors are used throughout
+// the codebase
+#![allow(
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type,
+    clippy::new_without_default
+)]
+
+pub mod memkv;
+pub mod server;
@@ -1,25 +1,23 @@
-mod memkv;
+// Explicit returns and typed self parameters are used throughout the codebase
+#![allow(clippy::needless_return, clippy::needless_arbitrary_self_type)]
 
-use futures::future::join_all;
-use log::{info, warn};
-use log4rs;
-use memmap::MmapMut;
-use serde::{Deserialize, Serialize};
+use parking_lot::Mutex;
+use rdkv::memkv;
+use rdkv::server::{self, Protocol};
+use std::env;
 use std::error;
 use std::fs;
-use std::fs::OpenOptions;
 use std::net::SocketAddr;
 use std::path::Path;
-use std::rc::Rc;
-use std::string::ToString;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use tokio::sync::watch;
-use tokio::time::sleep;
+use std::sync::Arc;
 use uuid::Uuid;
 
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7070";
+const DEFAULT_STORAGE_PATH: &str = "keyspace";
+const RESTORE_USAGE: &str = "usage: rdkv restore <backup directory> [sequence]";
+const RECOVER_USAGE: &str =
+    "usage: rdkv recover <backup directory> (--sequence <n> | --timestamp <unix ms>)";
+
 fn load_seeds(file: &str) -> Vec<SocketAddr> {
     let contents = fs::read_to_string(file).expect("Failed to load node config");
     return contents
@@ -28,99 +26,145 @@ fn load_seeds(file: &str) -> Vec<SocketAddr> {
         .collect();
 }
 
-fn print_addresses(addresses: Vec<SocketAddr>) {
+// Runs instead of the server, which must not have the storage open meanwhile
+fn restore(
+    settings: &config::Config,
+    storage_path: &str,
+    arguments: &[String],
+) -> Result<(), Box<dyn error::Error>> {
+    let (directory, sequence) = match arguments {
+        [directory] => (directory, None),
+        [directory, sequence] => (directory, Some(sequence.parse::<u64>()?)),
+        _ => return Err(RESTORE_USAGE.into()),
+    };
+    let manifest = memkv::backup::restore_backup(
+        Path::new(directory),
+        sequence,
+        memkv::StorageEngineKind::from_config(settings)?,
+        Path::new(storage_path),
+    )?;
     println!(
-        "{}",
-        addresses
-            .iter()
-            .map(|ip_addr| ip_addr.to_string())
-            .collect::<Vec<String>>()
-            .join(";")
-    )
-}
-
-#[derive(Serialize, Deserialize)]
-struct Person {
-    name: String,
-    age: u8,
-    phones: Vec<String>,
+        "restored backup {} from {} into {}",
+        manifest.sequence, directory, storage_path
+    );
+    return Ok(());
 }
 
-struct Message {
-    key: String,
-    value: memkv::Value,
+// Like `restore`, followed by replaying the archived write ahead log up to the target
+fn recover(
+    settings: &config::Config,
+    storage_path: &str,
+    arguments: &[String],
+) -> Result<(), Box<dyn error::Error>> {
+    let (directory, target) = match arguments {
+        [directory, option, value] if option == "--sequence" => (
+            directory,
+            memkv::recovery::RecoveryTarget::Sequence(value.parse()?),
+        ),
+        [directory, option, value] if option == "--timestamp" => (
+            directory,
+            memkv::recovery::RecoveryTarget::Timestamp(value.parse()?),
+        ),
+        _ => return Err(RECOVER_USAGE.into()),
+    };
+    let archive_path = settings.get_string("wal.archive_path")?;
+    // Segments the server did not archive before it stopped hold the latest writes
+    if let Ok(wal_path) = settings.get_string("wal.path") {
+        if Path::new(&wal_path).exists() {
+            memkv::write_ahead_log::archive_segments(
+                Path::new(&wal_path),
+                Path::new(&archive_path),
+            )?;
+        }
+    }
+    let report = memkv::recovery::recover(
+        Path::new(directory),
+        Path::new(&archive_path),
+        target,
+        memkv::StorageEngineKind::from_config(settings)?,
+        Path::new(storage_path),
+        memkv::StorageOptions::from_config(settings)?,
+    )?;
+    println!(
+        "restored backup {} and replayed {} writes up to sequence {} into {}",
+        report.backup_sequence, report.replayed_records, report.position.sequence, storage_path
+    );
+    if let Some(superseded_directory) = report.superseded_directory {
+        println!(
+            "later archived writes were moved to {}",
+            superseded_directory.display()
+        );
+    }
+    return Ok(());
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn error::Error>> {
-    let (tx, rx) = mpsc::channel::<Message>();
-
-    let (key_tx, key_rx) = mpsc::channel::<&str>();
-
     log4rs::init_file("config/log4rs.yml", Default::default()).unwrap();
     // let mut handles = vec![];
     let _node_id = Uuid::new_v4();
 
-    let mut seeds = load_seeds("config/seeds");
-    if Path::new("keyspace").exists() {
-        let _return = fs::remove_file("keyspace");
+    let _seeds = load_seeds("config/seeds");
+    let settings = config::Config::builder()
+        .add_source(config::File::with_name("config/rdkv").required(false))
+        .build()?;
+    let address = settings
+        .get_string("server.address")
+        .unwrap_or_else(|_| String::from(DEFAULT_ADDRESS));
+    let storage_path = settings
+        .get_string("storage.path")
+        .unwrap_or_else(|_| String::from(DEFAULT_STORAGE_PATH));
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    match arguments.first().map(|argument| argument.as_str()) {
+        Some("restore") => return restore(&settings, &storage_path, &arguments[1..]),
+        Some("recover") => return recover(&settings, &storage_path, &arguments[1..]),
+        _ => {}
     }
 
-    let mut kvmap = memkv::MemKvPage::new(Path::new("keyspace"))?;
-
-    thread::spawn(move || {
-        let vals = vec![
-            String::from("apple"),
-            String::from("banana"),
-            String::from("organe"),
-            String::from("strawberry"),
-        ];
-
-        for val in vals {
-            tx.send(Message {
-                key: val.clone(),
-                value: memkv::Value::String(val.clone().to_uppercase()),
-            })
-            .unwrap();
-            thread::sleep(Duration::from_secs(1));
-        }
-    });
-
-    let mut sign = Arc::new(kvmap);
-
-    thread::spawn(move || loop {
-        for key in &key_rx {
-            println!("test {:?}", sign.get(&key));
+    let engine = memkv::open_storage_engine(
+        memkv::StorageEngineKind::from_config(&settings)?,
+        Path::new(&storage_path),
+        memkv::StorageOptions::from_config(&settings)?,
+    )?;
+    // Logged writes can be replayed onto a backup, see `rdkv recover`
+    let engine = match (
+        settings.get_string("wal.path"),
+        settings.get_string("wal.archive_path"),
+    ) {
+        (Ok(wal_path), Ok(archive_path)) => {
+            let log = memkv::write_ahead_log::WriteAheadLog::open(
+                Path::new(&wal_path),
+                Path::new(&archive_path),
+            )?;
+            Box::new(memkv::write_ahead_log::LoggedEngine::new(engine, log))
         }
-        thread::sleep(Duration::from_secs(1));
-    });
-
-    loop {
-        for received in &rx {
-            println!("Got: {}", received.key);
+        _ => engine,
+    };
+    let engine: server::SharedEngine = Arc::new(Mutex::new(engine));
 
-            sign.insert(&received.key, received.value)?;
-            key_tx.send(&received.key)?;
+    // The binary protocol is always served, the others only if they have an address
+    let mut servers =
+        vec![server::TcpServer::bind(&address, Protocol::Binary, engine.clone()).await?];
+    if let Ok(resp_address) = settings.get_string("server.resp_address") {
+        servers.push(server::TcpServer::bind(&resp_address, Protocol::Resp, engine.clone()).await?);
+    }
+    if let Ok(memcached_address) = settings.get_string("server.memcached_address") {
+        servers.push(
+            server::TcpServer::bind(&memcached_address, Protocol::Memcached, engine.clone())
+                .await?,
+        );
+    }
+    if let Ok(http_address) = settings.get_string("server.http_address") {
+        let mut server =
+            server::TcpServer::bind(&http_address, Protocol::Http, engine.clone()).await?;
+        if let Ok(backup_path) = settings.get_string("backup.path") {
+            server.set_backup_directory(Path::new(&backup_path));
         }
-        thread::sleep(Duration::from_secs(1));
+        servers.push(server);
     }
-
-    /*kvmap.insert("albert", kv_mmap::Value::String(String::from("value")))?;
-    kvmap.insert("peter", kv_mmap::Value::Integer(123))?;
-    kvmap
-        .insert(
-            "tom",
-            kv_mmap::Value::String(String::from("my third value")),
-        )
-        .unwrap();
-    let person_a = Person {
-        name: String::from("peter pan"),
-        age: 20,
-        phones: vec![],
-    };
-    kvmap.insert("dan", kv_mmap::Value::Blob(person_a))?;
-    println!("{}", seeds.len());*/
+    futures::future::try_join_all(servers.into_iter().map(|server| server.run())).await?;
     return Ok(());
+
     /* let (sender, mut node_seed_receiver) = watch::channel(vec![]);
 
     handles.push(tokio::spawn(async move {
@@ -0,0 +1,706 @@
+use super::errors::KvError;
+use super::mem_kv_page::{get_sidecar_path, SIDECAR_EXTENSIONS};
+use super::storage_engine::{StorageEngine, StorageEngineKind};
+use super::write_ahead_log::WalPosition;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+const BACKUP_DIRECTORY_PREFIX: &str = "backup-";
+const FILES_DIRECTORY_NAME: &str = "files";
+// Directory in the staging directory of a page restore holding the replaced live files
+const PREVIOUS_DIRECTORY_NAME: &str = "previous";
+const COPY_BUFFER_SIZE: usize = 1024 * 64; // 64 KB
+
+// Name of the page file in checkpoints, sidecars append their extension
+const PAGE_FILE_NAME: &str = "page";
+
+/// Files an engine froze for a backup, see `StorageEngine::checkpoint`
+pub struct Checkpoint {
+    pub kind: StorageEngineKind,
+    pub files: Vec<CheckpointFile>,
+    // Last logged write contained in the files, if writes are logged
+    pub wal_position: Option<WalPosition>,
+}
+
+pub struct CheckpointFile {
+    // Name in the checkpoint directory and in the backup
+    pub name: String,
+    // Set for files which never change once written, see `BackupFile::sequence`
+    pub sequence: Option<u64>,
+}
+
+impl Checkpoint {
+    // Pages are small enough to be copied while the engine is locked
+    pub fn from_page(path: &Path, directory: &Path) -> Result<Checkpoint, KvError> {
+        let mut files = vec![];
+        for (name, live_path) in get_page_files(path) {
+            fs::copy(&live_path, directory.join(&name))?;
+            files.push(CheckpointFile {
+                name,
+                sequence: None,
+            });
+        }
+        return Ok(Checkpoint {
+            kind: StorageEngineKind::Page,
+            files,
+            wal_position: None,
+        });
+    }
+}
+
+// Names in the backup and live paths of a page and its existing sidecars
+fn get_page_files(path: &Path) -> Vec<(String, PathBuf)> {
+    let mut files = vec![(String::from(PAGE_FILE_NAME), PathBuf::from(path))];
+    for extension in SIDECAR_EXTENSIONS {
+        let sidecar_path = get_sidecar_path(path, extension);
+        if sidecar_path.exists() {
+            files.push((format!("{}.{}", PAGE_FILE_NAME, extension), sidecar_path));
+        }
+    }
+    return files;
+}
+
+// Live path of a page file named as in `get_page_files`
+fn get_live_page_path(path: &Path, name: &str) -> Option<PathBuf> {
+    return match name.strip_prefix(PAGE_FILE_NAME) {
+        Some("") => Some(PathBuf::from(path)),
+        Some(extension) => extension
+            .strip_prefix('.')
+            .map(|extension| get_sidecar_path(path, extension)),
+        None => None,
+    };
+}
+
+/// Hard links `source` to `target`, files on another file system are copied instead
+pub fn link_or_copy(source: &Path, target: &Path) -> Result<(), KvError> {
+    if fs::hard_link(source, target).is_err() {
+        fs::copy(source, target)?;
+    }
+    return Ok(());
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BackupManifest {
+    pub sequence: u64,
+    // Backup the unchanged files of an incremental backup are taken from
+    pub base_sequence: Option<u64>,
+    pub engine: String,
+    // Seconds since the unix epoch
+    pub created_at: u64,
+    pub files: Vec<BackupFile>,
+    // Base for point in time recovery, see `recovery::recover`
+    #[serde(default)]
+    pub wal_position: Option<WalPosition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    // Hex encoded sha256 of the content
+    pub checksum: String,
+    // Backup which holds the content, earlier than the manifest for unchanged files
+    pub stored_in: u64,
+    // Sequence of files which never change once written, like the tables of an lsm tree.
+    // Later backups refer to the stored file without reading it while the sequence matches.
+    #[serde(default)]
+    pub sequence: Option<u64>,
+}
+
+impl BackupManifest {
+    pub fn is_incremental(self: &Self) -> bool {
+        return self.base_sequence.is_some();
+    }
+
+    pub fn get_size(self: &Self) -> u64 {
+        return self.files.iter().map(|file| file.size).sum();
+    }
+}
+
+fn get_backup_path(root: &Path, sequence: u64) -> PathBuf {
+    return root.join(format!("{}{:06}", BACKUP_DIRECTORY_PREFIX, sequence));
+}
+
+fn get_stored_path(root: &Path, file: &BackupFile) -> PathBuf {
+    return get_backup_path(root, file.stored_in)
+        .join(FILES_DIRECTORY_NAME)
+        .join(&file.name);
+}
+
+fn invalid_backup(path: &Path, reason: &str) -> KvError {
+    return KvError::InvalidBackup {
+        path: PathBuf::from(path),
+        reason: String::from(reason),
+    };
+}
+
+// Copies `reader` into `writer` and returns the size and checksum of the data
+fn copy_and_hash(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<(u64, String), KvError> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+    let checksum = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    return Ok((size, checksum));
+}
+
+fn copy_file(source: &Path, target: &Path) -> Result<(u64, String), KvError> {
+    let mut writer = BufWriter::new(File::create(target)?);
+    let result = copy_and_hash(&mut File::open(source)?, &mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    return Ok(result);
+}
+
+// Sequences of all backup directories in `root` in ascending order, readable or not
+fn get_backup_sequences(root: &Path) -> Result<Vec<u64>, KvError> {
+    let mut sequences = vec![];
+    if !root.exists() {
+        return Ok(sequences);
+    }
+    for dir_entry in fs::read_dir(root)? {
+        let file_name = dir_entry?.file_name();
+        // Backups which are still being written have a suffix and don't parse
+        if let Some(sequence) = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(BACKUP_DIRECTORY_PREFIX))
+            .and_then(|sequence| sequence.parse::<u64>().ok())
+        {
+            sequences.push(sequence);
+        }
+    }
+    sequences.sort();
+    return Ok(sequences);
+}
+
+/// Returns the manifests of all backups in `root`, ordered by sequence. Backups with an
+/// unreadable manifest are skipped with a warning.
+pub fn list_backups(root: &Path) -> Result<Vec<BackupManifest>, KvError> {
+    let mut manifests = vec![];
+    for sequence in get_backup_sequences(root)? {
+        match read_manifest(root, sequence) {
+            Ok(manifest) => manifests.push(manifest),
+            Err(error) => warn!("Skipping backup {} in {:?}: {}", sequence, root, error),
+        }
+    }
+    return Ok(manifests);
+}
+
+pub fn read_manifest(root: &Path, sequence: u64) -> Result<BackupManifest, KvError> {
+    let path = get_backup_path(root, sequence).join(MANIFEST_FILE_NAME);
+    if !path.exists() {
+        return Err(invalid_backup(&path, "manifest does not exist"));
+    }
+    let manifest: BackupManifest = serde_json::from_slice(&fs::read(&path)?)
+        .map_err(|error| invalid_backup(&path, &error.to_string()))?;
+    if manifest.sequence != sequence {
+        return Err(invalid_backup(
+            &path,
+            "sequence does not match the directory",
+        ));
+    }
+    return Ok(manifest);
+}
+
+/// Checks that every file of the manifest is stored with its size and checksum. File names
+/// must not leave the data directory when they are restored.
+pub fn verify_backup(root: &Path, manifest: &BackupManifest) -> Result<(), KvError> {
+    let backup_path = get_backup_path(root, manifest.sequence);
+    manifest.engine.parse::<StorageEngineKind>()?;
+    for file in &manifest.files {
+        if file.name.is_empty() || file.name.contains(['/', '\\']) || file.name.starts_with('.') {
+            return Err(invalid_backup(
+                &backup_path,
+                &format!("invalid file name {:?}", file.name),
+            ));
+        }
+        if file.stored_in > manifest.sequence {
+            return Err(invalid_backup(
+                &backup_path,
+                &format!("{:?} is stored in a later backup", file.name),
+            ));
+        }
+        let path = get_stored_path(root, file);
+        if !path.exists() {
+            return Err(invalid_backup(&path, "file is missing"));
+        }
+        let (size, checksum) = copy_and_hash(&mut File::open(&path)?, &mut io::sink())?;
+        if size != file.size || checksum != file.checksum {
+            return Err(invalid_backup(
+                &path,
+                "checksum does not match the manifest",
+            ));
+        }
+    }
+    return Ok(());
+}
+
+/// Backup whose checkpoint is taken but whose files are not stored yet, see `start_backup`
+pub struct PendingBackup {
+    sequence: u64,
+    base: Option<BackupManifest>,
+    checkpoint: Checkpoint,
+    // Directory of the backup until the manifest is written
+    temporary_path: PathBuf,
+    backup_path: PathBuf,
+}
+
+/// Backs up a consistent copy of the engine into a new directory in `root`. Incremental
+/// backups only store files which changed since the latest backup and refer to it for the
+/// others.
+pub fn create_backup(
+    engine: &mut dyn StorageEngine,
+    root: &Path,
+    incremental: bool,
+) -> Result<BackupManifest, KvError> {
+    return finish_backup(start_backup(engine, root, incremental)?);
+}
+
+/// Takes the checkpoint of a new backup in `root`. Only this step needs the engine, so a
+/// running server locks the engine for it and writes go on while `finish_backup` stores
+/// the files. Backups into the same `root` must not run at the same time.
+pub fn start_backup(
+    engine: &mut dyn StorageEngine,
+    root: &Path,
+    incremental: bool,
+) -> Result<PendingBackup, KvError> {
+    fs::create_dir_all(root)?;
+    // Unreadable backups still take up their sequence
+    let sequence = get_backup_sequences(root)?
+        .last()
+        .map_or(1, |sequence| sequence + 1);
+    let base = match incremental {
+        true => list_backups(root)?.pop(),
+        false => None,
+    };
+
+    // The backup only becomes visible once the manifest is written
+    let backup_path = get_backup_path(root, sequence);
+    let temporary_path = backup_path.with_extension("tmp");
+    if temporary_path.exists() {
+        fs::remove_dir_all(&temporary_path)?;
+    }
+    let files_path = temporary_path.join(FILES_DIRECTORY_NAME);
+    fs::create_dir_all(&files_path)?;
+    let checkpoint = match engine.checkpoint(&files_path) {
+        Ok(checkpoint) => checkpoint,
+        Err(error) => {
+            fs::remove_dir_all(&temporary_path)?;
+            return Err(error);
+        }
+    };
+    return Ok(PendingBackup {
+        sequence,
+        base,
+        checkpoint,
+        temporary_path,
+        backup_path,
+    });
+}
+
+/// Stores the checkpointed files of `backup` and makes it visible. Files of the base backup
+/// with the same sequence are not read again, neither are any files if no logged write
+/// happened since the base backup.
+pub fn finish_backup(backup: PendingBackup) -> Result<BackupManifest, KvError> {
+    let checkpoint = &backup.checkpoint;
+    let is_unchanged = checkpoint.wal_position.is_some()
+        && backup.base.as_ref().map(|base| base.wal_position) == Some(checkpoint.wal_position);
+    let mut manifest = BackupManifest {
+        sequence: backup.sequence,
+        base_sequence: backup.base.as_ref().map(|base| base.sequence),
+        engine: String::from(checkpoint.kind.get_name()),
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+        files: vec![],
+        wal_position: checkpoint.wal_position,
+    };
+    let files_path = backup.temporary_path.join(FILES_DIRECTORY_NAME);
+    for file in &checkpoint.files {
+        let path = files_path.join(&file.name);
+        let unchanged = backup.base.as_ref().and_then(|base| {
+            base.files.iter().find(|stored| {
+                stored.name == file.name
+                    && (is_unchanged
+                        || (file.sequence.is_some() && stored.sequence == file.sequence))
+            })
+        });
+        let backup_file = match unchanged {
+            Some(stored) => {
+                fs::remove_file(&path)?;
+                BackupFile {
+                    sequence: file.sequence,
+                    ..stored.clone()
+                }
+            }
+            None => {
+                let mut reader = File::open(&path)?;
+                let (size, checksum) = copy_and_hash(&mut reader, &mut io::sink())?;
+                reader.sync_all()?;
+                BackupFile {
+                    name: file.name.clone(),
+                    size,
+                    checksum,
+                    stored_in: backup.sequence,
+                    sequence: file.sequence,
+                }
+            }
+        };
+        manifest.files.push(backup_file);
+    }
+
+    let manifest_path = backup.temporary_path.join(MANIFEST_FILE_NAME);
+    let mut manifest_file = File::create(&manifest_path)?;
+    manifest_file.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    manifest_file.sync_all()?;
+    fs::rename(&backup.temporary_path, &backup.backup_path)?;
+    info!(
+        "Created backup {} with {} files in {:?}",
+        backup.sequence,
+        manifest.files.len(),
+        backup.backup_path
+    );
+    return Ok(manifest);
+}
+
+/// Replaces the data of a stopped engine at `path` with a backup, the latest one if
+/// `sequence` is `None`. The backup is verified and copied next to `path` before any live
+/// file is touched, live files are only removed once the restored ones are in place.
+pub fn restore_backup(
+    root: &Path,
+    sequence: Option<u64>,
+    kind: StorageEngineKind,
+    path: &Path,
+) -> Result<BackupManifest, KvError> {
+    let manifest = match sequence {
+        Some(sequence) => read_manifest(root, sequence)?,
+        None => match list_backups(root)?.pop() {
+            Some(manifest) => manifest,
+            None => return Err(invalid_backup(root, "no backups found")),
+        },
+    };
+    if manifest.engine.parse::<StorageEngineKind>()? != kind {
+        return Err(invalid_backup(
+            &get_backup_path(root, manifest.sequence),
+            &format!(
+                "backup of a {} engine can't be restored into a {} engine",
+                manifest.engine,
+                kind.get_name()
+            ),
+        ));
+    }
+    verify_backup(root, &manifest)?;
+
+    let mut staging_path = path.as_os_str().to_os_string();
+    staging_path.push(".restore");
+    let staging_path = PathBuf::from(staging_path);
+    if staging_path.exists() {
+        fs::remove_dir_all(&staging_path)?;
+    }
+    fs::create_dir_all(&staging_path)?;
+    for file in &manifest.files {
+        let (size, checksum) =
+            copy_file(&get_stored_path(root, file), &staging_path.join(&file.name))?;
+        if size != file.size || checksum != file.checksum {
+            return Err(invalid_backup(
+                &get_stored_path(root, file),
+                "file changed during the restore",
+            ));
+        }
+    }
+
+    match kind {
+        StorageEngineKind::Page => {
+            let previous_path = staging_path.join(PREVIOUS_DIRECTORY_NAME);
+            fs::create_dir(&previous_path)?;
+            if let Err(error) = swap_page_files(&manifest, &staging_path, path) {
+                rollback_page_files(&manifest, &staging_path, path)?;
+                return Err(error);
+            }
+            fs::remove_dir_all(&staging_path)?;
+        }
+        _ => {
+            let mut previous_path = path.as_os_str().to_os_string();
+            previous_path.push(".previous");
+            let previous_path = PathBuf::from(previous_path);
+            if previous_path.exists() {
+                fs::remove_dir_all(&previous_path)?;
+            }
+            if path.exists() {
+                fs::rename(path, &previous_path)?;
+            }
+            if let Err(error) = fs::rename(&staging_path, path) {
+                if previous_path.exists() {
+                    fs::rename(&previous_path, path)?;
+                }
+                return Err(KvError::Io(error));
+            }
+            if previous_path.exists() {
+                fs::remove_dir_all(&previous_path)?;
+            }
+        }
+    }
+    info!("Restored backup {} into {:?}", manifest.sequence, path);
+    return Ok(manifest);
+}
+
+// Moves the live page files into the previous directory of the staging directory and the
+// staged files into their place
+fn swap_page_files(
+    manifest: &BackupManifest,
+    staging_path: &Path,
+    path: &Path,
+) -> Result<(), KvError> {
+    let mut live_paths = vec![];
+    for file in &manifest.files {
+        match get_live_page_path(path, &file.name) {
+            Some(live_path) => live_paths.push(live_path),
+            None => {
+                return Err(invalid_backup(
+                    &staging_path.join(&file.name),
+                    "unknown page file",
+                ))
+            }
+        }
+    }
+    // Sidecars which are not in the backup would describe the old page
+    let previous_path = staging_path.join(PREVIOUS_DIRECTORY_NAME);
+    for (name, live_path) in get_page_files(path) {
+        if live_path.exists() {
+            fs::rename(live_path, previous_path.join(name))?;
+        }
+    }
+    for (file, live_path) in manifest.files.iter().zip(live_paths) {
+        fs::rename(staging_path.join(&file.name), live_path)?;
+    }
+    return Ok(());
+}
+
+// Undoes a partial `swap_page_files`, staged files which were already moved are removed
+fn rollback_page_files(
+    manifest: &BackupManifest,
+    staging_path: &Path,
+    path: &Path,
+) -> Result<(), KvError> {
+    for file in &manifest.files {
+        if let Some(live_path) = get_live_page_path(path, &file.name) {
+            if !staging_path.join(&file.name).exists() && live_path.exists() {
+                fs::remove_file(live_path)?;
+            }
+        }
+    }
+    for dir_entry in fs::read_dir(staging_path.join(PREVIOUS_DIRECTORY_NAME))? {
+        let dir_entry = dir_entry?;
+        if let Some(live_path) = dir_entry
+            .file_name()
+            .to_str()
+            .and_then(|name| get_live_page_path(path, name))
+        {
+            fs::rename(dir_entry.path(), live_path)?;
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_backup, finish_backup, list_backups, restore_backup, start_backup, verify_backup,
+    };
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{
+        open_storage_engine, KvError, MemKvPage, StorageEngineKind, StorageOptions, Value,
+    };
+    use std::fs;
+    use std::path::Path;
+
+    const BACKUPS: &str = "test_backups";
+
+    #[test]
+    fn test_backup_and_restore() {
+        const PAGE: &str = "test_backup_page";
+        if Path::new(BACKUPS).exists() {
+            fs::remove_dir_all(BACKUPS).unwrap();
+        }
+        let root = Path::new(BACKUPS);
+        let mut page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        page.insert("a", Value::Integer(1)).unwrap();
+        let full = create_backup(&mut page, root, true).unwrap();
+        assert_eq!((full.sequence, full.base_sequence), (1, None));
+        assert_eq!(full.files[0].name, "page");
+
+        // The page changed, so the incremental backup stores it again
+        page.insert("b", Value::Integer(2)).unwrap();
+        let incremental = create_backup(&mut page, root, true).unwrap();
+        assert_eq!(incremental.base_sequence, Some(1));
+        assert_eq!(incremental.files[0].stored_in, 2);
+        page.delete("a").unwrap();
+        drop(page);
+
+        restore_backup(root, Some(2), StorageEngineKind::Page, Path::new(PAGE)).unwrap();
+        let page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        assert_eq!(page.get("a").unwrap(), Value::Integer(1));
+        assert_eq!(page.get("b").unwrap(), Value::Integer(2));
+        drop(page);
+        restore_backup(root, None, StorageEngineKind::Page, Path::new(PAGE)).unwrap();
+        assert!(restore_backup(root, Some(1), StorageEngineKind::Lsm, Path::new(PAGE)).is_err());
+
+        // Corrupted files are found before anything is replaced
+        let manifests = list_backups(root).unwrap();
+        assert_eq!(manifests.len(), 2);
+        let stored = root.join("backup-000001").join("files").join("page");
+        let mut data = fs::read(&stored).unwrap();
+        data[100] ^= 0xff;
+        fs::write(&stored, data).unwrap();
+        assert!(verify_backup(root, &manifests[0]).is_err());
+        assert!(matches!(
+            restore_backup(root, Some(1), StorageEngineKind::Page, Path::new(PAGE)),
+            Err(KvError::InvalidBackup { .. })
+        ));
+        let page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        assert_eq!(page.get("b").unwrap(), Value::Integer(2));
+        drop(page);
+        fs::remove_dir_all(BACKUPS).unwrap();
+        fs::remove_file(PAGE).unwrap();
+        fs::remove_file(format!("{}.bloom", PAGE)).unwrap();
+
+        const LSM: &str = "test_backup_lsm";
+        let mut tree = open_storage_engine(
+            StorageEngineKind::Lsm,
+            Path::new(LSM),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        tree.put("a", Value::Integer(1)).unwrap();
+        create_backup(tree.as_mut(), root, false).unwrap();
+        tree.put("a", Value::Integer(2)).unwrap();
+        drop(tree);
+        restore_backup(root, None, StorageEngineKind::Lsm, Path::new(LSM)).unwrap();
+        let tree = open_storage_engine(
+            StorageEngineKind::Lsm,
+            Path::new(LSM),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(tree.get("a").unwrap(), Value::Integer(1));
+        drop(tree);
+        fs::remove_dir_all(LSM).unwrap();
+
+        let mut memory = open_storage_engine(
+            StorageEngineKind::Memory,
+            Path::new(""),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        assert!(create_backup(memory.as_mut(), root, false).is_err());
+        fs::remove_dir_all(BACKUPS).unwrap();
+    }
+
+    #[test]
+    fn test_online_backup() {
+        const ROOT: &str = "test_online_backups";
+        const LSM: &str = "test_online_backup_lsm";
+        for directory in [ROOT, LSM] {
+            if Path::new(directory).exists() {
+                fs::remove_dir_all(directory).unwrap();
+            }
+        }
+        let root = Path::new(ROOT);
+        let mut tree = open_storage_engine(
+            StorageEngineKind::Lsm,
+            Path::new(LSM),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        tree.put("a", Value::Integer(1)).unwrap();
+        let full = create_backup(tree.as_mut(), root, false).unwrap();
+
+        // Writes after the checkpoint are not part of the backup
+        tree.put("b", Value::Integer(2)).unwrap();
+        let pending = start_backup(tree.as_mut(), root, true).unwrap();
+        tree.put("a", Value::Integer(3)).unwrap();
+        tree.flush().unwrap();
+        let incremental = finish_backup(pending).unwrap();
+
+        // The table of the full backup is referred to by its sequence
+        assert_eq!(incremental.files.len(), 2);
+        let table = incremental
+            .files
+            .iter()
+            .find(|file| file.name == full.files[0].name)
+            .unwrap();
+        assert_eq!(
+            (table.stored_in, table.sequence),
+            (1, full.files[0].sequence)
+        );
+        verify_backup(root, &incremental).unwrap();
+
+        drop(tree);
+        restore_backup(root, None, StorageEngineKind::Lsm, Path::new(LSM)).unwrap();
+        let tree = open_storage_engine(
+            StorageEngineKind::Lsm,
+            Path::new(LSM),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(tree.get("a").unwrap(), Value::Integer(1));
+        assert_eq!(tree.get("b").unwrap(), Value::Integer(2));
+        drop(tree);
+        fs::remove_dir_all(ROOT).unwrap();
+        fs::remove_dir_all(LSM).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_manifest() {
+        const ROOT: &str = "test_corrupted_manifest_backups";
+        const PAGE: &str = "test_corrupted_manifest_page";
+        if Path::new(ROOT).exists() {
+            fs::remove_dir_all(ROOT).unwrap();
+        }
+        let _page_files = PageFilesGuard::new(Path::new(PAGE)).unwrap();
+        let root = Path::new(ROOT);
+        let mut page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        page.insert("a", Value::Integer(1)).unwrap();
+        create_backup(&mut page, root, false).unwrap();
+        page.insert("b", Value::Integer(2)).unwrap();
+        create_backup(&mut page, root, false).unwrap();
+        fs::write(root.join("backup-000002").join("manifest.json"), b"{").unwrap();
+
+        // The broken backup is skipped but its sequence is not reused
+        let manifests = list_backups(root).unwrap();
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0].sequence, 1);
+        assert_eq!(create_backup(&mut page, root, true).unwrap().sequence, 3);
+        page.insert("c", Value::Integer(3)).unwrap();
+        drop(page);
+
+        restore_backup(root, None, StorageEngineKind::Page, Path::new(PAGE)).unwrap();
+        assert!(!Path::new(&format!("{}.restore", PAGE)).exists());
+        let page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        assert_eq!(page.get("b").unwrap(), Value::Integer(2));
+        assert!(page.get("c").is_err());
+        drop(page);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+// Set in the entry header flags when the value data only holds a reference
+// into the blob file of the page
+pub const FLAG_OVERFLOW: u8 = 0x10;
+
+pub const BLOB_REFERENCE_SIZE: usize = size_of::<u64>() * 2;
+
+// The blob file is append only, space of deleted values is not reclaimed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlobReference {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl BlobReference {
+    pub fn to_bytes(self: &Self) -> Vec<u8> {
+        return [self.offset.to_be_bytes(), self.length.to_be_bytes()].concat();
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<BlobReference, io::Error> {
+        if data.len() != BLOB_REFERENCE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid blob reference",
+            ));
+        }
+        let (offset, length) = data.split_at(size_of::<u64>());
+        return Ok(BlobReference {
+            offset: u64::from_be_bytes(offset.try_into().unwrap()),
+            length: u64::from_be_bytes(length.try_into().unwrap()),
+        });
+    }
+}
+
+/// Streams a single value to the end of the blob file
+pub struct BlobWriter {
+    file: File,
+    reference: BlobReference,
+}
+
+impl BlobWriter {
+    pub fn new(path: &Path) -> Result<BlobWriter, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let offset = file.metadata()?.len();
+        return Ok(BlobWriter {
+            file,
+            reference: BlobReference { offset, length: 0 },
+        });
+    }
+
+    pub fn finish(self: Self) -> Result<BlobReference, io::Error> {
+        self.file.sync_data()?;
+        return Ok(self.reference);
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.reference.length += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.file.flush();
+    }
+}
+
+pub fn append(path: &Path, data: &[u8]) -> Result<BlobReference, io::Error> {
+    let mut writer = BlobWriter::new(path)?;
+    writer.write_all(data)?;
+    return writer.finish();
+}
+
+pub fn reader(path: &Path, reference: &BlobReference) -> Result<io::Take<File>, io::Error> {
+    let mut file = File::open(path)?;
+    // References are read from the page, so they are checked against the file before use
+    let file_size = file.metadata()?.len();
+    let end = reference.offset.checked_add(reference.length);
+    if end.is_none_or(|end| end > file_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "blob reference is out of bounds",
+        ));
+    }
+    file.seek(SeekFrom::Start(reference.offset))?;
+    return Ok(file.take(reference.length));
+}
+
+pub fn read(path: &Path, reference: &BlobReference) -> Result<Vec<u8>, io::Error> {
+    // The reference is checked before anything is allocated for it
+    let mut reader = reader(path, reference)?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    return Ok(data);
+}
@@ -0,0 +1,115 @@
+use memmap::MmapMut;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem::size_of;
+use std::path::Path;
+
+const NUM_HASHES: u64 = 7;
+// Bounds the work per key for filters with a corrupted header
+const MAX_HASHES: u64 = 64;
+const MIN_BITS: u64 = 1024;
+// Header of the filter file holding the number of bits and hash functions
+const FILTER_HEADER_SIZE: usize = size_of::<u64>() * 2;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+const SECOND_HASH_SEED: u64 = 0x9e3779b97f4a7c15;
+
+/// Bloom filter over the keys of a page, kept in its own memory mapped file so bits
+/// are persisted together with the page. Deleted keys are never removed, which only
+/// causes false positives until the filter is rebuilt.
+pub struct BloomFilter {
+    mmap: MmapMut,
+    num_bits: u64,
+    num_hashes: u64,
+}
+
+impl BloomFilter {
+    // Sized to roughly 16 bits per key for pages of small entries
+    pub fn bits_for_page_size(page_size: u64) -> u64 {
+        let num_bits = (page_size / 4).max(MIN_BITS);
+        return num_bits.div_ceil(8) * 8;
+    }
+
+    pub fn create(path: &Path, num_bits: u64) -> Result<BloomFilter, io::Error> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(FILTER_HEADER_SIZE as u64 + num_bits / 8)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&f)? };
+        mmap[..size_of::<u64>()].copy_from_slice(&num_bits.to_be_bytes());
+        mmap[size_of::<u64>()..FILTER_HEADER_SIZE].copy_from_slice(&NUM_HASHES.to_be_bytes());
+        return Ok(BloomFilter {
+            mmap,
+            num_bits,
+            num_hashes: NUM_HASHES,
+        });
+    }
+
+    pub fn open(path: &Path) -> Result<BloomFilter, io::Error> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        let mmap = unsafe { MmapMut::map_mut(&f)? };
+        let invalid_filter = io::Error::new(io::ErrorKind::InvalidData, "invalid bloom filter");
+        if mmap.len() < FILTER_HEADER_SIZE {
+            return Err(invalid_filter);
+        }
+        let num_bits = u64::from_be_bytes(mmap[..size_of::<u64>()].try_into().unwrap());
+        let num_hashes = u64::from_be_bytes(
+            mmap[size_of::<u64>()..FILTER_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        if num_bits == 0
+            || num_bits % 8 != 0
+            || mmap.len() as u64 != FILTER_HEADER_SIZE as u64 + num_bits / 8
+            || !(1..=MAX_HASHES).contains(&num_hashes)
+        {
+            return Err(invalid_filter);
+        }
+        return Ok(BloomFilter {
+            mmap,
+            num_bits,
+            num_hashes,
+        });
+    }
+
+    pub fn get_num_bits(self: &Self) -> u64 {
+        return self.num_bits;
+    }
+
+    pub fn insert(self: &mut Self, key: &str) {
+        for bit in get_bits(key, self.num_bits, self.num_hashes) {
+            self.mmap[FILTER_HEADER_SIZE + (bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns false only if `key` was definitely never inserted
+    pub fn may_contain(self: &Self, key: &str) -> bool {
+        return get_bits(key, self.num_bits, self.num_hashes)
+            .all(|bit| self.mmap[FILTER_HEADER_SIZE + (bit / 8) as usize] & (1 << (bit % 8)) != 0);
+    }
+
+    pub fn flush(self: &Self) -> Result<(), io::Error> {
+        return self.mmap.flush();
+    }
+}
+
+// Double hashing to derive all positions from two hashes of the key
+fn get_bits(key: &str, num_bits: u64, num_hashes: u64) -> impl Iterator<Item = u64> {
+    let first_hash = fnv1a(key.as_bytes(), 0);
+    let second_hash = fnv1a(key.as_bytes(), SECOND_HASH_SEED) | 1;
+    return (0..num_hashes)
+        .map(move |i| first_hash.wrapping_add(i.wrapping_mul(second_hash)) % num_bits);
+}
+
+fn fnv1a(data: &[u8], seed: u64) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS ^ seed;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    return hash;
+}
@@ -0,0 +1,621 @@
+use memmap::MmapMut;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+// Nodes are page aligned, node 0 holds the header of the tree
+pub const NODE_SIZE: usize = 4096;
+// Guarantees a node split by bytes leaves both halves within a node
+pub const MAX_KEY_SIZE: usize = 1024;
+const MAGIC: &[u8; 8] = b"RDKVBPT1";
+const INITIAL_NODE_CAPACITY: u64 = 16;
+// Internal nodes have at least two children, so deeper paths only exist in corrupted trees
+const MAX_DEPTH: usize = 64;
+
+const LEAF_NODE: u8 = 0x1;
+const INTERNAL_NODE: u8 = 0x2;
+// Node kind and number of keys
+const NODE_HEADER_SIZE: usize = size_of::<u8>() + size_of::<u16>();
+// Key size, key bytes follow, then the entry offset or child id
+const ENTRY_OVERHEAD: usize = size_of::<u16>() + size_of::<u64>();
+
+// Offsets of the fields in the tree header
+const ROOT_OFFSET: usize = 8;
+const NODE_COUNT_OFFSET: usize = 16;
+const KEY_COUNT_OFFSET: usize = 24;
+const PAGE_OFFSET_OFFSET: usize = 32;
+const CLEAN_OFFSET: usize = 40;
+
+enum Node {
+    Leaf {
+        keys: Vec<String>,
+        offsets: Vec<u64>,
+    },
+    // keys[i] is the smallest key below children[i + 1]
+    Internal {
+        keys: Vec<String>,
+        children: Vec<u64>,
+    },
+}
+
+impl Node {
+    fn get_encoded_size(self: &Self) -> usize {
+        let (keys, extra) = match self {
+            Node::Leaf { keys, .. } => (keys, 0),
+            Node::Internal { keys, .. } => (keys, size_of::<u64>()),
+        };
+        return NODE_HEADER_SIZE
+            + extra
+            + keys
+                .iter()
+                .map(|key| key.len() + ENTRY_OVERHEAD)
+                .sum::<usize>();
+    }
+
+    fn encode(self: &Self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(NODE_SIZE);
+        let (kind, keys, values, first_child) = match self {
+            Node::Leaf { keys, offsets } => (LEAF_NODE, keys, &offsets[..], None),
+            Node::Internal { keys, children } => {
+                (INTERNAL_NODE, keys, &children[1..], Some(children[0]))
+            }
+        };
+        data.push(kind);
+        data.extend((keys.len() as u16).to_be_bytes());
+        if let Some(first_child) = first_child {
+            data.extend(first_child.to_be_bytes());
+        }
+        for (key, value) in keys.iter().zip(values) {
+            data.extend((key.len() as u16).to_be_bytes());
+            data.extend(key.as_bytes());
+            data.extend(value.to_be_bytes());
+        }
+        return data;
+    }
+
+    fn decode(data: &[u8]) -> Result<Node, io::Error> {
+        let kind = data[0];
+        let count = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let mut position = NODE_HEADER_SIZE;
+        let mut keys = Vec::with_capacity(count);
+        let mut values = Vec::with_capacity(count + 1);
+        if kind == INTERNAL_NODE {
+            values.push(read_u64(data, position)?);
+            position += size_of::<u64>();
+        } else if kind != LEAF_NODE {
+            return Err(corrupted_node());
+        }
+        for _ in 0..count {
+            if position + size_of::<u16>() > data.len() {
+                return Err(corrupted_node());
+            }
+            let key_size = u16::from_be_bytes([data[position], data[position + 1]]) as usize;
+            position += size_of::<u16>();
+            if position + key_size > data.len() {
+                return Err(corrupted_node());
+            }
+            let key = std::str::from_utf8(&data[position..position + key_size])
+                .map_err(|_| corrupted_node())?;
+            keys.push(String::from(key));
+            position += key_size;
+            values.push(read_u64(data, position)?);
+            position += size_of::<u64>();
+        }
+        return Ok(match kind {
+            LEAF_NODE => Node::Leaf {
+                keys,
+                offsets: values,
+            },
+            _ => Node::Internal {
+                keys,
+                children: values,
+            },
+        });
+    }
+
+    // Splits by bytes instead of key count as keys vary in size
+    fn split(self: Self) -> (Node, String, Node) {
+        let half = self.get_encoded_size() / 2;
+        let keys = match &self {
+            Node::Leaf { keys, .. } | Node::Internal { keys, .. } => keys,
+        };
+        let mut accumulated = NODE_HEADER_SIZE;
+        let mut middle = keys.len() / 2;
+        for (i, key) in keys.iter().enumerate() {
+            accumulated += key.len() + ENTRY_OVERHEAD;
+            if accumulated >= half {
+                middle = i.clamp(1, keys.len() - 1);
+                break;
+            }
+        }
+
+        return match self {
+            Node::Leaf {
+                mut keys,
+                mut offsets,
+            } => {
+                let right_keys = keys.split_off(middle);
+                let right_offsets = offsets.split_off(middle);
+                let separator = right_keys[0].clone();
+                (
+                    Node::Leaf { keys, offsets },
+                    separator,
+                    Node::Leaf {
+                        keys: right_keys,
+                        offsets: right_offsets,
+                    },
+                )
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                // The middle key moves up and is not kept in either half
+                let mut right_keys = keys.split_off(middle);
+                let separator = right_keys.remove(0);
+                let right_children = children.split_off(middle + 1);
+                (
+                    Node::Internal { keys, children },
+                    separator,
+                    Node::Internal {
+                        keys: right_keys,
+                        children: right_children,
+                    },
+                )
+            }
+        };
+    }
+}
+
+// Id the node was written to, the separator and id of the new right sibling if the node
+// was split and whether the key was not in the tree before
+type InsertResult = (u64, Option<(String, u64)>, bool);
+
+fn invalid_index() -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, "invalid B+tree index");
+}
+
+fn read_u64(data: &[u8], position: usize) -> Result<u64, io::Error> {
+    return match data.get(position..position + size_of::<u64>()) {
+        Some(bytes) => Ok(u64::from_be_bytes(bytes.try_into().unwrap())),
+        None => Err(corrupted_node()),
+    };
+}
+
+fn corrupted_node() -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, "corrupted B+tree node");
+}
+
+/// B+tree mapping keys to entry offsets, stored in its own memory mapped file.
+///
+/// Nodes are copy-on-write: a change never touches a node reachable from the root that
+/// was last flushed, so the file always holds a consistent tree. Nodes replaced since the
+/// last flush are reused after the next one, nodes replaced before closing the file are
+/// only reclaimed by rebuilding the index. Deletes don't rebalance the tree.
+pub struct BTreeIndex {
+    path: PathBuf,
+    mmap: MmapMut,
+    root: u64,
+    node_count: u64,
+    key_count: u64,
+    // Nodes written since the last flush may be changed in place
+    fresh_nodes: HashSet<u64>,
+    replaced_nodes: Vec<u64>,
+    free_nodes: Vec<u64>,
+    is_dirty: bool,
+}
+
+impl BTreeIndex {
+    pub fn create(path: &Path) -> Result<BTreeIndex, io::Error> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(INITIAL_NODE_CAPACITY * NODE_SIZE as u64)?;
+        let mut index = BTreeIndex {
+            path: PathBuf::from(path),
+            mmap: unsafe { MmapMut::map_mut(&f)? },
+            root: 0,
+            node_count: 1,
+            key_count: 0,
+            fresh_nodes: HashSet::new(),
+            replaced_nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            is_dirty: true,
+        };
+        index.mmap[..MAGIC.len()].copy_from_slice(MAGIC);
+        index.flush(0)?;
+        return Ok(index);
+    }
+
+    /// Opens an index in constant time, fails if the index was not flushed after its last
+    /// change as it may be missing entries
+    pub fn open(path: &Path) -> Result<BTreeIndex, io::Error> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        let mmap = unsafe { MmapMut::map_mut(&f)? };
+        if mmap.len() < NODE_SIZE || mmap.len() % NODE_SIZE != 0 || &mmap[..MAGIC.len()] != MAGIC {
+            return Err(invalid_index());
+        }
+        if mmap[CLEAN_OFFSET] != 0x1 {
+            return Err(invalid_index());
+        }
+        let index = BTreeIndex {
+            path: PathBuf::from(path),
+            root: read_u64(&mmap, ROOT_OFFSET)?,
+            node_count: read_u64(&mmap, NODE_COUNT_OFFSET)?,
+            key_count: read_u64(&mmap, KEY_COUNT_OFFSET)?,
+            mmap,
+            fresh_nodes: HashSet::new(),
+            replaced_nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            is_dirty: false,
+        };
+        if index.node_count * NODE_SIZE as u64 > index.mmap.len() as u64
+            || index.root >= index.node_count
+        {
+            return Err(invalid_index());
+        }
+        return Ok(index);
+    }
+
+    /// End of the page entries covered by the index at its last flush
+    pub fn get_page_offset(self: &Self) -> u64 {
+        return u64::from_be_bytes(
+            self.mmap[PAGE_OFFSET_OFFSET..PAGE_OFFSET_OFFSET + size_of::<u64>()]
+                .try_into()
+                .unwrap(),
+        );
+    }
+
+    pub fn len(self: &Self) -> usize {
+        return self.key_count as usize;
+    }
+
+    pub fn get(self: &Self, key: &str) -> Result<Option<u64>, io::Error> {
+        if self.root == 0 {
+            return Ok(None);
+        }
+        let mut node_id = self.root;
+        for _ in 0..MAX_DEPTH {
+            match self.read_node(node_id)? {
+                Node::Leaf { keys, offsets } => {
+                    return Ok(keys
+                        .binary_search_by(|probe| probe.as_str().cmp(key))
+                        .ok()
+                        .map(|i| offsets[i]));
+                }
+                Node::Internal { keys, children } => {
+                    node_id = children[keys.partition_point(|probe| probe.as_str() <= key)];
+                }
+            }
+        }
+        return Err(invalid_index());
+    }
+
+    /// Inserts `key` or updates its offset
+    pub fn insert(self: &mut Self, key: &str, offset: u64) -> Result<(), io::Error> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key too large for the B+tree index",
+            ));
+        }
+        self.mark_dirty()?;
+        if self.root == 0 {
+            let leaf = Node::Leaf {
+                keys: vec![String::from(key)],
+                offsets: vec![offset],
+            };
+            self.root = self.write_node(None, &leaf)?;
+            self.key_count = 1;
+            return Ok(());
+        }
+
+        let (node_id, split, is_new_key) = self.insert_into(self.root, key, offset, 0)?;
+        self.root = match split {
+            Some((separator, right_id)) => self.write_node(
+                None,
+                &Node::Internal {
+                    keys: vec![separator],
+                    children: vec![node_id, right_id],
+                },
+            )?,
+            None => node_id,
+        };
+        if is_new_key {
+            self.key_count += 1;
+        }
+        return Ok(());
+    }
+
+    fn insert_into(
+        self: &mut Self,
+        node_id: u64,
+        key: &str,
+        offset: u64,
+        depth: usize,
+    ) -> Result<InsertResult, io::Error> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid_index());
+        }
+        let mut node = self.read_node(node_id)?;
+        let is_new_key = match &mut node {
+            Node::Leaf { keys, offsets } => {
+                match keys.binary_search_by(|probe| probe.as_str().cmp(key)) {
+                    Ok(i) => {
+                        offsets[i] = offset;
+                        false
+                    }
+                    Err(i) => {
+                        keys.insert(i, String::from(key));
+                        offsets.insert(i, offset);
+                        true
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|probe| probe.as_str() <= key);
+                let (child_id, split, is_new_key) =
+                    self.insert_into(children[i], key, offset, depth + 1)?;
+                children[i] = child_id;
+                if let Some((separator, right_id)) = split {
+                    keys.insert(i, separator);
+                    children.insert(i + 1, right_id);
+                }
+                is_new_key
+            }
+        };
+
+        if node.get_encoded_size() <= NODE_SIZE {
+            return Ok((self.write_node(Some(node_id), &node)?, None, is_new_key));
+        }
+        let (left, separator, right) = node.split();
+        let left_id = self.write_node(Some(node_id), &left)?;
+        let right_id = self.write_node(None, &right)?;
+        return Ok((left_id, Some((separator, right_id)), is_new_key));
+    }
+
+    pub fn remove(self: &mut Self, key: &str) -> Result<Option<u64>, io::Error> {
+        let offset = match self.get(key)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        self.mark_dirty()?;
+        self.root = self.remove_from(self.root, key, 0)?;
+        self.key_count -= 1;
+        return Ok(Some(offset));
+    }
+
+    fn remove_from(
+        self: &mut Self,
+        node_id: u64,
+        key: &str,
+        depth: usize,
+    ) -> Result<u64, io::Error> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid_index());
+        }
+        let mut node = self.read_node(node_id)?;
+        match &mut node {
+            Node::Leaf { keys, offsets } => {
+                if let Ok(i) = keys.binary_search_by(|probe| probe.as_str().cmp(key)) {
+                    keys.remove(i);
+                    offsets.remove(i);
+                }
+            }
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|probe| probe.as_str() <= key);
+                children[i] = self.remove_from(children[i], key, depth + 1)?;
+            }
+        }
+        return self.write_node(Some(node_id), &node);
+    }
+
+    /// Returns all keys starting with `prefix` and their offsets in key order
+    pub fn scan(self: &Self, prefix: &str) -> Result<Vec<(String, u64)>, io::Error> {
+        let mut entries = Vec::new();
+        if self.root != 0 {
+            self.collect(self.root, prefix, &mut entries, 0, &mut HashSet::new())?;
+        }
+        return Ok(entries);
+    }
+
+    fn collect(
+        self: &Self,
+        node_id: u64,
+        prefix: &str,
+        entries: &mut Vec<(String, u64)>,
+        depth: usize,
+        visited: &mut HashSet<u64>,
+    ) -> Result<(), io::Error> {
+        // Nodes below the depth bound or reached twice come from a corrupted tree
+        if depth >= MAX_DEPTH || !visited.insert(node_id) {
+            return Err(invalid_index());
+        }
+        match self.read_node(node_id)? {
+            Node::Leaf { keys, offsets } => {
+                for (key, offset) in keys.into_iter().zip(offsets) {
+                    if key.starts_with(prefix) {
+                        entries.push((key, offset));
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                // Only children whose key range can hold keys starting with the prefix
+                let first = keys.partition_point(|probe| probe.as_str() <= prefix);
+                for (i, child_id) in children.into_iter().enumerate().skip(first) {
+                    if i > first && !keys[i - 1].starts_with(prefix) {
+                        break;
+                    }
+                    self.collect(child_id, prefix, entries, depth + 1, visited)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn clear(self: &mut Self) -> Result<(), io::Error> {
+        self.mark_dirty()?;
+        self.root = 0;
+        self.key_count = 0;
+        return Ok(());
+    }
+
+    /// Clears the clean flag of the index at `path` without opening it, so it is rebuilt
+    /// instead of used once the page changed without it
+    pub fn mark_stale(path: &Path) -> Result<(), io::Error> {
+        let mut f = OpenOptions::new().write(true).open(path)?;
+        if f.metadata()?.len() < NODE_SIZE as u64 {
+            return Ok(());
+        }
+        f.seek(SeekFrom::Start(CLEAN_OFFSET as u64))?;
+        f.write_all(&[0x0])?;
+        return f.sync_data();
+    }
+
+    /// Flushes all nodes before switching the header to the new root, `page_offset` is the
+    /// end of the page entries covered by the index
+    pub fn flush(self: &mut Self, page_offset: u64) -> Result<(), io::Error> {
+        if !self.is_dirty {
+            return Ok(());
+        }
+        self.mmap.flush()?;
+        self.write_header_u64(ROOT_OFFSET, self.root);
+        self.write_header_u64(NODE_COUNT_OFFSET, self.node_count);
+        self.write_header_u64(KEY_COUNT_OFFSET, self.key_count);
+        self.write_header_u64(PAGE_OFFSET_OFFSET, page_offset);
+        self.mmap[CLEAN_OFFSET] = 0x1;
+        self.mmap.flush_range(0, NODE_SIZE)?;
+
+        self.free_nodes.append(&mut self.replaced_nodes);
+        self.fresh_nodes.clear();
+        self.is_dirty = false;
+        return Ok(());
+    }
+
+    // Marks the index as unclean on disk before the first change after a flush, so a
+    // crash before the next flush is noticed when opening the index
+    fn mark_dirty(self: &mut Self) -> Result<(), io::Error> {
+        if self.is_dirty {
+            return Ok(());
+        }
+        self.mmap[CLEAN_OFFSET] = 0x0;
+        self.mmap.flush_range(0, NODE_SIZE)?;
+        self.is_dirty = true;
+        return Ok(());
+    }
+
+    fn write_header_u64(self: &mut Self, offset: usize, value: u64) {
+        self.mmap[offset..offset + size_of::<u64>()].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn read_node(self: &Self, node_id: u64) -> Result<Node, io::Error> {
+        if node_id == 0 || node_id >= self.node_count {
+            return Err(corrupted_node());
+        }
+        let start = node_id as usize * NODE_SIZE;
+        return Node::decode(&self.mmap[start..start + NODE_SIZE]);
+    }
+
+    // Writes `node` as the replacement of `previous_id` and returns the id it was written to
+    fn write_node(
+        self: &mut Self,
+        previous_id: Option<u64>,
+        node: &Node,
+    ) -> Result<u64, io::Error> {
+        let node_id = match previous_id {
+            Some(previous_id) if self.fresh_nodes.contains(&previous_id) => previous_id,
+            _ => {
+                if let Some(previous_id) = previous_id {
+                    self.replaced_nodes.push(previous_id);
+                }
+                let node_id = self.allocate_node()?;
+                self.fresh_nodes.insert(node_id);
+                node_id
+            }
+        };
+        let data = node.encode();
+        let start = node_id as usize * NODE_SIZE;
+        self.mmap[start..start + data.len()].copy_from_slice(&data);
+        return Ok(node_id);
+    }
+
+    fn allocate_node(self: &mut Self) -> Result<u64, io::Error> {
+        if let Some(node_id) = self.free_nodes.pop() {
+            return Ok(node_id);
+        }
+        if (self.node_count as usize + 1) * NODE_SIZE > self.mmap.len() {
+            // Flush before remapping so no changes are lost with the old map
+            self.mmap.flush()?;
+            let f = OpenOptions::new().read(true).write(true).open(&self.path)?;
+            f.set_len(self.mmap.len() as u64 * 2)?;
+            self.mmap = unsafe { MmapMut::map_mut(&f)? };
+        }
+        self.node_count += 1;
+        return Ok(self.node_count - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BTreeIndex, Node};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_btree_index() {
+        const PATH: &str = "test_btree_index";
+        let mut index = BTreeIndex::create(Path::new(PATH)).unwrap();
+        for i in 0..5000u64 {
+            index.insert(&format!("key-{:05}", i), i).unwrap();
+        }
+        index.insert("key-00043", 4300).unwrap();
+        for i in (0..5000u64).step_by(3) {
+            assert!(index.remove(&format!("key-{:05}", i)).unwrap().is_some());
+        }
+        assert!(index.remove("key-00000").unwrap().is_none());
+        assert_eq!(index.len(), 3333);
+        index.flush(123).unwrap();
+
+        // Unflushed changes make the index fail to open
+        index.insert("key-99999", 1).unwrap();
+        assert!(BTreeIndex::open(Path::new(PATH)).is_err());
+        index.remove("key-99999").unwrap();
+        index.flush(123).unwrap();
+        drop(index);
+
+        let index = BTreeIndex::open(Path::new(PATH)).unwrap();
+        assert_eq!(index.get_page_offset(), 123);
+        assert_eq!(index.len(), 3333);
+        assert_eq!(index.get("key-00043").unwrap(), Some(4300));
+        assert_eq!(index.get("key-00003").unwrap(), None);
+        let entries = index.scan("key-012").unwrap();
+        let expected: Vec<(String, u64)> = (1200..1300u64)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (format!("key-{:05}", i), i))
+            .collect();
+        assert_eq!(entries, expected);
+        assert_eq!(index.scan("").unwrap().len(), 3333);
+        drop(index);
+
+        // A node which is its own child must not make lookups loop
+        let mut index = BTreeIndex::create(Path::new(PATH)).unwrap();
+        let node_id = index.node_count;
+        let node = Node::Internal {
+            keys: vec![String::from("m")],
+            children: vec![node_id, node_id],
+        };
+        index.root = index.write_node(None, &node).unwrap();
+        assert_eq!(index.root, node_id);
+        assert!(index.get("a").is_err());
+        assert!(index.scan("").is_err());
+        assert!(index.insert("a", 1).is_err());
+
+        fs::remove_file(PATH).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use super::errors::KvError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+// The codec id is written as the first byte of every typed value so the
+// entry can be decoded without knowing the rust type it was created from
+#[derive(Copy, Clone, PartialEq)]
+pub enum Codec {
+    Json = 1,
+    Bincode = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = KvError;
+
+    fn try_from(from_value: u8) -> Result<Self, Self::Error> {
+        return match from_value {
+            0x1 => Ok(Codec::Json),
+            0x2 => Ok(Codec::Bincode),
+            codec => Err(KvError::InvalidCodec { codec }),
+        };
+    }
+}
+
+impl fmt::Debug for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Codec::Json => write!(f, "Json"),
+            Codec::Bincode => write!(f, "Bincode"),
+        }
+    }
+}
+
+impl Codec {
+    pub fn encode<T: Serialize>(self: &Self, value: &T) -> Result<Vec<u8>, KvError> {
+        return match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "bincode_codec")]
+            Codec::Bincode => Ok(bincode::serialize(value)?),
+            #[cfg(not(feature = "bincode_codec"))]
+            Codec::Bincode => Err(KvError::UnsupportedCodec { codec: *self as u8 }),
+        };
+    }
+
+    pub fn decode<T: DeserializeOwned>(self: &Self, data: &[u8]) -> Result<T, KvError> {
+        return match self {
+            Codec::Json => Ok(serde_json::from_slice(data)?),
+            #[cfg(feature = "bincode_codec")]
+            Codec::Bincode => Ok(bincode::deserialize(data)?),
+            #[cfg(not(feature = "bincode_codec"))]
+            Codec::Bincode => Err(KvError::UnsupportedCodec { codec: *self as u8 }),
+        };
+    }
+}
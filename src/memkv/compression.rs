@@ -0,0 +1,86 @@
+use super::errors::KvError;
+use std::fmt;
+use std::io::Read;
+use std::mem::size_of;
+
+// Compression is recorded per entry in the header flags, so a page can hold a
+// mix of raw and compressed values and the algorithm can be changed at any time
+const FLAG_LZ4: u8 = 0x2;
+const FLAG_ZSTD: u8 = 0x4;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Compression::None => write!(f, "None"),
+            Compression::Lz4 => write!(f, "Lz4"),
+            Compression::Zstd => write!(f, "Zstd"),
+        }
+    }
+}
+
+impl Compression {
+    pub fn from_flags(flags: u8) -> Compression {
+        if flags & FLAG_LZ4 != 0 {
+            return Compression::Lz4;
+        }
+        if flags & FLAG_ZSTD != 0 {
+            return Compression::Zstd;
+        }
+        return Compression::None;
+    }
+
+    pub fn flag(self: &Self) -> u8 {
+        return match self {
+            Compression::None => 0x0,
+            Compression::Lz4 => FLAG_LZ4,
+            Compression::Zstd => FLAG_ZSTD,
+        };
+    }
+
+    pub fn compress(self: &Self, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        return match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => Ok(zstd::encode_all(data, ZSTD_LEVEL)?),
+        };
+    }
+
+    /// Fails instead of allocating if `data` decompresses to more than `max_size` bytes, so a
+    /// corrupted size prefix can't make reading an entry allocate gigabytes
+    pub fn decompress(self: &Self, data: &[u8], max_size: usize) -> Result<Vec<u8>, KvError> {
+        let too_large = KvError::DecompressedValueTooLarge { max_size };
+        return match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => {
+                // The size is prepended as a little endian u32
+                if let Some(prefix) = data.get(..size_of::<u32>()) {
+                    let mut size = [0; size_of::<u32>()];
+                    size.copy_from_slice(prefix);
+                    if u32::from_le_bytes(size) as usize > max_size {
+                        return Err(too_large);
+                    }
+                }
+                Ok(lz4_flex::decompress_size_prepended(data)?)
+            }
+            Compression::Zstd => {
+                let mut decompressed = vec![];
+                zstd::stream::read::Decoder::new(data)?
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > max_size {
+                    return Err(too_large);
+                }
+                Ok(decompressed)
+            }
+        };
+    }
+}
@@ -0,0 +1,306 @@
+use super::errors::KvError;
+use super::mem_kv_page::{MemKvPage, Value};
+use parking_lot::{Condvar, Mutex};
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Pause before a failed flush is retried unless new writes arrive
+const FLUSH_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum DurabilityMode {
+    // Flush the entire page after every write
+    Always,
+    // Flush only the bytes touched since the last flush after every write
+    DirtyRange,
+    // Flush the dirty range at most once per interval
+    Periodic(Duration),
+    // Never flush explicitly and leave writing back dirty pages to the OS
+    Os,
+}
+
+impl fmt::Debug for DurabilityMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DurabilityMode::Always => write!(f, "Always"),
+            DurabilityMode::DirtyRange => write!(f, "DirtyRange"),
+            DurabilityMode::Periodic(interval) => write!(f, "Periodic({:?})", interval),
+            DurabilityMode::Os => write!(f, "Os"),
+        }
+    }
+}
+
+struct CommitState {
+    written_sequence: u64,
+    durable_sequence: u64,
+    // Writes up to this sequence were covered by a flush which failed
+    failed_sequence: u64,
+    flush_error: Option<String>,
+    flush_count: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    page: Mutex<MemKvPage>,
+    state: Mutex<CommitState>,
+    pending: Condvar,
+    durable: Condvar,
+}
+
+/// Returned by every write of a `GroupCommitPage`, the write is on disk once `wait` returns
+pub struct WriteTicket {
+    sequence: u64,
+    shared: Arc<Shared>,
+}
+
+impl WriteTicket {
+    pub fn is_durable(self: &Self) -> bool {
+        return self.shared.state.lock().durable_sequence >= self.sequence;
+    }
+
+    /// Waits until the write is on disk, fails if the flush covering it failed. Failed flushes
+    /// are retried, so `is_durable` may still turn true later.
+    pub fn wait(self: &Self) -> Result<(), KvError> {
+        let mut state = self.shared.state.lock();
+        loop {
+            if state.durable_sequence >= self.sequence {
+                return Ok(());
+            }
+            if state.failed_sequence >= self.sequence {
+                return Err(KvError::FlushFailed {
+                    reason: state.flush_error.clone().unwrap_or_default(),
+                });
+            }
+            self.shared.durable.wait(&mut state);
+        }
+    }
+}
+
+/// Shares a page between threads, writes are applied immediately but flushed by a
+/// single background thread so concurrent writers waiting on their tickets share one flush
+pub struct GroupCommitPage {
+    shared: Arc<Shared>,
+    mode: DurabilityMode,
+    sync_thread: Option<JoinHandle<()>>,
+}
+
+impl GroupCommitPage {
+    pub fn new(mut page: MemKvPage, mode: DurabilityMode) -> GroupCommitPage {
+        // Flushes are issued by the sync thread instead of after every write
+        page.set_durability(DurabilityMode::Os);
+        let shared = Arc::new(Shared {
+            page: Mutex::new(page),
+            state: Mutex::new(CommitState {
+                written_sequence: 0,
+                durable_sequence: 0,
+                failed_sequence: 0,
+                flush_error: None,
+                flush_count: 0,
+                shutdown: false,
+            }),
+            pending: Condvar::new(),
+            durable: Condvar::new(),
+        });
+
+        let sync_thread = match mode {
+            DurabilityMode::Os => None,
+            _ => {
+                let sync_shared = shared.clone();
+                Some(thread::spawn(move || {
+                    GroupCommitPage::run_sync(sync_shared, mode)
+                }))
+            }
+        };
+
+        return GroupCommitPage {
+            shared,
+            mode,
+            sync_thread,
+        };
+    }
+
+    pub fn get(self: &Self, key: &str) -> Result<Value, KvError> {
+        return self.shared.page.lock().get(key);
+    }
+
+    pub fn insert(self: &Self, key: &str, value: Value) -> Result<WriteTicket, KvError> {
+        let mut page = self.shared.page.lock();
+        page.insert(key, value)?;
+        return Ok(self.register_write());
+    }
+
+    pub fn delete(self: &Self, key: &str) -> Result<WriteTicket, KvError> {
+        let mut page = self.shared.page.lock();
+        page.delete(key)?;
+        return Ok(self.register_write());
+    }
+
+    pub fn flush_count(self: &Self) -> u64 {
+        return self.shared.state.lock().flush_count;
+    }
+
+    // Must be called while holding the page lock so sequence numbers follow the write order
+    fn register_write(self: &Self) -> WriteTicket {
+        let mut state = self.shared.state.lock();
+        state.written_sequence += 1;
+        if self.mode == DurabilityMode::Os {
+            state.durable_sequence = state.written_sequence;
+        }
+        self.shared.pending.notify_one();
+        return WriteTicket {
+            sequence: state.written_sequence,
+            shared: self.shared.clone(),
+        };
+    }
+
+    fn run_sync(shared: Arc<Shared>, mode: DurabilityMode) {
+        loop {
+            let target_sequence = {
+                let mut state = shared.state.lock();
+                // Writes don't cut the interval short, they are flushed together once it passed
+                if let DurabilityMode::Periodic(interval) = mode {
+                    let deadline = Instant::now() + interval;
+                    while !state.shutdown
+                        && !shared.pending.wait_until(&mut state, deadline).timed_out()
+                    {
+                    }
+                }
+                while state.written_sequence == state.durable_sequence && !state.shutdown {
+                    shared.pending.wait(&mut state);
+                }
+                if state.written_sequence == state.durable_sequence && state.shutdown {
+                    return;
+                }
+                state.written_sequence
+            };
+
+            // Every write registered up to here is covered by this flush
+            let result = {
+                let mut page = shared.page.lock();
+                match mode {
+                    DurabilityMode::Always => page.sync_all(),
+                    _ => page.sync(),
+                }
+            };
+            if let Err(error) = result {
+                log::error!("Failed to flush page: {}", error);
+                let mut state = shared.state.lock();
+                state.failed_sequence = target_sequence;
+                state.flush_error = Some(error.to_string());
+                shared.durable.notify_all();
+                if state.shutdown {
+                    return;
+                }
+                // The page keeps its dirty range, so the next flush retries the writes
+                shared.pending.wait_for(&mut state, FLUSH_RETRY_INTERVAL);
+                continue;
+            }
+
+            let mut state = shared.state.lock();
+            state.durable_sequence = target_sequence;
+            state.flush_count += 1;
+            shared.durable.notify_all();
+        }
+    }
+
+    fn sync_now(self: &Self) -> Result<(), KvError> {
+        return self.shared.page.lock().sync();
+    }
+}
+
+impl Drop for GroupCommitPage {
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.pending.notify_all();
+        if let Some(sync_thread) = self.sync_thread.take() {
+            let _ = sync_thread.join();
+        }
+        if let Err(error) = self.sync_now() {
+            log::error!("Failed to flush page on shutdown: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DurabilityMode, GroupCommitPage};
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{KvError, MemKvPage, Value, KV_PAGE_SIZE};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const TEST_KEYSPACE: &str = "test_keyspace_group_commit";
+
+    // Every writer waits until its write is durable before the next one
+    fn write_concurrently(page: &Arc<GroupCommitPage>, writers: u64, writes: u64) {
+        let writers: Vec<_> = (0..writers)
+            .map(|writer| {
+                let page = page.clone();
+                thread::spawn(move || {
+                    for i in 0..writes {
+                        let ticket = page
+                            .insert(&format!("{}-{}", writer, i), Value::Integer(i))
+                            .unwrap();
+                        ticket.wait().unwrap();
+                        assert!(ticket.is_durable());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let _page_files = PageFilesGuard::new(Path::new(TEST_KEYSPACE)).unwrap();
+        let page = MemKvPage::new(Path::new(TEST_KEYSPACE), KV_PAGE_SIZE).unwrap();
+        let page = Arc::new(GroupCommitPage::new(page, DurabilityMode::DirtyRange));
+        write_concurrently(&page, 8, 25);
+
+        if let Value::Integer(value) = page.get("7-24").unwrap() {
+            assert_eq!(value, 24);
+        } else {
+            panic!();
+        }
+        page.delete("7-24").unwrap().wait().unwrap();
+        assert!(page.get("7-24").is_err());
+    }
+
+    #[test]
+    fn test_periodic_group_commit() {
+        const KEYSPACE: &str = "test_keyspace_periodic_group_commit";
+        let _page_files = PageFilesGuard::new(Path::new(KEYSPACE)).unwrap();
+        let page = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+        let mode = DurabilityMode::Periodic(Duration::from_millis(20));
+        let page = Arc::new(GroupCommitPage::new(page, mode));
+        write_concurrently(&page, 8, 25);
+
+        // Writers waiting at the same time share a flush
+        assert!(page.flush_count() < 200 / 2);
+        assert_eq!(page.get("3-24").unwrap(), Value::Integer(24));
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        const KEYSPACE: &str = "test_keyspace_failed_flush";
+        let _page_files = PageFilesGuard::new(Path::new(KEYSPACE)).unwrap();
+        let mut page = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+        page.fail_next_flushes(1);
+        let page = Arc::new(GroupCommitPage::new(page, DurabilityMode::DirtyRange));
+
+        let ticket = page.insert("a", Value::Integer(1)).unwrap();
+        assert!(matches!(ticket.wait(), Err(KvError::FlushFailed { .. })));
+
+        // The retry flushes the write which failed together with the new one
+        let next_ticket = page.insert("b", Value::Integer(2)).unwrap();
+        next_ticket.wait().unwrap();
+        assert!(ticket.is_durable());
+    }
+}
@@ -0,0 +1,120 @@
+use super::errors::KvError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::mem::size_of;
+use std::path::Path;
+
+// Set in the entry header flags next to the compression bits
+pub const FLAG_ENCRYPTED: u8 = 0x8;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// Bytes `encrypt` adds to the data
+pub const ENCRYPTION_OVERHEAD: usize = size_of::<u32>() + NONCE_SIZE + TAG_SIZE;
+
+// Keyfile layout, keys are base64 encoded 256 bit AES keys:
+// { "active_key": 2, "keys": { "1": "...", "2": "..." } }
+#[derive(Deserialize)]
+struct Keyfile {
+    active_key: u32,
+    keys: HashMap<u32, String>,
+}
+
+/// Holds every key a page may have been written with, new data is always
+/// encrypted with the active key so rotating only requires adding a key
+pub struct Keyring {
+    active_key_id: u32,
+    keys: HashMap<u32, Aes256Gcm>,
+}
+
+impl Keyring {
+    pub fn new(active_key_id: u32, keys: HashMap<u32, [u8; KEY_SIZE]>) -> Result<Keyring, KvError> {
+        if !keys.contains_key(&active_key_id) {
+            return Err(KvError::InvalidKeyfile {
+                reason: format!("active key {} is not in the keyring", active_key_id),
+            });
+        }
+        return Ok(Keyring {
+            active_key_id,
+            keys: keys
+                .into_iter()
+                .map(|(id, key)| (id, Aes256Gcm::new(&key.into())))
+                .collect(),
+        });
+    }
+
+    pub fn from_keyfile(path: &Path) -> Result<Keyring, KvError> {
+        let keyfile: Keyfile = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let mut keys = HashMap::new();
+        for (id, encoded_key) in keyfile.keys {
+            let key: [u8; KEY_SIZE] = BASE64
+                .decode(encoded_key)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| KvError::InvalidKeyfile {
+                    reason: format!("key {} is not a base64 encoded 256 bit key", id),
+                })?;
+            keys.insert(id, key);
+        }
+        return Keyring::new(keyfile.active_key, keys);
+    }
+
+    /// Loads the keyfile referenced by the `encryption.keyfile` setting
+    pub fn from_config(settings: &config::Config) -> Result<Keyring, KvError> {
+        let keyfile = settings.get_string("encryption.keyfile")?;
+        return Keyring::from_keyfile(Path::new(&keyfile));
+    }
+
+    // Encrypted data is stored as key id | nonce | ciphertext with tag, so the
+    // size overhead is constant and data can be re-encrypted in place
+    pub fn encrypt(self: &Self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, KvError> {
+        let cipher = &self.keys[&self.active_key_id];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| KvError::DecryptionFailed)?;
+        return Ok([
+            self.active_key_id.to_be_bytes().to_vec(),
+            nonce.to_vec(),
+            ciphertext,
+        ]
+        .concat());
+    }
+
+    pub fn decrypt(self: &Self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, KvError> {
+        let key_id = Keyring::key_id(data)?;
+        let cipher = match self.keys.get(&key_id) {
+            Some(cipher) => cipher,
+            None => {
+                return Err(KvError::EncryptionKeyNotFound {
+                    key_id: Some(key_id),
+                })
+            }
+        };
+        let nonce_offset = size_of::<u32>();
+        let nonce = Nonce::from_slice(&data[nonce_offset..nonce_offset + NONCE_SIZE]);
+        let msg = &data[nonce_offset + NONCE_SIZE..];
+        return cipher
+            .decrypt(nonce, Payload { msg, aad })
+            .map_err(|_| KvError::DecryptionFailed);
+    }
+
+    pub fn is_active_key(self: &Self, data: &[u8]) -> Result<bool, KvError> {
+        return Ok(Keyring::key_id(data)? == self.active_key_id);
+    }
+
+    fn key_id(data: &[u8]) -> Result<u32, KvError> {
+        if data.len() < size_of::<u32>() + NONCE_SIZE {
+            return Err(KvError::DecryptionFailed);
+        }
+        return Ok(u32::from_be_bytes(
+            data[..size_of::<u32>()].try_into().unwrap(),
+        ));
+    }
+}
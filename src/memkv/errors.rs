@@ -1,62 +1,311 @@
 use std::error;
 use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str;
 
-#[derive(Clone, Debug)]
-pub struct NoSpaceLeftError;
+#[derive(Debug)]
+pub enum KvError {
+    // `available` is the space left on the page at its maximum size
+    NoSpaceLeft {
+        requested: u64,
+        available: u64,
+    },
+    KeyAlreadyExists {
+        key: String,
+    },
+    KeyDoesNotExist {
+        key: String,
+    },
+    MemmapCreationFailure {
+        path: PathBuf,
+    },
+    EntryAlreadyDeleted {
+        key: String,
+        offset: u64,
+    },
+    InvalidDataType {
+        data_type: u8,
+    },
+    // The bytes of an entry don't form a value of its data type
+    InvalidValue {
+        data_type: u8,
+    },
+    // A json document does not describe a value, see `Value::from_json`
+    InvalidValueJson {
+        reason: String,
+    },
+    InvalidCodec {
+        codec: u8,
+    },
+    UnsupportedCodec {
+        codec: u8,
+    },
+    InvalidJsonPath {
+        path: String,
+    },
+    JsonPathDoesNotExist {
+        path: String,
+    },
+    IndexAlreadyExists {
+        name: String,
+    },
+    IndexDoesNotExist {
+        name: String,
+    },
+    DecryptionFailed,
+    // `None` if no keyring is configured at all
+    EncryptionKeyNotFound {
+        key_id: Option<u32>,
+    },
+    InvalidKeyfile {
+        reason: String,
+    },
+    EncryptedLargeValue {
+        key: String,
+    },
+    InvalidPageSize {
+        page_size: u64,
+    },
+    InvalidStorageEngine {
+        name: String,
+    },
+    // A setting of the config file has a value which is not one of its options
+    InvalidSetting {
+        name: String,
+        value: String,
+    },
+    InvalidExportFormat {
+        name: String,
+    },
+    InvalidImportMode {
+        name: String,
+    },
+    // `line` of the export file, starting at 1
+    InvalidImportRecord {
+        line: u64,
+        reason: String,
+    },
+    // The storage engine does not implement the operation
+    UnsupportedOperation {
+        operation: String,
+    },
+    CorruptedTable {
+        path: PathBuf,
+    },
+    // A backup is incomplete or does not match its manifest
+    InvalidBackup {
+        path: PathBuf,
+        reason: String,
+    },
+    // A read of `size` bytes at `offset` does not fit into the page
+    OutOfBounds {
+        offset: u64,
+        size: u64,
+        page_size: u64,
+    },
+    // The entry an index points to belongs to a different key
+    IndexMismatch {
+        key: String,
+        entry_key: String,
+    },
+    // A compressed value decompresses to more than a page may hold
+    DecompressedValueTooLarge {
+        max_size: usize,
+    },
+    // The key and value of the entry at `offset` don't match the checksum stored with them
+    ChecksumMismatch {
+        offset: u64,
+    },
+    // The flush which should have made a write durable failed
+    FlushFailed {
+        reason: String,
+    },
+    Io(io::Error),
+    Json(serde_json::Error),
+    Utf8(str::Utf8Error),
+    Decompression(lz4_flex::block::DecompressError),
+    Config(config::ConfigError),
+    Csv(csv::Error),
+    #[cfg(feature = "bincode_codec")]
+    Bincode(bincode::Error),
+}
 
-impl fmt::Display for NoSpaceLeftError {
+impl fmt::Display for KvError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "no space left on page to add value")
+        match self {
+            KvError::NoSpaceLeft {
+                requested,
+                available,
+            } => write!(
+                f,
+                "no space left on page to add value, {} bytes requested but only {} available",
+                requested, available
+            ),
+            KvError::KeyAlreadyExists { key } => write!(f, "key {:?} already exists in page", key),
+            KvError::KeyDoesNotExist { key } => write!(f, "key {:?} does not exist in page", key),
+            KvError::MemmapCreationFailure { path } => {
+                write!(f, "failed to create memory map for {:?}", path)
+            }
+            KvError::EntryAlreadyDeleted { key, offset } => write!(
+                f,
+                "entry of key {:?} at offset {} is already deleted",
+                key, offset
+            ),
+            KvError::InvalidDataType { data_type } => write!(f, "invalid data type {}", data_type),
+            KvError::InvalidValue { data_type } => {
+                write!(f, "invalid value for data type {}", data_type)
+            }
+            KvError::InvalidValueJson { reason } => {
+                write!(f, "invalid json representation of value, {}", reason)
+            }
+            KvError::InvalidCodec { codec } => write!(f, "invalid codec {}", codec),
+            KvError::UnsupportedCodec { codec } => write!(
+                f,
+                "codec {} is not supported, enable the matching feature",
+                codec
+            ),
+            KvError::InvalidJsonPath { path } => write!(f, "invalid json path {:?}", path),
+            KvError::JsonPathDoesNotExist { path } => {
+                write!(f, "json path {:?} does not exist in document", path)
+            }
+            KvError::IndexAlreadyExists { name } => write!(f, "index {:?} already exists", name),
+            KvError::IndexDoesNotExist { name } => write!(f, "index {:?} does not exist", name),
+            KvError::DecryptionFailed => write!(
+                f,
+                "failed to decrypt entry, the page was opened with the wrong key or is corrupted"
+            ),
+            KvError::EncryptionKeyNotFound {
+                key_id: Some(key_id),
+            } => write!(
+                f,
+                "entry was encrypted with key {} that is not in the keyring",
+                key_id
+            ),
+            KvError::EncryptionKeyNotFound { key_id: None } => {
+                write!(f, "entry is encrypted but no keyring is configured")
+            }
+            KvError::InvalidKeyfile { reason } => write!(f, "invalid keyfile, {}", reason),
+            KvError::EncryptedLargeValue { key } => write!(
+                f,
+                "large value of key {:?} cannot be stored on an encrypted page",
+                key
+            ),
+            KvError::InvalidPageSize { page_size } => write!(f, "invalid page size {}", page_size),
+            KvError::InvalidStorageEngine { name } => {
+                write!(f, "unknown storage engine {:?}", name)
+            }
+            KvError::InvalidSetting { name, value } => {
+                write!(f, "invalid value {:?} of setting {}", value, name)
+            }
+            KvError::InvalidExportFormat { name } => {
+                write!(f, "unknown export format {:?}, use jsonl or csv", name)
+            }
+            KvError::InvalidImportMode { name } => write!(
+                f,
+                "unknown import mode {:?}, use overwrite, skip or fail",
+                name
+            ),
+            KvError::InvalidImportRecord { line, reason } => {
+                write!(f, "invalid record on line {}, {}", line, reason)
+            }
+            KvError::UnsupportedOperation { operation } => {
+                write!(f, "storage engine does not support {}", operation)
+            }
+            KvError::CorruptedTable { path } => write!(f, "corrupted sorted table file {:?}", path),
+            KvError::InvalidBackup { path, reason } => {
+                write!(f, "invalid backup {:?}, {}", path, reason)
+            }
+            KvError::OutOfBounds {
+                offset,
+                size,
+                page_size,
+            } => write!(
+                f,
+                "read of {} bytes at offset {} is out of bounds of page with {} bytes",
+                size, offset, page_size
+            ),
+            KvError::IndexMismatch { key, entry_key } => write!(
+                f,
+                "index entry of key {:?} points to entry of key {:?}",
+                key, entry_key
+            ),
+            KvError::DecompressedValueTooLarge { max_size } => write!(
+                f,
+                "compressed value decompresses to more than {} bytes",
+                max_size
+            ),
+            KvError::ChecksumMismatch { offset } => {
+                write!(f, "checksum mismatch of entry at offset {}", offset)
+            }
+            KvError::FlushFailed { reason } => write!(f, "flushing the page failed: {}", reason),
+            KvError::Io(error) => write!(f, "io error: {}", error),
+            KvError::Json(error) => write!(f, "json error: {}", error),
+            KvError::Utf8(error) => write!(f, "invalid utf-8: {}", error),
+            KvError::Decompression(error) => write!(f, "failed to decompress value: {}", error),
+            KvError::Config(error) => write!(f, "config error: {}", error),
+            KvError::Csv(error) => write!(f, "csv error: {}", error),
+            #[cfg(feature = "bincode_codec")]
+            KvError::Bincode(error) => write!(f, "bincode error: {}", error),
+        }
     }
 }
-impl error::Error for NoSpaceLeftError {}
-
-#[derive(Clone, Debug)]
-pub struct KeyAlreadyExistsError;
 
-impl fmt::Display for KeyAlreadyExistsError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "key already exists in page")
+impl error::Error for KvError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        return match self {
+            KvError::Io(error) => Some(error),
+            KvError::Json(error) => Some(error),
+            KvError::Utf8(error) => Some(error),
+            KvError::Decompression(error) => Some(error),
+            KvError::Config(error) => Some(error),
+            KvError::Csv(error) => Some(error),
+            #[cfg(feature = "bincode_codec")]
+            KvError::Bincode(error) => Some(error),
+            _ => None,
+        };
     }
 }
-impl error::Error for KeyAlreadyExistsError {}
-
-#[derive(Clone, Debug)]
-pub struct KeyDoesNotExistError;
 
-impl fmt::Display for KeyDoesNotExistError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "key does not exist in page")
+impl From<io::Error> for KvError {
+    fn from(error: io::Error) -> Self {
+        return KvError::Io(error);
     }
 }
-impl error::Error for KeyDoesNotExistError {}
 
-#[derive(Clone, Debug)]
-pub struct MemmapCreationFailureError;
+impl From<serde_json::Error> for KvError {
+    fn from(error: serde_json::Error) -> Self {
+        return KvError::Json(error);
+    }
+}
 
-impl fmt::Display for MemmapCreationFailureError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "failed to create memmap")
+impl From<str::Utf8Error> for KvError {
+    fn from(error: str::Utf8Error) -> Self {
+        return KvError::Utf8(error);
     }
 }
-impl error::Error for MemmapCreationFailureError {}
 
-#[derive(Clone, Debug)]
-pub struct EntryAlreadyDeletedInFileError;
+impl From<lz4_flex::block::DecompressError> for KvError {
+    fn from(error: lz4_flex::block::DecompressError) -> Self {
+        return KvError::Decompression(error);
+    }
+}
 
-impl fmt::Display for EntryAlreadyDeletedInFileError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "entry already deleted in file")
+impl From<config::ConfigError> for KvError {
+    fn from(error: config::ConfigError) -> Self {
+        return KvError::Config(error);
     }
 }
-impl error::Error for EntryAlreadyDeletedInFileError {}
 
-#[derive(Clone, Debug)]
-pub struct InvalidDataTypeError;
+impl From<csv::Error> for KvError {
+    fn from(error: csv::Error) -> Self {
+        return KvError::Csv(error);
+    }
+}
 
-impl fmt::Display for InvalidDataTypeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Invalid data type")
+#[cfg(feature = "bincode_codec")]
+impl From<bincode::Error> for KvError {
+    fn from(error: bincode::Error) -> Self {
+        return KvError::Bincode(error);
     }
 }
-impl error::Error for InvalidDataTypeError {}
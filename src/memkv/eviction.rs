@@ -0,0 +1,132 @@
+use rand::seq::IteratorRandom;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum EvictionPolicy {
+    LeastRecentlyUsed,
+    LeastFrequentlyUsed,
+    Random,
+    // Evicts expired keys, then the keys closest to expiring and falls back to LRU
+    TtlFirst,
+}
+
+impl fmt::Debug for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EvictionPolicy::LeastRecentlyUsed => write!(f, "LeastRecentlyUsed"),
+            EvictionPolicy::LeastFrequentlyUsed => write!(f, "LeastFrequentlyUsed"),
+            EvictionPolicy::Random => write!(f, "Random"),
+            EvictionPolicy::TtlFirst => write!(f, "TtlFirst"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvictionStats {
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+struct AccessStats {
+    // Logical clock instead of a timestamp so accesses within the same instant stay ordered
+    last_access: u64,
+    hits: u64,
+    size: u64,
+    expires_at: Option<Instant>,
+}
+
+/// Tracks per key access statistics and expiry next to the primary index
+pub struct AccessTracker {
+    entries: HashMap<String, AccessStats>,
+    clock: u64,
+    live_bytes: u64,
+    pub stats: EvictionStats,
+}
+
+impl AccessTracker {
+    pub fn new() -> AccessTracker {
+        return AccessTracker {
+            entries: HashMap::new(),
+            clock: 0,
+            live_bytes: 0,
+            stats: EvictionStats::default(),
+        };
+    }
+
+    pub fn record_insert(self: &mut Self, key: &str, size: u64) {
+        self.clock += 1;
+        self.live_bytes += size;
+        self.entries.insert(
+            String::from(key),
+            AccessStats {
+                last_access: self.clock,
+                hits: 0,
+                size,
+                expires_at: None,
+            },
+        );
+    }
+
+    pub fn record_access(self: &mut Self, key: &str) {
+        self.clock += 1;
+        if let Some(stats) = self.entries.get_mut(key) {
+            stats.last_access = self.clock;
+            stats.hits += 1;
+        }
+    }
+
+    pub fn remove(self: &mut Self, key: &str) {
+        if let Some(stats) = self.entries.remove(key) {
+            self.live_bytes -= stats.size;
+        }
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.entries.clear();
+        self.live_bytes = 0;
+    }
+
+    pub fn get_live_bytes(self: &Self) -> u64 {
+        return self.live_bytes;
+    }
+
+    pub fn set_expiry(self: &mut Self, key: &str, ttl: Option<Duration>) {
+        if let Some(stats) = self.entries.get_mut(key) {
+            stats.expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        }
+    }
+
+    pub fn get_remaining_ttl(self: &Self, key: &str) -> Option<Duration> {
+        let expires_at = self.entries.get(key)?.expires_at?;
+        return Some(expires_at.saturating_duration_since(Instant::now()));
+    }
+
+    pub fn is_expired(self: &Self, key: &str) -> bool {
+        return match self.entries.get(key).and_then(|stats| stats.expires_at) {
+            Some(expires_at) => expires_at <= Instant::now(),
+            None => false,
+        };
+    }
+
+    pub fn find_victim(self: &Self, policy: EvictionPolicy) -> Option<String> {
+        let entries = self.entries.iter();
+        let victim = match policy {
+            EvictionPolicy::LeastRecentlyUsed => entries.min_by_key(|(_, stats)| stats.last_access),
+            EvictionPolicy::LeastFrequentlyUsed => {
+                entries.min_by_key(|(_, stats)| (stats.hits, stats.last_access))
+            }
+            EvictionPolicy::Random => entries.choose(&mut rand::thread_rng()),
+            EvictionPolicy::TtlFirst => entries.min_by_key(|(_, stats)| {
+                // Keys without ttl sort after every key with one
+                (
+                    stats.expires_at.is_none(),
+                    stats.expires_at,
+                    stats.last_access,
+                )
+            }),
+        };
+        return victim.map(|(key, _)| key.clone());
+    }
+}
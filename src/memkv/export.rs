@@ -0,0 +1,529 @@
+use super::errors::KvError;
+use super::mem_kv_page::Value;
+use super::storage_engine::StorageEngine;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+const CSV_HEADER: [&str; 5] = ["key", "type", "codec", "value", "ttl_ms"];
+// Entries read from the engine at a time while exporting
+const EXPORT_BATCH_SIZE: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExportFormat {
+    // One json document per line, the value as in `Value::to_json` plus key and ttl
+    JsonLines,
+    // The columns of `CSV_HEADER`, binary data is base64 and json documents are serialized
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = KvError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        return match name.to_lowercase().as_str() {
+            "jsonl" | "ndjson" => Ok(ExportFormat::JsonLines),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(KvError::InvalidExportFormat {
+                name: String::from(name),
+            }),
+        };
+    }
+}
+
+impl ExportFormat {
+    /// Guesses the format from the file extension
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        return path.extension()?.to_str()?.parse().ok();
+    }
+}
+
+/// What an import does with keys that already exist
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImportMode {
+    Overwrite,
+    Skip,
+    // Records before the existing key stay imported
+    Fail,
+}
+
+impl FromStr for ImportMode {
+    type Err = KvError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        return match name.to_lowercase().as_str() {
+            "overwrite" => Ok(ImportMode::Overwrite),
+            "skip" => Ok(ImportMode::Skip),
+            "fail" => Ok(ImportMode::Fail),
+            _ => Err(KvError::InvalidImportMode {
+                name: String::from(name),
+            }),
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportRecord {
+    pub key: String,
+    pub value: Value,
+    // Remaining time to live at the time of the export
+    pub ttl: Option<Duration>,
+}
+
+impl ExportRecord {
+    pub fn to_json(self: &Self) -> serde_json::Value {
+        let mut document = self.value.to_json();
+        document["key"] = serde_json::Value::from(self.key.as_str());
+        if let Some(ttl) = self.ttl {
+            document["ttl_ms"] = serde_json::Value::from(ttl.as_millis() as u64);
+        }
+        return document;
+    }
+
+    pub fn from_json(document: &serde_json::Value) -> Result<ExportRecord, KvError> {
+        let invalid = |reason: &str| KvError::InvalidValueJson {
+            reason: String::from(reason),
+        };
+        let key = document
+            .get("key")
+            .and_then(|key| key.as_str())
+            .ok_or_else(|| invalid("missing \"key\""))?;
+        let ttl = match document.get("ttl_ms") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(ttl) => match ttl.as_u64() {
+                Some(millis) => Some(Duration::from_millis(millis)),
+                None => return Err(invalid("ttl must be an unsigned integer")),
+            },
+        };
+        return Ok(ExportRecord {
+            key: String::from(key),
+            value: Value::from_json(document)?,
+            ttl,
+        });
+    }
+
+    fn to_csv_row(self: &Self) -> [String; 5] {
+        let document = self.value.to_json();
+        let get_text = |field: &str| match &document[field] {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        return [
+            self.key.clone(),
+            get_text("type"),
+            get_text("codec"),
+            get_text("value"),
+            self.ttl
+                .map(|ttl| ttl.as_millis().to_string())
+                .unwrap_or_default(),
+        ];
+    }
+
+    // Builds the json representation of the row so the value is checked by `from_json`
+    fn from_csv_row(row: &csv::StringRecord) -> Result<ExportRecord, KvError> {
+        let invalid = |reason: &str| KvError::InvalidValueJson {
+            reason: String::from(reason),
+        };
+        if row.len() != CSV_HEADER.len() {
+            return Err(invalid("wrong number of columns"));
+        }
+        let value = match &row[1] {
+            "Integer" => match row[3].parse::<u64>() {
+                Ok(number) => serde_json::Value::from(number),
+                Err(_) => return Err(invalid("value of an Integer must be an unsigned integer")),
+            },
+            "Json" => serde_json::from_str(&row[3])?,
+            _ => serde_json::Value::from(&row[3]),
+        };
+        let mut document = serde_json::json!({"key": &row[0], "type": &row[1], "value": value});
+        if !row[2].is_empty() {
+            document["codec"] = serde_json::Value::from(&row[2]);
+        }
+        if !row[4].is_empty() {
+            match row[4].parse::<u64>() {
+                Ok(millis) => document["ttl_ms"] = serde_json::Value::from(millis),
+                Err(_) => return Err(invalid("ttl must be an unsigned integer")),
+            }
+        }
+        return ExportRecord::from_json(&document);
+    }
+}
+
+/// Streams records into an export file
+pub enum RecordWriter<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, format: ExportFormat) -> Result<RecordWriter<W>, KvError> {
+        return match format {
+            ExportFormat::JsonLines => Ok(RecordWriter::JsonLines(writer)),
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(CSV_HEADER)?;
+                Ok(RecordWriter::Csv(Box::new(writer)))
+            }
+        };
+    }
+
+    pub fn write(self: &mut Self, record: &ExportRecord) -> Result<(), KvError> {
+        match self {
+            RecordWriter::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, &record.to_json())?;
+                writer.write_all(b"\n")?;
+            }
+            RecordWriter::Csv(writer) => writer.write_record(record.to_csv_row())?,
+        }
+        return Ok(());
+    }
+
+    pub fn flush(self: &mut Self) -> Result<(), KvError> {
+        match self {
+            RecordWriter::JsonLines(writer) => writer.flush()?,
+            RecordWriter::Csv(writer) => writer.flush()?,
+        }
+        return Ok(());
+    }
+}
+
+/// Streams records out of an export file, errors name the line of the broken record
+pub enum RecordReader<R: BufRead> {
+    JsonLines { lines: io::Lines<R>, line: u64 },
+    Csv(csv::StringRecordsIntoIter<R>),
+}
+
+impl<R: BufRead> RecordReader<R> {
+    pub fn new(reader: R, format: ExportFormat) -> Result<RecordReader<R>, KvError> {
+        return match format {
+            ExportFormat::JsonLines => Ok(RecordReader::JsonLines {
+                lines: reader.lines(),
+                line: 0,
+            }),
+            ExportFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(reader);
+                if reader.headers()? != &csv::StringRecord::from(CSV_HEADER.to_vec()) {
+                    return Err(KvError::InvalidImportRecord {
+                        line: 1,
+                        reason: format!("header must be {}", CSV_HEADER.join(",")),
+                    });
+                }
+                Ok(RecordReader::Csv(reader.into_records()))
+            }
+        };
+    }
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = Result<ExportRecord, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (line, record) = match self {
+            RecordReader::JsonLines { lines, line } => loop {
+                *line += 1;
+                match lines.next()? {
+                    Ok(text) if text.trim().is_empty() => continue,
+                    Ok(text) => match serde_json::from_str(&text) {
+                        Ok(document) => break (*line, ExportRecord::from_json(&document)),
+                        Err(error) => break (*line, Err(error.into())),
+                    },
+                    Err(error) => return Some(Err(error.into())),
+                }
+            },
+            RecordReader::Csv(records) => match records.next()? {
+                Ok(row) => (
+                    row.position().map(|position| position.line()).unwrap_or(0),
+                    ExportRecord::from_csv_row(&row),
+                ),
+                Err(error) => return Some(Err(error.into())),
+            },
+        };
+        return Some(record.map_err(|error| KvError::InvalidImportRecord {
+            line,
+            reason: error.to_string(),
+        }));
+    }
+}
+
+/// Writes all entries with keys starting with `prefix`, ordered by key. Entries are read in
+/// batches, so only a batch of values is held in memory.
+pub fn export_engine<W: Write>(
+    engine: &dyn StorageEngine,
+    prefix: &str,
+    writer: &mut RecordWriter<W>,
+) -> Result<usize, KvError> {
+    let mut count = 0;
+    let mut last_key: Option<String> = None;
+    loop {
+        let batch = engine.scan_after(prefix, last_key.as_deref(), EXPORT_BATCH_SIZE)?;
+        let is_last_batch = batch.len() < EXPORT_BATCH_SIZE;
+        for (key, value) in batch {
+            // Set before the lookup so an expired key still moves the scan past it
+            last_key = Some(key.clone());
+            // Engines without expiry would read the value again to answer `get_ttl`
+            let ttl = match engine.supports_ttl() {
+                true => match engine.get_ttl(&key) {
+                    Ok(ttl) => ttl,
+                    // The entry expired since the batch was read
+                    Err(KvError::KeyDoesNotExist { .. }) => continue,
+                    Err(error) => return Err(error),
+                },
+                false => None,
+            };
+            let record = ExportRecord { key, value, ttl };
+            writer.write(&record)?;
+            count += 1;
+        }
+        if is_last_batch {
+            break;
+        }
+    }
+    writer.flush()?;
+    return Ok(count);
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub imported: usize,
+    // Existing keys left alone by `ImportMode::Skip`
+    pub skipped: usize,
+}
+
+/// Writes the records one at a time, so imports are not atomic. Records with a ttl need an
+/// engine that supports `StorageEngine::expire`.
+pub fn import_engine(
+    engine: &mut dyn StorageEngine,
+    records: impl Iterator<Item = Result<ExportRecord, KvError>>,
+    mode: ImportMode,
+) -> Result<ImportReport, KvError> {
+    let mut report = ImportReport::default();
+    for record in records {
+        let record = record?;
+        let exists = match engine.get(&record.key) {
+            Ok(_) => true,
+            Err(KvError::KeyDoesNotExist { .. }) => false,
+            Err(error) => return Err(error),
+        };
+        match mode {
+            ImportMode::Skip if exists => {
+                report.skipped += 1;
+                continue;
+            }
+            ImportMode::Fail if exists => {
+                return Err(KvError::KeyAlreadyExists { key: record.key });
+            }
+            _ => {}
+        }
+        // The record is not written without its expiry
+        if record.ttl.is_some() && !engine.supports_ttl() {
+            return Err(KvError::UnsupportedOperation {
+                operation: String::from("expire"),
+            });
+        }
+        engine.put(&record.key, record.value)?;
+        if record.ttl.is_some() {
+            engine.expire(&record.key, record.ttl)?;
+        }
+        report.imported += 1;
+    }
+    engine.flush()?;
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        export_engine, import_engine, ExportFormat, ImportMode, ImportReport, RecordReader,
+        RecordWriter, EXPORT_BATCH_SIZE,
+    };
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{
+        Codec, KvError, MemKvPage, MemoryEngine, StorageEngine, StorageStats, Value,
+    };
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn export(engine: &dyn StorageEngine, format: ExportFormat) -> Vec<u8> {
+        let mut writer = RecordWriter::new(vec![], format).unwrap();
+        assert_eq!(export_engine(engine, "", &mut writer).unwrap(), 5);
+        return match writer {
+            RecordWriter::JsonLines(data) => data,
+            RecordWriter::Csv(writer) => writer.into_inner().unwrap(),
+        };
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        const PAGE: &str = "test_export_page";
+        let _page_files = PageFilesGuard::new(Path::new(PAGE)).unwrap();
+        let mut page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        page.insert("string", Value::String(String::from("a,\"b\"\nc")))
+            .unwrap();
+        page.insert("integer", Value::Integer(u64::MAX)).unwrap();
+        page.insert("blob", Value::Blob(vec![0, 1, 255])).unwrap();
+        page.insert("typed", Value::Typed(Codec::Json, b"[1]".to_vec()))
+            .unwrap();
+        page.insert_with_ttl(
+            "json",
+            Value::json_from_str("{\"a\": [1, null]}").unwrap(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+
+        for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+            let data = export(&page, format);
+            let records: Vec<_> = RecordReader::new(data.as_slice(), format)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let report = import_engine(
+                &mut page,
+                records.clone().into_iter().map(Ok),
+                ImportMode::Skip,
+            )
+            .unwrap();
+            assert_eq!(report.skipped, 5);
+
+            let mut memory = MemoryEngine::new();
+            memory.put("integer", Value::Integer(1)).unwrap();
+            let untimed = records.iter().filter(|record| record.ttl.is_none());
+            let report = import_engine(
+                &mut memory,
+                untimed.clone().cloned().map(Ok),
+                ImportMode::Overwrite,
+            )
+            .unwrap();
+            assert_eq!(
+                report,
+                ImportReport {
+                    imported: 4,
+                    skipped: 0
+                }
+            );
+            assert_eq!(memory.scan("").unwrap(), {
+                let mut entries = page.scan("").unwrap();
+                entries.retain(|(key, _)| key != "json");
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                entries
+            });
+            assert!(matches!(
+                import_engine(&mut memory, untimed.cloned().map(Ok), ImportMode::Fail),
+                Err(KvError::KeyAlreadyExists { .. })
+            ));
+
+            // Engines without expiry can't take the ttl of the json entry
+            let ttl = records.iter().find(|record| record.key == "json").unwrap();
+            assert!(ttl.ttl.unwrap() > Duration::from_secs(50));
+            assert!(import_engine(
+                &mut memory,
+                [Ok(ttl.clone())].into_iter(),
+                ImportMode::Overwrite
+            )
+            .is_err());
+        }
+
+        let broken = "{\"key\": \"a\", \"type\": \"String\", \"value\": \"a\"}\n\n{\"key\": 1}\n";
+        let errors: Vec<_> = RecordReader::new(broken.as_bytes(), ExportFormat::JsonLines)
+            .unwrap()
+            .filter_map(|record| record.err())
+            .collect();
+        assert!(matches!(
+            errors.as_slice(),
+            [KvError::InvalidImportRecord { line: 3, .. }]
+        ));
+        assert!(RecordReader::new("a,b\n".as_bytes(), ExportFormat::Csv).is_err());
+        assert!("xml".parse::<ExportFormat>().is_err());
+        assert_eq!(
+            ExportFormat::from_path(Path::new("dump.csv")),
+            Some(ExportFormat::Csv)
+        );
+
+        drop(page);
+    }
+
+    #[test]
+    fn test_export_batches() {
+        let mut memory = MemoryEngine::new();
+        let count = EXPORT_BATCH_SIZE * 2 + 1;
+        for i in 0..count {
+            memory
+                .put(&format!("key-{:05}", i), Value::Integer(i as u64))
+                .unwrap();
+        }
+        let mut writer = RecordWriter::new(vec![], ExportFormat::JsonLines).unwrap();
+        assert_eq!(export_engine(&memory, "", &mut writer).unwrap(), count);
+        let data = match writer {
+            RecordWriter::JsonLines(data) => data,
+            RecordWriter::Csv(_) => unreachable!(),
+        };
+        let keys: Vec<String> = RecordReader::new(data.as_slice(), ExportFormat::JsonLines)
+            .unwrap()
+            .map(|record| record.unwrap().key)
+            .collect();
+        assert_eq!(keys.len(), count);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    // Reports keys before `expired_before` as expired once they were scanned
+    struct ExpiringEngine {
+        memory: MemoryEngine,
+        expired_before: String,
+    }
+
+    impl StorageEngine for ExpiringEngine {
+        fn get(&self, key: &str) -> Result<Value, KvError> {
+            return self.memory.get(key);
+        }
+
+        fn put(&mut self, key: &str, value: Value) -> Result<(), KvError> {
+            return self.memory.put(key, value);
+        }
+
+        fn delete(&mut self, key: &str) -> Result<(), KvError> {
+            return self.memory.delete(key);
+        }
+
+        fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+            return self.memory.scan(prefix);
+        }
+
+        fn flush(&mut self) -> Result<(), KvError> {
+            return self.memory.flush();
+        }
+
+        fn stats(&self) -> Result<StorageStats, KvError> {
+            return self.memory.stats();
+        }
+
+        fn supports_ttl(&self) -> bool {
+            return true;
+        }
+
+        fn get_ttl(&self, key: &str) -> Result<Option<Duration>, KvError> {
+            if key < self.expired_before.as_str() {
+                return Err(KvError::KeyDoesNotExist {
+                    key: String::from(key),
+                });
+            }
+            return Ok(None);
+        }
+    }
+
+    #[test]
+    fn test_export_expired_batch() {
+        // The whole first batch expires while it is exported
+        let mut engine = ExpiringEngine {
+            memory: MemoryEngine::new(),
+            expired_before: format!("key-{:05}", EXPORT_BATCH_SIZE),
+        };
+        for i in 0..EXPORT_BATCH_SIZE + 10 {
+            engine
+                .put(&format!("key-{:05}", i), Value::Integer(i as u64))
+                .unwrap();
+        }
+        let mut writer = RecordWriter::new(vec![], ExportFormat::JsonLines).unwrap();
+        assert_eq!(export_engine(&engine, "", &mut writer).unwrap(), 10);
+    }
+}
@@ -0,0 +1,128 @@
+use super::errors::KvError;
+use serde_json::Value as JsonValue;
+
+// Supports the subset of JSONPath needed to address a single node, e.g.
+// `$.phones[0]`, `$.address.city` or `$['first name']`
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonPathSegment {
+    Field(String),
+    Index(usize),
+}
+
+pub fn parse(path: &str) -> Result<Vec<JsonPathSegment>, KvError> {
+    let invalid_path = || KvError::InvalidJsonPath {
+        path: String::from(path),
+    };
+    let chars: Vec<char> = path.chars().collect();
+    if chars.first() != Some(&'$') {
+        return Err(invalid_path());
+    }
+
+    let mut segments = vec![];
+    let mut position = 1;
+    while position < chars.len() {
+        match chars[position] {
+            '.' => {
+                let start = position + 1;
+                position = start;
+                while position < chars.len() && chars[position] != '.' && chars[position] != '[' {
+                    position += 1;
+                }
+                if position == start {
+                    return Err(invalid_path());
+                }
+                segments.push(JsonPathSegment::Field(
+                    chars[start..position].iter().collect(),
+                ));
+            }
+            '[' => {
+                let end = match chars[position..].iter().position(|c| *c == ']') {
+                    Some(length) => position + length,
+                    None => return Err(invalid_path()),
+                };
+                let inner: String = chars[position + 1..end].iter().collect();
+                segments.push(parse_bracket(&inner).ok_or_else(invalid_path)?);
+                position = end + 1;
+            }
+            _ => return Err(invalid_path()),
+        }
+    }
+    return Ok(segments);
+}
+
+fn parse_bracket(inner: &str) -> Option<JsonPathSegment> {
+    let quoted = inner.len() >= 2
+        && ((inner.starts_with('\'') && inner.ends_with('\''))
+            || (inner.starts_with('"') && inner.ends_with('"')));
+    if quoted {
+        return Some(JsonPathSegment::Field(String::from(
+            &inner[1..inner.len() - 1],
+        )));
+    }
+    return inner.parse::<usize>().ok().map(JsonPathSegment::Index);
+}
+
+/// Renders `segments` back into a path accepted by `parse`
+pub fn format_path(segments: &[JsonPathSegment]) -> String {
+    let mut path = String::from("$");
+    for segment in segments {
+        match segment {
+            JsonPathSegment::Field(name) => path.push_str(&format!("[{:?}]", name)),
+            JsonPathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    return path;
+}
+
+pub fn get<'a>(document: &'a JsonValue, segments: &[JsonPathSegment]) -> Option<&'a JsonValue> {
+    let mut node = document;
+    for segment in segments {
+        node = match segment {
+            JsonPathSegment::Field(name) => node.as_object()?.get(name)?,
+            JsonPathSegment::Index(index) => node.as_array()?.get(*index)?,
+        };
+    }
+    return Some(node);
+}
+
+// Missing fields on the last object are created, array indices must already exist
+pub fn set(
+    document: &mut JsonValue,
+    segments: &[JsonPathSegment],
+    value: JsonValue,
+) -> Result<(), KvError> {
+    let does_not_exist = || KvError::JsonPathDoesNotExist {
+        path: format_path(segments),
+    };
+    let (last, parents) = match segments.split_last() {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+
+    let mut node = document;
+    for segment in parents {
+        node = match segment {
+            JsonPathSegment::Field(name) => node.as_object_mut().and_then(|o| o.get_mut(name)),
+            JsonPathSegment::Index(index) => node.as_array_mut().and_then(|a| a.get_mut(*index)),
+        }
+        .ok_or_else(does_not_exist)?;
+    }
+
+    match last {
+        JsonPathSegment::Field(name) => {
+            node.as_object_mut()
+                .ok_or_else(does_not_exist)?
+                .insert(name.clone(), value);
+        }
+        JsonPathSegment::Index(index) => {
+            *node
+                .as_array_mut()
+                .and_then(|a| a.get_mut(*index))
+                .ok_or_else(does_not_exist)? = value;
+        }
+    }
+    return Ok(());
+}
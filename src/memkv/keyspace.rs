@@ -0,0 +1,195 @@
+use super::errors::KvError;
+use super::mem_kv_page::{MemKvPage, Value, PAGE_HEADER_SIZE};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PAGE_FILE_PREFIX: &str = "page-";
+
+/// Keyspace spread over several pages in one directory. A new page is started once the
+/// last one is full, and lookups use the bloom filter of each page to skip pages that
+/// cannot contain the key.
+pub struct Keyspace {
+    directory: PathBuf,
+    page_size: u64,
+    pages: Vec<MemKvPage>,
+}
+
+impl Keyspace {
+    pub fn new(directory: &Path, page_size: u64) -> Result<Self, KvError> {
+        fs::create_dir_all(directory)?;
+
+        let mut page_numbers = Vec::new();
+        for dir_entry in fs::read_dir(directory)? {
+            let file_name = dir_entry?.file_name();
+            // Sidecar files of a page carry an extension and don't parse as a number
+            if let Some(number) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(PAGE_FILE_PREFIX))
+                .and_then(|number| number.parse::<usize>().ok())
+            {
+                page_numbers.push(number);
+            }
+        }
+        page_numbers.sort();
+
+        let mut keyspace = Keyspace {
+            directory: PathBuf::from(directory),
+            page_size,
+            pages: Vec::new(),
+        };
+        for number in page_numbers {
+            let page = MemKvPage::new(&keyspace.get_page_path(number), page_size)?;
+            keyspace.pages.push(page);
+        }
+        if keyspace.pages.is_empty() {
+            keyspace.add_page()?;
+        }
+        return Ok(keyspace);
+    }
+
+    pub fn get_page_count(self: &Self) -> usize {
+        return self.pages.len();
+    }
+
+    pub fn get(self: &Self, key: &str) -> Result<Value, KvError> {
+        return match self.find_page(key)? {
+            Some(page) => self.pages[page].get(key),
+            None => Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            }),
+        };
+    }
+
+    pub fn insert(self: &mut Self, key: &str, value: Value) -> Result<(), KvError> {
+        if self.find_page(key)?.is_some() {
+            return Err(KvError::KeyAlreadyExists {
+                key: String::from(key),
+            });
+        }
+
+        let last_page = self.pages.len() - 1;
+        let entry_size = self.pages[last_page].get_max_entry_size(key, &value)?;
+        if entry_size > self.pages[last_page].get_free_space() {
+            // An entry that doesn't fit an empty page would only leave empty pages behind
+            let capacity = self.page_size - PAGE_HEADER_SIZE;
+            if entry_size > capacity {
+                return Err(KvError::NoSpaceLeft {
+                    requested: entry_size,
+                    available: capacity,
+                });
+            }
+            self.add_page()?;
+        }
+        let last_page = self.pages.len() - 1;
+        return self.pages[last_page].insert(key, value);
+    }
+
+    pub fn delete(self: &mut Self, key: &str) -> Result<(), KvError> {
+        return match self.find_page(key)? {
+            Some(page) => self.pages[page].delete(key),
+            None => Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            }),
+        };
+    }
+
+    // Only looks at the index, so reading a key doesn't count as an access twice and an
+    // unreadable value still counts as present
+    fn find_page(self: &Self, key: &str) -> Result<Option<usize>, KvError> {
+        for (number, page) in self.pages.iter().enumerate() {
+            if page.may_contain(key) && page.contains_live_key(key)? {
+                return Ok(Some(number));
+            }
+        }
+        return Ok(None);
+    }
+
+    fn add_page(self: &mut Self) -> Result<(), KvError> {
+        let path = self.get_page_path(self.pages.len());
+        info!("Adding page {:?} to keyspace", path);
+        self.pages.push(MemKvPage::new(&path, self.page_size)?);
+        return Ok(());
+    }
+
+    fn get_page_path(self: &Self, number: usize) -> PathBuf {
+        return self
+            .directory
+            .join(format!("{}{}", PAGE_FILE_PREFIX, number));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Keyspace;
+    use crate::memkv::{KvError, Value};
+    use std::fs;
+    use std::path::Path;
+
+    const TEST_DIRECTORY: &str = "test_keyspace_multi_page";
+
+    #[test]
+    fn test_multi_page_keyspace() {
+        if Path::new(TEST_DIRECTORY).exists() {
+            fs::remove_dir_all(TEST_DIRECTORY).unwrap();
+        }
+        {
+            let mut keyspace = Keyspace::new(Path::new(TEST_DIRECTORY), 1024).unwrap();
+            for i in 0..100 {
+                keyspace
+                    .insert(&format!("key-{}", i), Value::Integer(i))
+                    .unwrap();
+            }
+            assert!(keyspace.get_page_count() > 1);
+            assert!(keyspace.insert("key-5", Value::Integer(0)).is_err());
+
+            // Entries larger than a page are rejected without adding pages
+            let page_count = keyspace.get_page_count();
+            assert!(matches!(
+                keyspace.insert("large", Value::Blob(vec![0; 1024])),
+                Err(KvError::NoSpaceLeft { .. })
+            ));
+            assert_eq!(keyspace.get_page_count(), page_count);
+
+            // Most pages are skipped without consulting their index
+            let candidates = keyspace
+                .pages
+                .iter()
+                .filter(|page| page.may_contain("key-99"))
+                .count();
+            assert!(candidates < keyspace.get_page_count());
+            keyspace.delete("key-0").unwrap();
+            keyspace
+                .insert("doc", Value::json_from_str("{\"a\":1}").unwrap())
+                .unwrap();
+        }
+
+        // A value that can't be read still occupies its key
+        for dir_entry in fs::read_dir(TEST_DIRECTORY).unwrap() {
+            let path = dir_entry.unwrap().path();
+            let mut data = fs::read(&path).unwrap();
+            if let Some(position) = data.windows(7).position(|window| window == b"{\"a\":1}") {
+                data[position + 6] = b']';
+                fs::write(&path, data).unwrap();
+            }
+        }
+
+        let mut keyspace = Keyspace::new(Path::new(TEST_DIRECTORY), 1024).unwrap();
+        assert!(keyspace.get_page_count() > 1);
+        assert!(keyspace.get("key-0").is_err());
+        assert!(keyspace.get("doc").is_err());
+        assert!(matches!(
+            keyspace.insert("doc", Value::Integer(1)),
+            Err(KvError::KeyAlreadyExists { .. })
+        ));
+        for i in 1..100 {
+            if let Value::Integer(value) = keyspace.get(&format!("key-{}", i)).unwrap() {
+                assert_eq!(value, i);
+            } else {
+                panic!();
+            }
+        }
+
+        fs::remove_dir_all(TEST_DIRECTORY).unwrap();
+    }
+}
@@ -0,0 +1,759 @@
+use super::backup::link_or_copy;
+use super::errors::KvError;
+use super::mem_kv_page::Value;
+use super::sstable::{decode_record, encode_record, Record, SsTable, SsTableWriter};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+const WAL_FILE_NAME: &str = "wal.log";
+const COMPACTION_JOURNAL_FILE_NAME: &str = "compaction.json";
+const TABLE_FILE_EXTENSION: &str = "sst";
+const DEFAULT_MEMTABLE_THRESHOLD: usize = 1024 * 1024 * 4; // 4 MB
+
+// Number of overlapping level 0 tables that triggers merging them into level 1
+const LEVEL_0_COMPACTION_TRIGGER: usize = 4;
+const LEVEL_1_MAX_SIZE: u64 = 1024 * 1024 * 10; // 10 MB
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+const TARGET_TABLE_SIZE: u64 = 1024 * 1024 * 2; // 2 MB
+
+type RecordSource<'a> = Box<dyn Iterator<Item = Result<Record, KvError>> + 'a>;
+
+// Written before a compaction writes its outputs and committed once they are complete, a
+// compaction interrupted by a crash is rolled back or finished by `finish_compaction`
+#[derive(Serialize, Deserialize)]
+struct CompactionJournal {
+    // Level the outputs are written to, outputs get sequences from `first_output_sequence`
+    level: usize,
+    first_output_sequence: u64,
+    // File names of the tables replaced by the outputs
+    inputs: Vec<String>,
+    committed: bool,
+}
+
+/// Log structured merge tree storing writes in a memtable backed by a write ahead log.
+/// Full memtables are written to level 0 as sorted tables which are merged into the
+/// non overlapping tables of the deeper levels by leveled compaction.
+pub struct LsmTree {
+    directory: PathBuf,
+    memtable: BTreeMap<String, Option<Value>>,
+    memtable_size: usize,
+    memtable_threshold: usize,
+    wal: File,
+    // Level 0 is ordered from newest to oldest, all other levels by their smallest key
+    levels: Vec<Vec<SsTable>>,
+    next_sequence: u64,
+}
+
+impl LsmTree {
+    pub fn open(directory: &Path) -> Result<Self, KvError> {
+        fs::create_dir_all(directory)?;
+        finish_compaction(directory)?;
+
+        let mut levels: Vec<Vec<SsTable>> = vec![Vec::new()];
+        let mut next_sequence = 0;
+        for dir_entry in fs::read_dir(directory)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|extension| extension.to_str())
+                != Some(TABLE_FILE_EXTENSION)
+            {
+                continue;
+            }
+            let (level, sequence) = match parse_table_name(&path) {
+                Some(table_name) => table_name,
+                None => {
+                    warn!("Ignoring unknown table file {:?}", path);
+                    continue;
+                }
+            };
+            while levels.len() <= level {
+                levels.push(Vec::new());
+            }
+            levels[level].push(SsTable::open(&path, level, sequence)?);
+            next_sequence = next_sequence.max(sequence + 1);
+        }
+        levels[0].sort_by_key(|table| Reverse(table.get_sequence()));
+        for level in levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.get_min_key().cmp(b.get_min_key()));
+        }
+
+        let wal_path = directory.join(WAL_FILE_NAME);
+        let mut wal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&wal_path)?;
+        let mut tree = LsmTree {
+            directory: PathBuf::from(directory),
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            memtable_threshold: DEFAULT_MEMTABLE_THRESHOLD,
+            wal: wal.try_clone()?,
+            levels,
+            next_sequence,
+        };
+        tree.replay_wal(&mut wal)?;
+        return Ok(tree);
+    }
+
+    // A record torn by a crash ends the log, everything before it is recovered
+    fn replay_wal(self: &mut Self, wal: &mut File) -> Result<(), KvError> {
+        let mut contents = Vec::new();
+        wal.read_to_end(&mut contents)?;
+        let mut offset = 0;
+        while let Some(((key, value), record_size)) = decode_record(&contents[offset..])? {
+            self.memtable.insert(key, value);
+            self.memtable_size += record_size;
+            offset += record_size;
+        }
+        if offset < contents.len() {
+            warn!(
+                "Dropping {} bytes of incomplete records from the write ahead log",
+                contents.len() - offset
+            );
+            self.wal.set_len(offset as u64)?;
+        }
+        return Ok(());
+    }
+
+    pub fn get_directory(self: &Self) -> &Path {
+        return &self.directory;
+    }
+
+    pub fn set_memtable_threshold(self: &mut Self, threshold: usize) {
+        self.memtable_threshold = threshold;
+    }
+
+    pub fn get(self: &Self, key: &str) -> Result<Value, KvError> {
+        return match self.lookup(key)? {
+            Some(value) => Ok(value),
+            None => Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            }),
+        };
+    }
+
+    // The newest version of a key wins, a tombstone hides all older versions
+    fn lookup(self: &Self, key: &str) -> Result<Option<Value>, KvError> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for table in &self.levels[0] {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        // Tables of deeper levels only overlap after a failed compaction, the newest wins
+        for level in self.levels.iter().skip(1) {
+            let mut tables: Vec<&SsTable> = level
+                .iter()
+                .filter(|table| table.overlaps(key, key))
+                .collect();
+            tables.sort_by_key(|table| Reverse(table.get_sequence()));
+            for table in tables {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        return Ok(None);
+    }
+
+    pub fn put(self: &mut Self, key: &str, value: Value) -> Result<(), KvError> {
+        return self.write(key, Some(value));
+    }
+
+    pub fn delete(self: &mut Self, key: &str) -> Result<(), KvError> {
+        if self.lookup(key)?.is_none() {
+            return Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            });
+        }
+        return self.write(key, None);
+    }
+
+    fn write(self: &mut Self, key: &str, value: Option<Value>) -> Result<(), KvError> {
+        let record = encode_record(key, value.as_ref())?;
+        self.wal.write_all(&record)?;
+        self.wal.sync_data()?;
+        self.memtable_size += record.len();
+        self.memtable.insert(String::from(key), value);
+
+        if self.memtable_size >= self.memtable_threshold {
+            self.flush_memtable()?;
+            self.compact()?;
+        }
+        return Ok(());
+    }
+
+    /// Writes the memtable to a new level 0 table and truncates the write ahead log
+    pub fn flush_memtable(self: &mut Self) -> Result<(), KvError> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let sequence = self.take_sequence();
+        let mut writer = SsTableWriter::new(&self.get_table_path(0, sequence));
+        for (key, value) in &self.memtable {
+            writer.add(key, value.as_ref())?;
+        }
+        let table = writer.finish(0, sequence)?;
+        info!(
+            "Flushed memtable with {} records to {:?}",
+            table.get_entry_count(),
+            self.get_table_path(0, sequence)
+        );
+        self.levels[0].insert(0, table);
+
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        return Ok(());
+    }
+
+    /// Returns all live entries whose key starts with `prefix`, ordered by key
+    pub fn scan(self: &Self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+        let max_key = format!("{}{}", prefix, char::MAX);
+        // Older versions are applied first so newer records and tombstones replace them
+        let mut merged: BTreeMap<String, Option<Value>> = BTreeMap::new();
+        let mut tables: Vec<&SsTable> = vec![];
+        for level in self.levels.iter().skip(1).rev() {
+            let start = tables.len();
+            tables.extend(level);
+            tables[start..].sort_by_key(|table| table.get_sequence());
+        }
+        tables.extend(self.levels[0].iter().rev());
+        for table in tables
+            .into_iter()
+            .filter(|table| table.overlaps(prefix, &max_key))
+        {
+            for record in table.records() {
+                let (key, value): Record = record?;
+                if key.starts_with(prefix) {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        for (key, value) in self.memtable.range(String::from(prefix)..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            merged.insert(key.clone(), value.clone());
+        }
+        return Ok(merged
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect());
+    }
+
+    /// Returns at most `limit` entries with keys starting with `prefix` after `after`, ordered
+    /// by key. The memtable and the tables are merged lazily from the first block which can
+    /// hold such keys, so a batch only reads about `limit` records of every table.
+    pub fn scan_after(
+        self: &Self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>, KvError> {
+        let start = match after {
+            Some(after) if after > prefix => after,
+            _ => prefix,
+        };
+        let max_key = format!("{}{}", prefix, char::MAX);
+        // Sources are ordered from newest to oldest like in `lookup`
+        let memtable = self
+            .memtable
+            .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let mut sources: Vec<Peekable<RecordSource>> =
+            vec![(Box::new(memtable) as RecordSource).peekable()];
+        let mut tables: Vec<&SsTable> = self.levels[0].iter().collect();
+        for level in self.levels.iter().skip(1) {
+            let start = tables.len();
+            tables.extend(level);
+            tables[start..].sort_by_key(|table| Reverse(table.get_sequence()));
+        }
+        for table in tables
+            .into_iter()
+            .filter(|table| table.overlaps(start, &max_key))
+        {
+            sources.push((Box::new(table.records_from(start)) as RecordSource).peekable());
+        }
+
+        let mut entries = vec![];
+        while entries.len() < limit {
+            let mut next_key: Option<String> = None;
+            for source in sources.iter_mut() {
+                match source.peek() {
+                    Some(Ok((key, _)))
+                        if next_key.as_ref().is_none_or(|next_key| key < next_key) =>
+                    {
+                        next_key = Some(key.clone());
+                    }
+                    Some(Err(_)) => {
+                        if let Some(Err(error)) = source.next() {
+                            return Err(error);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let key = match next_key {
+                Some(key) if key.starts_with(prefix) => key,
+                _ => break,
+            };
+            // Every source holding the key moves on, the newest record wins
+            let mut newest = None;
+            for source in sources.iter_mut() {
+                if matches!(source.peek(), Some(Ok((source_key, _))) if *source_key == key) {
+                    let (_, value) = source.next().unwrap()?;
+                    newest.get_or_insert(value);
+                }
+            }
+            if Some(key.as_str()) == after {
+                continue;
+            }
+            if let Some(Some(value)) = newest {
+                entries.push((key, value));
+            }
+        }
+        return Ok(entries);
+    }
+
+    // Size of all tables and the write ahead log, overwritten records included
+    pub fn get_size(self: &Self) -> u64 {
+        let table_size: u64 = self
+            .levels
+            .iter()
+            .flatten()
+            .map(|table| table.get_size())
+            .sum();
+        return table_size + self.memtable_size as u64;
+    }
+
+    pub fn get_table_count(self: &Self, level: usize) -> usize {
+        return self.levels.get(level).map_or(0, |tables| tables.len());
+    }
+
+    fn compact(self: &mut Self) -> Result<(), KvError> {
+        loop {
+            if self.levels[0].len() >= LEVEL_0_COMPACTION_TRIGGER {
+                self.compact_level(0)?;
+                continue;
+            }
+            let full_level = (1..self.levels.len())
+                .find(|&level| self.get_level_size(level) > get_max_level_size(level));
+            match full_level {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Merges all of level 0, or the first table of a deeper level, with the overlapping
+    // tables of the next level. The levels only change once the outputs are complete.
+    fn compact_level(self: &mut Self, level: usize) -> Result<(), KvError> {
+        finish_compaction(&self.directory)?;
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+        }
+        let input_count = if level == 0 { self.levels[0].len() } else { 1 };
+        let inputs = &self.levels[level][..input_count];
+        let min_key = inputs
+            .iter()
+            .map(|table| table.get_min_key())
+            .min()
+            .map(String::from);
+        let max_key = inputs
+            .iter()
+            .map(|table| table.get_max_key())
+            .max()
+            .map(String::from);
+        let (min_key, max_key) = match (min_key, max_key) {
+            (Some(min_key), Some(max_key)) => (min_key, max_key),
+            _ => return Ok(()),
+        };
+        let mut overlapping: Vec<&SsTable> = self.levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(&min_key, &max_key))
+            .collect();
+        overlapping.sort_by_key(|table| table.get_sequence());
+
+        // Newer tables are applied last so their records replace older ones
+        let mut merged: BTreeMap<String, Option<Value>> = BTreeMap::new();
+        for table in overlapping.iter().copied().chain(inputs.iter().rev()) {
+            for record in table.records() {
+                let (key, value): Record = record?;
+                merged.insert(key, value);
+            }
+        }
+        // Nothing below the target level can be shadowed, so tombstones are dropped there
+        let is_bottom_level = self.levels[level + 2..]
+            .iter()
+            .all(|tables| tables.is_empty());
+        if is_bottom_level {
+            merged.retain(|_, value| value.is_some());
+        }
+
+        let mut journal = CompactionJournal {
+            level: level + 1,
+            first_output_sequence: self.next_sequence,
+            inputs: overlapping
+                .iter()
+                .copied()
+                .chain(inputs)
+                .map(|table| get_table_name(table.get_level(), table.get_sequence()))
+                .collect(),
+            committed: false,
+        };
+        let (input_count, overlapping_count) = (inputs.len(), overlapping.len());
+        write_journal(&self.directory, &journal)?;
+        let outputs = match self.write_tables(level + 1, &merged) {
+            Ok(outputs) => outputs,
+            Err(error) => {
+                // Removes the outputs written so far, the levels are unchanged
+                if let Err(rollback_error) = finish_compaction(&self.directory) {
+                    warn!("Failed to roll back compaction: {}", rollback_error);
+                }
+                return Err(error);
+            }
+        };
+        journal.committed = true;
+        write_journal(&self.directory, &journal)?;
+        info!(
+            "Compacted {} tables of level {} and {} tables of level {} into {} tables",
+            input_count,
+            level,
+            overlapping_count,
+            level + 1,
+            outputs.len()
+        );
+
+        let replaced: Vec<SsTable> = self.levels[level].drain(..input_count).collect();
+        let (overlapping, rest): (Vec<SsTable>, Vec<SsTable>) =
+            std::mem::take(&mut self.levels[level + 1])
+                .into_iter()
+                .partition(|table| table.overlaps(&min_key, &max_key));
+        self.levels[level + 1] = rest;
+        self.levels[level + 1].extend(outputs);
+        self.levels[level + 1].sort_by(|a, b| a.get_min_key().cmp(b.get_min_key()));
+        drop(replaced);
+        drop(overlapping);
+        return finish_compaction(&self.directory);
+    }
+
+    fn write_tables(
+        self: &mut Self,
+        level: usize,
+        merged: &BTreeMap<String, Option<Value>>,
+    ) -> Result<Vec<SsTable>, KvError> {
+        let mut outputs = Vec::new();
+        let mut writer: Option<SsTableWriter> = None;
+        let mut writer_sequence = 0;
+        for (key, value) in merged {
+            if writer.is_none() {
+                writer_sequence = self.take_sequence();
+                writer = Some(SsTableWriter::new(
+                    &self.get_table_path(level, writer_sequence),
+                ));
+            }
+            let current = writer.as_mut().unwrap();
+            current.add(key, value.as_ref())?;
+            if current.get_size() >= TARGET_TABLE_SIZE {
+                outputs.push(writer.take().unwrap().finish(level, writer_sequence)?);
+            }
+        }
+        if let Some(writer) = writer.filter(|writer| !writer.is_empty()) {
+            outputs.push(writer.finish(level, writer_sequence)?);
+        }
+        return Ok(outputs);
+    }
+
+    fn get_level_size(self: &Self, level: usize) -> u64 {
+        return self.levels[level]
+            .iter()
+            .map(|table| table.get_size())
+            .sum();
+    }
+
+    /// Flushes the memtable and hard links all tables into `directory`, returns their names
+    /// and sequences. The write ahead log is empty after the flush and is left out.
+    pub fn link_tables(self: &mut Self, directory: &Path) -> Result<Vec<(String, u64)>, KvError> {
+        self.flush_memtable()?;
+        let mut tables = vec![];
+        for table in self.levels.iter().flatten() {
+            let name = get_table_name(table.get_level(), table.get_sequence());
+            link_or_copy(&self.directory.join(&name), &directory.join(&name))?;
+            tables.push((name, table.get_sequence()));
+        }
+        return Ok(tables);
+    }
+
+    fn take_sequence(self: &mut Self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        return sequence;
+    }
+
+    fn get_table_path(self: &Self, level: usize, sequence: u64) -> PathBuf {
+        return self.directory.join(get_table_name(level, sequence));
+    }
+}
+
+fn write_journal(directory: &Path, journal: &CompactionJournal) -> Result<(), KvError> {
+    let path = directory.join(COMPACTION_JOURNAL_FILE_NAME);
+    let temporary_path = path.with_extension("tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(&serde_json::to_vec(journal)?)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, &path)?;
+    return Ok(());
+}
+
+// Removes the inputs of a committed compaction or the outputs of one that was interrupted
+fn finish_compaction(directory: &Path) -> Result<(), KvError> {
+    let path = directory.join(COMPACTION_JOURNAL_FILE_NAME);
+    if !path.exists() {
+        return Ok(());
+    }
+    let journal: CompactionJournal = serde_json::from_slice(&fs::read(&path)?)?;
+    let mut obsolete_tables = vec![];
+    if journal.committed {
+        obsolete_tables.extend(journal.inputs.iter().map(|name| directory.join(name)));
+    } else {
+        warn!("Rolling back interrupted compaction in {:?}", directory);
+        for dir_entry in fs::read_dir(directory)? {
+            let table_path = dir_entry?.path();
+            if table_path
+                .extension()
+                .is_some_and(|extension| extension == TABLE_FILE_EXTENSION)
+                && parse_table_name(&table_path).is_some_and(|(level, sequence)| {
+                    level == journal.level && sequence >= journal.first_output_sequence
+                })
+            {
+                obsolete_tables.push(table_path);
+            }
+        }
+    }
+    for table_path in obsolete_tables {
+        if table_path.exists() {
+            fs::remove_file(table_path)?;
+        }
+    }
+    fs::remove_file(path)?;
+    return Ok(());
+}
+
+fn get_max_level_size(level: usize) -> u64 {
+    return LEVEL_1_MAX_SIZE * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1);
+}
+
+// Tables are named <level>-<sequence>.sst
+fn get_table_name(level: usize, sequence: u64) -> String {
+    return format!("{}-{}.{}", level, sequence, TABLE_FILE_EXTENSION);
+}
+
+fn parse_table_name(path: &Path) -> Option<(usize, u64)> {
+    let stem = path.file_stem()?.to_str()?;
+    let (level, sequence) = stem.split_once('-')?;
+    return Some((level.parse().ok()?, sequence.parse().ok()?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_journal, CompactionJournal, LsmTree};
+    use crate::memkv::Value;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn run_test<T>(directory: &str, test: T)
+    where
+        T: FnOnce() + std::panic::UnwindSafe,
+    {
+        if Path::new(directory).exists() {
+            fs::remove_dir_all(directory).unwrap();
+        }
+        let result = std::panic::catch_unwind(test);
+        fs::remove_dir_all(directory).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn assert_integer(tree: &LsmTree, key: &str, expected: u64) {
+        if let Value::Integer(value) = tree.get(key).unwrap() {
+            assert_eq!(value, expected);
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn test_wal_recovery() {
+        const DIRECTORY: &str = "test_lsm_wal_recovery";
+        run_test(DIRECTORY, || {
+            {
+                let mut tree = LsmTree::open(Path::new(DIRECTORY)).unwrap();
+                tree.put("albert", Value::Integer(1)).unwrap();
+                tree.put("peter", Value::String(String::from("pan")))
+                    .unwrap();
+                tree.put("albert", Value::Integer(2)).unwrap();
+                tree.delete("peter").unwrap();
+                assert!(tree.delete("peter").is_err());
+            }
+            // A torn record at the end of the log is dropped
+            let mut wal = OpenOptions::new()
+                .append(true)
+                .open(Path::new(DIRECTORY).join("wal.log"))
+                .unwrap();
+            wal.write_all(&[0x2, 0x0, 0x0]).unwrap();
+
+            let tree = LsmTree::open(Path::new(DIRECTORY)).unwrap();
+            assert_integer(&tree, "albert", 2);
+            assert!(tree.get("peter").is_err());
+            assert_eq!(tree.get_table_count(0), 0);
+        });
+    }
+
+    #[test]
+    fn test_flush_and_compaction() {
+        const DIRECTORY: &str = "test_lsm_compaction";
+        run_test(DIRECTORY, || {
+            {
+                let mut tree = LsmTree::open(Path::new(DIRECTORY)).unwrap();
+                tree.set_memtable_threshold(1024);
+                for i in 0..200 {
+                    tree.put(&format!("key-{:03}", i), Value::Integer(i))
+                        .unwrap();
+                }
+                for i in (0..200).step_by(2) {
+                    tree.put(&format!("key-{:03}", i), Value::Integer(i * 10))
+                        .unwrap();
+                }
+                for i in 0..50 {
+                    tree.delete(&format!("key-{:03}", i)).unwrap();
+                }
+                assert!(tree.get_table_count(0) < 4);
+                assert!(tree.get_table_count(1) > 0);
+                tree.flush_memtable().unwrap();
+            }
+
+            let tree = LsmTree::open(Path::new(DIRECTORY)).unwrap();
+            for i in 0..50 {
+                assert!(tree.get(&format!("key-{:03}", i)).is_err());
+            }
+            for i in 50..200 {
+                let expected = if i % 2 == 0 { i * 10 } else { i };
+                assert_integer(&tree, &format!("key-{:03}", i), expected);
+            }
+            assert!(tree.get("key-999").is_err());
+        });
+    }
+
+    #[test]
+    fn test_interrupted_compaction() {
+        const DIRECTORY: &str = "test_lsm_interrupted_compaction";
+        run_test(DIRECTORY, || {
+            let directory = Path::new(DIRECTORY);
+            let mut tree = LsmTree::open(directory).unwrap();
+            tree.put("a", Value::Integer(1)).unwrap();
+            tree.put("b", Value::Integer(1)).unwrap();
+            tree.flush_memtable().unwrap();
+            tree.compact_level(0).unwrap();
+            let old_table = fs::read(directory.join("1-1.sst")).unwrap();
+            tree.put("a", Value::Integer(2)).unwrap();
+            tree.flush_memtable().unwrap();
+            let flushed_table = fs::read(directory.join("0-2.sst")).unwrap();
+            tree.compact_level(0).unwrap();
+            let new_table = fs::read(directory.join("1-3.sst")).unwrap();
+            drop(tree);
+            let restore_inputs = || {
+                fs::write(directory.join("1-1.sst"), &old_table).unwrap();
+                fs::write(directory.join("0-2.sst"), &flushed_table).unwrap();
+            };
+            let mut journal = CompactionJournal {
+                level: 1,
+                first_output_sequence: 3,
+                inputs: vec![String::from("1-1.sst"), String::from("0-2.sst")],
+                committed: true,
+            };
+
+            // Crashed after the outputs were committed, the inputs are removed
+            restore_inputs();
+            write_journal(directory, &journal).unwrap();
+            let tree = LsmTree::open(directory).unwrap();
+            assert_eq!((tree.get_table_count(0), tree.get_table_count(1)), (0, 1));
+            assert_integer(&tree, "a", 2);
+            drop(tree);
+
+            // Crashed while writing the outputs, the outputs are removed
+            restore_inputs();
+            journal.committed = false;
+            write_journal(directory, &journal).unwrap();
+            let tree = LsmTree::open(directory).unwrap();
+            assert!(!directory.join("1-3.sst").exists());
+            assert_eq!((tree.get_table_count(0), tree.get_table_count(1)), (1, 1));
+            assert_integer(&tree, "a", 2);
+            assert_integer(&tree, "b", 1);
+            drop(tree);
+
+            // Overlapping tables of a level are read newest first
+            fs::remove_file(directory.join("0-2.sst")).unwrap();
+            fs::write(directory.join("1-3.sst"), &new_table).unwrap();
+            let tree = LsmTree::open(directory).unwrap();
+            assert_eq!(tree.get_table_count(1), 2);
+            assert_integer(&tree, "a", 2);
+            assert_eq!(
+                tree.scan("").unwrap()[0],
+                (String::from("a"), Value::Integer(2))
+            );
+        });
+    }
+
+    #[test]
+    fn test_scan_after() {
+        const DIRECTORY: &str = "test_lsm_scan_after";
+        run_test(DIRECTORY, || {
+            let mut tree = LsmTree::open(Path::new(DIRECTORY)).unwrap();
+            for i in 0..100 {
+                tree.put(&format!("key-{:03}", i), Value::Integer(i))
+                    .unwrap();
+            }
+            tree.flush_memtable().unwrap();
+            for i in (0..100).step_by(3) {
+                tree.delete(&format!("key-{:03}", i)).unwrap();
+            }
+            tree.put("key-050", Value::Integer(500)).unwrap();
+            tree.flush_memtable().unwrap();
+            tree.put("key-001", Value::Integer(10)).unwrap();
+            tree.put("key-003", Value::Integer(30)).unwrap();
+            tree.put("other", Value::Integer(0)).unwrap();
+
+            // Walking in batches sees the same entries as a full scan
+            let mut entries = vec![];
+            let mut last_key: Option<String> = None;
+            loop {
+                let batch = tree.scan_after("key-", last_key.as_deref(), 7).unwrap();
+                assert!(batch.len() <= 7);
+                if batch.is_empty() {
+                    break;
+                }
+                last_key = Some(batch.last().unwrap().0.clone());
+                entries.extend(batch);
+            }
+            assert_eq!(entries, tree.scan("key-").unwrap());
+            assert_eq!(entries.len(), 67);
+            assert_eq!(
+                tree.scan_after("key-", Some("key-002"), 1).unwrap(),
+                vec![(String::from("key-003"), Value::Integer(30))]
+            );
+        });
+    }
+}
@@ -1,43 +1,74 @@
-use super::errors;
-use log::{error, info, warn};
+use super::blob_file;
+use super::blob_file::{BlobReference, BlobWriter, BLOB_REFERENCE_SIZE, FLAG_OVERFLOW};
+use super::bloom_filter::BloomFilter;
+use super::btree_index::BTreeIndex;
+use super::codec::Codec;
+use super::compression::Compression;
+use super::durability::DurabilityMode;
+use super::encryption::{Keyring, ENCRYPTION_OVERHEAD, FLAG_ENCRYPTED};
+use super::errors::KvError;
+use super::eviction::{AccessTracker, EvictionPolicy, EvictionStats};
+use super::json_path;
+use super::page_index::PageIndex;
+use super::secondary_index::{IndexExtractor, IndexKey, SecondaryIndex};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, error, info, warn};
 use memmap::MmapMut;
-use serde::{Deserialize, Serialize};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json;
+use sha2::{Digest, Sha256};
 use std::cmp::Ordering;
 use std::collections::hash_map::HashMap;
-use std::collections::BinaryHeap;
-use std::error;
+use std::collections::{BTreeMap, BinaryHeap};
 use std::fmt;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io;
-use std::io::Write;
+use std::io::{Cursor, Read, Write};
 use std::mem::size_of;
-use std::panic;
+use std::ops::RangeBounds;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str;
-use std::sync::RwLock;
-use std::usize;
-
-const KV_PAGE_SIZE: u64 = 1024 * 1024 * 4; // 4 MB
+use std::time::{Duration, Instant};
+
+pub const KV_PAGE_SIZE: u64 = 1024 * 1024 * 4; // 4 MB
+
+// The page header stores the current page size, the remaining bytes are reserved
+pub(crate) const PAGE_HEADER_SIZE: u64 = 64;
+pub(crate) const ENTRY_HEADER_SIZE: u64 = (size_of::<u8>() * 2 + size_of::<usize>() * 2) as u64;
+pub(crate) const ENTRY_DELETED_FLAG: u8 = 0x1;
+// Entries with this flag are followed by a checksum, entries written before it existed are not
+pub(crate) const FLAG_CHECKSUM: u8 = 0x20;
+pub(crate) const ENTRY_CHECKSUM_SIZE: u64 = 8;
+// Files next to the page, see `get_sidecar_path`
+pub(crate) const SIDECAR_EXTENSIONS: [&str; 4] = ["bloom", "blob", "btree", "indexes"];
+pub(crate) const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+pub(crate) const DEFAULT_LARGE_VALUE_THRESHOLD: usize = 1024 * 1024; // 1 MB
 
 #[derive(Copy, Clone)]
 pub enum ValueDataType {
     String = 1,
     Integer = 2,
     Blob = 3,
+    Typed = 4,
+    Json = 5,
 }
 
 impl TryFrom<u8> for ValueDataType {
-    type Error = errors::InvalidDataTypeError;
+    type Error = KvError;
 
     fn try_from(from_value: u8) -> Result<Self, Self::Error> {
         return match from_value {
             0x1 => Ok(ValueDataType::String),
             0x2 => Ok(ValueDataType::Integer),
             0x3 => Ok(ValueDataType::Blob),
-            _ => Err(errors::InvalidDataTypeError),
+            0x4 => Ok(ValueDataType::Typed),
+            0x5 => Ok(ValueDataType::Json),
+            data_type => Err(KvError::InvalidDataType { data_type }),
         };
     }
 }
@@ -48,31 +79,138 @@ impl fmt::Debug for ValueDataType {
             ValueDataType::String => write!(f, "String"),
             ValueDataType::Integer => write!(f, "Integer"),
             ValueDataType::Blob => write!(f, "Blob"),
+            ValueDataType::Typed => write!(f, "Typed"),
+            ValueDataType::Json => write!(f, "Json"),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     String(String),
     Integer(u64),
     Blob(Vec<u8>),
+    Typed(Codec, Vec<u8>),
+    Json(serde_json::Value),
 }
 
 impl Value {
-    fn get_bytes_length(self: &Self) -> Result<usize, Box<dyn error::Error>> {
+    /// Parses `text` into a json document, rejecting anything that is not valid json
+    pub fn json_from_str(text: &str) -> Result<Value, KvError> {
+        return Ok(Value::Json(serde_json::from_str(text)?));
+    }
+
+    fn get_bytes_length(self: &Self) -> Result<usize, KvError> {
         return match self {
-            Value::String(text) => Ok(text.as_bytes().len()),
+            Value::String(text) => Ok(text.len()),
             Value::Integer(number) => Ok(number.to_be_bytes().len()),
             Value::Blob(bytes) => Ok(bytes.len()),
+            Value::Typed(_, bytes) => Ok(bytes.len() + size_of::<u8>()),
+            Value::Json(document) => Ok(serde_json::to_vec(document)?.len()),
+        };
+    }
+
+    pub(crate) fn into_bytes(self: Self) -> Result<Vec<u8>, KvError> {
+        return match self {
+            Value::String(text) => Ok(Vec::from(text.as_bytes())),
+            Value::Integer(number) => Ok(Vec::from(number.to_be_bytes())),
+            Value::Blob(bytes) => Ok(bytes),
+            Value::Typed(codec, bytes) => Ok([vec![codec as u8], bytes].concat()),
+            Value::Json(document) => Ok(serde_json::to_vec(&document)?),
+        };
+    }
+
+    pub(crate) fn from_bytes(data_type: ValueDataType, bytes: &[u8]) -> Result<Value, KvError> {
+        return match data_type {
+            ValueDataType::String => Ok(Value::String(String::from(str::from_utf8(bytes)?))),
+            ValueDataType::Integer => match bytes.try_into() {
+                Ok(bytes) => Ok(Value::Integer(u64::from_be_bytes(bytes))),
+                Err(_) => Err(KvError::InvalidValue {
+                    data_type: data_type as u8,
+                }),
+            },
+            ValueDataType::Blob => Ok(Value::Blob(bytes.to_vec())),
+            ValueDataType::Typed => {
+                if bytes.is_empty() {
+                    return Err(KvError::InvalidValue {
+                        data_type: data_type as u8,
+                    });
+                }
+                Ok(Value::Typed(bytes[0].try_into()?, bytes[1..].to_vec()))
+            }
+            ValueDataType::Json => Ok(Value::Json(serde_json::from_slice(bytes)?)),
         };
     }
 
-    fn get_data_type(self: &Self) -> ValueDataType {
+    pub fn get_data_type(self: &Self) -> ValueDataType {
         return match self {
             Value::String(_) => ValueDataType::String,
             Value::Integer(_) => ValueDataType::Integer,
             Value::Blob(_) => ValueDataType::Blob,
+            Value::Typed(_, _) => ValueDataType::Typed,
+            Value::Json(_) => ValueDataType::Json,
+        };
+    }
+
+    /// Describes the value as json object with its data type, for example
+    /// `{"type": "Integer", "value": 7}`. Binary data is base64 encoded and typed values
+    /// additionally name their codec.
+    pub fn to_json(self: &Self) -> serde_json::Value {
+        let data_type = format!("{:?}", self.get_data_type());
+        return match self {
+            Value::String(text) => serde_json::json!({"type": data_type, "value": text}),
+            Value::Integer(number) => serde_json::json!({"type": data_type, "value": number}),
+            Value::Blob(bytes) => {
+                serde_json::json!({"type": data_type, "value": BASE64.encode(bytes)})
+            }
+            Value::Typed(codec, bytes) => serde_json::json!({
+                "type": data_type,
+                "codec": format!("{:?}", codec),
+                "value": BASE64.encode(bytes),
+            }),
+            Value::Json(document) => serde_json::json!({"type": data_type, "value": document}),
+        };
+    }
+
+    /// Reverses `to_json`
+    pub fn from_json(document: &serde_json::Value) -> Result<Value, KvError> {
+        let invalid = |reason: &str| KvError::InvalidValueJson {
+            reason: String::from(reason),
+        };
+        let value = document
+            .get("value")
+            .ok_or_else(|| invalid("missing \"value\""))?;
+        let decode = |value: &serde_json::Value| -> Result<Vec<u8>, KvError> {
+            let text = value
+                .as_str()
+                .ok_or_else(|| invalid("binary data must be a base64 string"))?;
+            return BASE64
+                .decode(text)
+                .map_err(|_| invalid("binary data must be a base64 string"));
+        };
+        return match document
+            .get("type")
+            .and_then(|data_type| data_type.as_str())
+        {
+            Some("String") => match value.as_str() {
+                Some(text) => Ok(Value::String(String::from(text))),
+                None => Err(invalid("value of a String must be a string")),
+            },
+            Some("Integer") => match value.as_u64() {
+                Some(number) => Ok(Value::Integer(number)),
+                None => Err(invalid("value of an Integer must be an unsigned integer")),
+            },
+            Some("Blob") => Ok(Value::Blob(decode(value)?)),
+            Some("Typed") => {
+                let codec = match document.get("codec").and_then(|codec| codec.as_str()) {
+                    Some("Json") => Codec::Json,
+                    Some("Bincode") => Codec::Bincode,
+                    _ => return Err(invalid("unknown codec")),
+                };
+                Ok(Value::Typed(codec, decode(value)?))
+            }
+            Some("Json") => Ok(Value::Json(value.clone())),
+            _ => Err(invalid("unknown data type")),
         };
     }
 }
@@ -80,14 +218,33 @@ impl Value {
 pub struct MemKvPage {
     path: PathBuf,
     mmap: MmapMut,
-    index: HashMap<String, u64>,
+    index: PageIndex,
     deleted_entries: BinaryHeap<MemKvPageGap>,
+    // Pages opened through their B+tree index only find deleted entries once they defrag
+    deleted_entries_loaded: bool,
     offset: u64,
+    page_size: u64,
+    max_page_size: u64,
+    durability: DurabilityMode,
+    dirty_range: Option<(usize, usize)>,
+    // Number of flushes tests make fail, see `fail_next_flushes`
+    #[cfg(test)]
+    failing_flushes: u32,
+    last_sync: Instant,
+    codec: Codec,
+    compression: Compression,
+    compression_threshold: usize,
+    keyring: Option<Keyring>,
+    large_value_threshold: usize,
+    secondary_indexes: HashMap<String, SecondaryIndex>,
+    eviction: Option<(EvictionPolicy, u64)>,
+    access_tracker: Mutex<AccessTracker>,
+    bloom_filter: BloomFilter,
 }
 
 struct MemKvPageEntry {
     header: MemKvPageEntryHeader,
-    key: String,
+    key_data: Vec<u8>,
     value: Value,
     value_data: Vec<u8>,
 }
@@ -98,16 +255,36 @@ impl MemKvPageEntry {
         key: &str,
         value: Value,
         value_data_type: ValueDataType,
-    ) -> Result<MemKvPageEntry, Box<dyn error::Error>> {
-        let value_data = match value.clone() {
-            Value::String(text) => Vec::from(text.as_bytes()),
-            Value::Integer(number) => Vec::from(number.to_be_bytes()),
-            Value::Blob(bytes) => bytes,
-        };
+        compression: Compression,
+        compression_threshold: usize,
+        keyring: Option<&Keyring>,
+    ) -> Result<MemKvPageEntry, KvError> {
+        let mut value_data = value.clone().into_bytes()?;
+
+        // Only keep the compressed data if it actually saves space
+        let mut flags = FLAG_CHECKSUM;
+        if compression != Compression::None && value_data.len() >= compression_threshold {
+            let compressed_data = compression.compress(&value_data)?;
+            if compressed_data.len() < value_data.len() {
+                value_data = compressed_data;
+                flags |= compression.flag();
+            }
+        }
+
+        // Values are compressed before encryption as ciphertext does not compress, the key is
+        // used as associated data so values cannot be swapped between entries
+        let mut key_data = Vec::from(key.as_bytes());
+        if let Some(keyring) = keyring {
+            value_data = keyring.encrypt(&value_data, &key_data)?;
+            key_data = keyring.encrypt(&key_data, &[])?;
+            flags |= FLAG_ENCRYPTED;
+        }
 
+        let mut header = MemKvPageEntryHeader::new(offset, &key_data, &value_data, value_data_type);
+        header.flags = flags;
         return Ok(MemKvPageEntry {
-            header: MemKvPageEntryHeader::new(offset, key, &value_data, value_data_type),
-            key: String::from(key),
+            header,
+            key_data,
             value,
             value_data,
         });
@@ -117,7 +294,7 @@ impl MemKvPageEntry {
 #[derive(Clone)]
 struct MemKvPageEntryHeader {
     data_type: ValueDataType,
-    flags: u8, // Flags are currently only used to marked deleted entries with 0x1
+    flags: u8, // 0x1 marks deleted entries, 0x20 checksums, compression bits are in compression.rs
     key_size: u64,
     value_size: u64,
     offset: u64,
@@ -132,17 +309,18 @@ impl MemKvPageEntryHeader {
         return self.key_size
             + self.value_size
             + (size_of::<u8>() * 2) as u64
-            + (size_of::<usize>() * 2) as u64;
+            + (size_of::<usize>() * 2) as u64
+            + get_checksum_size(self.flags);
     }
 
     fn new(
         offset: u64,
-        key: &str,
+        key: &[u8],
         value: &[u8],
         value_data_type: ValueDataType,
     ) -> MemKvPageEntryHeader {
         return MemKvPageEntryHeader {
-            offset: offset,
+            offset,
             flags: 0x0,
             key_size: key.len() as u64,
             value_size: value.len() as u64,
@@ -151,6 +329,29 @@ impl MemKvPageEntryHeader {
     }
 }
 
+pub(crate) fn get_checksum_size(flags: u8) -> u64 {
+    if flags & FLAG_CHECKSUM == 0x0 {
+        return 0;
+    }
+    return ENTRY_CHECKSUM_SIZE;
+}
+
+// Covers all flags but the deleted one, which is set in place when the entry is deleted
+pub(crate) fn get_entry_checksum(
+    data_type: ValueDataType,
+    flags: u8,
+    key_data: &[u8],
+    value_data: &[u8],
+) -> [u8; ENTRY_CHECKSUM_SIZE as usize] {
+    let mut hasher = Sha256::new();
+    hasher.update([data_type as u8, flags & !ENTRY_DELETED_FLAG]);
+    hasher.update(key_data);
+    hasher.update(value_data);
+    let mut checksum = [0; ENTRY_CHECKSUM_SIZE as usize];
+    checksum.copy_from_slice(&hasher.finalize()[..ENTRY_CHECKSUM_SIZE as usize]);
+    return checksum;
+}
+
 struct MemKvPageGap {
     offset: u64,
     length: u64,
@@ -173,7 +374,7 @@ impl Ord for MemKvPageGap {
 
 impl PartialOrd for MemKvPageGap {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.offset.cmp(&other.offset))
+        Some(self.cmp(other))
     }
 }
 
@@ -185,357 +386,1390 @@ impl PartialEq for MemKvPageGap {
 
 impl Eq for MemKvPageGap {}
 
+pub struct LargeValueWriter<'a> {
+    page: &'a mut MemKvPage,
+    key: String,
+    writer: BlobWriter,
+}
+
+impl<'a> LargeValueWriter<'a> {
+    pub fn finish(self: Self) -> Result<(), KvError> {
+        let reference = self.writer.finish()?;
+        return self
+            .page
+            .insert_overflow(&self.key, ValueDataType::Blob, reference, vec![]);
+    }
+}
+
+impl<'a> Write for LargeValueWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.writer.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
 impl MemKvPage {
-    pub fn new(path: &Path) -> Result<Self, Box<dyn error::Error>> {
+    /// Opens the page at `path` or creates it with `page_size` bytes, pages only grow beyond
+    /// their initial size once `set_max_page_size` allows it
+    pub fn new(path: &Path, page_size: u64) -> Result<Self, KvError> {
+        return Self::open(path, page_size, None);
+    }
+
+    /// Like `new` but with `keyring` set before the page is loaded, which is required to
+    /// open pages holding encrypted entries, see `set_keyring`
+    pub fn new_with_keyring(
+        path: &Path,
+        page_size: u64,
+        keyring: Keyring,
+    ) -> Result<Self, KvError> {
+        return Self::open(path, page_size, Some(keyring));
+    }
+
+    fn open(path: &Path, page_size: u64, keyring: Option<Keyring>) -> Result<Self, KvError> {
         if Path::new(path).exists() {
-            return Ok(Self::load_page_from_file(path));
-        } else {
-            return Self::create_page(path);
+            // Writes through the in-memory index never reach the B+tree, so a B+tree left by
+            // `new_with_btree_index` is marked stale and rebuilt the next time it is used
+            let btree_path = get_sidecar_path(path, "btree");
+            if btree_path.exists() {
+                BTreeIndex::mark_stale(&btree_path)?;
+            }
+            return Self::load_page_from_file(path, keyring);
+        }
+        let mut page = Self::create_page(path, page_size)?;
+        page.keyring = keyring;
+        return Ok(page);
+    }
+
+    /// Opens or creates the page at `path` with its index kept in a B+tree file next to the
+    /// page instead of in memory. Opening doesn't scan the page unless the B+tree was not
+    /// flushed after its last change or the page holds entries after the end known to the
+    /// B+tree. TTLs and eviction only know about entries written since the page was opened.
+    pub fn new_with_btree_index(path: &Path, page_size: u64) -> Result<Self, KvError> {
+        let btree_path = get_sidecar_path(path, "btree");
+        if Path::new(path).exists() {
+            if let Ok(btree_index) = BTreeIndex::open(&btree_path) {
+                let page = Self::load_page_with_index(path, btree_index)?;
+                // Only zeroed space may follow the last entry, anything else was written
+                // without the B+tree and would be overwritten
+                if page.read_header_from_offset(page.offset).is_err() {
+                    return Ok(page);
+                }
+            }
+            warn!("Rebuilding B+tree index of page {:?}", path);
+        }
+
+        let mut page = Self::new(path, page_size)?;
+        let mut btree_index = BTreeIndex::create(&btree_path)?;
+        for (key, offset) in page.index.scan("")? {
+            btree_index.insert(&key, offset)?;
+        }
+        btree_index.flush(page.offset)?;
+        page.index = PageIndex::BTree(btree_index);
+        return Ok(page);
+    }
+
+    fn load_page_with_index(path: &Path, btree_index: BTreeIndex) -> Result<Self, KvError> {
+        let (mmap, page_size) = MemKvPage::map_page_file(path)?;
+        let offset = btree_index.get_page_offset();
+        if offset < PAGE_HEADER_SIZE || offset > page_size {
+            return Err(KvError::InvalidPageSize { page_size });
+        }
+
+        let bloom_path = get_sidecar_path(path, "bloom");
+        let (bloom_filter, is_bloom_filter_valid) = match BloomFilter::open(&bloom_path) {
+            Ok(bloom_filter) => (bloom_filter, true),
+            Err(_) => (
+                BloomFilter::create(&bloom_path, BloomFilter::bits_for_page_size(page_size))?,
+                false,
+            ),
+        };
+        let mut page = MemKvPage::from_parts(path, mmap, page_size, bloom_filter);
+        page.index = PageIndex::BTree(btree_index);
+        page.offset = offset;
+        page.deleted_entries_loaded = false;
+        if !is_bloom_filter_valid {
+            page.rebuild_bloom_filter()?;
+        }
+        page.load_index_definitions()?;
+        return Ok(page);
+    }
+
+    fn map_page_file(path: &Path) -> Result<(MmapMut, u64), KvError> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        let mmap = unsafe { MmapMut::map_mut(&f)? };
+        if (mmap.len() as u64) <= PAGE_HEADER_SIZE {
+            return Err(KvError::InvalidPageSize {
+                page_size: mmap.len() as u64,
+            });
+        }
+        let page_size = read_u64(&mmap, 0)?;
+        if page_size != mmap.len() as u64 {
+            return Err(KvError::InvalidPageSize { page_size });
+        }
+        return Ok((mmap, page_size));
+    }
+
+    fn load_page_from_file(path: &Path, keyring: Option<Keyring>) -> Result<Self, KvError> {
+        let (mmap, page_size) = MemKvPage::map_page_file(path)?;
+
+        let bloom_path = get_sidecar_path(path, "bloom");
+        let bloom_filter = match BloomFilter::open(&bloom_path) {
+            Ok(bloom_filter) => bloom_filter,
+            Err(_) => BloomFilter::create(&bloom_path, BloomFilter::bits_for_page_size(page_size))?,
+        };
+        let mut page = MemKvPage::from_parts(path, mmap, page_size, bloom_filter);
+        // Keys of encrypted entries are decrypted while the index is built
+        page.keyring = keyring;
+        page.load_entries()?;
+
+        // A filter that was not flushed before a crash could hide keys, so it is only
+        // trusted if it contains every key found on the page
+        if !page
+            .index
+            .keys()?
+            .iter()
+            .all(|key| page.bloom_filter.may_contain(key))
+        {
+            warn!("Rebuilding bloom filter of page {:?}", path);
+            page.rebuild_bloom_filter()?;
+        }
+        page.load_index_definitions()?;
+        return Ok(page);
+    }
+
+    fn load_entries(self: &mut Self) -> Result<(), KvError> {
+        let mut offset = PAGE_HEADER_SIZE;
+        while offset + ENTRY_HEADER_SIZE <= self.page_size {
+            // The zeroed space after the last entry does not hold a valid data type
+            let header = match self.read_header_from_offset(offset) {
+                Ok(header) => header,
+                Err(_) => break,
+            };
+            let entry_size = header.get_entry_size();
+            if offset + entry_size > self.page_size {
+                break;
+            }
+            if header.flags & ENTRY_DELETED_FLAG != 0x0 {
+                self.deleted_entries.push(MemKvPageGap::new(header));
+            } else {
+                let key = self.read_key(&header)?;
+                self.access_tracker
+                    .get_mut()
+                    .record_insert(&key, entry_size);
+                self.index.insert(&key, offset)?;
+            }
+            offset += entry_size;
+        }
+        self.offset = offset;
+        return Ok(());
+    }
+
+    fn load_deleted_entries(self: &mut Self) -> Result<(), KvError> {
+        if self.deleted_entries_loaded {
+            return Ok(());
         }
+        let mut offset = PAGE_HEADER_SIZE;
+        while offset < self.offset {
+            let header = self.read_header_from_offset(offset)?;
+            offset += header.get_entry_size();
+            if header.flags & ENTRY_DELETED_FLAG != 0x0 {
+                self.deleted_entries.push(MemKvPageGap::new(header));
+            }
+        }
+        self.deleted_entries_loaded = true;
+        return Ok(());
     }
 
-    fn load_page_from_file(path: &Path) -> Self {
-        panic!("Not implemented")
+    fn from_parts(path: &Path, mmap: MmapMut, page_size: u64, bloom_filter: BloomFilter) -> Self {
+        return MemKvPage {
+            path: PathBuf::from(path),
+            mmap,
+            index: PageIndex::Memory(HashMap::new()),
+            offset: PAGE_HEADER_SIZE,
+            page_size,
+            max_page_size: page_size,
+            durability: DurabilityMode::Always,
+            dirty_range: None,
+            #[cfg(test)]
+            failing_flushes: 0,
+            last_sync: Instant::now(),
+            deleted_entries: BinaryHeap::new(),
+            deleted_entries_loaded: true,
+            codec: Codec::Json,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            keyring: None,
+            large_value_threshold: DEFAULT_LARGE_VALUE_THRESHOLD,
+            secondary_indexes: HashMap::new(),
+            eviction: None,
+            access_tracker: Mutex::new(AccessTracker::new()),
+            bloom_filter,
+        };
     }
 
-    fn create_page(path: &Path) -> Result<Self, Box<dyn error::Error>> {
+    fn create_page(path: &Path, page_size: u64) -> Result<Self, KvError> {
+        if page_size <= PAGE_HEADER_SIZE {
+            return Err(KvError::InvalidPageSize { page_size });
+        }
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
-            .open(path)
-            .expect("Error loading memory mapped file");
-        f.set_len(KV_PAGE_SIZE)?;
-        let maybe_mmap = panic::catch_unwind(|| {
-            return unsafe { MmapMut::map_mut(&f).expect("Error creating memory map") };
-        });
-
-        return match maybe_mmap {
-            Ok(mmap) => Ok(MemKvPage {
-                path: PathBuf::from(path),
-                mmap: mmap,
-                index: HashMap::new(),
-                offset: 0,
-                deleted_entries: BinaryHeap::new(),
-            }),
-            Err(_) => {
-                error!("Failed to create memory map");
+            .open(path)?;
+        f.set_len(page_size)?;
+        let maybe_mmap = unsafe { MmapMut::map_mut(&f) };
+
+        let mut page = match maybe_mmap {
+            Ok(mmap) => {
+                let bloom_filter = BloomFilter::create(
+                    &get_sidecar_path(path, "bloom"),
+                    BloomFilter::bits_for_page_size(page_size),
+                )?;
+                MemKvPage::from_parts(path, mmap, page_size, bloom_filter)
+            }
+            Err(error) => {
+                error!("Failed to create memory map: {}", error);
                 fs::remove_file(path)?;
-                return Err(errors::MemmapCreationFailureError.into());
+                return Err(KvError::MemmapCreationFailure {
+                    path: PathBuf::from(path),
+                });
             }
         };
+        page.write_page_header()?;
+        page.persist()?;
+        return Ok(page);
+    }
+
+    fn write_page_header(self: &mut Self) -> Result<(), KvError> {
+        let index = MemKvPage::write_to_mmap(&mut self.mmap, 0, &self.page_size.to_be_bytes())?;
+        self.mark_dirty(0, index);
+        return Ok(());
+    }
+
+    /// Allows the page to grow in place up to `max_page_size` bytes once it is full
+    pub fn set_max_page_size(self: &mut Self, max_page_size: u64) {
+        self.max_page_size = max_page_size;
+    }
+
+    pub fn get_file_path(self: &Self) -> &Path {
+        return &self.path;
+    }
+
+    pub fn get_page_size(self: &Self) -> u64 {
+        return self.page_size;
+    }
+
+    /// Bytes left for new entries, including the space the page may still grow by
+    pub(crate) fn get_free_space(self: &Self) -> u64 {
+        return self.max_page_size.max(self.page_size) - self.offset;
+    }
+
+    /// Upper bound of the bytes an entry of `key` and `value` takes up on the page, compression
+    /// can only make it smaller
+    pub(crate) fn get_max_entry_size(
+        self: &Self,
+        key: &str,
+        value: &Value,
+    ) -> Result<u64, KvError> {
+        let mut value_size = value.get_bytes_length()?;
+        let mut key_size = key.len();
+        if value_size > self.large_value_threshold {
+            value_size = BLOB_REFERENCE_SIZE;
+        }
+        if self.keyring.is_some() {
+            key_size += ENCRYPTION_OVERHEAD;
+            value_size += ENCRYPTION_OVERHEAD;
+        }
+        return Ok(ENTRY_HEADER_SIZE + (key_size + value_size) as u64 + ENTRY_CHECKSUM_SIZE);
+    }
+
+    fn ensure_space(self: &mut Self, size: u64) -> Result<(), KvError> {
+        let required_size = self.offset + size;
+        if required_size <= self.page_size {
+            return Ok(());
+        }
+        if required_size > self.max_page_size {
+            return Err(KvError::NoSpaceLeft {
+                requested: size,
+                available: self.max_page_size.max(self.page_size) - self.offset,
+            });
+        }
+
+        // Double the page to keep the number of remaps low
+        let new_page_size = (self.page_size * 2)
+            .max(required_size)
+            .min(self.max_page_size);
+        self.persist()?;
+        let f = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        f.set_len(new_page_size)?;
+        self.mmap = unsafe { MmapMut::map_mut(&f)? };
+        self.page_size = new_page_size;
+        self.write_page_header()?;
+        self.rebuild_bloom_filter()?;
+        info!("Grew page {:?} to {} bytes", self.path, new_page_size);
+        return Ok(());
     }
 
-    fn read_header(self: &Self, key: &str) -> Result<MemKvPageEntryHeader, Box<dyn error::Error>> {
-        let start_offset = self.index[&String::from(key)];
+    fn read_header(self: &Self, key: &str) -> Result<MemKvPageEntryHeader, KvError> {
+        let start_offset = match self.index.get(key)? {
+            Some(start_offset) => start_offset,
+            None => {
+                return Err(KvError::KeyDoesNotExist {
+                    key: String::from(key),
+                })
+            }
+        };
         return self.read_header_from_offset(start_offset);
     }
 
     fn read_header_from_offset(
         self: &Self,
         start_offset: u64,
-    ) -> Result<MemKvPageEntryHeader, Box<dyn error::Error>> {
-        // We assume here that the data was written on the same architecture as it is being read
-        let start_offset = start_offset as usize;
-        let data_type_size = size_of::<u8>();
-        let flags_size = size_of::<u8>();
-        let key_len_size = size_of::<usize>();
-        let value_len_size = size_of::<usize>();
-
-        let mut data_type_buffer = vec![0; data_type_size];
-        data_type_buffer.copy_from_slice(&self.mmap[start_offset..start_offset + data_type_size]);
-        let data_type: ValueDataType =
-            u8::from_be_bytes(data_type_buffer.try_into().unwrap()).try_into()?; // try into should never panic as we fix size
-
-        let mut flags_buffer = vec![0; flags_size];
-        flags_buffer.copy_from_slice(
-            &self.mmap[start_offset + data_type_size..start_offset + data_type_size + flags_size],
-        );
-        let flags: u8 = u8::from_be_bytes(flags_buffer.try_into().unwrap());
-
-        let mut key_len_buffer = vec![0; key_len_size];
-        let mut value_len_buffer = vec![0; value_len_size];
-        key_len_buffer.copy_from_slice(
-            &self.mmap[start_offset + data_type_size + flags_size
-                ..start_offset + data_type_size + flags_size + key_len_size],
-        );
-        value_len_buffer.copy_from_slice(
-            &self.mmap[start_offset + data_type_size + flags_size + key_len_size
-                ..start_offset + data_type_size + flags_size + key_len_size + value_len_size],
-        );
-        let key_size = u64::from_be_bytes(key_len_buffer.try_into().unwrap());
-        let value_size = u64::from_be_bytes(value_len_buffer.try_into().unwrap());
+    ) -> Result<MemKvPageEntryHeader, KvError> {
+        // Sizes are checked so a corrupted header can never make an entry reach past the page
+        let mmap: &[u8] = &self.mmap;
+        let data_type: ValueDataType = read_slice(mmap, start_offset, 1)?[0].try_into()?;
+        let flags = read_slice(mmap, start_offset + 1, 1)?[0];
+        let key_size = read_u64(mmap, start_offset + 2)?;
+        let value_size = read_u64(mmap, start_offset + 2 + size_of::<u64>() as u64)?;
+        let entry_size = key_size
+            .checked_add(value_size)
+            .and_then(|size| size.checked_add(ENTRY_HEADER_SIZE + get_checksum_size(flags)));
+        match entry_size {
+            Some(entry_size) if entry_size <= self.page_size - start_offset => {}
+            _ => {
+                return Err(KvError::OutOfBounds {
+                    offset: start_offset,
+                    size: entry_size.unwrap_or(u64::MAX),
+                    page_size: self.page_size,
+                })
+            }
+        }
 
         return Ok(MemKvPageEntryHeader {
             data_type,
             flags,
-            offset: start_offset as u64,
+            offset: start_offset,
             key_size,
             value_size,
         });
     }
 
-    fn read_key(
-        self: &Self,
-        header: &MemKvPageEntryHeader,
-    ) -> Result<String, Box<dyn error::Error>> {
-        let header_offset = header.get_absolute_data_offset() as usize;
-        let mut key_buffer = vec![0; header.key_size as usize];
-        key_buffer
-            .copy_from_slice(&self.mmap[header_offset..header_offset + header.key_size as usize]);
+    fn read_raw_key(self: &Self, header: &MemKvPageEntryHeader) -> Result<Vec<u8>, KvError> {
+        let data_offset = header.get_absolute_data_offset();
+        let key_buffer = read_slice(&self.mmap, data_offset, header.key_size)?;
+        return Ok(key_buffer.to_vec());
+    }
+
+    // Checks the value together with the key if the entry has a checksum
+    fn read_raw_value(self: &Self, header: &MemKvPageEntryHeader) -> Result<Vec<u8>, KvError> {
+        let data_offset = header.get_absolute_data_offset() + header.key_size;
+        let value_buffer = read_slice(&self.mmap, data_offset, header.value_size)?;
+        if header.flags & FLAG_CHECKSUM != 0x0 {
+            let checksum = read_slice(
+                &self.mmap,
+                data_offset + header.value_size,
+                ENTRY_CHECKSUM_SIZE,
+            )?;
+            let key_buffer = read_slice(
+                &self.mmap,
+                header.get_absolute_data_offset(),
+                header.key_size,
+            )?;
+            if get_entry_checksum(header.data_type, header.flags, key_buffer, value_buffer)
+                != checksum
+            {
+                return Err(KvError::ChecksumMismatch {
+                    offset: header.offset,
+                });
+            }
+        }
+        return Ok(value_buffer.to_vec());
+    }
+
+    fn read_key(self: &Self, header: &MemKvPageEntryHeader) -> Result<String, KvError> {
+        let key_buffer = self.decrypt_if_needed(header, &self.read_raw_key(header)?, &[])?;
         let entry_key = String::from(str::from_utf8(&key_buffer)?);
         return Ok(entry_key);
     }
 
-    fn read_value(
-        self: &Self,
-        header: &MemKvPageEntryHeader,
-    ) -> Result<(Value, Vec<u8>), Box<dyn error::Error>> {
-        let header_offset = header.get_absolute_data_offset() as usize;
-        let mut key_buffer = vec![0; header.key_size as usize];
-        let mut value_buffer = vec![0; header.value_size as usize];
-        key_buffer
-            .copy_from_slice(&self.mmap[header_offset..header_offset + header.key_size as usize]);
-        value_buffer.copy_from_slice(
-            &self.mmap[header_offset + header.key_size as usize
-                ..header_offset + header.key_size as usize + header.value_size as usize],
-        );
-        let value = match header.data_type {
-            ValueDataType::String => {
-                Value::String(String::from(str::from_utf8(&value_buffer.clone())?))
-            }
-            ValueDataType::Integer => {
-                Value::Integer(u64::from_be_bytes(value_buffer.clone().try_into().unwrap()))
-            }
-            ValueDataType::Blob => Value::Blob(value_buffer.clone()),
-        };
+    fn read_value(self: &Self, header: &MemKvPageEntryHeader) -> Result<(Value, Vec<u8>), KvError> {
+        let mut value_buffer = self.read_raw_value(header)?;
+        if header.flags & FLAG_ENCRYPTED != 0x0 {
+            let key = self.read_key(header)?;
+            value_buffer = self.decrypt_if_needed(header, &value_buffer, key.as_bytes())?;
+        }
+        if header.flags & FLAG_OVERFLOW != 0x0 {
+            let reference = BlobReference::from_bytes(&value_buffer)?;
+            value_buffer = blob_file::read(&self.get_blob_path(), &reference)?;
+        }
+        let value_buffer = Compression::from_flags(header.flags)
+            .decompress(&value_buffer, self.get_max_value_size())?;
+        let value = Value::from_bytes(header.data_type, &value_buffer)?;
         return Ok((value, value_buffer));
     }
 
-    fn read_entry(self: &Self, key: &str) -> Result<MemKvPageEntry, Box<dyn error::Error>> {
+    fn read_entry(self: &Self, key: &str) -> Result<MemKvPageEntry, KvError> {
         let header = self.read_header(key)?;
 
         let entry_key = self.read_key(&header)?;
-        assert_eq!(key, entry_key);
+        if key != entry_key {
+            return Err(KvError::IndexMismatch {
+                key: String::from(key),
+                entry_key,
+            });
+        }
         let (value, value_data) = self.read_value(&header)?;
 
         return Ok(MemKvPageEntry {
+            key_data: self.read_raw_key(&header)?,
             header,
-            key: String::from(entry_key),
             value,
             value_data,
         });
     }
 
-    pub fn get(self: &Self, key: &str) -> Result<Value, Box<dyn error::Error>> {
-        if !self.index.contains_key(&String::from(key)) {
-            return Err(errors::KeyDoesNotExistError.into());
+    pub fn get(self: &Self, key: &str) -> Result<Value, KvError> {
+        if !self.contains_live_key(key)? {
+            return Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            });
         }
         let entry = self.read_entry(key)?;
+        self.access_tracker.lock().record_access(key);
 
         return Ok(entry.value);
     }
 
-    fn write_header(
-        self: &mut Self,
-        header: MemKvPageEntryHeader,
-    ) -> Result<(), Box<dyn error::Error>> {
-        // Write type
-        let mut index = MemKvPage::write_to_mmap(
-            &mut (self.mmap),
-            header.offset as usize,
-            &(header.data_type as u8).to_be_bytes(),
-        )?;
-        // Write flags - by default just 0x0
-        index =
-            MemKvPage::write_to_mmap(&mut self.mmap, index, &(header.flags as u8).to_be_bytes())?;
-
-        // Write size of key
-        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &header.key_size.to_be_bytes())?;
-
-        // Write size of value
-        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &header.value_size.to_be_bytes())?;
-
-        return Ok(());
+    /// Returns all live entries whose key starts with `prefix`, ordered by key
+    pub fn scan(self: &Self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+        let mut entries = Vec::new();
+        for (key, _) in self.index.scan(prefix)? {
+            if self.contains_live_key(&key)? {
+                let value = self.get(&key)?;
+                entries.push((key, value));
+            }
+        }
+        return Ok(entries);
     }
 
-    fn append_entry(self: &mut Self, entry: MemKvPageEntry) -> Result<u64, Box<dyn error::Error>> {
-        return self.write_entry(entry);
+    /// Returns at most `limit` live entries with keys starting with `prefix` after `after`,
+    /// ordered by key. Only the values of the batch are read, but the index has no seek, so
+    /// the keys of the prefix are scanned and sorted for every batch.
+    pub fn scan_after(
+        self: &Self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>, KvError> {
+        let mut entries = Vec::new();
+        for (key, _) in self.index.scan(prefix)? {
+            if entries.len() >= limit {
+                break;
+            }
+            if after.is_some_and(|after| key.as_str() <= after) || !self.contains_live_key(&key)? {
+                continue;
+            }
+            let value = self.get(&key)?;
+            entries.push((key, value));
+        }
+        return Ok(entries);
     }
 
-    fn write_entry(self: &mut Self, entry: MemKvPageEntry) -> Result<u64, Box<dyn error::Error>> {
-        let data_offset = entry.header.get_absolute_data_offset() as usize;
-        self.write_header(entry.header)?;
-
-        // Write key
-        let mut index =
-            MemKvPage::write_to_mmap(&mut self.mmap, data_offset, &entry.key.as_bytes())?;
+    pub fn get_key_count(self: &Self) -> Result<usize, KvError> {
+        return Ok(self
+            .index
+            .keys()?
+            .iter()
+            .filter(|key| !self.access_tracker.lock().is_expired(key))
+            .count());
+    }
 
-        // Write value
-        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &entry.value_data)?;
+    // Bytes in use up to the end of the last entry, including deleted entries
+    pub fn get_used_size(self: &Self) -> u64 {
+        return self.offset;
+    }
 
-        return Ok(index as u64);
+    pub(crate) fn contains_live_key(self: &Self, key: &str) -> Result<bool, KvError> {
+        return Ok(self.index.contains_key(key)? && !self.access_tracker.lock().is_expired(key));
     }
 
-    pub fn insert(self: &mut Self, key: &str, value: Value) -> Result<(), Box<dyn error::Error>> {
-        if (self.offset + value.get_bytes_length()? as u64) > KV_PAGE_SIZE {
-            return Err(errors::NoSpaceLeftError.into());
+    // Expired keys stay on the page until they are overwritten or evicted
+    fn check_key_available(self: &mut Self, key: &str) -> Result<(), KvError> {
+        if !self.index.contains_key(key)? {
+            return Ok(());
         }
-        if self.index.contains_key(&String::from(key)) {
-            return Err(errors::KeyAlreadyExistsError.into());
+        if !self.access_tracker.get_mut().is_expired(key) {
+            return Err(KvError::KeyAlreadyExists {
+                key: String::from(key),
+            });
         }
-
-        self.index.insert(String::from(key), self.offset);
-
-        let data_type = value.get_data_type();
-        self.offset =
-            self.append_entry(MemKvPageEntry::new(self.offset, key, value, data_type)?)?;
-        self.persist();
-        Ok(())
+        self.delete(key)?;
+        self.access_tracker.get_mut().stats.expirations += 1;
+        return Ok(());
     }
 
-    pub fn delete(self: &mut Self, key: &str) -> Result<(), Box<dyn error::Error>> {
-        if !self.index.contains_key(&String::from(key)) {
-            return Err(errors::KeyDoesNotExistError.into());
+    /// Streams the raw bytes of a value, large values are read directly from the blob file
+    /// without loading them into memory
+    pub fn get_reader(self: &Self, key: &str) -> Result<Box<dyn Read>, KvError> {
+        if !self.contains_live_key(key)? {
+            return Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            });
         }
-
-        // Update header to write that it has been deleted
-        let mut header = self.read_header(key)?;
-        let entry_size = header.get_entry_size();
-        if header.flags != 0x0 {
-            return Err(errors::EntryAlreadyDeletedInFileError.into());
+        let header = self.read_header(key)?;
+        if header.flags & FLAG_OVERFLOW != 0x0 {
+            let reference = BlobReference::from_bytes(&self.read_raw_value(&header)?)?;
+            return Ok(Box::new(blob_file::reader(
+                &self.get_blob_path(),
+                &reference,
+            )?));
         }
-        header.flags = 0x1;
-        self.write_header(header.clone())?;
-
-        self.index.remove(key);
-        self.deleted_entries.push(MemKvPageGap::new(header));
-        self.persist();
-        return Ok(());
+        let (_, value_data) = self.read_value(&header)?;
+        return Ok(Box::new(Cursor::new(value_data)));
     }
 
-    pub fn defrag(self: &mut Self) {
-        let next_gap: Option<MemKvPageGap> = self.deleted_entries.pop();
-        if next_gap.is_none() {
-            println!("Nothing to delete");
-            return;
+    /// Starts streaming a blob value for `key` into the blob file, the key only becomes
+    /// visible once `LargeValueWriter::finish` is called
+    pub fn put_writer(self: &mut Self, key: &str) -> Result<LargeValueWriter<'_>, KvError> {
+        self.check_key_available(key)?;
+        if self.keyring.is_some() {
+            return Err(KvError::EncryptedLargeValue {
+                key: String::from(key),
+            });
         }
+        let writer = BlobWriter::new(&self.get_blob_path())?;
+        return Ok(LargeValueWriter {
+            page: self,
+            key: String::from(key),
+            writer,
+        });
+    }
 
-        let next_gap = match next_gap {
-            Some(gap) => gap,
-            None => panic!("unreachable match"),
-        };
-        println!(
-            "Next gap is from {} len {}",
-            next_gap.offset, next_gap.length
-        );
-
-        // On the last entry we need to do nothing just reset the offset
-        if next_gap.offset + next_gap.length == self.offset {
-            println!("not doing anything on last entry");
-            self.offset = next_gap.offset;
-            return;
-        } else {
-            println!("moving things aroudnd");
-            // Copy values back over deleted gap
-            let previous_offset = self.offset as usize;
-            let new_offset = self.offset - next_gap.length;
+    /// Values larger than `threshold` bytes are written to the blob file next to the page
+    pub fn set_large_value_threshold(self: &mut Self, threshold: usize) {
+        self.large_value_threshold = threshold;
+    }
 
-            self.mmap.copy_within(
-                (next_gap.offset + next_gap.length) as usize..self.offset as usize,
-                next_gap.offset as usize,
-            );
-            // 0 out moved data
-            (&mut self.mmap[new_offset as usize..previous_offset])
-                .write_all(&vec![0; next_gap.length as usize])
-                .unwrap();
+    // Largest value stored on the page itself, which bounds what a compressed value may
+    // decompress to. Values above the threshold only fit if the page is larger.
+    fn get_max_value_size(self: &Self) -> usize {
+        let capacity = self.page_size.max(self.max_page_size) as usize;
+        return self.large_value_threshold.max(capacity);
+    }
 
-            let mut entry_update_offset = next_gap.offset;
-            // Update indices
-            // todo(@koogle): Rewrite to perform defrag one entry at a time
+    fn get_blob_path(self: &Self) -> PathBuf {
+        return get_sidecar_path(&self.path, "blob");
+    }
 
-            while let Ok(header) = self.read_header_from_offset(entry_update_offset) {
-                let key = self.read_key(&header).unwrap();
-                *self.index.get_mut(&key).unwrap() = header.offset;
-                entry_update_offset += header.get_entry_size()
-            }
+    fn get_bloom_path(self: &Self) -> PathBuf {
+        return get_sidecar_path(&self.path, "bloom");
+    }
 
-            self.persist();
-            self.offset = new_offset;
-        }
+    /// Returns false if `key` is definitely not stored on this page without touching the index
+    pub fn may_contain(self: &Self, key: &str) -> bool {
+        return self.bloom_filter.may_contain(key);
     }
 
-    fn delete_page(self: &mut Self, delete_file: bool) -> Result<(), io::Error> {
-        self.index.drain();
-        self.offset = 0;
-        self.persist();
-        if delete_file {
-            fs::remove_file(self.path.clone())?;
+    // Also drops the bits of deleted keys
+    fn rebuild_bloom_filter(self: &mut Self) -> Result<(), KvError> {
+        let mut bloom_filter = BloomFilter::create(
+            &self.get_bloom_path(),
+            BloomFilter::bits_for_page_size(self.page_size),
+        )?;
+        for key in self.index.keys()? {
+            bloom_filter.insert(&key);
         }
+        bloom_filter.flush()?;
+        self.bloom_filter = bloom_filter;
         return Ok(());
     }
 
-    fn persist(self: &Self) {
-        // Flush entire map
-        self.mmap.flush().unwrap();
+    /// Compresses values of at least `threshold` bytes written from now on, existing entries
+    /// keep the compression they were written with
+    pub fn set_compression(self: &mut Self, compression: Compression, threshold: usize) {
+        self.compression = compression;
+        self.compression_threshold = threshold;
     }
 
-    fn write_to_mmap(mmap: &mut MmapMut, offset: usize, data: &[u8]) -> Result<usize, io::Error> {
-        let data_size = data.len();
-        (&mut mmap[offset..offset + data_size]).write_all(&data)?;
-
-        return Ok(offset + data_size);
+    /// Encrypts keys and values written from now on with the active key of `keyring`, which
+    /// must also hold every key existing entries were encrypted with. Pages which already
+    /// hold encrypted entries must be opened with `new_with_keyring`.
+    pub fn set_keyring(self: &mut Self, keyring: Keyring) {
+        self.keyring = Some(keyring);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{MemKvPage, Value};
-    use serde::{Deserialize, Serialize};
-    use serde_json::json;
-    use std::fs;
-    use std::panic;
-    use std::path::Path;
+    /// Re-encrypts every entry that was not written with the active key in place, returning
+    /// the number of rewritten entries. Entries written before encryption was enabled are
+    /// left untouched as their size would change.
+    pub fn rotate_encryption_key(self: &mut Self) -> Result<usize, KvError> {
+        let keyring = match &self.keyring {
+            Some(keyring) => keyring,
+            None => return Err(KvError::EncryptionKeyNotFound { key_id: None }),
+        };
 
-    const TEST_KEYSPACE: &str = "test_keyspace";
+        let mut rewrites = vec![];
+        for offset in self.index.offsets()? {
+            let header = self.read_header_from_offset(offset)?;
+            let key_data = self.read_raw_key(&header)?;
+            if header.flags & FLAG_ENCRYPTED == 0x0 || keyring.is_active_key(&key_data)? {
+                continue;
+            }
+            let key = keyring.decrypt(&key_data, &[])?;
+            let value = keyring.decrypt(&self.read_raw_value(&header)?, &key)?;
+            let mut data = keyring.encrypt(&key, &[])?;
+            let value_data = keyring.encrypt(&value, &key)?;
+            let checksum = get_entry_checksum(header.data_type, header.flags, &data, &value_data);
+            data.extend(value_data);
+            if header.flags & FLAG_CHECKSUM != 0x0 {
+                data.extend(checksum);
+            }
+            rewrites.push((header.get_absolute_data_offset() as usize, data));
+        }
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq)]
-    struct Person {
-        name: String,
-        age: u8,
-        phones: Vec<String>,
+        for (data_offset, data) in &rewrites {
+            let index = MemKvPage::write_to_mmap(&mut self.mmap, *data_offset, data)?;
+            self.mark_dirty(*data_offset, index);
+        }
+        self.persist()?;
+        return Ok(rewrites.len());
     }
 
-    fn run_test<T>(test: T) -> ()
-    where
-        T: FnOnce() -> () + panic::UnwindSafe,
-    {
-        setup();
-
-        let result = panic::catch_unwind(|| test());
-
-        teardown();
+    fn decrypt_if_needed(
+        self: &Self,
+        header: &MemKvPageEntryHeader,
+        data: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, KvError> {
+        if header.flags & FLAG_ENCRYPTED == 0x0 {
+            return Ok(data.to_vec());
+        }
+        return match &self.keyring {
+            Some(keyring) => keyring.decrypt(data, aad),
+            None => Err(KvError::EncryptionKeyNotFound { key_id: None }),
+        };
+    }
 
-        assert!(result.is_ok())
+    /// Sets the codec used by `put_typed`, entries written with other codecs remain readable
+    pub fn set_codec(self: &mut Self, codec: Codec) {
+        self.codec = codec;
     }
 
-    fn setup() {
-        if Path::new(TEST_KEYSPACE).exists() {
-            fs::remove_file(TEST_KEYSPACE).unwrap();
-        }
+    pub fn put_typed<T: Serialize>(self: &mut Self, key: &str, value: &T) -> Result<(), KvError> {
+        let data = self.codec.encode(value)?;
+        return self.insert(key, Value::Typed(self.codec, data));
     }
 
-    fn teardown() {
-        if Path::new(TEST_KEYSPACE).exists() {
-            fs::remove_file(TEST_KEYSPACE).unwrap();
-        }
+    pub fn get_typed<T: DeserializeOwned>(self: &Self, key: &str) -> Result<T, KvError> {
+        return match self.get(key)? {
+            Value::Typed(codec, data) => codec.decode(&data),
+            // Blobs written before typed values existed were serialized as json by hand
+            Value::Blob(data) => Codec::Json.decode(&data),
+            value => Err(KvError::InvalidDataType {
+                data_type: value.get_data_type() as u8,
+            }),
+        };
     }
 
-    #[test]
-    fn test_put_and_get() {
-        run_test(|| {
-            let mut kvmap = MemKvPage::new(Path::new(TEST_KEYSPACE)).unwrap();
-            kvmap
+    pub fn get_path(self: &Self, key: &str, path: &str) -> Result<serde_json::Value, KvError> {
+        let segments = json_path::parse(path)?;
+        let document = match self.get(key)? {
+            Value::Json(document) => document,
+            value => {
+                return Err(KvError::InvalidDataType {
+                    data_type: value.get_data_type() as u8,
+                })
+            }
+        };
+        return match json_path::get(&document, &segments) {
+            Some(node) => Ok(node.clone()),
+            None => Err(KvError::JsonPathDoesNotExist {
+                path: String::from(path),
+            }),
+        };
+    }
+
+    /// Patches the node at `path` and rewrites the document as a new entry, the old
+    /// entry is left as a gap for `defrag`
+    pub fn set_path(
+        self: &mut Self,
+        key: &str,
+        path: &str,
+        value: serde_json::Value,
+    ) -> Result<(), KvError> {
+        let segments = json_path::parse(path)?;
+        let mut document = match self.get(key)? {
+            Value::Json(document) => document,
+            value => {
+                return Err(KvError::InvalidDataType {
+                    data_type: value.get_data_type() as u8,
+                })
+            }
+        };
+        json_path::set(&mut document, &segments, value)?;
+        return self.upsert(key, Value::Json(document));
+    }
+
+    /// Inserts `key` or replaces the value of an existing key
+    pub fn upsert(self: &mut Self, key: &str, value: Value) -> Result<(), KvError> {
+        let (entry, index_keys) = self.encode_entry(key, value)?;
+        // Check for space before deleting so a full page never loses the old value, caches
+        // evict to make room instead
+        if self.contains_live_key(key)? {
+            if self.eviction.is_none() {
+                self.ensure_space(entry.header.get_entry_size())?;
+            }
+            self.delete(key)?;
+        }
+        self.check_key_available(key)?;
+        return self.add_entry(key, entry, index_keys);
+    }
+
+    fn write_header(self: &mut Self, header: MemKvPageEntryHeader) -> Result<(), KvError> {
+        // Write type
+        let mut index = MemKvPage::write_to_mmap(
+            &mut (self.mmap),
+            header.offset as usize,
+            &(header.data_type as u8).to_be_bytes(),
+        )?;
+        // Write flags - by default just 0x0
+        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &header.flags.to_be_bytes())?;
+
+        // Write size of key
+        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &header.key_size.to_be_bytes())?;
+
+        // Write size of value
+        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &header.value_size.to_be_bytes())?;
+
+        self.mark_dirty(header.offset as usize, index);
+        return Ok(());
+    }
+
+    fn append_entry(self: &mut Self, entry: MemKvPageEntry) -> Result<u64, KvError> {
+        return self.write_entry(entry);
+    }
+
+    fn write_entry(self: &mut Self, entry: MemKvPageEntry) -> Result<u64, KvError> {
+        let entry_offset = entry.header.offset as usize;
+        let data_offset = entry.header.get_absolute_data_offset() as usize;
+        let flags = entry.header.flags;
+        let data_type = entry.header.data_type;
+        self.write_header(entry.header)?;
+
+        // Write key
+        let mut index = MemKvPage::write_to_mmap(&mut self.mmap, data_offset, &entry.key_data)?;
+
+        // Write value
+        index = MemKvPage::write_to_mmap(&mut self.mmap, index, &entry.value_data)?;
+
+        // Write checksum
+        if flags & FLAG_CHECKSUM != 0x0 {
+            let checksum = get_entry_checksum(data_type, flags, &entry.key_data, &entry.value_data);
+            index = MemKvPage::write_to_mmap(&mut self.mmap, index, &checksum)?;
+        }
+
+        self.mark_dirty(entry_offset, index);
+        return Ok(index as u64);
+    }
+
+    pub fn insert(self: &mut Self, key: &str, value: Value) -> Result<(), KvError> {
+        self.check_key_available(key)?;
+        let (entry, index_keys) = self.encode_entry(key, value)?;
+        return self.add_entry(key, entry, index_keys);
+    }
+
+    // Builds the entry as it will be stored, large values are written to the blob file
+    fn encode_entry(
+        self: &Self,
+        key: &str,
+        value: Value,
+    ) -> Result<(MemKvPageEntry, Vec<(String, IndexKey)>), KvError> {
+        let index_keys = self.extract_index_keys(&value);
+        let data_type = value.get_data_type();
+        if value.get_bytes_length()? > self.large_value_threshold {
+            if self.keyring.is_some() {
+                return Err(KvError::EncryptedLargeValue {
+                    key: String::from(key),
+                });
+            }
+            let reference = blob_file::append(&self.get_blob_path(), &value.into_bytes()?)?;
+            let entry = self.encode_overflow_entry(key, data_type, reference)?;
+            return Ok((entry, index_keys));
+        }
+
+        let entry = MemKvPageEntry::new(
+            self.offset,
+            key,
+            value,
+            data_type,
+            self.compression,
+            self.compression_threshold,
+            self.keyring.as_ref(),
+        )?;
+        return Ok((entry, index_keys));
+    }
+
+    fn encode_overflow_entry(
+        self: &Self,
+        key: &str,
+        data_type: ValueDataType,
+        reference: BlobReference,
+    ) -> Result<MemKvPageEntry, KvError> {
+        let mut entry = MemKvPageEntry::new(
+            self.offset,
+            key,
+            Value::Blob(reference.to_bytes()),
+            data_type,
+            Compression::None,
+            0,
+            None,
+        )?;
+        entry.header.flags |= FLAG_OVERFLOW;
+        return Ok(entry);
+    }
+
+    fn insert_overflow(
+        self: &mut Self,
+        key: &str,
+        data_type: ValueDataType,
+        reference: BlobReference,
+        index_keys: Vec<(String, IndexKey)>,
+    ) -> Result<(), KvError> {
+        self.check_key_available(key)?;
+        let entry = self.encode_overflow_entry(key, data_type, reference)?;
+        return self.add_entry(key, entry, index_keys);
+    }
+
+    fn add_entry(
+        self: &mut Self,
+        key: &str,
+        mut entry: MemKvPageEntry,
+        index_keys: Vec<(String, IndexKey)>,
+    ) -> Result<(), KvError> {
+        // Checked against the stored entry so compressed values only use the space they need
+        let entry_size = entry.header.get_entry_size();
+        if let Some((policy, budget)) = self.eviction {
+            self.make_room(policy, budget, entry_size)?;
+        }
+        self.ensure_space(entry_size)?;
+
+        // Evicting may have compacted the page and moved the end of it
+        entry.header.offset = self.offset;
+        self.index.insert(key, self.offset)?;
+        self.offset = self.append_entry(entry)?;
+        self.access_tracker.get_mut().record_insert(key, entry_size);
+        self.bloom_filter.insert(key);
+        for (name, index_key) in index_keys {
+            if let Some(secondary_index) = self.secondary_indexes.get_mut(&name) {
+                secondary_index.add(index_key, key);
+            }
+        }
+        self.persist()?;
+        Ok(())
+    }
+
+    pub fn delete(self: &mut Self, key: &str) -> Result<(), KvError> {
+        if !self.index.contains_key(key)? {
+            return Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            });
+        }
+
+        // Update header to write that it has been deleted
+        let mut header = self.read_header(key)?;
+        if header.flags & ENTRY_DELETED_FLAG != 0x0 {
+            return Err(KvError::EntryAlreadyDeleted {
+                key: String::from(key),
+                offset: header.offset,
+            });
+        }
+        let index_keys = if self.secondary_indexes.is_empty() {
+            vec![]
+        } else {
+            self.extract_index_keys(&self.read_entry(key)?.value)
+        };
+        header.flags |= ENTRY_DELETED_FLAG;
+        self.write_header(header.clone())?;
+
+        self.index.remove(key)?;
+        self.access_tracker.get_mut().remove(key);
+        for (name, index_key) in index_keys {
+            if let Some(secondary_index) = self.secondary_indexes.get_mut(&name) {
+                secondary_index.remove(&index_key, key);
+            }
+        }
+        self.deleted_entries.push(MemKvPageGap::new(header));
+        self.persist()?;
+        return Ok(());
+    }
+
+    pub fn insert_with_ttl(
+        self: &mut Self,
+        key: &str,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        self.insert(key, value)?;
+        self.access_tracker.get_mut().set_expiry(key, Some(ttl));
+        return Ok(());
+    }
+
+    /// Sets the time to live of `key`, `None` makes the key persistent again. Expiry is only
+    /// tracked in memory next to the index, so once the page is closed the key stays until
+    /// it is deleted.
+    pub fn expire(self: &mut Self, key: &str, ttl: Option<Duration>) -> Result<(), KvError> {
+        if !self.contains_live_key(key)? {
+            return Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            });
+        }
+        self.access_tracker.get_mut().set_expiry(key, ttl);
+        return Ok(());
+    }
+
+    pub fn get_ttl(self: &Self, key: &str) -> Result<Option<Duration>, KvError> {
+        if !self.contains_live_key(key)? {
+            return Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            });
+        }
+        return Ok(self.access_tracker.lock().get_remaining_ttl(key));
+    }
+
+    /// Turns the page into a cache, instead of failing with `KvError::NoSpaceLeft` entries are
+    /// evicted by `policy` until the live entries fit into `budget` bytes and the page
+    pub fn set_eviction(self: &mut Self, policy: EvictionPolicy, budget: u64) {
+        self.eviction = Some((policy, budget));
+    }
+
+    pub fn get_eviction_stats(self: &Self) -> EvictionStats {
+        return self.access_tracker.lock().stats;
+    }
+
+    fn make_room(
+        self: &mut Self,
+        policy: EvictionPolicy,
+        budget: u64,
+        size: u64,
+    ) -> Result<(), KvError> {
+        let capacity = self.page_size.max(self.max_page_size);
+        loop {
+            let live_bytes = self.access_tracker.get_mut().get_live_bytes();
+            if live_bytes + size <= budget && PAGE_HEADER_SIZE + live_bytes + size <= capacity {
+                break;
+            }
+            let victim = match self.access_tracker.get_mut().find_victim(policy) {
+                Some(victim) => victim,
+                None => break,
+            };
+            let expired = self.access_tracker.get_mut().is_expired(&victim);
+            self.delete(&victim)?;
+            let stats = &mut self.access_tracker.get_mut().stats;
+            if expired {
+                stats.expirations += 1;
+            } else {
+                stats.evictions += 1;
+            }
+        }
+
+        // Deleted entries only free up space once the page is compacted
+        if self.offset + size > capacity {
+            self.load_deleted_entries()?;
+            while !self.deleted_entries.is_empty() {
+                self.defrag()?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Indexes the field at the json `path` of every json, json-typed or json blob value. The
+    /// definition is stored next to the page and the index is rebuilt when the page is opened.
+    pub fn create_index(self: &mut Self, name: &str, path: &str) -> Result<(), KvError> {
+        let segments = json_path::parse(path)?;
+        let extractor = IndexExtractor::JsonPath(String::from(path), segments);
+        self.add_secondary_index(name, extractor)?;
+        return self.save_index_definitions();
+    }
+
+    /// Indexes values by the key returned from `extractor`, values yielding `None` are skipped.
+    /// Functions can't be stored, so the index has to be created again after reopening.
+    pub fn create_index_with<F>(self: &mut Self, name: &str, extractor: F) -> Result<(), KvError>
+    where
+        F: Fn(&Value) -> Option<IndexKey> + Send + Sync + 'static,
+    {
+        return self.add_secondary_index(name, IndexExtractor::Function(Box::new(extractor)));
+    }
+
+    pub fn drop_index(self: &mut Self, name: &str) -> Result<(), KvError> {
+        if self.secondary_indexes.remove(name).is_none() {
+            return Err(KvError::IndexDoesNotExist {
+                name: String::from(name),
+            });
+        }
+        return self.save_index_definitions();
+    }
+
+    fn get_indexes_path(self: &Self) -> PathBuf {
+        return get_sidecar_path(&self.path, "indexes");
+    }
+
+    // Stores the name and path of every json path index
+    fn save_index_definitions(self: &Self) -> Result<(), KvError> {
+        let definitions: BTreeMap<&str, &str> = self
+            .secondary_indexes
+            .iter()
+            .filter_map(|(name, secondary_index)| {
+                secondary_index
+                    .get_json_path()
+                    .map(|path| (name.as_str(), path))
+            })
+            .collect();
+        let path = self.get_indexes_path();
+        if definitions.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let temporary_path = path.with_extension("indexes.tmp");
+        let mut file = fs::File::create(&temporary_path)?;
+        file.write_all(&serde_json::to_vec(&definitions)?)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, &path)?;
+        return Ok(());
+    }
+
+    fn load_index_definitions(self: &mut Self) -> Result<(), KvError> {
+        let path = self.get_indexes_path();
+        if !path.exists() {
+            return Ok(());
+        }
+        let definitions: BTreeMap<String, String> = serde_json::from_slice(&fs::read(&path)?)?;
+        for (name, path) in definitions {
+            let segments = json_path::parse(&path)?;
+            self.add_secondary_index(&name, IndexExtractor::JsonPath(path, segments))?;
+        }
+        return Ok(());
+    }
+
+    pub fn find_by<K: Into<IndexKey>>(
+        self: &Self,
+        name: &str,
+        value: K,
+    ) -> Result<Vec<String>, KvError> {
+        // Expired keys stay in the index until they are overwritten or evicted
+        return match self.secondary_indexes.get(name) {
+            Some(secondary_index) => Ok(self.retain_live_keys(secondary_index.find(&value.into()))),
+            None => Err(KvError::IndexDoesNotExist {
+                name: String::from(name),
+            }),
+        };
+    }
+
+    pub fn find_range<R: RangeBounds<IndexKey>>(
+        self: &Self,
+        name: &str,
+        range: R,
+    ) -> Result<Vec<String>, KvError> {
+        return match self.secondary_indexes.get(name) {
+            Some(secondary_index) => Ok(self.retain_live_keys(secondary_index.find_range(range))),
+            None => Err(KvError::IndexDoesNotExist {
+                name: String::from(name),
+            }),
+        };
+    }
+
+    fn retain_live_keys(self: &Self, keys: Vec<String>) -> Vec<String> {
+        return keys
+            .into_iter()
+            .filter(|key| !self.access_tracker.lock().is_expired(key))
+            .collect();
+    }
+
+    fn add_secondary_index(
+        self: &mut Self,
+        name: &str,
+        extractor: IndexExtractor,
+    ) -> Result<(), KvError> {
+        if self.secondary_indexes.contains_key(name) {
+            return Err(KvError::IndexAlreadyExists {
+                name: String::from(name),
+            });
+        }
+
+        // Backfill from the entries already on the page
+        let mut secondary_index = SecondaryIndex::new(extractor);
+        for key in self.index.keys()? {
+            if let Some(index_key) = secondary_index.extract(&self.read_entry(&key)?.value) {
+                secondary_index.add(index_key, &key);
+            }
+        }
+        self.secondary_indexes
+            .insert(String::from(name), secondary_index);
+        return Ok(());
+    }
+
+    fn extract_index_keys(self: &Self, value: &Value) -> Vec<(String, IndexKey)> {
+        return self
+            .secondary_indexes
+            .iter()
+            .filter_map(|(name, secondary_index)| {
+                secondary_index
+                    .extract(value)
+                    .map(|index_key| (name.clone(), index_key))
+            })
+            .collect();
+    }
+
+    /// Closes the gap of the deleted entry closest to the end of the page by moving all
+    /// following entries over it
+    pub fn defrag(self: &mut Self) -> Result<(), KvError> {
+        self.load_deleted_entries()?;
+        let next_gap = match self.deleted_entries.pop() {
+            Some(gap) => gap,
+            None => return Ok(()),
+        };
+        debug!(
+            "Closing gap of {} bytes at offset {} of page {:?}",
+            next_gap.length, next_gap.offset, self.path
+        );
+        let gap_end = next_gap.offset + next_gap.length;
+        if gap_end > self.offset {
+            return Err(KvError::OutOfBounds {
+                offset: next_gap.offset,
+                size: next_gap.length,
+                page_size: self.offset,
+            });
+        }
+
+        // On the last entry we need to do nothing just reset the offset
+        if gap_end == self.offset {
+            // Zero out the entry so loading the page stops at the new end
+            self.mmap[next_gap.offset as usize..self.offset as usize].fill(0);
+            self.mark_dirty(next_gap.offset as usize, self.offset as usize);
+            self.persist()?;
+            self.offset = next_gap.offset;
+            return Ok(());
+        } else {
+            // Copy values back over deleted gap
+            let previous_offset = self.offset as usize;
+            let new_offset = self.offset - next_gap.length;
+
+            self.mmap
+                .copy_within(gap_end as usize..previous_offset, next_gap.offset as usize);
+            // 0 out moved data
+            self.mmap[new_offset as usize..previous_offset].fill(0);
+            self.mark_dirty(next_gap.offset as usize, previous_offset);
+
+            let mut entry_update_offset = next_gap.offset;
+            // Update indices
+            // todo(@koogle): Rewrite to perform defrag one entry at a time
+
+            while entry_update_offset < new_offset {
+                let header = match self.read_header_from_offset(entry_update_offset) {
+                    Ok(header) => header,
+                    Err(_) => break,
+                };
+                // Deleted entries must not be added back to the index
+                if header.flags & ENTRY_DELETED_FLAG == 0x0 {
+                    let key = self.read_key(&header)?;
+                    self.index.insert(&key, header.offset)?;
+                }
+                entry_update_offset += header.get_entry_size()
+            }
+
+            self.persist()?;
+            self.offset = new_offset;
+            return Ok(());
+        }
+    }
+
+    pub fn set_durability(self: &mut Self, durability: DurabilityMode) {
+        self.durability = durability;
+    }
+
+    /// Flushes every byte written since the last sync
+    pub fn sync(self: &mut Self) -> Result<(), KvError> {
+        // The range is only cleared once it is on disk, so a failed flush is retried
+        if let Some((start, end)) = self.dirty_range {
+            self.flush_mmap(Some((start, end)))?;
+            self.bloom_filter.flush()?;
+            self.dirty_range = None;
+        }
+        // The index is flushed last so it never refers to entries which are not on disk
+        self.index.flush(self.offset)?;
+        self.last_sync = Instant::now();
+        return Ok(());
+    }
+
+    pub fn sync_all(self: &mut Self) -> Result<(), KvError> {
+        self.flush_mmap(None)?;
+        self.bloom_filter.flush()?;
+        self.index.flush(self.offset)?;
+        self.dirty_range = None;
+        self.last_sync = Instant::now();
+        return Ok(());
+    }
+
+    // Flushes `range` of the page or all of it
+    fn flush_mmap(self: &mut Self, range: Option<(usize, usize)>) -> Result<(), KvError> {
+        #[cfg(test)]
+        if self.failing_flushes > 0 {
+            self.failing_flushes -= 1;
+            return Err(KvError::Io(io::Error::other("injected flush failure")));
+        }
+        match range {
+            Some((start, end)) => self.mmap.flush_range(start, end - start)?,
+            None => self.mmap.flush()?,
+        }
+        return Ok(());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fail_next_flushes(self: &mut Self, count: u32) {
+        self.failing_flushes = count;
+    }
+
+    fn mark_dirty(self: &mut Self, start: usize, end: usize) {
+        self.dirty_range = match self.dirty_range {
+            Some((dirty_start, dirty_end)) => Some((dirty_start.min(start), dirty_end.max(end))),
+            None => Some((start, end)),
+        };
+    }
+
+    fn persist(self: &mut Self) -> Result<(), KvError> {
+        return match self.durability {
+            // Flush entire map
+            DurabilityMode::Always => self.sync_all(),
+            DurabilityMode::DirtyRange => self.sync(),
+            DurabilityMode::Periodic(interval) => {
+                if self.last_sync.elapsed() >= interval {
+                    self.sync()?;
+                }
+                Ok(())
+            }
+            DurabilityMode::Os => Ok(()),
+        };
+    }
+
+    fn write_to_mmap(mmap: &mut MmapMut, offset: usize, data: &[u8]) -> Result<usize, KvError> {
+        let data_size = data.len();
+        match mmap.get_mut(offset..offset + data_size) {
+            Some(target) => target.copy_from_slice(data),
+            None => {
+                return Err(KvError::OutOfBounds {
+                    offset: offset as u64,
+                    size: data_size as u64,
+                    page_size: mmap.len() as u64,
+                })
+            }
+        }
+
+        return Ok(offset + data_size);
+    }
+}
+
+pub(crate) fn read_slice(data: &[u8], offset: u64, size: u64) -> Result<&[u8], KvError> {
+    return match offset.checked_add(size) {
+        Some(end) if end <= data.len() as u64 => Ok(&data[offset as usize..end as usize]),
+        _ => Err(KvError::OutOfBounds {
+            offset,
+            size,
+            page_size: data.len() as u64,
+        }),
+    };
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: u64) -> Result<u64, KvError> {
+    let mut buffer = [0; size_of::<u64>()];
+    buffer.copy_from_slice(read_slice(data, offset, size_of::<u64>() as u64)?);
+    return Ok(u64::from_be_bytes(buffer));
+}
+
+// Files belonging to a page share its path with an additional extension
+pub fn get_sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar_path = path.as_os_str().to_os_string();
+    sidecar_path.push(".");
+    sidecar_path.push(extension);
+    return PathBuf::from(sidecar_path);
+}
+
+/// Removes the page file at `path` and all its sidecars, missing files are skipped
+pub fn remove_page_files(path: &Path) -> Result<(), KvError> {
+    for extension in SIDECAR_EXTENSIONS {
+        let sidecar_path = get_sidecar_path(path, extension);
+        if sidecar_path.exists() {
+            fs::remove_file(sidecar_path)?;
+        }
+    }
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_entry_checksum, get_sidecar_path, BTreeIndex, Codec, Compression, DurabilityMode,
+        EvictionPolicy, IndexKey, Keyring, KvError, MemKvPage, PageIndex, Value,
+        ENTRY_CHECKSUM_SIZE, ENTRY_HEADER_SIZE, FLAG_CHECKSUM, KV_PAGE_SIZE, PAGE_HEADER_SIZE,
+        SIDECAR_EXTENSIONS,
+    };
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::panic;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    const TEST_KEYSPACE: &str = "test_keyspace";
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Person {
+        name: String,
+        age: u8,
+        phones: Vec<String>,
+    }
+
+    fn run_test<T>(keyspace: &str, test: T)
+    where
+        T: FnOnce() + panic::UnwindSafe,
+    {
+        setup(keyspace);
+
+        let result = panic::catch_unwind(test);
+
+        teardown(keyspace);
+
+        assert!(result.is_ok())
+    }
+
+    fn setup(keyspace: &str) {
+        remove_page_files(keyspace);
+    }
+
+    fn teardown(keyspace: &str) {
+        remove_page_files(keyspace);
+    }
+
+    fn remove_page_files(keyspace: &str) {
+        super::remove_page_files(Path::new(keyspace)).unwrap();
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        run_test(TEST_KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(TEST_KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap
                 .insert("albert", Value::String(String::from("value")))
                 .unwrap();
             kvmap.insert("peter", Value::Integer(123)).unwrap();
@@ -572,17 +1806,822 @@ mod tests {
                 panic!("test");
             }
 
-            assert_eq!(kvmap.offset, 157);
-            assert_eq!(*kvmap.index.get("peter").unwrap(), 29);
+            assert_eq!(kvmap.offset, PAGE_HEADER_SIZE + 189);
+            assert_eq!(
+                kvmap.index.get("peter").unwrap().unwrap(),
+                PAGE_HEADER_SIZE + 37
+            );
             kvmap.delete("albert").unwrap();
             kvmap.delete("dan").unwrap();
-            kvmap.defrag();
-            assert_eq!(*kvmap.index.get("peter").unwrap(), 29);
-            assert_eq!(kvmap.offset, 95);
-            kvmap.defrag();
-            assert_eq!(*kvmap.index.get("peter").unwrap(), 0);
-            kvmap.defrag();
-            assert_eq!(kvmap.offset, 66);
+            kvmap.defrag().unwrap();
+            assert_eq!(
+                kvmap.index.get("peter").unwrap().unwrap(),
+                PAGE_HEADER_SIZE + 37
+            );
+            assert_eq!(kvmap.offset, PAGE_HEADER_SIZE + 119);
+            kvmap.defrag().unwrap();
+            assert_eq!(kvmap.index.get("peter").unwrap().unwrap(), PAGE_HEADER_SIZE);
+            kvmap.defrag().unwrap();
+            assert_eq!(kvmap.offset, PAGE_HEADER_SIZE + 82);
+        });
+    }
+
+    #[test]
+    fn test_put_and_get_typed() {
+        const KEYSPACE: &str = "test_keyspace_typed";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            let person_a = Person {
+                name: String::from("peter pan"),
+                age: 20,
+                phones: vec![String::from("+44 20 7946 0000")],
+            };
+            kvmap.put_typed("dan", &person_a).unwrap();
+            assert_eq!(kvmap.get_typed::<Person>("dan").unwrap(), person_a);
+
+            if let Value::Typed(codec, data) = kvmap.get("dan").unwrap() {
+                assert_eq!(codec, Codec::Json);
+                assert_eq!(data, serde_json::to_vec(&person_a).unwrap());
+            } else {
+                panic!();
+            }
+
+            kvmap
+                .insert("tom", Value::Blob(serde_json::to_vec(&person_a).unwrap()))
+                .unwrap();
+            assert_eq!(kvmap.get_typed::<Person>("tom").unwrap(), person_a);
+            assert!(kvmap.get_typed::<Person>("missing").is_err());
+        });
+    }
+
+    #[test]
+    fn test_json_paths() {
+        const KEYSPACE: &str = "test_keyspace_json";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert!(Value::json_from_str("{\"name\": ").is_err());
+            kvmap
+                .insert(
+                    "dan",
+                    Value::Json(json!({"name": "peter pan", "age": 20, "phones": ["123"]})),
+                )
+                .unwrap();
+
+            assert_eq!(kvmap.get_path("dan", "$.phones[0]").unwrap(), json!("123"));
+            assert_eq!(
+                kvmap.get_path("dan", "$['name']").unwrap(),
+                json!("peter pan")
+            );
+            assert!(kvmap.get_path("dan", "$.phones[1]").is_err());
+            assert!(kvmap.get_path("dan", "phones").is_err());
+
+            kvmap.set_path("dan", "$.phones[0]", json!("456")).unwrap();
+            kvmap.set_path("dan", "$.city", json!("London")).unwrap();
+            assert!(kvmap.set_path("dan", "$.phones[3]", json!("789")).is_err());
+
+            if let Value::Json(document) = kvmap.get("dan").unwrap() {
+                assert_eq!(
+                    document,
+                    json!({"name": "peter pan", "age": 20, "phones": ["456"], "city": "London"})
+                );
+            } else {
+                panic!();
+            }
+
+            // A patch which does not fit must leave the old document in place
+            kvmap.delete("dan").unwrap();
+            kvmap.defrag().unwrap();
+            kvmap.set_large_value_threshold(usize::MAX);
+            kvmap.insert("doc", Value::Json(json!({"a": "x"}))).unwrap();
+            let patch = json!("y".repeat(20));
+            let patched_size = serde_json::to_vec(&json!({ "a": patch })).unwrap().len() as u64;
+            let filler_size =
+                KV_PAGE_SIZE - kvmap.offset - ENTRY_HEADER_SIZE - 1 - patched_size - 5;
+            kvmap
+                .insert("f", Value::Blob(vec![0; filler_size as usize]))
+                .unwrap();
+            assert!(matches!(
+                kvmap.set_path("doc", "$.a", patch),
+                Err(KvError::NoSpaceLeft { .. })
+            ));
+            assert_eq!(kvmap.get_path("doc", "$.a").unwrap(), json!("x"));
+        });
+    }
+
+    #[test]
+    fn test_secondary_indexes() {
+        const KEYSPACE: &str = "test_keyspace_secondary_index";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap
+                .insert("dan", Value::Json(json!({"name": "peter pan", "age": 20})))
+                .unwrap();
+            kvmap.create_index("name", "$.name").unwrap();
+            kvmap.create_index("age", "$.age").unwrap();
+            kvmap
+                .create_index_with("length", |value| match value {
+                    Value::String(text) => Some(IndexKey::Integer(text.len() as i64)),
+                    _ => None,
+                })
+                .unwrap();
+            assert!(kvmap.create_index("name", "$.name").is_err());
+
+            let person_b = Person {
+                name: String::from("wendy"),
+                age: 13,
+                phones: vec![],
+            };
+            kvmap.put_typed("wendy", &person_b).unwrap();
+            kvmap
+                .insert("tom", Value::String(String::from("abc")))
+                .unwrap();
+
+            assert_eq!(kvmap.find_by("name", "peter pan").unwrap(), vec!["dan"]);
+            assert_eq!(kvmap.find_by("name", "wendy").unwrap(), vec!["wendy"]);
+            assert_eq!(kvmap.find_by("length", 3).unwrap(), vec!["tom"]);
+            assert_eq!(
+                kvmap
+                    .find_range("age", IndexKey::Integer(10)..IndexKey::Integer(30))
+                    .unwrap(),
+                vec!["wendy", "dan"]
+            );
+
+            kvmap.set_path("dan", "$.name", json!("hook")).unwrap();
+            assert!(kvmap.find_by("name", "peter pan").unwrap().is_empty());
+            assert_eq!(kvmap.find_by("name", "hook").unwrap(), vec!["dan"]);
+
+            kvmap.delete("wendy").unwrap();
+            assert!(kvmap.find_by("name", "wendy").unwrap().is_empty());
+
+            // Keys leave the index once they expire
+            kvmap
+                .insert_with_ttl(
+                    "tink",
+                    Value::Json(json!({"name": "tink", "age": 10})),
+                    Duration::from_millis(1),
+                )
+                .unwrap();
+            assert_eq!(kvmap.find_by("name", "tink").unwrap(), vec!["tink"]);
+            thread::sleep(Duration::from_millis(10));
+            assert!(kvmap.find_by("name", "tink").unwrap().is_empty());
+            assert_eq!(
+                kvmap
+                    .find_range("age", IndexKey::Integer(0)..IndexKey::Integer(30))
+                    .unwrap(),
+                vec!["dan"]
+            );
+
+            kvmap.drop_index("name").unwrap();
+            assert!(kvmap.find_by("name", "hook").is_err());
+            drop(kvmap);
+
+            // Json path indexes are rebuilt when the page is opened, function indexes are not
+            let kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert_eq!(kvmap.find_by("age", 20).unwrap(), vec!["dan"]);
+            assert!(kvmap.find_by("name", "hook").is_err());
+            assert!(kvmap.find_by("length", 3).is_err());
+        });
+    }
+
+    #[test]
+    fn test_compression() {
+        const KEYSPACE: &str = "test_keyspace_compression";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            let document = json!({ "phones": vec!["+44 20 7946 0000"; 200] });
+
+            kvmap.set_compression(Compression::Lz4, 64);
+            kvmap.insert("lz4", Value::Json(document.clone())).unwrap();
+            kvmap.insert("small", Value::Integer(7)).unwrap();
+            kvmap.set_compression(Compression::Zstd, 64);
+            kvmap.insert("zstd", Value::Json(document.clone())).unwrap();
+            kvmap.set_compression(Compression::None, 64);
+            kvmap.insert("raw", Value::Json(document.clone())).unwrap();
+
+            let raw_size = kvmap.read_header("raw").unwrap().value_size;
+            for key in ["lz4", "zstd"] {
+                assert!(kvmap.read_header(key).unwrap().value_size < raw_size);
+                if let Value::Json(value) = kvmap.get(key).unwrap() {
+                    assert_eq!(value, document);
+                } else {
+                    panic!();
+                }
+            }
+            assert_eq!(kvmap.read_header("small").unwrap().flags, FLAG_CHECKSUM);
+
+            // The size prefix of a compressed value is checked before allocating, the checksum
+            // is updated so the value reaches decompression
+            let header = kvmap.read_header("lz4").unwrap();
+            let offset = (header.get_absolute_data_offset() + header.key_size) as usize;
+            let value_end = offset + header.value_size as usize;
+            kvmap.mmap[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let checksum = get_entry_checksum(
+                header.data_type,
+                header.flags,
+                &kvmap.read_raw_key(&header).unwrap(),
+                &kvmap.mmap[offset..value_end],
+            );
+            kvmap.mmap[value_end..value_end + checksum.len()].copy_from_slice(&checksum);
+            assert!(matches!(
+                kvmap.get("lz4"),
+                Err(KvError::DecompressedValueTooLarge { .. })
+            ));
+
+            kvmap.delete("lz4").unwrap();
+            assert!(kvmap.delete("lz4").is_err());
+        });
+    }
+
+    #[test]
+    fn test_encryption() {
+        const KEYSPACE: &str = "test_keyspace_encryption";
+        const KEYFILE: &str = "test_keyspace_encryption.keys";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.set_keyring(Keyring::new(1, HashMap::from([(1, [1; 32])])).unwrap());
+            kvmap
+                .insert("albert", Value::String(String::from("secret value")))
+                .unwrap();
+            kvmap.set_compression(Compression::Lz4, 0);
+            kvmap
+                .insert("tom", Value::String(String::from("another secret value")))
+                .unwrap();
+
+            let contents =
+                String::from_utf8_lossy(&kvmap.mmap[..kvmap.offset as usize]).to_string();
+            assert!(!contents.contains("albert") && !contents.contains("secret"));
+            if let Value::String(value) = kvmap.get("tom").unwrap() {
+                assert_eq!(value, "another secret value");
+            } else {
+                panic!();
+            }
+
+            kvmap.set_keyring(Keyring::new(1, HashMap::from([(1, [2; 32])])).unwrap());
+            let error = kvmap.get("albert").unwrap_err();
+            assert!(error.to_string().contains("wrong key"));
+
+            // Rotate to a key loaded through the config crate
+            fs::write(
+                KEYFILE,
+                json!({
+                    "active_key": 2,
+                    "keys": {
+                        "1": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=",
+                        "2": "AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM="
+                    }
+                })
+                .to_string(),
+            )
+            .unwrap();
+            let settings = config::Config::builder()
+                .set_override("encryption.keyfile", KEYFILE)
+                .unwrap()
+                .build()
+                .unwrap();
+            kvmap.set_keyring(Keyring::from_config(&settings).unwrap());
+            fs::remove_file(KEYFILE).unwrap();
+            assert_eq!(kvmap.rotate_encryption_key().unwrap(), 2);
+            assert_eq!(kvmap.rotate_encryption_key().unwrap(), 0);
+
+            kvmap.set_keyring(Keyring::new(2, HashMap::from([(2, [3; 32])])).unwrap());
+            if let Value::String(value) = kvmap.get("albert").unwrap() {
+                assert_eq!(value, "secret value");
+            } else {
+                panic!();
+            }
+            kvmap.delete("albert").unwrap();
+            kvmap.defrag().unwrap();
+            if let Value::String(value) = kvmap.get("tom").unwrap() {
+                assert_eq!(value, "another secret value");
+            } else {
+                panic!();
+            }
+            kvmap
+                .insert("wendy", Value::Json(json!({"name": "wendy"})))
+                .unwrap();
+            drop(kvmap);
+
+            // Keys are decrypted while the page is loaded
+            assert!(matches!(
+                MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE),
+                Err(KvError::EncryptionKeyNotFound { .. })
+            ));
+            let keyring = Keyring::new(2, HashMap::from([(2, [3; 32])])).unwrap();
+            let kvmap =
+                MemKvPage::new_with_keyring(Path::new(KEYSPACE), KV_PAGE_SIZE, keyring).unwrap();
+            assert_eq!(kvmap.get_key_count().unwrap(), 2);
+            assert_eq!(
+                kvmap.get("tom").unwrap(),
+                Value::String(String::from("another secret value"))
+            );
+            assert_eq!(kvmap.get_path("wendy", "$.name").unwrap(), json!("wendy"));
+            assert!(kvmap.get("albert").is_err());
+        });
+    }
+
+    #[test]
+    fn test_large_values() {
+        const KEYSPACE: &str = "test_keyspace_large_values";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            let large_blob: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            kvmap
+                .insert("large", Value::Blob(large_blob.clone()))
+                .unwrap();
+            kvmap.insert("small", Value::Integer(1)).unwrap();
+            assert!(kvmap.offset < PAGE_HEADER_SIZE + 100);
+
+            if let Value::Blob(value) = kvmap.get("large").unwrap() {
+                assert_eq!(value, large_blob);
+            } else {
+                panic!();
+            }
+
+            let mut writer = kvmap.put_writer("streamed").unwrap();
+            for chunk in large_blob.chunks(64 * 1024) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let mut streamed = vec![];
+            kvmap
+                .get_reader("streamed")
+                .unwrap()
+                .read_to_end(&mut streamed)
+                .unwrap();
+            assert_eq!(streamed, large_blob);
+
+            let mut small = vec![];
+            kvmap
+                .get_reader("small")
+                .unwrap()
+                .read_to_end(&mut small)
+                .unwrap();
+            assert_eq!(small, 1u64.to_be_bytes());
+
+            // A corrupted reference fails the read instead of allocating its length
+            let header = kvmap.read_header("large").unwrap();
+            let length_offset = (header.get_absolute_data_offset() + header.get_entry_size()
+                - ENTRY_HEADER_SIZE
+                - 8) as usize;
+            kvmap.mmap[length_offset..length_offset + 8]
+                .copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+            assert!(kvmap.get("large").is_err());
+        });
+    }
+
+    #[test]
+    fn test_error_variants() {
+        const KEYSPACE: &str = "test_keyspace_errors";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.insert("a", Value::Integer(1)).unwrap();
+            assert!(matches!(
+                kvmap.insert("a", Value::Integer(2)),
+                Err(KvError::KeyAlreadyExists { key }) if key == "a"
+            ));
+            assert!(matches!(
+                kvmap.get("b"),
+                Err(KvError::KeyDoesNotExist { key }) if key == "b"
+            ));
+            assert!(matches!(
+                kvmap.get_path("a", "$.name"),
+                Err(KvError::InvalidDataType { .. })
+            ));
+            assert!(matches!(
+                kvmap.find_by("name", "peter"),
+                Err(KvError::IndexDoesNotExist { name }) if name == "name"
+            ));
+
+            let error = kvmap.delete("b").unwrap_err();
+            assert_eq!(error.to_string(), "key \"b\" does not exist in page");
+        });
+    }
+
+    #[test]
+    fn test_value_json() {
+        let values = vec![
+            Value::String(String::from("peter")),
+            Value::Integer(u64::MAX),
+            Value::Blob(vec![0, 159, 146, 150]),
+            Value::Typed(Codec::Json, b"[1]".to_vec()),
+            Value::json_from_str("{\"name\": [1, 2]}").unwrap(),
+        ];
+        for value in values {
+            let document = value.to_json();
+            assert_eq!(Value::from_json(&document).unwrap().to_json(), document);
+        }
+        assert_eq!(
+            Value::Blob(b"abc".to_vec()).to_json(),
+            serde_json::json!({"type": "Blob", "value": "YWJj"})
+        );
+        for document in [
+            serde_json::json!({"type": "Integer", "value": -1}),
+            serde_json::json!({"type": "Blob", "value": "%"}),
+            serde_json::json!({"type": "Typed", "value": ""}),
+            serde_json::json!({"type": "Float", "value": 1.5}),
+            serde_json::json!({"type": "String"}),
+        ] {
+            assert!(matches!(
+                Value::from_json(&document),
+                Err(KvError::InvalidValueJson { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_growable_pages() {
+        const KEYSPACE: &str = "test_keyspace_growable";
+        run_test(KEYSPACE, || {
+            assert!(MemKvPage::new(Path::new(KEYSPACE), PAGE_HEADER_SIZE).is_err());
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), 1024).unwrap();
+            assert_eq!(fs::metadata(KEYSPACE).unwrap().len(), 1024);
+            assert_eq!(kvmap.mmap[..8], 1024u64.to_be_bytes());
+
+            let value = Value::String(String::from("x").repeat(500));
+            kvmap.insert("a", value.clone()).unwrap();
+            assert!(matches!(
+                kvmap.insert("b", value.clone()),
+                Err(KvError::NoSpaceLeft { requested, available }) if requested > available
+            ));
+
+            kvmap.set_max_page_size(2600);
+            kvmap.insert("b", value.clone()).unwrap();
+            assert_eq!(kvmap.get_page_size(), 2048);
+            assert_eq!(fs::metadata(KEYSPACE).unwrap().len(), 2048);
+            assert_eq!(kvmap.mmap[..8], 2048u64.to_be_bytes());
+            kvmap.insert("c", value.clone()).unwrap();
+            kvmap.insert("d", value.clone()).unwrap();
+            assert_eq!(kvmap.get_page_size(), 2600);
+            assert!(kvmap.insert("e", value.clone()).is_err());
+
+            if let Value::String(text) = kvmap.get("a").unwrap() {
+                assert_eq!(text.len(), 500);
+            } else {
+                panic!();
+            }
+        });
+    }
+
+    #[test]
+    fn test_dirty_range_durability() {
+        const KEYSPACE: &str = "test_keyspace_durability";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.set_durability(DurabilityMode::Os);
+            kvmap.insert("albert", Value::Integer(1)).unwrap();
+            kvmap.insert("peter", Value::Integer(2)).unwrap();
+            assert_eq!(
+                kvmap.dirty_range,
+                Some((PAGE_HEADER_SIZE as usize, kvmap.offset as usize))
+            );
+
+            kvmap.set_durability(DurabilityMode::DirtyRange);
+            kvmap.delete("albert").unwrap();
+            assert_eq!(kvmap.dirty_range, None);
+            kvmap.set_durability(DurabilityMode::Os);
+            kvmap.defrag().unwrap();
+            assert!(kvmap.dirty_range.is_some());
+
+            // A failed flush keeps the range for the next one
+            kvmap.fail_next_flushes(1);
+            let dirty_range = kvmap.dirty_range;
+            assert!(kvmap.sync().is_err());
+            assert_eq!(kvmap.dirty_range, dirty_range);
+            kvmap.sync().unwrap();
+            assert_eq!(kvmap.dirty_range, None);
+        });
+    }
+
+    #[test]
+    fn test_eviction() {
+        const KEYSPACE: &str = "test_keyspace_eviction";
+        run_test(KEYSPACE, || {
+            // Every entry below is 18 bytes of header, 1 byte of key, 100 bytes of value and
+            // 8 bytes of checksum
+            let value = Value::String(String::from("x").repeat(100));
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), 1024).unwrap();
+            kvmap.set_eviction(EvictionPolicy::LeastRecentlyUsed, 127 * 3);
+            for key in ["a", "b", "c"] {
+                kvmap.insert(key, value.clone()).unwrap();
+            }
+            kvmap.get("a").unwrap();
+            kvmap.insert("d", value.clone()).unwrap();
+            assert!(kvmap.get("b").is_err());
+            assert!(kvmap.get("a").is_ok());
+            assert_eq!(kvmap.get_eviction_stats().evictions, 1);
+
+            kvmap.set_eviction(EvictionPolicy::LeastFrequentlyUsed, 127 * 3);
+            kvmap.get("c").unwrap();
+            kvmap.get("c").unwrap();
+            kvmap.get("d").unwrap();
+            kvmap.insert("e", value.clone()).unwrap();
+            assert!(kvmap.get("a").is_ok());
+            assert!(kvmap.get("c").is_ok());
+            assert!(kvmap.get("d").is_err());
+
+            // Runs out of page instead of budget and has to compact the page
+            kvmap.set_eviction(EvictionPolicy::Random, 1024);
+            for key in ["f", "g", "h", "i", "j", "k", "l", "m", "n", "o"] {
+                kvmap.insert(key, value.clone()).unwrap();
+            }
+            assert!(kvmap.get("o").is_ok());
+            assert!(kvmap.offset <= 1024);
+
+            kvmap.set_eviction(EvictionPolicy::TtlFirst, 1024);
+            kvmap
+                .insert_with_ttl("p", value.clone(), Duration::from_millis(1))
+                .unwrap();
+            assert!(kvmap.get_ttl("o").unwrap().is_none());
+            thread::sleep(Duration::from_millis(5));
+            assert!(kvmap.get("p").is_err());
+            assert!(kvmap.get_ttl("p").is_err());
+            kvmap.insert("q", value.clone()).unwrap();
+            assert!(!kvmap.index.contains_key("p").unwrap());
+            assert_eq!(kvmap.get_eviction_stats().expirations, 1);
+        });
+    }
+
+    #[test]
+    fn test_load_page_and_bloom_filter() {
+        const KEYSPACE: &str = "test_keyspace_bloom_filter";
+        run_test(KEYSPACE, || {
+            {
+                let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+                kvmap.insert("albert", Value::Integer(1)).unwrap();
+                kvmap.insert("peter", Value::Integer(2)).unwrap();
+                kvmap.insert("tom", Value::Integer(3)).unwrap();
+                kvmap.delete("peter").unwrap();
+                assert!(kvmap.may_contain("albert"));
+                assert!(!kvmap.may_contain("dan"));
+            }
+
+            let kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert_eq!(kvmap.index.len(), 2);
+            assert_eq!(kvmap.deleted_entries.len(), 1);
+            assert_eq!(
+                kvmap.offset,
+                PAGE_HEADER_SIZE + 3 * 18 + 6 + 5 + 3 + 3 * 8 + 3 * ENTRY_CHECKSUM_SIZE
+            );
+            assert!(kvmap.may_contain("tom"));
+            if let Value::Integer(value) = kvmap.get("tom").unwrap() {
+                assert_eq!(value, 3);
+            } else {
+                panic!();
+            }
+            drop(kvmap);
+
+            // A filter missing keys is rebuilt instead of hiding them
+            fs::remove_file(get_sidecar_path(Path::new(KEYSPACE), "bloom")).unwrap();
+            let kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert!(kvmap.may_contain("albert") && kvmap.may_contain("tom"));
+        });
+    }
+
+    #[test]
+    fn test_btree_index() {
+        const KEYSPACE: &str = "test_keyspace_btree_index";
+        run_test(KEYSPACE, || {
+            {
+                let mut kvmap =
+                    MemKvPage::new_with_btree_index(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+                for i in 0..500 {
+                    kvmap
+                        .insert(&format!("user:{:03}", i), Value::Integer(i))
+                        .unwrap();
+                }
+                kvmap.insert("group:1", Value::Integer(1)).unwrap();
+                kvmap.delete("user:499").unwrap();
+                kvmap.delete("user:000").unwrap();
+            }
+
+            // Reopening takes offsets from the B+tree instead of scanning the page
+            let mut kvmap =
+                MemKvPage::new_with_btree_index(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert!(matches!(kvmap.index, PageIndex::BTree(_)));
+            assert_eq!(kvmap.get_key_count().unwrap(), 499);
+            let users = kvmap.scan("user:").unwrap();
+            assert_eq!(users.len(), 498);
+            assert_eq!(users[0].0, "user:001");
+            assert_eq!(users[497].0, "user:498");
+
+            kvmap.defrag().unwrap();
+            kvmap.defrag().unwrap();
+            assert_eq!(kvmap.deleted_entries.len(), 0);
+            if let Value::Integer(value) = kvmap.get("user:250").unwrap() {
+                assert_eq!(value, 250);
+            } else {
+                panic!();
+            }
+
+            // Changes that never got flushed make the index fall back to scanning the page
+            kvmap.set_durability(DurabilityMode::Os);
+            kvmap.insert("group:2", Value::Integer(2)).unwrap();
+            drop(kvmap);
+            let kvmap = MemKvPage::new_with_btree_index(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert_eq!(kvmap.get_key_count().unwrap(), 500);
+            assert!(kvmap.get("group:2").is_ok());
+        });
+    }
+
+    #[test]
+    fn test_btree_index_with_page_index() {
+        const KEYSPACE: &str = "test_keyspace_btree_mixed";
+        run_test(KEYSPACE, || {
+            let btree_path = get_sidecar_path(Path::new(KEYSPACE), "btree");
+            let mut kvmap =
+                MemKvPage::new_with_btree_index(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.insert("a", Value::Integer(1)).unwrap();
+            drop(kvmap);
+            let stale_btree = fs::read(&btree_path).unwrap();
+
+            // Opening without the B+tree marks it stale as its writes would not be indexed
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            assert!(btree_path.exists());
+            assert!(BTreeIndex::open(&btree_path).is_err());
+            kvmap.insert("b", Value::Integer(2)).unwrap();
+            drop(kvmap);
+            let mut kvmap =
+                MemKvPage::new_with_btree_index(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.insert("c", Value::Integer(3)).unwrap();
+            drop(kvmap);
+
+            // A B+tree which does not know about later entries is rebuilt instead of used
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.insert("d", Value::Integer(4)).unwrap();
+            drop(kvmap);
+            fs::write(&btree_path, stale_btree).unwrap();
+            let mut kvmap =
+                MemKvPage::new_with_btree_index(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+            kvmap.insert("e", Value::Integer(5)).unwrap();
+            for (i, key) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+                assert_eq!(kvmap.get(key).unwrap(), Value::Integer(i as u64 + 1));
+            }
+        });
+    }
+
+    // Runs the read and write paths on a page file, errors are expected but nothing may panic
+    fn exercise_page(keyspace: &str, with_btree_index: bool) {
+        let kvmap = match with_btree_index {
+            true => MemKvPage::new_with_btree_index(Path::new(keyspace), KV_PAGE_SIZE),
+            false => MemKvPage::new(Path::new(keyspace), KV_PAGE_SIZE),
+        };
+        let mut kvmap = match kvmap {
+            Ok(kvmap) => kvmap,
+            Err(_) => return,
+        };
+        let keys = kvmap.index.keys().unwrap_or_default();
+        for key in &keys {
+            let _ = kvmap.get(key);
+            let _ = kvmap.get_path(key, "$.name");
+            if let Ok(mut reader) = kvmap.get_reader(key) {
+                let _ = reader.read_to_end(&mut vec![]);
+            }
+        }
+        let _ = kvmap.scan("");
+        let _ = kvmap.create_index("name", "$.name");
+        for key in keys.iter().take(2) {
+            let _ = kvmap.delete(key);
+        }
+        let _ = kvmap.upsert("fuzz", Value::Integer(1));
+        let _ = kvmap.defrag();
+        let _ = kvmap.defrag();
+        let _ = kvmap.sync_all();
+    }
+
+    #[test]
+    fn test_fuzz_page_files() {
+        const KEYSPACE: &str = "test_keyspace_fuzz";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new_with_btree_index(Path::new(KEYSPACE), 2048).unwrap();
+            kvmap
+                .insert("albert", Value::String(String::from("value")))
+                .unwrap();
+            kvmap.insert("peter", Value::Integer(123)).unwrap();
+            kvmap
+                .insert(
+                    "dan",
+                    Value::Json(json!({"name": "dan", "phones": ["123"]})),
+                )
+                .unwrap();
+            kvmap.put_typed("person", &vec![1, 2, 3]).unwrap();
+            kvmap.set_large_value_threshold(64);
+            kvmap.insert("blob", Value::Blob(vec![7; 100])).unwrap();
+            kvmap.set_large_value_threshold(usize::MAX);
+            kvmap.set_compression(Compression::Lz4, 0);
+            kvmap
+                .insert("lz4", Value::String(String::from("ab").repeat(100)))
+                .unwrap();
+            kvmap.delete("peter").unwrap();
+            kvmap.sync_all().unwrap();
+
+            let mut entry_offsets = vec![];
+            let mut offset = PAGE_HEADER_SIZE;
+            while offset < kvmap.offset {
+                entry_offsets.push(offset);
+                offset += kvmap
+                    .read_header_from_offset(offset)
+                    .unwrap()
+                    .get_entry_size();
+            }
+            drop(kvmap);
+            let page = fs::read(KEYSPACE).unwrap();
+            let sidecars: Vec<(PathBuf, Vec<u8>)> = SIDECAR_EXTENSIONS
+                .iter()
+                .map(|extension| get_sidecar_path(Path::new(KEYSPACE), extension))
+                .filter(|path| path.exists())
+                .map(|path| {
+                    let data = fs::read(&path).unwrap();
+                    (path, data)
+                })
+                .collect();
+            assert!(sidecars.len() >= 3);
+
+            // Mutations of a valid page reach much deeper than random files
+            let mut rng = StdRng::seed_from_u64(42);
+            for round in 0..900 {
+                remove_page_files(KEYSPACE);
+                let mut data = page.clone();
+                match round % 6 {
+                    0 => {
+                        for _ in 0..rng.gen_range(1..8) {
+                            let position = rng.gen_range(PAGE_HEADER_SIZE as usize..data.len());
+                            data[position] = rng.gen();
+                        }
+                    }
+                    1 => {
+                        // Key or value size of an entry, either arbitrary or just out of bounds
+                        let offset = entry_offsets[rng.gen_range(0..entry_offsets.len())] as usize;
+                        let position = offset + 2 + 8 * rng.gen_range(0..2);
+                        let size = match rng.gen_range(0..3) {
+                            0 => rng.gen::<u64>(),
+                            1 => u64::MAX - rng.gen_range(0..ENTRY_HEADER_SIZE),
+                            _ => data.len() as u64 - rng.gen_range(0..offset as u64),
+                        };
+                        data[position..position + 8].copy_from_slice(&size.to_be_bytes());
+                    }
+                    2 => {
+                        // The page header with the page size
+                        let position = rng.gen_range(0..PAGE_HEADER_SIZE as usize - 8);
+                        data[position..position + 8]
+                            .copy_from_slice(&rng.gen::<u64>().to_be_bytes());
+                    }
+                    3 => data.truncate(rng.gen_range(0..data.len())),
+                    4 => rng.fill(&mut data[PAGE_HEADER_SIZE as usize..]),
+                    _ => {}
+                }
+                fs::write(KEYSPACE, &data).unwrap();
+                for (i, (path, sidecar)) in sidecars.iter().enumerate() {
+                    let mut sidecar = sidecar.clone();
+                    // The bloom filter, blob file and B+tree take turns being corrupted
+                    if round % 6 == 5 && round / 6 % sidecars.len() == i && !sidecar.is_empty() {
+                        if rng.gen_bool(0.2) {
+                            sidecar.truncate(rng.gen_range(0..sidecar.len()));
+                        }
+                        for _ in 0..rng.gen_range(1..16) {
+                            if sidecar.is_empty() {
+                                break;
+                            }
+                            let position = rng.gen_range(0..sidecar.len());
+                            sidecar[position] = rng.gen();
+                        }
+                    }
+                    fs::write(path, sidecar).unwrap();
+                }
+
+                exercise_page(KEYSPACE, rng.gen_bool(0.5));
+            }
+        });
+    }
+
+    #[test]
+    fn test_checksums() {
+        const KEYSPACE: &str = "test_keyspace_checksums";
+        run_test(KEYSPACE, || {
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), 4096).unwrap();
+            kvmap.insert("albert", Value::Integer(1)).unwrap();
+            // Entries written before checksums existed are read without one
+            let (mut entry, index_keys) = kvmap.encode_entry("peter", Value::Integer(2)).unwrap();
+            entry.header.flags &= !FLAG_CHECKSUM;
+            kvmap.add_entry("peter", entry, index_keys).unwrap();
+            kvmap.insert("tom", Value::Integer(3)).unwrap();
+            drop(kvmap);
+
+            let mut kvmap = MemKvPage::new(Path::new(KEYSPACE), 4096).unwrap();
+            assert_eq!(kvmap.get("peter").unwrap(), Value::Integer(2));
+            assert_eq!(kvmap.get("tom").unwrap(), Value::Integer(3));
+            kvmap.delete("tom").unwrap();
+            kvmap.insert("tom", Value::Integer(4)).unwrap();
+
+            // A flipped bit in the value no longer decodes to a wrong value
+            let header = kvmap.read_header("albert").unwrap();
+            let offset = (header.get_absolute_data_offset() + header.key_size) as usize;
+            kvmap.mmap[offset + 7] ^= 0x1;
+            assert!(matches!(
+                kvmap.get("albert"),
+                Err(KvError::ChecksumMismatch { offset }) if offset == PAGE_HEADER_SIZE
+            ));
+            assert_eq!(kvmap.get("tom").unwrap(), Value::Integer(4));
         });
     }
 }
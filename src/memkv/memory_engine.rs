@@ -0,0 +1,69 @@
+use super::errors::KvError;
+use super::mem_kv_page::Value;
+use super::storage_engine::{StorageEngine, StorageStats};
+use std::collections::HashMap;
+
+/// Storage engine keeping all entries in a hash map, meant for testing the layers on top
+/// of `StorageEngine` without touching the disk
+pub struct MemoryEngine {
+    entries: HashMap<String, Value>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        return MemoryEngine {
+            entries: HashMap::new(),
+        };
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &str) -> Result<Value, KvError> {
+        return match self.entries.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            }),
+        };
+    }
+
+    fn put(&mut self, key: &str, value: Value) -> Result<(), KvError> {
+        self.entries.insert(String::from(key), value);
+        return Ok(());
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), KvError> {
+        return match self.entries.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(KvError::KeyDoesNotExist {
+                key: String::from(key),
+            }),
+        };
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+        let mut entries: Vec<(String, Value)> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        return Ok(entries);
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        return Ok(());
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        let mut size = 0;
+        for (key, value) in &self.entries {
+            size += (key.len() + value.clone().into_bytes()?.len()) as u64;
+        }
+        return Ok(StorageStats {
+            key_count: self.entries.len() as u64,
+            size,
+        });
+    }
+}
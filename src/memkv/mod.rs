@@ -1,4 +1,42 @@
+pub mod backup;
+pub mod blob_file;
+pub mod bloom_filter;
+pub mod btree_index;
+pub mod codec;
+pub mod compression;
+pub mod durability;
+pub mod encryption;
 pub mod errors;
+pub mod eviction;
+pub mod export;
+pub mod json_path;
+pub mod keyspace;
+pub mod lsm_tree;
 pub mod mem_kv_page;
+pub mod memory_engine;
+pub mod page_index;
+pub mod page_inspector;
+pub mod recovery;
+pub mod secondary_index;
+pub mod sstable;
+pub mod storage_engine;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod write_ahead_log;
+pub use codec::Codec;
+pub use compression::Compression;
+pub use durability::{DurabilityMode, GroupCommitPage};
+pub use encryption::Keyring;
+pub use errors::KvError;
+pub use eviction::{EvictionPolicy, EvictionStats};
+pub use keyspace::Keyspace;
+pub use lsm_tree::LsmTree;
+pub use mem_kv_page::LargeValueWriter;
 pub use mem_kv_page::MemKvPage;
 pub use mem_kv_page::Value;
+pub use mem_kv_page::KV_PAGE_SIZE;
+pub use memory_engine::MemoryEngine;
+pub use secondary_index::IndexKey;
+pub use storage_engine::{
+    open_storage_engine, StorageEngine, StorageEngineKind, StorageOptions, StorageStats,
+};
@@ -0,0 +1,99 @@
+use super::btree_index::BTreeIndex;
+use std::collections::HashMap;
+use std::io;
+
+/// Maps the keys of a page to the offsets of their entries, either in memory or in an
+/// on-disk B+tree which doesn't need to be rebuilt when the page is opened
+pub enum PageIndex {
+    Memory(HashMap<String, u64>),
+    BTree(BTreeIndex),
+}
+
+impl PageIndex {
+    pub fn get(self: &Self, key: &str) -> Result<Option<u64>, io::Error> {
+        return match self {
+            PageIndex::Memory(index) => Ok(index.get(key).copied()),
+            PageIndex::BTree(index) => index.get(key),
+        };
+    }
+
+    pub fn contains_key(self: &Self, key: &str) -> Result<bool, io::Error> {
+        return Ok(self.get(key)?.is_some());
+    }
+
+    pub fn insert(self: &mut Self, key: &str, offset: u64) -> Result<(), io::Error> {
+        match self {
+            PageIndex::Memory(index) => {
+                index.insert(String::from(key), offset);
+            }
+            PageIndex::BTree(index) => index.insert(key, offset)?,
+        }
+        return Ok(());
+    }
+
+    pub fn remove(self: &mut Self, key: &str) -> Result<Option<u64>, io::Error> {
+        return match self {
+            PageIndex::Memory(index) => Ok(index.remove(key)),
+            PageIndex::BTree(index) => index.remove(key),
+        };
+    }
+
+    /// Returns the keys starting with `prefix` and their offsets ordered by key
+    pub fn scan(self: &Self, prefix: &str) -> Result<Vec<(String, u64)>, io::Error> {
+        return match self {
+            PageIndex::Memory(index) => {
+                let mut entries: Vec<(String, u64)> = index
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, offset)| (key.clone(), *offset))
+                    .collect();
+                entries.sort();
+                Ok(entries)
+            }
+            PageIndex::BTree(index) => index.scan(prefix),
+        };
+    }
+
+    pub fn keys(self: &Self) -> Result<Vec<String>, io::Error> {
+        return match self {
+            PageIndex::Memory(index) => Ok(index.keys().cloned().collect()),
+            PageIndex::BTree(index) => {
+                Ok(index.scan("")?.into_iter().map(|(key, _)| key).collect())
+            }
+        };
+    }
+
+    pub fn offsets(self: &Self) -> Result<Vec<u64>, io::Error> {
+        return match self {
+            PageIndex::Memory(index) => Ok(index.values().copied().collect()),
+            PageIndex::BTree(index) => Ok(index
+                .scan("")?
+                .into_iter()
+                .map(|(_, offset)| offset)
+                .collect()),
+        };
+    }
+
+    pub fn len(self: &Self) -> usize {
+        return match self {
+            PageIndex::Memory(index) => index.len(),
+            PageIndex::BTree(index) => index.len(),
+        };
+    }
+
+    pub fn clear(self: &mut Self) -> Result<(), io::Error> {
+        match self {
+            PageIndex::Memory(index) => index.clear(),
+            PageIndex::BTree(index) => index.clear()?,
+        }
+        return Ok(());
+    }
+
+    /// Persists the index after the page entries up to `page_offset` were flushed
+    pub fn flush(self: &mut Self, page_offset: u64) -> Result<(), io::Error> {
+        return match self {
+            PageIndex::Memory(_) => Ok(()),
+            PageIndex::BTree(index) => index.flush(page_offset),
+        };
+    }
+}
@@ -0,0 +1,312 @@
+use super::blob_file::{self, BlobReference, FLAG_OVERFLOW};
+use super::compression::Compression;
+use super::encryption::{Keyring, FLAG_ENCRYPTED};
+use super::errors::KvError;
+use super::mem_kv_page::{
+    get_checksum_size, get_entry_checksum, get_sidecar_path, read_slice, read_u64, MemKvPage,
+    Value, ValueDataType, DEFAULT_LARGE_VALUE_THRESHOLD, ENTRY_CHECKSUM_SIZE, ENTRY_DELETED_FLAG,
+    ENTRY_HEADER_SIZE, FLAG_CHECKSUM, KV_PAGE_SIZE, PAGE_HEADER_SIZE,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str;
+
+/// Offline view of a page file. The file is read into memory and never written, so pages
+/// which cannot be opened anymore can still be inspected. Entries are checked against their
+/// checksum if they have one and by decoding their key and value.
+pub struct PageInspection {
+    pub path: PathBuf,
+    pub file_size: u64,
+    // Page size stored in the header, differs from `file_size` on damaged pages
+    pub header_page_size: u64,
+    pub entries: Vec<EntryInspection>,
+    // Ranges of bytes without readable entry headers, as offset and length
+    pub corrupted_ranges: Vec<(u64, u64)>,
+    // Offset behind the last entry, where new entries would be appended
+    pub end_offset: u64,
+}
+
+pub struct EntryInspection {
+    pub offset: u64,
+    pub data_type: ValueDataType,
+    pub flags: u8,
+    pub key_size: u64,
+    pub value_size: u64,
+    // `Err` if the checksum does not match or the key or the value could not be decoded
+    pub content: Result<(String, Value), KvError>,
+}
+
+impl EntryInspection {
+    pub fn is_deleted(self: &Self) -> bool {
+        return self.flags & ENTRY_DELETED_FLAG != 0x0;
+    }
+
+    pub fn has_checksum(self: &Self) -> bool {
+        return self.flags & FLAG_CHECKSUM != 0x0;
+    }
+
+    pub fn get_entry_size(self: &Self) -> u64 {
+        return ENTRY_HEADER_SIZE + self.key_size + self.value_size + get_checksum_size(self.flags);
+    }
+}
+
+pub struct Fragmentation {
+    pub deleted_entries: usize,
+    pub deleted_bytes: u64,
+    // Bytes taken by entries, deleted or not, excluding the page header
+    pub used_bytes: u64,
+    pub free_bytes: u64,
+}
+
+impl Fragmentation {
+    /// Share of the used bytes which `defrag` would reclaim
+    pub fn get_ratio(self: &Self) -> f64 {
+        if self.used_bytes == 0 {
+            return 0.0;
+        }
+        return self.deleted_bytes as f64 / self.used_bytes as f64;
+    }
+}
+
+impl PageInspection {
+    pub fn get_fragmentation(self: &Self) -> Fragmentation {
+        let deleted: Vec<&EntryInspection> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.is_deleted())
+            .collect();
+        return Fragmentation {
+            deleted_entries: deleted.len(),
+            deleted_bytes: deleted.iter().map(|entry| entry.get_entry_size()).sum(),
+            used_bytes: self.end_offset.saturating_sub(PAGE_HEADER_SIZE),
+            free_bytes: self.file_size.saturating_sub(self.end_offset),
+        };
+    }
+}
+
+/// Walks every entry of the page at `path`. Encrypted entries can only be checked with the
+/// keyring they were written with. Unreadable headers don't stop the walk, it continues at
+/// the next offset that holds a decodable entry.
+pub fn inspect_page(path: &Path, keyring: Option<&Keyring>) -> Result<PageInspection, KvError> {
+    let data = fs::read(path)?;
+    if (data.len() as u64) <= PAGE_HEADER_SIZE {
+        return Err(KvError::InvalidPageSize {
+            page_size: data.len() as u64,
+        });
+    }
+    let blob_path = get_sidecar_path(path, "blob");
+    let mut inspection = PageInspection {
+        path: PathBuf::from(path),
+        file_size: data.len() as u64,
+        header_page_size: read_u64(&data, 0)?,
+        entries: vec![],
+        corrupted_ranges: vec![],
+        end_offset: PAGE_HEADER_SIZE,
+    };
+
+    let mut offset = PAGE_HEADER_SIZE;
+    while offset + ENTRY_HEADER_SIZE <= inspection.file_size {
+        if let Ok(entry) = read_entry(&data, offset, keyring, &blob_path) {
+            offset += entry.get_entry_size();
+            inspection.end_offset = offset;
+            inspection.entries.push(entry);
+            continue;
+        }
+        // The zeroed space after the last entry does not hold a valid data type
+        if data[offset as usize..].iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let next_offset = (offset + 1..inspection.file_size)
+            .find(|next_offset| {
+                read_entry(&data, *next_offset, keyring, &blob_path)
+                    .is_ok_and(|entry| entry.content.is_ok())
+            })
+            .unwrap_or(inspection.file_size);
+        inspection
+            .corrupted_ranges
+            .push((offset, next_offset - offset));
+        offset = next_offset;
+        inspection.end_offset = offset;
+    }
+    return Ok(inspection);
+}
+
+// Fails only if the header itself is invalid, decoding errors are kept in the entry
+fn read_entry(
+    data: &[u8],
+    offset: u64,
+    keyring: Option<&Keyring>,
+    blob_path: &Path,
+) -> Result<EntryInspection, KvError> {
+    let data_type: ValueDataType = read_slice(data, offset, 1)?[0].try_into()?;
+    let flags = read_slice(data, offset + 1, 1)?[0];
+    let key_size = read_u64(data, offset + 2)?;
+    let value_size = read_u64(data, offset + 10)?;
+    let key_offset = offset + ENTRY_HEADER_SIZE;
+    let key_data = read_slice(data, key_offset, key_size)?;
+    let value_data = read_slice(data, key_offset + key_size, value_size)?;
+    let content = match flags & FLAG_CHECKSUM != 0x0 {
+        true => {
+            let checksum = read_slice(
+                data,
+                key_offset + key_size + value_size,
+                ENTRY_CHECKSUM_SIZE,
+            )?;
+            match get_entry_checksum(data_type, flags, key_data, value_data) == checksum {
+                true => Ok(()),
+                false => Err(KvError::ChecksumMismatch { offset }),
+            }
+        }
+        false => Ok(()),
+    };
+    return Ok(EntryInspection {
+        offset,
+        data_type,
+        flags,
+        key_size,
+        value_size,
+        // Pages don't record their large value threshold, so only the default one is known
+        content: content.and_then(|_| {
+            decode_entry(
+                data_type,
+                flags,
+                key_data,
+                value_data,
+                keyring,
+                blob_path,
+                DEFAULT_LARGE_VALUE_THRESHOLD.max(data.len()),
+            )
+        }),
+    });
+}
+
+// Mirrors `MemKvPage::read_key` and `MemKvPage::read_value`
+fn decode_entry(
+    data_type: ValueDataType,
+    flags: u8,
+    key_data: &[u8],
+    value_data: &[u8],
+    keyring: Option<&Keyring>,
+    blob_path: &Path,
+    max_value_size: usize,
+) -> Result<(String, Value), KvError> {
+    let mut key_data = key_data.to_vec();
+    let mut value_data = value_data.to_vec();
+    if flags & FLAG_ENCRYPTED != 0x0 {
+        let keyring = keyring.ok_or(KvError::EncryptionKeyNotFound { key_id: None })?;
+        key_data = keyring.decrypt(&key_data, &[])?;
+        value_data = keyring.decrypt(&value_data, &key_data)?;
+    }
+    let key = String::from(str::from_utf8(&key_data)?);
+    if flags & FLAG_OVERFLOW != 0x0 {
+        let reference = BlobReference::from_bytes(&value_data)?;
+        value_data = blob_file::read(blob_path, &reference)?;
+    }
+    let value_data = Compression::from_flags(flags).decompress(&value_data, max_value_size)?;
+    return Ok((key, Value::from_bytes(data_type, &value_data)?));
+}
+
+pub struct SalvageReport {
+    pub salvaged_entries: usize,
+    // Live entries that could not be decoded
+    pub lost_entries: usize,
+}
+
+/// Copies every readable live entry into a new page at `target`. Values are written with
+/// `keyring` if given, so encrypted pages stay encrypted.
+pub fn salvage_page(
+    inspection: &PageInspection,
+    target: &Path,
+    keyring: Option<Keyring>,
+) -> Result<SalvageReport, KvError> {
+    if target.exists() {
+        return Err(KvError::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("salvage target {:?} already exists", target),
+        )));
+    }
+    let page_size = inspection.file_size.max(KV_PAGE_SIZE);
+    let mut page = match keyring {
+        Some(keyring) => MemKvPage::new_with_keyring(target, page_size, keyring)?,
+        None => MemKvPage::new(target, page_size)?,
+    };
+    page.set_max_page_size(u64::MAX);
+    let mut report = SalvageReport {
+        salvaged_entries: 0,
+        lost_entries: 0,
+    };
+    for entry in inspection
+        .entries
+        .iter()
+        .filter(|entry| !entry.is_deleted())
+    {
+        match &entry.content {
+            Ok((key, value)) => {
+                page.upsert(key, value.clone())?;
+                report.salvaged_entries += 1;
+            }
+            Err(_) => report.lost_entries += 1,
+        }
+    }
+    page.sync_all()?;
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inspect_page, salvage_page};
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{MemKvPage, Value};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_inspect_and_salvage() {
+        const PAGE: &str = "test_inspect_page";
+        const SALVAGED: &str = "test_inspect_page_salvaged";
+        let _page_files = PageFilesGuard::new(Path::new(PAGE)).unwrap();
+        let _salvaged_files = PageFilesGuard::new(Path::new(SALVAGED)).unwrap();
+        let mut page = MemKvPage::new(Path::new(PAGE), 4096).unwrap();
+        for i in 0..5 {
+            page.insert(&format!("key-{}", i), Value::Integer(i))
+                .unwrap();
+        }
+        page.delete("key-1").unwrap();
+        page.sync_all().unwrap();
+        drop(page);
+
+        let inspection = inspect_page(Path::new(PAGE), None).unwrap();
+        assert_eq!(inspection.header_page_size, 4096);
+        assert_eq!(inspection.entries.len(), 5);
+        assert!(inspection.corrupted_ranges.is_empty());
+        let fragmentation = inspection.get_fragmentation();
+        assert_eq!(fragmentation.deleted_entries, 1);
+        assert!((fragmentation.get_ratio() - 0.2).abs() < 0.01);
+
+        // Break the header of "key-2" and turn the value of "key-4" into invalid utf-8
+        let mut data = fs::read(PAGE).unwrap();
+        let offsets: Vec<usize> = inspection
+            .entries
+            .iter()
+            .map(|entry| entry.offset as usize)
+            .collect();
+        data[offsets[2]] = 0x7f;
+        data[offsets[4]] = 0x1;
+        data[offsets[4] + 18 + 5..offsets[4] + 18 + 5 + 8].copy_from_slice(&[0xff; 8]);
+        fs::write(PAGE, data).unwrap();
+
+        let inspection = inspect_page(Path::new(PAGE), None).unwrap();
+        assert_eq!(
+            inspection.corrupted_ranges,
+            vec![(offsets[2] as u64, (offsets[3] - offsets[2]) as u64)]
+        );
+        assert!(inspection.entries.last().unwrap().content.is_err());
+        let report = salvage_page(&inspection, Path::new(SALVAGED), None).unwrap();
+        assert_eq!(report.salvaged_entries, 2);
+        assert_eq!(report.lost_entries, 1);
+        let salvaged = MemKvPage::new(Path::new(SALVAGED), 4096).unwrap();
+        assert_eq!(salvaged.get("key-3").unwrap(), Value::Integer(3));
+        assert!(salvage_page(&inspection, Path::new(SALVAGED), None).is_err());
+        drop(salvaged);
+    }
+}
@@ -0,0 +1,262 @@
+use super::backup::{self, BackupManifest};
+use super::errors::KvError;
+use super::storage_engine::{open_storage_engine, StorageEngineKind, StorageOptions};
+use super::write_ahead_log::{
+    get_segment_path, list_segments, read_segment, write_segment, WalPosition, WalRecord,
+};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SUPERSEDED_DIRECTORY_PREFIX: &str = "superseded-";
+
+/// Last write to recover, later writes are discarded
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecoveryTarget {
+    Sequence(u64),
+    // Milliseconds since the unix epoch
+    Timestamp(u64),
+}
+
+impl RecoveryTarget {
+    pub fn includes(self: &Self, position: &WalPosition) -> bool {
+        return match self {
+            RecoveryTarget::Sequence(sequence) => position.sequence <= *sequence,
+            RecoveryTarget::Timestamp(timestamp_ms) => position.timestamp_ms <= *timestamp_ms,
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RecoveryReport {
+    pub backup_sequence: u64,
+    pub replayed_records: usize,
+    // Last write of the recovered state
+    pub position: WalPosition,
+    // Where the archived records after the target were moved, if there were any
+    pub superseded_directory: Option<PathBuf>,
+}
+
+// Latest backup which does not contain writes after the target
+fn find_base_backup(
+    backup_root: &Path,
+    target: RecoveryTarget,
+) -> Result<(BackupManifest, WalPosition), KvError> {
+    let base = backup::list_backups(backup_root)?
+        .into_iter()
+        .rev()
+        .find_map(|manifest| match manifest.wal_position {
+            Some(position) if target.includes(&position) => Some((manifest, position)),
+            _ => None,
+        });
+    return base.ok_or_else(|| KvError::InvalidBackup {
+        path: PathBuf::from(backup_root),
+        reason: format!(
+            "no backup with a write ahead log position before {:?}",
+            target
+        ),
+    });
+}
+
+// Archived records after `position` up to the target, fails if the chain has a gap
+fn read_records(
+    archive_directory: &Path,
+    segments: &[PathBuf],
+    mut position: WalPosition,
+    target: RecoveryTarget,
+) -> Result<Vec<WalRecord>, KvError> {
+    let mut records = vec![];
+    for segment in segments {
+        for record in read_segment(segment)? {
+            if record.position.sequence <= position.sequence {
+                continue;
+            }
+            if !target.includes(&record.position) {
+                return Ok(records);
+            }
+            if record.position.sequence != position.sequence + 1 {
+                return Err(KvError::InvalidBackup {
+                    path: PathBuf::from(archive_directory),
+                    reason: format!(
+                        "archived log is missing the records after sequence {}",
+                        position.sequence
+                    ),
+                });
+            }
+            position = record.position;
+            records.push(record);
+        }
+    }
+    return Ok(records);
+}
+
+/// Restores the latest backup before `target` into the stopped engine at `path` and replays
+/// the archived log up to the target. Segments still in the log directory must be archived
+/// first, see `write_ahead_log::archive_segments`. The archive is checked before anything
+/// is restored. Archived records after the target are moved aside, so the log can continue
+/// from the recovered state.
+pub fn recover(
+    backup_root: &Path,
+    archive_directory: &Path,
+    target: RecoveryTarget,
+    kind: StorageEngineKind,
+    path: &Path,
+    options: StorageOptions,
+) -> Result<RecoveryReport, KvError> {
+    let (base, mut position) = find_base_backup(backup_root, target)?;
+    let segments = list_segments(archive_directory)?;
+    let records = read_records(archive_directory, &segments, position, target)?;
+    backup::restore_backup(backup_root, Some(base.sequence), kind, path)?;
+
+    let mut engine = open_storage_engine(kind, path, options)?;
+    for record in &records {
+        record.apply(engine.as_mut())?;
+        position = record.position;
+    }
+    engine.flush()?;
+    drop(engine);
+    info!(
+        "Recovered backup {} and {} logged writes up to sequence {}",
+        base.sequence,
+        records.len(),
+        position.sequence
+    );
+
+    return Ok(RecoveryReport {
+        backup_sequence: base.sequence,
+        replayed_records: records.len(),
+        position,
+        superseded_directory: set_aside(archive_directory, &segments, position.sequence)?,
+    });
+}
+
+// Moves the records after `sequence` into a new directory of the archive, a segment which
+// holds records on both sides is split
+fn set_aside(
+    archive_directory: &Path,
+    segments: &[PathBuf],
+    sequence: u64,
+) -> Result<Option<PathBuf>, KvError> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let superseded_directory =
+        archive_directory.join(format!("{}{}", SUPERSEDED_DIRECTORY_PREFIX, created_at));
+    let mut is_superseded = false;
+    for segment in segments {
+        let records = read_segment(segment)?;
+        let split = records
+            .iter()
+            .position(|record| record.position.sequence > sequence);
+        let superseded = match split {
+            Some(split) => &records[split..],
+            None => continue,
+        };
+        fs::create_dir_all(&superseded_directory)?;
+        is_superseded = true;
+        write_segment(
+            &get_segment_path(&superseded_directory, superseded[0].position.sequence),
+            superseded,
+        )?;
+        match split {
+            Some(0) => fs::remove_file(segment)?,
+            Some(split) => {
+                let temporary_path = segment.with_extension("tmp");
+                write_segment(&temporary_path, &records[..split])?;
+                fs::rename(&temporary_path, segment)?;
+            }
+            None => {}
+        }
+    }
+    return Ok(match is_superseded {
+        true => Some(superseded_directory),
+        false => None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recover, RecoveryTarget};
+    use crate::memkv::backup::create_backup;
+    use crate::memkv::write_ahead_log::{
+        archive_segments, list_segments, LoggedEngine, WriteAheadLog,
+    };
+    use crate::memkv::{
+        open_storage_engine, StorageEngine, StorageEngineKind, StorageOptions, Value,
+    };
+    use std::fs;
+    use std::path::Path;
+
+    const DIRECTORY: &str = "test_recovery";
+
+    fn open_engine(directory: &Path) -> LoggedEngine {
+        let engine = open_storage_engine(
+            StorageEngineKind::Lsm,
+            &directory.join("lsm"),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        let mut log =
+            WriteAheadLog::open(&directory.join("wal"), &directory.join("archive")).unwrap();
+        log.set_max_segment_size(128);
+        return LoggedEngine::new(engine, log);
+    }
+
+    #[test]
+    fn test_point_in_time_recovery() {
+        let directory = Path::new(DIRECTORY);
+        if directory.exists() {
+            fs::remove_dir_all(directory).unwrap();
+        }
+        let backups = directory.join("backups");
+        let archive = directory.join("archive");
+        let mut engine = open_engine(directory);
+        engine.put("a", Value::Integer(1)).unwrap();
+        let manifest = create_backup(&mut engine, &backups, false).unwrap();
+        assert_eq!(manifest.wal_position.unwrap().sequence, 1);
+        for i in 2..=10 {
+            engine.put("a", Value::Integer(i)).unwrap();
+        }
+        engine.delete("a").unwrap();
+        drop(engine);
+        archive_segments(&directory.join("wal"), &archive).unwrap();
+
+        let recover_to = |target| {
+            recover(
+                &backups,
+                &archive,
+                target,
+                StorageEngineKind::Lsm,
+                &directory.join("lsm"),
+                StorageOptions::default(),
+            )
+        };
+        assert!(recover_to(RecoveryTarget::Timestamp(0)).is_err());
+        let report = recover_to(RecoveryTarget::Sequence(7)).unwrap();
+        assert_eq!((report.backup_sequence, report.replayed_records), (1, 6));
+        assert!(report.superseded_directory.unwrap().exists());
+
+        // The log continues after the recovered write
+        let mut engine = open_engine(directory);
+        assert_eq!(engine.get("a").unwrap(), Value::Integer(7));
+        engine.put("b", Value::Integer(1)).unwrap();
+        drop(engine);
+        archive_segments(&directory.join("wal"), &archive).unwrap();
+        let report = recover_to(RecoveryTarget::Sequence(u64::MAX)).unwrap();
+        assert_eq!(report.position.sequence, 8);
+        assert!(report.superseded_directory.is_none());
+        let engine = open_engine(directory);
+        assert_eq!(engine.get("b").unwrap(), Value::Integer(1));
+        drop(engine);
+
+        // A gap in the archive fails before the live data is replaced
+        fs::remove_file(&list_segments(&archive).unwrap()[1]).unwrap();
+        assert!(recover_to(RecoveryTarget::Sequence(u64::MAX)).is_err());
+        let engine = open_engine(directory);
+        assert_eq!(engine.get("b").unwrap(), Value::Integer(1));
+        drop(engine);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use super::codec::Codec;
+use super::json_path;
+use super::json_path::JsonPathSegment;
+use super::mem_kv_page::Value;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeBounds;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IndexKey {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+}
+
+impl IndexKey {
+    // Floats, arrays, objects and nulls are not indexed
+    fn from_json(value: &JsonValue) -> Option<IndexKey> {
+        return match value {
+            JsonValue::Bool(flag) => Some(IndexKey::Bool(*flag)),
+            JsonValue::Number(number) => number.as_i64().map(IndexKey::Integer),
+            JsonValue::String(text) => Some(IndexKey::String(text.clone())),
+            _ => None,
+        };
+    }
+}
+
+impl From<bool> for IndexKey {
+    fn from(flag: bool) -> Self {
+        return IndexKey::Bool(flag);
+    }
+}
+
+impl From<i64> for IndexKey {
+    fn from(number: i64) -> Self {
+        return IndexKey::Integer(number);
+    }
+}
+
+impl From<&str> for IndexKey {
+    fn from(text: &str) -> Self {
+        return IndexKey::String(String::from(text));
+    }
+}
+
+impl From<String> for IndexKey {
+    fn from(text: String) -> Self {
+        return IndexKey::String(text);
+    }
+}
+
+pub type IndexExtractorFn = dyn Fn(&Value) -> Option<IndexKey> + Send + Sync;
+
+pub enum IndexExtractor {
+    // The path as written is kept so the index can be persisted
+    JsonPath(String, Vec<JsonPathSegment>),
+    Function(Box<IndexExtractorFn>),
+}
+
+impl IndexExtractor {
+    fn extract(self: &Self, value: &Value) -> Option<IndexKey> {
+        return match self {
+            IndexExtractor::JsonPath(_, segments) => {
+                let document = match value {
+                    Value::Json(document) => document.clone(),
+                    Value::Typed(Codec::Json, data) | Value::Blob(data) => {
+                        serde_json::from_slice(data).ok()?
+                    }
+                    _ => return None,
+                };
+                IndexKey::from_json(json_path::get(&document, segments)?)
+            }
+            IndexExtractor::Function(extractor) => extractor(value),
+        };
+    }
+}
+
+// Maps each indexed field value to the set of primary keys holding it
+pub struct SecondaryIndex {
+    extractor: IndexExtractor,
+    entries: BTreeMap<IndexKey, BTreeSet<String>>,
+}
+
+impl SecondaryIndex {
+    pub fn new(extractor: IndexExtractor) -> SecondaryIndex {
+        return SecondaryIndex {
+            extractor,
+            entries: BTreeMap::new(),
+        };
+    }
+
+    pub fn extract(self: &Self, value: &Value) -> Option<IndexKey> {
+        return self.extractor.extract(value);
+    }
+
+    /// Json path of the indexed field, `None` for indexes over an extractor function
+    pub fn get_json_path(self: &Self) -> Option<&str> {
+        return match &self.extractor {
+            IndexExtractor::JsonPath(path, _) => Some(path),
+            IndexExtractor::Function(_) => None,
+        };
+    }
+
+    pub fn add(self: &mut Self, index_key: IndexKey, key: &str) {
+        self.entries
+            .entry(index_key)
+            .or_default()
+            .insert(String::from(key));
+    }
+
+    pub fn remove(self: &mut Self, index_key: &IndexKey, key: &str) {
+        if let Some(keys) = self.entries.get_mut(index_key) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.entries.remove(index_key);
+            }
+        }
+    }
+
+    pub fn find(self: &Self, index_key: &IndexKey) -> Vec<String> {
+        return match self.entries.get(index_key) {
+            Some(keys) => keys.iter().cloned().collect(),
+            None => vec![],
+        };
+    }
+
+    pub fn find_range<R: RangeBounds<IndexKey>>(self: &Self, range: R) -> Vec<String> {
+        return self
+            .entries
+            .range(range)
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect();
+    }
+}
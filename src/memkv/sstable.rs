@@ -0,0 +1,347 @@
+use super::errors::KvError;
+use super::mem_kv_page::{Value, ValueDataType};
+use memmap::Mmap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+// Records use the same header as page entries: data type | flags | key size | value size
+const RECORD_HEADER_SIZE: usize = size_of::<u8>() * 2 + size_of::<u64>() * 2;
+const TOMBSTONE_FLAG: u8 = 0x1;
+// Every n-th record of a table is kept in the sparse index
+const SPARSE_INDEX_INTERVAL: u64 = 16;
+// Footer holding the end of the data section, the number of index entries and records
+const FOOTER_SIZE: usize = size_of::<u64>() * 3;
+
+/// A key with its value, or `None` for a tombstone shadowing older values of the key
+pub type Record = (String, Option<Value>);
+
+pub fn encode_record(key: &str, value: Option<&Value>) -> Result<Vec<u8>, KvError> {
+    let (data_type, flags, value_bytes) = match value {
+        Some(value) => (value.get_data_type(), 0x0, value.clone().into_bytes()?),
+        None => (ValueDataType::Blob, TOMBSTONE_FLAG, vec![]),
+    };
+    return Ok([
+        vec![data_type as u8, flags],
+        (key.len() as u64).to_be_bytes().to_vec(),
+        (value_bytes.len() as u64).to_be_bytes().to_vec(),
+        key.as_bytes().to_vec(),
+        value_bytes,
+    ]
+    .concat());
+}
+
+/// Decodes the record at the start of `data` and returns it with its encoded size, or
+/// `None` if `data` ends before the record does
+pub fn decode_record(data: &[u8]) -> Result<Option<(Record, usize)>, KvError> {
+    if data.len() < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+    let data_type: ValueDataType = data[0].try_into()?;
+    let flags = data[1];
+    let key_size = read_u64(data, 2) as usize;
+    let value_size = read_u64(data, 2 + size_of::<u64>()) as usize;
+    let record_size = match RECORD_HEADER_SIZE
+        .checked_add(key_size)
+        .and_then(|size| size.checked_add(value_size))
+    {
+        Some(record_size) if record_size <= data.len() => record_size,
+        _ => return Ok(None),
+    };
+
+    let key_end = RECORD_HEADER_SIZE + key_size;
+    let key = String::from(std::str::from_utf8(&data[RECORD_HEADER_SIZE..key_end])?);
+    let value = if flags & TOMBSTONE_FLAG != 0x0 {
+        None
+    } else {
+        Some(Value::from_bytes(data_type, &data[key_end..record_size])?)
+    };
+    return Ok(Some(((key, value), record_size)));
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    return u64::from_be_bytes(data[offset..offset + size_of::<u64>()].try_into().unwrap());
+}
+
+fn read_sized_key(path: &Path, data: &[u8], position: &mut usize) -> Result<String, KvError> {
+    if *position + size_of::<u64>() > data.len() {
+        return Err(KvError::CorruptedTable {
+            path: PathBuf::from(path),
+        });
+    }
+    let key_size = read_u64(data, *position) as usize;
+    *position += size_of::<u64>();
+    if key_size > data.len() - *position {
+        return Err(KvError::CorruptedTable {
+            path: PathBuf::from(path),
+        });
+    }
+    let key = String::from(std::str::from_utf8(&data[*position..*position + key_size])?);
+    *position += key_size;
+    return Ok(key);
+}
+
+/// Builds a sorted table in memory, records have to be added in key order
+pub struct SsTableWriter {
+    path: PathBuf,
+    data: Vec<u8>,
+    index: Vec<(String, u64)>,
+    entry_count: u64,
+    last_key: String,
+}
+
+impl SsTableWriter {
+    pub fn new(path: &Path) -> SsTableWriter {
+        return SsTableWriter {
+            path: PathBuf::from(path),
+            data: Vec::new(),
+            index: Vec::new(),
+            entry_count: 0,
+            last_key: String::new(),
+        };
+    }
+
+    pub fn add(self: &mut Self, key: &str, value: Option<&Value>) -> Result<(), KvError> {
+        if self.entry_count.is_multiple_of(SPARSE_INDEX_INTERVAL) {
+            self.index.push((String::from(key), self.data.len() as u64));
+        }
+        self.data.extend(encode_record(key, value)?);
+        self.entry_count += 1;
+        self.last_key = String::from(key);
+        return Ok(());
+    }
+
+    pub fn get_size(self: &Self) -> u64 {
+        return self.data.len() as u64;
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.entry_count == 0;
+    }
+
+    // The table is written to a temporary file first so a crash never leaves a partial
+    // table behind under its final name
+    pub fn finish(self: Self, level: usize, sequence: u64) -> Result<SsTable, KvError> {
+        let mut contents = self.data;
+        let data_end = contents.len() as u64;
+        for (key, offset) in &self.index {
+            contents.extend((key.len() as u64).to_be_bytes());
+            contents.extend(key.as_bytes());
+            contents.extend(offset.to_be_bytes());
+        }
+        contents.extend((self.last_key.len() as u64).to_be_bytes());
+        contents.extend(self.last_key.as_bytes());
+        contents.extend(data_end.to_be_bytes());
+        contents.extend((self.index.len() as u64).to_be_bytes());
+        contents.extend(self.entry_count.to_be_bytes());
+
+        let mut temporary_path = self.path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary_path)?;
+        f.write_all(&contents)?;
+        f.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+
+        return SsTable::open(&self.path, level, sequence);
+    }
+}
+
+/// Immutable sorted table file, memory mapped for reads. Only the sparse index is kept
+/// in memory, a lookup scans at most `SPARSE_INDEX_INTERVAL` records.
+pub struct SsTable {
+    path: PathBuf,
+    mmap: Mmap,
+    level: usize,
+    sequence: u64,
+    index: Vec<(String, usize)>,
+    data_end: usize,
+    max_key: String,
+    entry_count: u64,
+}
+
+impl SsTable {
+    pub fn open(path: &Path, level: usize, sequence: u64) -> Result<SsTable, KvError> {
+        let f = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&f)? };
+        if mmap.len() < FOOTER_SIZE {
+            return Err(KvError::CorruptedTable {
+                path: PathBuf::from(path),
+            });
+        }
+        let footer = mmap.len() - FOOTER_SIZE;
+        let data_end = read_u64(&mmap, footer) as usize;
+        let index_count = read_u64(&mmap, footer + size_of::<u64>());
+        let entry_count = read_u64(&mmap, footer + size_of::<u64>() * 2);
+        if data_end > footer || index_count == 0 {
+            return Err(KvError::CorruptedTable {
+                path: PathBuf::from(path),
+            });
+        }
+
+        let mut position = data_end;
+        let mut index = Vec::new();
+        for _ in 0..index_count {
+            let key = read_sized_key(path, &mmap[..footer], &mut position)?;
+            if position + size_of::<u64>() > footer {
+                return Err(KvError::CorruptedTable {
+                    path: PathBuf::from(path),
+                });
+            }
+            let offset = read_u64(&mmap, position) as usize;
+            position += size_of::<u64>();
+            if offset >= data_end {
+                return Err(KvError::CorruptedTable {
+                    path: PathBuf::from(path),
+                });
+            }
+            index.push((key, offset));
+        }
+        let max_key = read_sized_key(path, &mmap[..footer], &mut position)?;
+
+        return Ok(SsTable {
+            path: PathBuf::from(path),
+            mmap,
+            level,
+            sequence,
+            index,
+            data_end,
+            max_key,
+            entry_count,
+        });
+    }
+
+    /// Returns `None` if the table knows nothing about `key` and `Some(None)` if the key
+    /// was deleted
+    pub fn get(self: &Self, key: &str) -> Result<Option<Option<Value>>, KvError> {
+        if key < self.get_min_key() || key > self.get_max_key() {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|(index_key, _)| index_key.as_str() <= key)
+            - 1;
+        let block_end = match self.index.get(block + 1) {
+            Some((_, offset)) => *offset,
+            None => self.data_end,
+        };
+
+        let mut offset = self.index[block].1;
+        while offset < block_end {
+            let ((record_key, value), record_size) =
+                match decode_record(&self.mmap[offset..block_end])? {
+                    Some(record) => record,
+                    None => {
+                        return Err(KvError::CorruptedTable {
+                            path: self.path.clone(),
+                        })
+                    }
+                };
+            if record_key == key {
+                return Ok(Some(value));
+            }
+            if record_key.as_str() > key {
+                break;
+            }
+            offset += record_size;
+        }
+        return Ok(None);
+    }
+
+    pub fn records(self: &Self) -> SsTableRecords<'_> {
+        return SsTableRecords {
+            table: self,
+            offset: 0,
+        };
+    }
+
+    /// Records from the first key which is not smaller than `start`, reading starts at the
+    /// block of the sparse index which can hold it
+    pub fn records_from<'a>(
+        self: &'a Self,
+        start: &'a str,
+    ) -> impl Iterator<Item = Result<Record, KvError>> + 'a {
+        let block = self
+            .index
+            .partition_point(|(index_key, _)| index_key.as_str() <= start)
+            .saturating_sub(1);
+        let records = SsTableRecords {
+            table: self,
+            offset: self.index[block].1,
+        };
+        return records.skip_while(move |record| {
+            return matches!(record, Ok((key, _)) if key.as_str() < start);
+        });
+    }
+
+    pub fn get_min_key(self: &Self) -> &str {
+        return &self.index[0].0;
+    }
+
+    pub fn get_max_key(self: &Self) -> &str {
+        return &self.max_key;
+    }
+
+    pub fn overlaps(self: &Self, min_key: &str, max_key: &str) -> bool {
+        return self.get_min_key() <= max_key && min_key <= self.get_max_key();
+    }
+
+    pub fn get_level(self: &Self) -> usize {
+        return self.level;
+    }
+
+    pub fn get_sequence(self: &Self) -> u64 {
+        return self.sequence;
+    }
+
+    pub fn get_entry_count(self: &Self) -> u64 {
+        return self.entry_count;
+    }
+
+    pub fn get_size(self: &Self) -> u64 {
+        return self.mmap.len() as u64;
+    }
+
+    pub fn remove(self: Self) -> Result<(), io::Error> {
+        let path = self.path.clone();
+        drop(self);
+        return fs::remove_file(path);
+    }
+}
+
+pub struct SsTableRecords<'a> {
+    table: &'a SsTable,
+    offset: usize,
+}
+
+impl<'a> Iterator for SsTableRecords<'a> {
+    type Item = Result<Record, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.table.data_end {
+            return None;
+        }
+        return match decode_record(&self.table.mmap[self.offset..self.table.data_end]) {
+            Ok(Some((record, record_size))) => {
+                self.offset += record_size;
+                Some(Ok(record))
+            }
+            Ok(None) => {
+                self.offset = self.table.data_end;
+                Some(Err(KvError::CorruptedTable {
+                    path: self.table.path.clone(),
+                }))
+            }
+            Err(error) => {
+                self.offset = self.table.data_end;
+                Some(Err(error))
+            }
+        };
+    }
+}
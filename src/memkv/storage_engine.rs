@@ -0,0 +1,522 @@
+use super::backup::{Checkpoint, CheckpointFile};
+use super::compression::Compression;
+use super::durability::DurabilityMode;
+use super::encryption::Keyring;
+use super::errors::KvError;
+use super::eviction::EvictionPolicy;
+use super::lsm_tree::LsmTree;
+use super::mem_kv_page::{MemKvPage, Value, DEFAULT_COMPRESSION_THRESHOLD, KV_PAGE_SIZE};
+use super::memory_engine::MemoryEngine;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_SYNC_INTERVAL_MS: u64 = 1000;
+
+/// Common interface of the storage backends, all of them store the same `Value` model
+pub trait StorageEngine: Send {
+    fn get(&self, key: &str) -> Result<Value, KvError>;
+
+    /// Inserts `key` or replaces its current value
+    fn put(&mut self, key: &str, value: Value) -> Result<(), KvError>;
+
+    fn delete(&mut self, key: &str) -> Result<(), KvError>;
+
+    /// Returns all entries whose key starts with `prefix`, ordered by key
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>, KvError>;
+
+    /// Returns at most `limit` entries whose key starts with `prefix` and comes after
+    /// `after`, ordered by key. Large engines are walked batch by batch with it. The default
+    /// scans the whole prefix for every batch, so a full walk is quadratic in the key count.
+    fn scan_after(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>, KvError> {
+        return Ok(self
+            .scan(prefix)?
+            .into_iter()
+            .filter(|(key, _)| after.is_none_or(|after| key.as_str() > after))
+            .take(limit)
+            .collect());
+    }
+
+    /// Makes all writes so far durable
+    fn flush(&mut self) -> Result<(), KvError>;
+
+    fn stats(&self) -> Result<StorageStats, KvError>;
+
+    /// Sets the time to live of `key`, `None` makes the key persistent again
+    fn expire(&mut self, key: &str, _ttl: Option<Duration>) -> Result<(), KvError> {
+        self.get(key)?;
+        return Err(KvError::UnsupportedOperation {
+            operation: String::from("expire"),
+        });
+    }
+
+    /// Whether `expire` is supported, writes with a time to live check it before storing
+    fn supports_ttl(&self) -> bool {
+        return false;
+    }
+
+    /// Returns the remaining time to live of `key`, engines without expiry never set one
+    fn get_ttl(&self, key: &str) -> Result<Option<Duration>, KvError> {
+        self.get(key)?;
+        return Ok(None);
+    }
+
+    /// Makes all writes durable and freezes the files holding the data in `directory`, where
+    /// later writes don't change them. The engine is locked meanwhile on a running server, so
+    /// files are only linked or snapshotted, see `backup::start_backup`.
+    fn checkpoint(&mut self, _directory: &Path) -> Result<Checkpoint, KvError> {
+        return Err(KvError::UnsupportedOperation {
+            operation: String::from("checkpoint"),
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StorageStats {
+    pub key_count: u64,
+    // Bytes used by the engine, which may include overwritten and deleted entries
+    pub size: u64,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum StorageEngineKind {
+    // Single memory mapped page, see `MemKvPage`
+    Page,
+    // Memtable with sorted table files in a directory, see `LsmTree`
+    Lsm,
+    // Plain hash map without persistence, see `MemoryEngine`
+    Memory,
+}
+
+impl FromStr for StorageEngineKind {
+    type Err = KvError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        return match name {
+            "page" => Ok(StorageEngineKind::Page),
+            "lsm" => Ok(StorageEngineKind::Lsm),
+            "memory" => Ok(StorageEngineKind::Memory),
+            _ => Err(KvError::InvalidStorageEngine {
+                name: String::from(name),
+            }),
+        };
+    }
+}
+
+impl StorageEngineKind {
+    pub fn get_name(self: &Self) -> &'static str {
+        return match self {
+            StorageEngineKind::Page => "page",
+            StorageEngineKind::Lsm => "lsm",
+            StorageEngineKind::Memory => "memory",
+        };
+    }
+
+    /// Reads the `storage.engine` setting, defaulting to the page engine
+    pub fn from_config(settings: &config::Config) -> Result<StorageEngineKind, KvError> {
+        return match settings.get_string("storage.engine") {
+            Ok(name) => Ok(name.parse()?),
+            Err(config::ConfigError::NotFound(_)) => Ok(StorageEngineKind::Page),
+            Err(error) => Err(error.into()),
+        };
+    }
+}
+
+/// Settings of the page engine, the other engines ignore them
+pub struct StorageOptions {
+    pub page_size: u64,
+    pub max_page_size: Option<u64>,
+    pub compression: Compression,
+    pub compression_threshold: usize,
+    pub durability: DurabilityMode,
+    // Policy and budget in bytes, see `MemKvPage::set_eviction`
+    pub eviction: Option<(EvictionPolicy, u64)>,
+    pub keyring: Option<Keyring>,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        return StorageOptions {
+            page_size: KV_PAGE_SIZE,
+            max_page_size: None,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            durability: DurabilityMode::Always,
+            eviction: None,
+            keyring: None,
+        };
+    }
+}
+
+impl StorageOptions {
+    /// Reads the `storage` settings and the keyfile of `encryption.keyfile`, missing
+    /// settings keep their defaults
+    pub fn from_config(settings: &config::Config) -> Result<StorageOptions, KvError> {
+        let mut options = StorageOptions::default();
+        if let Some(page_size) = get_optional(settings, "storage.page_size")? {
+            options.page_size = page_size;
+        }
+        options.max_page_size = get_optional(settings, "storage.max_page_size")?;
+        if let Some(name) = get_optional::<String>(settings, "storage.compression")? {
+            options.compression = match name.as_str() {
+                "none" => Compression::None,
+                "lz4" => Compression::Lz4,
+                "zstd" => Compression::Zstd,
+                _ => return Err(invalid_setting("storage.compression", name)),
+            };
+        }
+        if let Some(threshold) = get_optional(settings, "storage.compression_threshold")? {
+            options.compression_threshold = threshold;
+        }
+        if let Some(name) = get_optional::<String>(settings, "storage.durability")? {
+            options.durability = match name.as_str() {
+                "always" => DurabilityMode::Always,
+                "dirty_range" => DurabilityMode::DirtyRange,
+                "os" => DurabilityMode::Os,
+                "periodic" => DurabilityMode::Periodic(Duration::from_millis(
+                    get_optional(settings, "storage.sync_interval_ms")?
+                        .unwrap_or(DEFAULT_SYNC_INTERVAL_MS),
+                )),
+                _ => return Err(invalid_setting("storage.durability", name)),
+            };
+        }
+        if let Some(name) = get_optional::<String>(settings, "storage.eviction")? {
+            let policy = match name.as_str() {
+                "lru" => EvictionPolicy::LeastRecentlyUsed,
+                "lfu" => EvictionPolicy::LeastFrequentlyUsed,
+                "random" => EvictionPolicy::Random,
+                "ttl" => EvictionPolicy::TtlFirst,
+                _ => return Err(invalid_setting("storage.eviction", name)),
+            };
+            // Without a budget the page evicts once it can't grow any further
+            let budget = get_optional(settings, "storage.eviction_budget")?
+                .unwrap_or(options.max_page_size.unwrap_or(options.page_size));
+            options.eviction = Some((policy, budget));
+        }
+        if get_optional::<String>(settings, "encryption.keyfile")?.is_some() {
+            options.keyring = Some(Keyring::from_config(settings)?);
+        }
+        return Ok(options);
+    }
+}
+
+// `None` if the setting is missing
+fn get_optional<'de, T: serde::Deserialize<'de>>(
+    settings: &config::Config,
+    name: &str,
+) -> Result<Option<T>, KvError> {
+    return match settings.get::<T>(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(error) => Err(error.into()),
+    };
+}
+
+fn invalid_setting(name: &str, value: String) -> KvError {
+    return KvError::InvalidSetting {
+        name: String::from(name),
+        value,
+    };
+}
+
+/// Opens the engine of the given kind, `path` is the page file or the directory of the tree
+pub fn open_storage_engine(
+    kind: StorageEngineKind,
+    path: &Path,
+    options: StorageOptions,
+) -> Result<Box<dyn StorageEngine>, KvError> {
+    return match kind {
+        StorageEngineKind::Page => Ok(Box::new(open_page(path, options)?)),
+        StorageEngineKind::Lsm => Ok(Box::new(LsmTree::open(path)?)),
+        StorageEngineKind::Memory => Ok(Box::new(MemoryEngine::new())),
+    };
+}
+
+fn open_page(path: &Path, options: StorageOptions) -> Result<MemKvPage, KvError> {
+    let mut page = match options.keyring {
+        Some(keyring) => MemKvPage::new_with_keyring(path, options.page_size, keyring)?,
+        None => MemKvPage::new(path, options.page_size)?,
+    };
+    if let Some(max_page_size) = options.max_page_size {
+        page.set_max_page_size(max_page_size);
+    }
+    page.set_compression(options.compression, options.compression_threshold);
+    page.set_durability(options.durability);
+    if let Some((policy, budget)) = options.eviction {
+        page.set_eviction(policy, budget);
+    }
+    return Ok(page);
+}
+
+impl StorageEngine for MemKvPage {
+    fn get(&self, key: &str) -> Result<Value, KvError> {
+        return MemKvPage::get(self, key);
+    }
+
+    fn put(&mut self, key: &str, value: Value) -> Result<(), KvError> {
+        return self.upsert(key, value);
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), KvError> {
+        return MemKvPage::delete(self, key);
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+        return MemKvPage::scan(self, prefix);
+    }
+
+    fn scan_after(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>, KvError> {
+        return MemKvPage::scan_after(self, prefix, after, limit);
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        return self.sync_all();
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        return Ok(StorageStats {
+            key_count: self.get_key_count()? as u64,
+            size: self.get_used_size(),
+        });
+    }
+
+    fn expire(&mut self, key: &str, ttl: Option<Duration>) -> Result<(), KvError> {
+        return MemKvPage::expire(self, key, ttl);
+    }
+
+    // Expiry is only kept in memory, see `MemKvPage::expire`
+    fn supports_ttl(&self) -> bool {
+        return true;
+    }
+
+    fn get_ttl(&self, key: &str) -> Result<Option<Duration>, KvError> {
+        return MemKvPage::get_ttl(self, key);
+    }
+
+    fn checkpoint(&mut self, directory: &Path) -> Result<Checkpoint, KvError> {
+        self.sync_all()?;
+        return Checkpoint::from_page(self.get_file_path(), directory);
+    }
+}
+
+impl StorageEngine for LsmTree {
+    fn get(&self, key: &str) -> Result<Value, KvError> {
+        return LsmTree::get(self, key);
+    }
+
+    fn put(&mut self, key: &str, value: Value) -> Result<(), KvError> {
+        return LsmTree::put(self, key, value);
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), KvError> {
+        return LsmTree::delete(self, key);
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+        return LsmTree::scan(self, prefix);
+    }
+
+    fn scan_after(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>, KvError> {
+        return LsmTree::scan_after(self, prefix, after, limit);
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        return self.flush_memtable();
+    }
+
+    // Counting keys has to merge all tables as overwritten keys appear in several of them
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        return Ok(StorageStats {
+            key_count: LsmTree::scan(self, "")?.len() as u64,
+            size: self.get_size(),
+        });
+    }
+
+    // Tables never change once written, so they are linked and identified by their sequence
+    fn checkpoint(&mut self, directory: &Path) -> Result<Checkpoint, KvError> {
+        let files = self
+            .link_tables(directory)?
+            .into_iter()
+            .map(|(name, sequence)| CheckpointFile {
+                name,
+                sequence: Some(sequence),
+            })
+            .collect();
+        return Ok(Checkpoint {
+            kind: StorageEngineKind::Lsm,
+            files,
+            wal_position: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_storage_engine, StorageEngine, StorageEngineKind, StorageOptions};
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{Compression, DurabilityMode, EvictionPolicy, KvError, Value};
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn check_engine(engine: &mut dyn StorageEngine) {
+        engine.put("albert", Value::Integer(1)).unwrap();
+        engine.put("albert", Value::Integer(2)).unwrap();
+        if let Value::Integer(value) = engine.get("albert").unwrap() {
+            assert_eq!(value, 2);
+        } else {
+            panic!();
+        }
+        engine.delete("albert").unwrap();
+        assert!(engine.get("albert").is_err());
+        assert!(engine.delete("albert").is_err());
+
+        engine.put("user:2", Value::Integer(2)).unwrap();
+        engine.put("user:1", Value::Integer(1)).unwrap();
+        engine.put("group:1", Value::Integer(3)).unwrap();
+        let keys: Vec<String> = engine
+            .scan("user:")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["user:1", "user:2"]);
+        engine.flush().unwrap();
+        assert_eq!(engine.stats().unwrap().key_count, 3);
+        assert_eq!(engine.get_ttl("user:1").unwrap(), None);
+        assert!(engine.get_ttl("albert").is_err());
+    }
+
+    #[test]
+    fn test_storage_engines() {
+        assert!("btree".parse::<StorageEngineKind>().is_err());
+
+        const PAGE: &str = "test_storage_engine_page";
+        check_engine(
+            open_storage_engine(
+                "page".parse().unwrap(),
+                Path::new(PAGE),
+                StorageOptions::default(),
+            )
+            .unwrap()
+            .as_mut(),
+        );
+        let mut engine = open_storage_engine(
+            StorageEngineKind::Page,
+            Path::new(PAGE),
+            StorageOptions::default(),
+        )
+        .unwrap();
+        engine
+            .expire("user:1", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert!(engine.get_ttl("user:1").unwrap().is_some());
+        drop(engine);
+        fs::remove_file(PAGE).unwrap();
+        fs::remove_file(format!("{}.bloom", PAGE)).unwrap();
+
+        const LSM: &str = "test_storage_engine_lsm";
+        check_engine(
+            open_storage_engine(
+                "lsm".parse().unwrap(),
+                Path::new(LSM),
+                StorageOptions::default(),
+            )
+            .unwrap()
+            .as_mut(),
+        );
+        fs::remove_dir_all(LSM).unwrap();
+
+        check_engine(
+            open_storage_engine(
+                StorageEngineKind::Memory,
+                Path::new(""),
+                StorageOptions::default(),
+            )
+            .unwrap()
+            .as_mut(),
+        );
+    }
+
+    #[test]
+    fn test_storage_options_from_config() {
+        const PAGE: &str = "test_storage_engine_config";
+        const KEYFILE: &str = "test_storage_engine_config.keyfile";
+        let _page_files = PageFilesGuard::new(Path::new(PAGE)).unwrap();
+        fs::write(
+            KEYFILE,
+            r#"{"active_key": 1, "keys": {"1": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="}}"#,
+        )
+        .unwrap();
+        let settings = config::Config::builder()
+            .set_override("storage.page_size", 8192)
+            .unwrap()
+            .set_override("storage.max_page_size", 65536)
+            .unwrap()
+            .set_override("storage.compression", "zstd")
+            .unwrap()
+            .set_override("storage.durability", "periodic")
+            .unwrap()
+            .set_override("storage.sync_interval_ms", 50)
+            .unwrap()
+            .set_override("storage.eviction", "lru")
+            .unwrap()
+            .set_override("encryption.keyfile", KEYFILE)
+            .unwrap()
+            .build()
+            .unwrap();
+        let options = StorageOptions::from_config(&settings).unwrap();
+        fs::remove_file(KEYFILE).unwrap();
+        assert_eq!(options.page_size, 8192);
+        assert_eq!(options.max_page_size, Some(65536));
+        assert_eq!(options.compression, Compression::Zstd);
+        assert_eq!(
+            options.durability,
+            DurabilityMode::Periodic(Duration::from_millis(50))
+        );
+        assert!(options.eviction == Some((EvictionPolicy::LeastRecentlyUsed, 65536)));
+
+        // The page is written with the configured keyring
+        let mut engine =
+            open_storage_engine(StorageEngineKind::Page, Path::new(PAGE), options).unwrap();
+        engine
+            .put("secret", Value::String(String::from("plain text value")))
+            .unwrap();
+        engine.flush().unwrap();
+        drop(engine);
+        let data = fs::read(PAGE).unwrap();
+        assert_eq!(data.len(), 8192);
+        assert!(!data.windows(16).any(|window| window == b"plain text value"));
+        assert!(open_storage_engine(
+            StorageEngineKind::Page,
+            Path::new(PAGE),
+            StorageOptions::default()
+        )
+        .is_err());
+
+        let settings = config::Config::builder()
+            .set_override("storage.compression", "gzip")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(matches!(
+            StorageOptions::from_config(&settings),
+            Err(KvError::InvalidSetting { .. })
+        ));
+    }
+}
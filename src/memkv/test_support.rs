@@ -0,0 +1,29 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::memkv::mem_kv_page::remove_page_files;
+use crate::memkv::KvError;
+
+/// Removes a page and its sidecars when created and again when dropped, so tests leave no
+/// files behind even when they fail. Declare it before the page so the page is closed first.
+pub(crate) struct PageFilesGuard {
+    path: PathBuf,
+}
+
+impl PageFilesGuard {
+    pub(crate) fn new(path: &Path) -> Result<PageFilesGuard, KvError> {
+        remove_page_files(path)?;
+        return Ok(PageFilesGuard {
+            path: PathBuf::from(path),
+        });
+    }
+}
+
+impl Drop for PageFilesGuard {
+    fn drop(&mut self) {
+        if let Err(error) = remove_page_files(&self.path) {
+            warn!("Failed to remove page {:?}: {}", self.path, error);
+        }
+    }
+}
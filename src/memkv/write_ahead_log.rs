@@ -0,0 +1,550 @@
+use super::backup::Checkpoint;
+use super::errors::KvError;
+use super::mem_kv_page::Value;
+use super::storage_engine::{StorageEngine, StorageStats};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SEGMENT_EXTENSION: &str = "wal";
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 1024 * 1024 * 16; // 16 MB
+
+// Payload length as u32 followed by the first 8 bytes of its sha256
+const RECORD_HEADER_SIZE: usize = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalOperation {
+    Put {
+        key: String,
+        value: Value,
+    },
+    Delete {
+        key: String,
+    },
+    // Expiry in milliseconds since the unix epoch, so replaying does not extend the ttl.
+    // Keys which expired before the replay are deleted.
+    Expire {
+        key: String,
+        expires_at_ms: Option<u64>,
+    },
+}
+
+/// Position in the log, records are numbered from 1 without gaps
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct WalPosition {
+    pub sequence: u64,
+    // Milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalRecord {
+    pub position: WalPosition,
+    pub operation: WalOperation,
+}
+
+fn get_checksum(payload: &[u8]) -> [u8; 8] {
+    let digest = Sha256::digest(payload);
+    let mut checksum = [0; 8];
+    checksum.copy_from_slice(&digest[..8]);
+    return checksum;
+}
+
+impl WalRecord {
+    pub fn encode(self: &Self) -> Result<Vec<u8>, KvError> {
+        let (operation, key, value, expires_at_ms) = match &self.operation {
+            WalOperation::Put { key, value } => ("put", key, Some(value.to_json()), None),
+            WalOperation::Delete { key } => ("delete", key, None, None),
+            WalOperation::Expire { key, expires_at_ms } => ("expire", key, None, *expires_at_ms),
+        };
+        let payload = serde_json::to_vec(&serde_json::json!({
+            "sequence": self.position.sequence,
+            "timestamp_ms": self.position.timestamp_ms,
+            "operation": operation,
+            "key": key,
+            "value": value,
+            "expires_at_ms": expires_at_ms,
+        }))?;
+        let mut data = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        data.extend_from_slice(&get_checksum(&payload));
+        data.extend_from_slice(&payload);
+        return Ok(data);
+    }
+
+    /// Returns the record at the start of `data` and its encoded size, `None` if the record
+    /// is incomplete or does not match its checksum
+    pub fn decode(data: &[u8]) -> Result<Option<(WalRecord, usize)>, KvError> {
+        if data.len() < RECORD_HEADER_SIZE {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let payload = match data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length) {
+            Some(payload) if get_checksum(payload) == data[4..RECORD_HEADER_SIZE] => payload,
+            _ => return Ok(None),
+        };
+        let document: serde_json::Value = serde_json::from_slice(payload)?;
+        let invalid = |reason: &str| KvError::InvalidValueJson {
+            reason: String::from(reason),
+        };
+        let get_number = |field: &str| {
+            document[field]
+                .as_u64()
+                .ok_or_else(|| invalid(&format!("missing {:?}", field)))
+        };
+        let key = match document["key"].as_str() {
+            Some(key) => String::from(key),
+            None => return Err(invalid("missing \"key\"")),
+        };
+        let operation = match document["operation"].as_str() {
+            Some("put") => WalOperation::Put {
+                key,
+                value: Value::from_json(&document["value"])?,
+            },
+            Some("delete") => WalOperation::Delete { key },
+            // Records written before expiries were absolute hold the ttl at the time of the write
+            Some("expire") => WalOperation::Expire {
+                key,
+                expires_at_ms: match document["ttl_ms"].as_u64() {
+                    Some(ttl_ms) => Some(get_number("timestamp_ms")?.saturating_add(ttl_ms)),
+                    None => document["expires_at_ms"].as_u64(),
+                },
+            },
+            _ => return Err(invalid("unknown operation")),
+        };
+        let record = WalRecord {
+            position: WalPosition {
+                sequence: get_number("sequence")?,
+                timestamp_ms: get_number("timestamp_ms")?,
+            },
+            operation,
+        };
+        return Ok(Some((record, RECORD_HEADER_SIZE + length)));
+    }
+
+    pub fn apply(self: &Self, engine: &mut dyn StorageEngine) -> Result<(), KvError> {
+        return match &self.operation {
+            WalOperation::Put { key, value } => engine.put(key, value.clone()),
+            WalOperation::Delete { key } => engine.delete(key),
+            WalOperation::Expire {
+                key,
+                expires_at_ms: Some(expires_at_ms),
+            } => match expires_at_ms.checked_sub(get_timestamp_ms()) {
+                Some(ttl_ms) if ttl_ms > 0 => {
+                    engine.expire(key, Some(Duration::from_millis(ttl_ms)))
+                }
+                // The engine may have dropped the key on its own already
+                _ => match engine.delete(key) {
+                    Err(KvError::KeyDoesNotExist { .. }) => Ok(()),
+                    result => result,
+                },
+            },
+            WalOperation::Expire {
+                key,
+                expires_at_ms: None,
+            } => engine.expire(key, None),
+        };
+    }
+}
+
+/// Reads all records of a segment, a record torn by a crash ends it
+pub fn read_segment(path: &Path) -> Result<Vec<WalRecord>, KvError> {
+    let data = fs::read(path)?;
+    let mut records = vec![];
+    let mut offset = 0;
+    while let Some((record, size)) = WalRecord::decode(&data[offset..])? {
+        records.push(record);
+        offset += size;
+    }
+    return Ok(records);
+}
+
+pub fn write_segment(path: &Path, records: &[WalRecord]) -> Result<(), KvError> {
+    let mut data = vec![];
+    for record in records {
+        data.extend_from_slice(&record.encode()?);
+    }
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    return Ok(());
+}
+
+/// Segments are named by the sequence of their first record
+pub fn get_segment_path(directory: &Path, first_sequence: u64) -> PathBuf {
+    return directory.join(format!("{:020}.{}", first_sequence, SEGMENT_EXTENSION));
+}
+
+/// Returns the segments in `directory` ordered by their first sequence
+pub fn list_segments(directory: &Path) -> Result<Vec<PathBuf>, KvError> {
+    let mut segments = vec![];
+    for dir_entry in fs::read_dir(directory)? {
+        let path = dir_entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) == Some(SEGMENT_EXTENSION) {
+            segments.push(path);
+        }
+    }
+    segments.sort();
+    return Ok(segments);
+}
+
+/// Moves a segment into the archive, the copy is synced before the original is removed.
+/// Empty segments are dropped.
+pub fn archive_segment(segment: &Path, archive_directory: &Path) -> Result<(), KvError> {
+    if fs::metadata(segment)?.len() > 0 {
+        let archive_path = archive_directory.join(segment.file_name().unwrap());
+        let temporary_path = archive_path.with_extension("tmp");
+        fs::copy(segment, &temporary_path)?;
+        File::open(&temporary_path)?.sync_all()?;
+        fs::rename(&temporary_path, &archive_path)?;
+    }
+    fs::remove_file(segment)?;
+    return Ok(());
+}
+
+/// Archives all segments of `directory`, for logs which are not open
+pub fn archive_segments(directory: &Path, archive_directory: &Path) -> Result<(), KvError> {
+    for segment in list_segments(directory)? {
+        archive_segment(&segment, archive_directory)?;
+    }
+    return Ok(());
+}
+
+fn get_timestamp_ms() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64);
+}
+
+/// Log of every write, split into segments which are moved into an archive directory once
+/// they are full. Together with a backup the archive allows recovering any later state,
+/// see `recovery::recover`.
+pub struct WriteAheadLog {
+    directory: PathBuf,
+    archive_directory: PathBuf,
+    segment: File,
+    segment_path: PathBuf,
+    segment_size: u64,
+    max_segment_size: u64,
+    last_position: Option<WalPosition>,
+    // Segment size and position before the last append, see `revert_last`
+    revert_point: Option<(u64, Option<WalPosition>)>,
+}
+
+impl WriteAheadLog {
+    /// Archives the segments left by the previous run and continues after the last archived
+    /// record
+    pub fn open(directory: &Path, archive_directory: &Path) -> Result<Self, KvError> {
+        fs::create_dir_all(directory)?;
+        fs::create_dir_all(archive_directory)?;
+        archive_segments(directory, archive_directory)?;
+        let last_position = match list_segments(archive_directory)?.last() {
+            Some(segment) => read_segment(segment)?.last().map(|record| record.position),
+            None => None,
+        };
+        let next_sequence = last_position.map_or(1, |position| position.sequence + 1);
+        let segment_path = get_segment_path(directory, next_sequence);
+        return Ok(WriteAheadLog {
+            directory: PathBuf::from(directory),
+            archive_directory: PathBuf::from(archive_directory),
+            segment: File::create(&segment_path)?,
+            segment_path,
+            segment_size: 0,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            last_position,
+            revert_point: None,
+        });
+    }
+
+    pub fn set_max_segment_size(self: &mut Self, max_segment_size: u64) {
+        self.max_segment_size = max_segment_size;
+    }
+
+    pub fn get_last_position(self: &Self) -> Option<WalPosition> {
+        return self.last_position;
+    }
+
+    /// Appends a record and syncs it to disk. Full segments are archived before the next
+    /// append, so the last record can still be reverted.
+    pub fn append(self: &mut Self, operation: WalOperation) -> Result<WalPosition, KvError> {
+        if self.segment_size >= self.max_segment_size {
+            self.rotate()?;
+        }
+        let position = WalPosition {
+            sequence: self
+                .last_position
+                .map_or(1, |position| position.sequence + 1),
+            timestamp_ms: get_timestamp_ms(),
+        };
+        let data = WalRecord {
+            position,
+            operation,
+        }
+        .encode()?;
+        let result = self
+            .segment
+            .write_all(&data)
+            .and_then(|_| self.segment.sync_data());
+        if let Err(error) = result {
+            // A torn record would hide every record appended after it
+            self.truncate(self.segment_size)?;
+            return Err(KvError::Io(error));
+        }
+        self.revert_point = Some((self.segment_size, self.last_position));
+        self.segment_size += data.len() as u64;
+        self.last_position = Some(position);
+        return Ok(position);
+    }
+
+    /// Removes the record appended last, for writes which failed after they were logged
+    pub fn revert_last(self: &mut Self) -> Result<(), KvError> {
+        if let Some((segment_size, last_position)) = self.revert_point.take() {
+            self.truncate(segment_size)?;
+            self.segment_size = segment_size;
+            self.last_position = last_position;
+        }
+        return Ok(());
+    }
+
+    fn truncate(self: &mut Self, size: u64) -> Result<(), KvError> {
+        self.segment.set_len(size)?;
+        self.segment.seek(SeekFrom::Start(size))?;
+        self.segment.sync_data()?;
+        return Ok(());
+    }
+
+    /// Archives the current segment, so the archive holds every record written so far
+    pub fn rotate(self: &mut Self) -> Result<(), KvError> {
+        if self.segment_size == 0 {
+            return Ok(());
+        }
+        let next_sequence = self
+            .last_position
+            .map_or(1, |position| position.sequence + 1);
+        let segment_path = get_segment_path(&self.directory, next_sequence);
+        self.segment = File::create(&segment_path)?;
+        let closed_path = std::mem::replace(&mut self.segment_path, segment_path);
+        self.segment_size = 0;
+        self.revert_point = None;
+        archive_segment(&closed_path, &self.archive_directory)?;
+        info!("Archived write ahead log segment {:?}", closed_path);
+        return Ok(());
+    }
+}
+
+/// Wraps an engine and logs every write before it is applied, writes the engine rejects
+/// are removed from the log again. Reads go straight to the engine.
+pub struct LoggedEngine {
+    engine: Box<dyn StorageEngine>,
+    log: WriteAheadLog,
+}
+
+impl LoggedEngine {
+    pub fn new(engine: Box<dyn StorageEngine>, log: WriteAheadLog) -> LoggedEngine {
+        return LoggedEngine { engine, log };
+    }
+
+    fn apply<F>(self: &mut Self, operation: WalOperation, apply: F) -> Result<(), KvError>
+    where
+        F: FnOnce(&mut dyn StorageEngine) -> Result<(), KvError>,
+    {
+        self.log.append(operation)?;
+        if let Err(error) = apply(self.engine.as_mut()) {
+            self.log.revert_last()?;
+            return Err(error);
+        }
+        return Ok(());
+    }
+}
+
+impl StorageEngine for LoggedEngine {
+    fn get(&self, key: &str) -> Result<Value, KvError> {
+        return self.engine.get(key);
+    }
+
+    fn put(&mut self, key: &str, value: Value) -> Result<(), KvError> {
+        let operation = WalOperation::Put {
+            key: String::from(key),
+            value: value.clone(),
+        };
+        return self.apply(operation, |engine| engine.put(key, value));
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), KvError> {
+        let operation = WalOperation::Delete {
+            key: String::from(key),
+        };
+        return self.apply(operation, |engine| engine.delete(key));
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>, KvError> {
+        return self.engine.scan(prefix);
+    }
+
+    fn scan_after(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Value)>, KvError> {
+        return self.engine.scan_after(prefix, after, limit);
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        return self.engine.flush();
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        return self.engine.stats();
+    }
+
+    fn expire(&mut self, key: &str, ttl: Option<Duration>) -> Result<(), KvError> {
+        let operation = WalOperation::Expire {
+            key: String::from(key),
+            expires_at_ms: ttl.map(|ttl| get_timestamp_ms().saturating_add(ttl.as_millis() as u64)),
+        };
+        return self.apply(operation, |engine| engine.expire(key, ttl));
+    }
+
+    fn supports_ttl(&self) -> bool {
+        return self.engine.supports_ttl();
+    }
+
+    fn get_ttl(&self, key: &str) -> Result<Option<Duration>, KvError> {
+        return self.engine.get_ttl(key);
+    }
+
+    // Backups record the position they contain, recovery replays the records after it
+    fn checkpoint(&mut self, directory: &Path) -> Result<Checkpoint, KvError> {
+        let mut checkpoint = self.engine.checkpoint(directory)?;
+        self.log.rotate()?;
+        checkpoint.wal_position = self.log.get_last_position();
+        return Ok(checkpoint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_checksum, list_segments, read_segment, LoggedEngine, WalOperation, WalPosition,
+        WalRecord, WriteAheadLog,
+    };
+    use crate::memkv::{MemoryEngine, StorageEngine, Value};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+
+    const WAL: &str = "test_wal";
+    const ARCHIVE: &str = "test_wal_archive";
+
+    #[test]
+    fn test_logged_engine() {
+        for directory in [WAL, ARCHIVE] {
+            if Path::new(directory).exists() {
+                fs::remove_dir_all(directory).unwrap();
+            }
+        }
+        let mut log = WriteAheadLog::open(Path::new(WAL), Path::new(ARCHIVE)).unwrap();
+        log.set_max_segment_size(256);
+        let mut engine = LoggedEngine::new(Box::new(MemoryEngine::new()), log);
+        for i in 0..10 {
+            engine
+                .put(&format!("key-{}", i), Value::Integer(i))
+                .unwrap();
+        }
+        engine.delete("key-0").unwrap();
+        assert!(engine.delete("key-0").is_err());
+        assert!(engine
+            .expire("key-1", Some(Duration::from_secs(1)))
+            .is_err());
+        // Rejected writes are removed from the log again
+        assert_eq!(engine.log.get_last_position().unwrap().sequence, 11);
+        assert!(list_segments(Path::new(ARCHIVE)).unwrap().len() > 1);
+
+        // A torn record ends the segment
+        let segment = list_segments(Path::new(WAL)).unwrap().pop().unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[0, 0, 1, 0, 1, 2]).unwrap();
+        drop(file);
+        drop(engine);
+
+        let log = WriteAheadLog::open(Path::new(WAL), Path::new(ARCHIVE)).unwrap();
+        assert_eq!(log.get_last_position().unwrap().sequence, 11);
+        assert_eq!(list_segments(Path::new(WAL)).unwrap().len(), 1);
+        let records: Vec<WalRecord> = list_segments(Path::new(ARCHIVE))
+            .unwrap()
+            .iter()
+            .flat_map(|segment| read_segment(segment).unwrap())
+            .collect();
+        let sequences: Vec<u64> = records
+            .iter()
+            .map(|record| record.position.sequence)
+            .collect();
+        assert_eq!(sequences, (1..=11).collect::<Vec<u64>>());
+        assert_eq!(
+            records[10].operation,
+            WalOperation::Delete {
+                key: String::from("key-0")
+            }
+        );
+        drop(log);
+
+        for directory in [WAL, ARCHIVE] {
+            fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_absolute_expiry() {
+        let position = WalPosition {
+            sequence: 1,
+            timestamp_ms: 1000,
+        };
+        let record = WalRecord {
+            position,
+            operation: WalOperation::Expire {
+                key: String::from("a"),
+                expires_at_ms: Some(5000),
+            },
+        };
+        let data = record.encode().unwrap();
+        assert_eq!(
+            WalRecord::decode(&data).unwrap(),
+            Some((record, data.len()))
+        );
+
+        // Older records hold a ttl relative to their timestamp
+        let payload = serde_json::to_vec(&serde_json::json!({
+            "sequence": 1,
+            "timestamp_ms": 1000,
+            "operation": "expire",
+            "key": "a",
+            "value": null,
+            "ttl_ms": 500,
+        }))
+        .unwrap();
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend(get_checksum(&payload));
+        data.extend(&payload);
+        let (record, _) = WalRecord::decode(&data).unwrap().unwrap();
+        assert_eq!(
+            record.operation,
+            WalOperation::Expire {
+                key: String::from("a"),
+                expires_at_ms: Some(1500),
+            }
+        );
+
+        // The key expired long before the replay, so it is dropped instead of expiring again
+        let mut engine = MemoryEngine::new();
+        engine.put("a", Value::Integer(1)).unwrap();
+        record.apply(&mut engine).unwrap();
+        assert!(engine.get("a").is_err());
+        record.apply(&mut engine).unwrap();
+    }
+}
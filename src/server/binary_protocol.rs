@@ -0,0 +1,443 @@
+use super::errors::ProtocolError;
+use crate::memkv::mem_kv_page::ValueDataType;
+use crate::memkv::{KvError, Value};
+use std::io;
+use std::mem::size_of;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Every frame is a u32 big endian payload size followed by the payload. Request payloads
+// start with an opcode and response payloads with a tag, keys and values are prefixed with
+// their u32 size and values also with their data type.
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024; // 64 MB
+const INITIAL_FRAME_CAPACITY: usize = 64 * 1024;
+
+const OP_GET: u8 = 0x1;
+const OP_PUT: u8 = 0x2;
+const OP_DELETE: u8 = 0x3;
+const OP_SCAN: u8 = 0x4;
+const OP_CAS: u8 = 0x5;
+
+const TAG_DONE: u8 = 0x0;
+const TAG_VALUE: u8 = 0x1;
+const TAG_ENTRIES: u8 = 0x2;
+const TAG_ERROR: u8 = 0x3;
+
+#[derive(Debug, Clone)]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: Value,
+    },
+    Delete {
+        key: String,
+    },
+    Scan {
+        prefix: String,
+    },
+    // Writes `value` only if the current value equals `expected`, `None` if the key must not
+    // exist yet
+    Cas {
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    Done,
+    Value(Value),
+    Entries(Vec<(String, Value)>),
+    Error(ErrorKind, String),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    NotFound = 1,
+    AlreadyExists = 2,
+    NoSpaceLeft = 3,
+    BadRequest = 4,
+    Internal = 5,
+    // The current value did not match the expected value of a compare and swap
+    Conflict = 6,
+    // The storage engine of the server does not implement the operation
+    Unsupported = 7,
+}
+
+impl ErrorKind {
+    pub fn from_kv_error(error: &KvError) -> ErrorKind {
+        return match error {
+            KvError::KeyDoesNotExist { .. } => ErrorKind::NotFound,
+            KvError::KeyAlreadyExists { .. } => ErrorKind::AlreadyExists,
+            KvError::NoSpaceLeft { .. } => ErrorKind::NoSpaceLeft,
+            KvError::InvalidValueJson { .. }
+            | KvError::Json(_)
+            | KvError::InvalidDataType { .. } => ErrorKind::BadRequest,
+            KvError::UnsupportedOperation { .. } => ErrorKind::Unsupported,
+            _ => ErrorKind::Internal,
+        };
+    }
+}
+
+impl TryFrom<u8> for ErrorKind {
+    type Error = ProtocolError;
+
+    fn try_from(from_value: u8) -> Result<Self, Self::Error> {
+        return match from_value {
+            0x1 => Ok(ErrorKind::NotFound),
+            0x2 => Ok(ErrorKind::AlreadyExists),
+            0x3 => Ok(ErrorKind::NoSpaceLeft),
+            0x4 => Ok(ErrorKind::BadRequest),
+            0x5 => Ok(ErrorKind::Internal),
+            0x6 => Ok(ErrorKind::Conflict),
+            0x7 => Ok(ErrorKind::Unsupported),
+            kind => Err(ProtocolError::Malformed {
+                reason: format!("invalid error kind {}", kind),
+            }),
+        };
+    }
+}
+
+impl Request {
+    pub fn encode(self: &Self) -> Result<Vec<u8>, KvError> {
+        let mut payload = vec![];
+        match self {
+            Request::Get { key } => {
+                payload.push(OP_GET);
+                put_bytes(&mut payload, key.as_bytes());
+            }
+            Request::Put { key, value } => {
+                payload.push(OP_PUT);
+                put_bytes(&mut payload, key.as_bytes());
+                put_value(&mut payload, value)?;
+            }
+            Request::Delete { key } => {
+                payload.push(OP_DELETE);
+                put_bytes(&mut payload, key.as_bytes());
+            }
+            Request::Scan { prefix } => {
+                payload.push(OP_SCAN);
+                put_bytes(&mut payload, prefix.as_bytes());
+            }
+            Request::Cas {
+                key,
+                expected,
+                value,
+            } => {
+                payload.push(OP_CAS);
+                put_bytes(&mut payload, key.as_bytes());
+                match expected {
+                    Some(expected) => {
+                        payload.push(1);
+                        put_value(&mut payload, expected)?;
+                    }
+                    None => payload.push(0),
+                }
+                put_value(&mut payload, value)?;
+            }
+        }
+        return Ok(payload);
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Request, ProtocolError> {
+        let mut reader = PayloadReader::new(payload);
+        let request = match reader.read_u8()? {
+            OP_GET => Request::Get {
+                key: reader.read_string()?,
+            },
+            OP_PUT => Request::Put {
+                key: reader.read_string()?,
+                value: reader.read_value()?,
+            },
+            OP_DELETE => Request::Delete {
+                key: reader.read_string()?,
+            },
+            OP_SCAN => Request::Scan {
+                prefix: reader.read_string()?,
+            },
+            OP_CAS => Request::Cas {
+                key: reader.read_string()?,
+                expected: match reader.read_u8()? {
+                    0 => None,
+                    _ => Some(reader.read_value()?),
+                },
+                value: reader.read_value()?,
+            },
+            opcode => return Err(ProtocolError::InvalidOpcode { opcode }),
+        };
+        reader.finish()?;
+        return Ok(request);
+    }
+}
+
+impl Response {
+    pub fn from_error(error: &KvError) -> Response {
+        return Response::Error(ErrorKind::from_kv_error(error), error.to_string());
+    }
+
+    pub fn encode(self: &Self) -> Result<Vec<u8>, KvError> {
+        let mut payload = vec![];
+        match self {
+            Response::Done => payload.push(TAG_DONE),
+            Response::Value(value) => {
+                payload.push(TAG_VALUE);
+                put_value(&mut payload, value)?;
+            }
+            Response::Entries(entries) => {
+                payload.push(TAG_ENTRIES);
+                payload.extend((entries.len() as u32).to_be_bytes());
+                for (key, value) in entries {
+                    put_bytes(&mut payload, key.as_bytes());
+                    put_value(&mut payload, value)?;
+                }
+            }
+            Response::Error(kind, message) => {
+                payload.push(TAG_ERROR);
+                payload.push(*kind as u8);
+                put_bytes(&mut payload, message.as_bytes());
+            }
+        }
+        return Ok(payload);
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Response, ProtocolError> {
+        let mut reader = PayloadReader::new(payload);
+        let response = match reader.read_u8()? {
+            TAG_DONE => Response::Done,
+            TAG_VALUE => Response::Value(reader.read_value()?),
+            TAG_ENTRIES => {
+                let count = reader.read_u32()?;
+                let mut entries = vec![];
+                for _ in 0..count {
+                    entries.push((reader.read_string()?, reader.read_value()?));
+                }
+                Response::Entries(entries)
+            }
+            TAG_ERROR => Response::Error(reader.read_u8()?.try_into()?, reader.read_string()?),
+            tag => {
+                return Err(ProtocolError::Malformed {
+                    reason: format!("invalid response tag {}", tag),
+                })
+            }
+        };
+        reader.finish()?;
+        return Ok(response);
+    }
+}
+
+/// Reads the next frame, `None` if the peer closed the connection between two frames
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<u8>>, ProtocolError> {
+    let size = match reader.read_u32().await {
+        Ok(size) => size,
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    if size > MAX_FRAME_SIZE {
+        return Err(ProtocolError::FrameTooLarge { size });
+    }
+    // The buffer grows with the data actually received, so a large announced size alone
+    // doesn't allocate it
+    let mut payload = Vec::with_capacity((size as usize).min(INITIAL_FRAME_CAPACITY));
+    (&mut *reader)
+        .take(size as u64)
+        .read_to_end(&mut payload)
+        .await?;
+    if payload.len() < size as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    return Ok(Some(payload));
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+) -> Result<(), io::Error> {
+    writer.write_u32(payload.len() as u32).await?;
+    return writer.write_all(payload).await;
+}
+
+fn put_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    payload.extend((bytes.len() as u32).to_be_bytes());
+    payload.extend(bytes);
+}
+
+fn put_value(payload: &mut Vec<u8>, value: &Value) -> Result<(), KvError> {
+    payload.push(value.get_data_type() as u8);
+    put_bytes(payload, &value.clone().into_bytes()?);
+    return Ok(());
+}
+
+struct PayloadReader<'a> {
+    payload: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(payload: &'a [u8]) -> PayloadReader<'a> {
+        return PayloadReader {
+            payload,
+            position: 0,
+        };
+    }
+
+    fn read_bytes(self: &mut Self, size: usize) -> Result<&'a [u8], ProtocolError> {
+        if size > self.payload.len() - self.position {
+            return Err(ProtocolError::Malformed {
+                reason: String::from("unexpected end of payload"),
+            });
+        }
+        let bytes = &self.payload[self.position..self.position + size];
+        self.position += size;
+        return Ok(bytes);
+    }
+
+    fn read_u8(self: &mut Self) -> Result<u8, ProtocolError> {
+        return Ok(self.read_bytes(size_of::<u8>())?[0]);
+    }
+
+    fn read_u32(self: &mut Self) -> Result<u32, ProtocolError> {
+        let mut buffer = [0; size_of::<u32>()];
+        buffer.copy_from_slice(self.read_bytes(size_of::<u32>())?);
+        return Ok(u32::from_be_bytes(buffer));
+    }
+
+    fn read_sized_bytes(self: &mut Self) -> Result<&'a [u8], ProtocolError> {
+        let size = self.read_u32()? as usize;
+        return self.read_bytes(size);
+    }
+
+    fn read_string(self: &mut Self) -> Result<String, ProtocolError> {
+        return match std::str::from_utf8(self.read_sized_bytes()?) {
+            Ok(text) => Ok(String::from(text)),
+            Err(_) => Err(ProtocolError::Malformed {
+                reason: String::from("string is not valid utf-8"),
+            }),
+        };
+    }
+
+    fn read_value(self: &mut Self) -> Result<Value, ProtocolError> {
+        let data_type: ValueDataType = self.read_u8()?.try_into()?;
+        return Ok(Value::from_bytes(data_type, self.read_sized_bytes()?)?);
+    }
+
+    fn finish(self: &Self) -> Result<(), ProtocolError> {
+        if self.position != self.payload.len() {
+            return Err(ProtocolError::Malformed {
+                reason: String::from("trailing bytes after payload"),
+            });
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, write_frame, ErrorKind, Request, Response, MAX_FRAME_SIZE};
+    use crate::memkv::{KvError, Value};
+    use crate::server::errors::ProtocolError;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_encode_and_decode() {
+        let request = Request::Put {
+            key: String::from("dan"),
+            value: Value::Json(json!({"name": "dan"})),
+        };
+        match Request::decode(&request.encode().unwrap()).unwrap() {
+            Request::Put {
+                key,
+                value: Value::Json(document),
+            } => {
+                assert_eq!(key, "dan");
+                assert_eq!(document["name"], "dan");
+            }
+            _ => panic!(),
+        }
+
+        let response = Response::Entries(vec![
+            (String::from("a"), Value::Integer(1)),
+            (String::from("b"), Value::String(String::from("text"))),
+        ]);
+        match Response::decode(&response.encode().unwrap()).unwrap() {
+            Response::Entries(entries) => {
+                assert_eq!(entries.len(), 2);
+                assert!(matches!(entries[0], (ref key, Value::Integer(1)) if key == "a"));
+            }
+            _ => panic!(),
+        }
+        let request = Request::Cas {
+            key: String::from("a"),
+            expected: None,
+            value: Value::Integer(2),
+        };
+        assert!(matches!(
+            Request::decode(&request.encode().unwrap()).unwrap(),
+            Request::Cas {
+                expected: None,
+                value: Value::Integer(2),
+                ..
+            }
+        ));
+        assert_eq!(
+            ErrorKind::from_kv_error(&KvError::InvalidDataType { data_type: 9 }),
+            ErrorKind::BadRequest
+        );
+        let response = Response::Error(
+            ErrorKind::from_kv_error(&KvError::UnsupportedOperation {
+                operation: String::from("expire"),
+            }),
+            String::from("unsupported"),
+        );
+        assert!(matches!(
+            Response::decode(&response.encode().unwrap()).unwrap(),
+            Response::Error(ErrorKind::Unsupported, _)
+        ));
+        let response = Response::Error(ErrorKind::NotFound, String::from("missing"));
+        assert!(matches!(
+            Response::decode(&response.encode().unwrap()).unwrap(),
+            Response::Error(ErrorKind::NotFound, message) if message == "missing"
+        ));
+
+        let payload = Request::Get {
+            key: String::from("a"),
+        }
+        .encode()
+        .unwrap();
+        assert!(matches!(
+            Request::decode(&payload[..payload.len() - 1]),
+            Err(ProtocolError::Malformed { .. })
+        ));
+        assert!(matches!(
+            Request::decode(&[0x9]),
+            Err(ProtocolError::InvalidOpcode { opcode: 0x9 })
+        ));
+        // An integer has to be exactly eight bytes
+        assert!(matches!(
+            Request::decode(&[0x2, 0, 0, 0, 1, b'a', 0x2, 0, 0, 0, 1, 7]),
+            Err(ProtocolError::Value(_))
+        ));
+
+        let mut data = vec![];
+        write_frame(&mut data, &payload).await.unwrap();
+        let mut reader = &data[..];
+        assert_eq!(read_frame(&mut reader).await.unwrap().unwrap(), payload);
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+        // A frame that ends early is an error even if it announced a large size
+        let mut data = (MAX_FRAME_SIZE - 1).to_be_bytes().to_vec();
+        data.extend(b"abc");
+        assert!(matches!(
+            read_frame(&mut &data[..]).await,
+            Err(ProtocolError::Io(_))
+        ));
+        let data = (MAX_FRAME_SIZE + 1).to_be_bytes();
+        assert!(matches!(
+            read_frame(&mut &data[..]).await,
+            Err(ProtocolError::FrameTooLarge { .. })
+        ));
+    }
+}
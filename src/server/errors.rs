@@ -0,0 +1,61 @@
+use crate::memkv::KvError;
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    FrameTooLarge { size: u32 },
+    InvalidOpcode { opcode: u8 },
+    UnknownCommand { name: String },
+    // The payload ends early, has trailing bytes or holds a key that is not utf-8
+    Malformed { reason: String },
+    Value(KvError),
+    Io(io::Error),
+    Http(hyper::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::FrameTooLarge { size } => {
+                write!(f, "frame of {} bytes is too large", size)
+            }
+            ProtocolError::InvalidOpcode { opcode } => write!(f, "invalid opcode {}", opcode),
+            ProtocolError::UnknownCommand { name } => write!(f, "unknown command {:?}", name),
+            ProtocolError::Malformed { reason } => write!(f, "malformed frame, {}", reason),
+            ProtocolError::Value(error) => write!(f, "invalid value: {}", error),
+            ProtocolError::Io(error) => write!(f, "io error: {}", error),
+            ProtocolError::Http(error) => write!(f, "http error: {}", error),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        return match self {
+            ProtocolError::Value(error) => Some(error),
+            ProtocolError::Io(error) => Some(error),
+            ProtocolError::Http(error) => Some(error),
+            _ => None,
+        };
+    }
+}
+
+impl From<KvError> for ProtocolError {
+    fn from(error: KvError) -> Self {
+        return ProtocolError::Value(error);
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(error: io::Error) -> Self {
+        return ProtocolError::Io(error);
+    }
+}
+
+impl From<hyper::Error> for ProtocolError {
+    fn from(error: hyper::Error) -> Self {
+        return ProtocolError::Http(error);
+    }
+}
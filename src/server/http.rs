@@ -0,0 +1,269 @@
+use super::{lookup, value_from_bytes, value_to_bytes, with_engine, SharedEngine};
+use crate::memkv::backup;
+use crate::memkv::{KvError, Value};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT, ALLOW, CONTENT_TYPE, IF_NONE_MATCH};
+use hyper::{Method, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use std::convert::Infallible;
+use std::path::PathBuf;
+
+pub const MAX_BODY_SIZE: usize = 64 * 1024 * 1024; // 64 MB
+const JSON_CONTENT_TYPE: &str = "application/json";
+const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+// Raw values name their data type in this header since the body alone doesn't tell
+pub const VALUE_TYPE_HEADER: &str = "x-rdkv-type";
+
+// Backups of all servers take the next sequence of their directory, so they run one at a time
+static BACKUP_LOCK: Mutex<()> = parking_lot::const_mutex(());
+
+type HttpResponse = Response<Full<Bytes>>;
+type HttpResult = Result<HttpResponse, HttpError>;
+
+// Failures are answered with a json error document
+struct HttpError {
+    status: StatusCode,
+    message: String,
+    // Methods which are allowed instead, for `405 Method Not Allowed`
+    allow: Option<&'static str>,
+}
+
+impl HttpError {
+    fn new(status: StatusCode, message: &str) -> HttpError {
+        return HttpError {
+            status,
+            message: String::from(message),
+            allow: None,
+        };
+    }
+
+    fn method_not_allowed(allow: &'static str) -> HttpError {
+        return HttpError {
+            status: StatusCode::METHOD_NOT_ALLOWED,
+            message: String::from("method not allowed"),
+            allow: Some(allow),
+        };
+    }
+
+    fn into_response(self: Self) -> HttpResponse {
+        let mut response =
+            json_response(self.status, &serde_json::json!({ "error": self.message }));
+        if let Some(allow) = self.allow {
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static(allow));
+        }
+        return response;
+    }
+}
+
+impl From<KvError> for HttpError {
+    fn from(error: KvError) -> Self {
+        let status = match error {
+            KvError::KeyDoesNotExist { .. } => StatusCode::NOT_FOUND,
+            KvError::KeyAlreadyExists { .. } => StatusCode::CONFLICT,
+            KvError::NoSpaceLeft { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            KvError::InvalidValueJson { .. } | KvError::Json(_) => StatusCode::BAD_REQUEST,
+            KvError::UnsupportedOperation { .. } => StatusCode::NOT_IMPLEMENTED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return HttpError::new(status, &error.to_string());
+    }
+}
+
+/// Serves the REST api, `GET/PUT/DELETE /kv/{key}` and `GET /kv?prefix=` for listings.
+/// Values are sent as raw bytes unless the client asks for their json representation with
+/// `Accept` or `Content-Type` set to `application/json`. `GET /backup` lists the backups in
+/// `backup_directory` and `POST /backup?incremental=true` takes a new one.
+pub async fn handle_request(
+    engine: SharedEngine,
+    backup_directory: Option<PathBuf>,
+    request: Request<Incoming>,
+) -> Result<HttpResponse, Infallible> {
+    return Ok(route(engine, backup_directory, request)
+        .await
+        .unwrap_or_else(HttpError::into_response));
+}
+
+async fn route(
+    engine: SharedEngine,
+    backup_directory: Option<PathBuf>,
+    request: Request<Incoming>,
+) -> HttpResult {
+    let path = request.uri().path();
+    if path == "/backup" {
+        let directory = match backup_directory {
+            Some(directory) => directory,
+            None => {
+                return Err(HttpError::new(
+                    StatusCode::NOT_FOUND,
+                    "backups are not configured",
+                ))
+            }
+        };
+        return match *request.method() {
+            Method::GET => {
+                let manifests = backup::list_backups(&directory)?;
+                Ok(json_response(StatusCode::OK, &serde_json::json!(manifests)))
+            }
+            Method::POST => {
+                create_backup(engine, directory, request.uri().query().unwrap_or("")).await
+            }
+            _ => Err(HttpError::method_not_allowed("GET, POST")),
+        };
+    }
+    if path == "/kv" {
+        if request.method() != Method::GET {
+            return Err(HttpError::method_not_allowed("GET"));
+        }
+        return list(&engine, request.uri().query().unwrap_or("")).await;
+    }
+    let key = match path.strip_prefix("/kv/") {
+        Some(key) => match percent_decode_str(key).decode_utf8() {
+            Ok(key) if !key.is_empty() => key.into_owned(),
+            Ok(_) => return Err(HttpError::new(StatusCode::BAD_REQUEST, "missing key")),
+            Err(_) => {
+                return Err(HttpError::new(
+                    StatusCode::BAD_REQUEST,
+                    "key is not valid utf-8",
+                ))
+            }
+        },
+        None => return Err(HttpError::new(StatusCode::NOT_FOUND, "not found")),
+    };
+
+    return match *request.method() {
+        Method::GET => get(&engine, key, wants_json(request.headers(), ACCEPT)).await,
+        Method::PUT => put(&engine, key, request).await,
+        Method::DELETE => {
+            with_engine(&engine, move |engine| engine.delete(&key)).await??;
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        _ => Err(HttpError::method_not_allowed("GET, PUT, DELETE")),
+    };
+}
+
+async fn get(engine: &SharedEngine, key: String, as_json: bool) -> HttpResult {
+    let value = with_engine(engine, move |engine| engine.get(&key)).await??;
+    if as_json {
+        return Ok(json_response(StatusCode::OK, &value.to_json()));
+    }
+    let content_type = match value {
+        Value::String(_) | Value::Integer(_) => TEXT_CONTENT_TYPE,
+        Value::Json(_) => JSON_CONTENT_TYPE,
+        Value::Blob(_) | Value::Typed(_, _) => BINARY_CONTENT_TYPE,
+    };
+    let data_type = format!("{:?}", value.get_data_type());
+    let mut response = Response::new(Full::new(Bytes::from(value_to_bytes(value)?)));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(data_type) = HeaderValue::from_str(&data_type) {
+        headers.insert(VALUE_TYPE_HEADER, data_type);
+    }
+    return Ok(response);
+}
+
+// With `If-None-Match: *` only missing keys are written
+async fn put(engine: &SharedEngine, key: String, request: Request<Incoming>) -> HttpResult {
+    let headers = request.headers().clone();
+    let only_missing = headers
+        .get(IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == b"*");
+    let body = match Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(error) if error.is::<LengthLimitError>() => {
+            return Err(HttpError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &error.to_string(),
+            ))
+        }
+        Err(error) => return Err(HttpError::new(StatusCode::BAD_REQUEST, &error.to_string())),
+    };
+
+    let value = if wants_json(&headers, CONTENT_TYPE) {
+        let document: serde_json::Value = serde_json::from_slice(&body).map_err(KvError::from)?;
+        Value::from_json(&document)?
+    } else if headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(BINARY_CONTENT_TYPE.as_bytes()))
+    {
+        Value::Blob(body.to_vec())
+    } else {
+        value_from_bytes(&body)
+    };
+
+    with_engine(engine, move |engine| {
+        if only_missing && lookup(engine, &key)?.is_some() {
+            return Err(KvError::KeyAlreadyExists { key });
+        }
+        return engine.put(&key, value);
+    })
+    .await??;
+    return Ok(empty_response(StatusCode::NO_CONTENT));
+}
+
+// The engine is only locked for the checkpoint, the files are stored while writes go on
+async fn create_backup(engine: SharedEngine, directory: PathBuf, query: &str) -> HttpResult {
+    let incremental = form_urlencoded::parse(query.as_bytes())
+        .any(|(name, value)| name == "incremental" && (value == "true" || value == "1"));
+    let manifest = tokio::task::spawn_blocking(move || {
+        let _backup = BACKUP_LOCK.lock();
+        let pending = backup::start_backup(engine.lock().as_mut(), &directory, incremental)?;
+        return backup::finish_backup(pending);
+    })
+    .await
+    .map_err(|error| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string()))??;
+    return Ok(json_response(
+        StatusCode::CREATED,
+        &serde_json::json!(manifest),
+    ));
+}
+
+// Listings are always json, every entry holds its key next to the value representation
+async fn list(engine: &SharedEngine, query: &str) -> HttpResult {
+    let prefix = form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "prefix")
+        .map(|(_, prefix)| prefix.into_owned())
+        .unwrap_or_default();
+    let entries = with_engine(engine, move |engine| engine.scan(&prefix)).await??;
+    let entries: Vec<serde_json::Value> = entries
+        .into_iter()
+        .map(|(key, value)| {
+            let mut document = value.to_json();
+            document["key"] = serde_json::Value::String(key);
+            document
+        })
+        .collect();
+    return Ok(json_response(
+        StatusCode::OK,
+        &serde_json::Value::Array(entries),
+    ));
+}
+
+fn wants_json(headers: &HeaderMap, name: hyper::header::HeaderName) -> bool {
+    return headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(JSON_CONTENT_TYPE));
+}
+
+fn json_response(status: StatusCode, document: &serde_json::Value) -> HttpResponse {
+    let mut response = Response::new(Full::new(Bytes::from(document.to_string())));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(JSON_CONTENT_TYPE));
+    return response;
+}
+
+fn empty_response(status: StatusCode) -> HttpResponse {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    return response;
+}
@@ -0,0 +1,679 @@
+use super::errors::ProtocolError;
+use super::resp::read_line;
+use super::{check_ttl_support, lookup, value_from_bytes, value_to_bytes};
+use crate::memkv::{KvError, StorageEngine, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncReadExt};
+
+pub const MAX_ITEM_SIZE: usize = 64 * 1024 * 1024; // 64 MB
+const MAX_KEY_SIZE: usize = 250;
+// Expiry times up to 30 days are relative, larger ones are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+// Items whose flags are kept at most, the least recently written ones are dropped beyond it
+const MAX_TRACKED_ITEMS: usize = 100_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemcachedCommand {
+    Get {
+        keys: Vec<String>,
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Increment {
+        key: String,
+        amount: u64,
+        is_decrement: bool,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+struct ItemMetadata {
+    // Version of the value the item was stored with, see `get_version`
+    version: u64,
+    flags: u32,
+    // Number of the memcached write, so storing the same data again changes the cas unique
+    generation: u64,
+    expires_at: Option<Instant>,
+}
+
+// Hash of the stored value, which changes with every write that changes the value through
+// any protocol and stays the same across restarts
+fn get_version(value: &Value) -> Result<u64, KvError> {
+    let mut hasher = Sha256::new();
+    hasher.update([value.get_data_type() as u8]);
+    hasher.update(value.clone().into_bytes()?);
+    return Ok(u64::from_be_bytes(
+        hasher.finalize()[..8].try_into().unwrap(),
+    ));
+}
+
+/// Client flags of the items and the numbers of their last memcached write. Cas uniques
+/// are derived from the stored value and that number, so writes through other protocols
+/// change them and uniques handed out before a restart never match again. Flags are kept in
+/// memory and read as 0 once another protocol changed the value, after a restart or once
+/// the item was among the least recently written beyond `max_items`.
+pub struct MemcachedItems {
+    items: HashMap<String, ItemMetadata>,
+    // Keys by generation, so the least recently written items are dropped first
+    keys: BTreeMap<u64, String>,
+    max_items: usize,
+    next_generation: u64,
+}
+
+impl MemcachedItems {
+    pub fn new() -> MemcachedItems {
+        return MemcachedItems::with_max_items(MAX_TRACKED_ITEMS);
+    }
+
+    fn with_max_items(max_items: usize) -> MemcachedItems {
+        return MemcachedItems {
+            items: HashMap::new(),
+            keys: BTreeMap::new(),
+            max_items,
+            // Generation 0 is left for values memcached did not write in this run
+            next_generation: 1,
+        };
+    }
+
+    fn update(
+        self: &mut Self,
+        key: &str,
+        value: &Value,
+        flags: u32,
+        ttl: Option<Duration>,
+    ) -> Result<(), KvError> {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        let item = ItemMetadata {
+            version: get_version(value)?,
+            flags,
+            generation,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        if let Some(previous) = self.items.insert(String::from(key), item) {
+            self.keys.remove(&previous.generation);
+        }
+        self.keys.insert(generation, String::from(key));
+        if self.items.len() > self.max_items {
+            self.prune();
+        }
+        return Ok(());
+    }
+
+    // Drops expired items, then the least recently written ones until a tenth of the limit
+    // is free again, so the expired items are not looked for on every write
+    fn prune(self: &mut Self) {
+        let now = Instant::now();
+        let keys = &mut self.keys;
+        self.items.retain(|_, item| {
+            let is_expired = item.expires_at.is_some_and(|expires_at| expires_at <= now);
+            if is_expired {
+                keys.remove(&item.generation);
+            }
+            return !is_expired;
+        });
+        let target = self.max_items - self.max_items / 10;
+        while self.items.len() > target {
+            match self.keys.pop_first() {
+                Some((_, key)) => self.items.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    // Flags and cas unique of the stored `value` of `key`
+    fn get(self: &Self, key: &str, value: &Value) -> Result<(u32, u64), KvError> {
+        let version = get_version(value)?;
+        let (flags, generation) = match self.items.get(key) {
+            Some(item) if item.version == version => (item.flags, item.generation),
+            _ => (0, 0),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(version.to_be_bytes());
+        hasher.update(generation.to_be_bytes());
+        let cas = u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap());
+        return Ok((flags, cas));
+    }
+
+    fn remove(self: &mut Self, key: &str) {
+        if let Some(item) = self.items.remove(key) {
+            self.keys.remove(&item.generation);
+        }
+    }
+}
+
+/// Reads the next command including the data block of storage commands, `None` once the
+/// client closed the connection
+pub async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<MemcachedCommand>, ProtocolError> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let line = match std::str::from_utf8(&line) {
+        Ok(line) => line,
+        Err(_) => return Err(bad_format()),
+    };
+    let mut parts = line.split(' ').filter(|part| !part.is_empty());
+    let name = parts.next().unwrap_or("");
+    let arguments: Vec<&str> = parts.collect();
+
+    let command = match name {
+        "get" | "gets" => {
+            if arguments.is_empty() {
+                return Err(ProtocolError::UnknownCommand {
+                    name: String::from(name),
+                });
+            }
+            MemcachedCommand::Get {
+                keys: arguments
+                    .iter()
+                    .map(|key| parse_key(key))
+                    .collect::<Result<_, _>>()?,
+                with_cas: name == "gets",
+            }
+        }
+        "set" | "add" | "replace" | "cas" => {
+            let size = if name == "cas" { 5 } else { 4 };
+            let noreply = parse_noreply(&arguments, size)?;
+            let mode = match name {
+                "set" => StoreMode::Set,
+                "add" => StoreMode::Add,
+                "replace" => StoreMode::Replace,
+                _ => StoreMode::Cas(parse_number(arguments[4])?),
+            };
+            let data_size: usize = parse_number(arguments[3])?;
+            if data_size > MAX_ITEM_SIZE {
+                return Err(ProtocolError::FrameTooLarge {
+                    size: data_size as u32,
+                });
+            }
+            let mut data = vec![0; data_size + 2];
+            reader.read_exact(&mut data).await?;
+            if !data.ends_with(b"\r\n") {
+                return Err(ProtocolError::Malformed {
+                    reason: String::from("bad data chunk"),
+                });
+            }
+            data.truncate(data_size);
+            MemcachedCommand::Store {
+                mode,
+                key: parse_key(arguments[0])?,
+                flags: parse_number(arguments[1])?,
+                exptime: parse_number(arguments[2])?,
+                data,
+                noreply,
+            }
+        }
+        "delete" => MemcachedCommand::Delete {
+            noreply: parse_noreply(&arguments, 1)?,
+            key: parse_key(arguments[0])?,
+        },
+        "incr" | "decr" => MemcachedCommand::Increment {
+            noreply: parse_noreply(&arguments, 2)?,
+            key: parse_key(arguments[0])?,
+            amount: parse_number(arguments[1])?,
+            is_decrement: name == "decr",
+        },
+        "version" => MemcachedCommand::Version,
+        "quit" => MemcachedCommand::Quit,
+        _ => {
+            return Err(ProtocolError::UnknownCommand {
+                name: String::from(name),
+            })
+        }
+    };
+    return Ok(Some(command));
+}
+
+fn bad_format() -> ProtocolError {
+    return ProtocolError::Malformed {
+        reason: String::from("bad command line format"),
+    };
+}
+
+// Checks that there are `size` arguments plus an optional trailing `noreply`
+fn parse_noreply(arguments: &[&str], size: usize) -> Result<bool, ProtocolError> {
+    return match arguments.len() {
+        length if length == size => Ok(false),
+        length if length == size + 1 && arguments[size] == "noreply" => Ok(true),
+        _ => Err(bad_format()),
+    };
+}
+
+fn parse_number<T: std::str::FromStr>(argument: &str) -> Result<T, ProtocolError> {
+    return argument.parse().map_err(|_| bad_format());
+}
+
+fn parse_key(key: &str) -> Result<String, ProtocolError> {
+    if key.len() > MAX_KEY_SIZE || key.chars().any(|c| c.is_control()) {
+        return Err(bad_format());
+    }
+    return Ok(String::from(key));
+}
+
+/// Runs a command and returns the reply, which is empty for `noreply` commands
+pub fn execute(
+    engine: &mut dyn StorageEngine,
+    items: &mut MemcachedItems,
+    command: MemcachedCommand,
+) -> Vec<u8> {
+    let (reply, noreply) = match command {
+        MemcachedCommand::Get { keys, with_cas } => (get(engine, items, &keys, with_cas), false),
+        MemcachedCommand::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        } => (
+            store(engine, items, mode, &key, flags, exptime, &data),
+            noreply,
+        ),
+        MemcachedCommand::Delete { key, noreply } => (delete(engine, items, &key), noreply),
+        MemcachedCommand::Increment {
+            key,
+            amount,
+            is_decrement,
+            noreply,
+        } => (
+            increment(engine, items, &key, amount, is_decrement),
+            noreply,
+        ),
+        MemcachedCommand::Version => (
+            Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()),
+            false,
+        ),
+        MemcachedCommand::Quit => (Ok(vec![]), true),
+    };
+    if noreply {
+        return vec![];
+    }
+    return reply.unwrap_or_else(|error| format!("SERVER_ERROR {}\r\n", error).into_bytes());
+}
+
+fn get(
+    engine: &mut dyn StorageEngine,
+    items: &mut MemcachedItems,
+    keys: &[String],
+    with_cas: bool,
+) -> Result<Vec<u8>, KvError> {
+    let mut reply = vec![];
+    for key in keys {
+        let value = match lookup(engine, key)? {
+            Some(value) => value,
+            None => {
+                items.remove(key);
+                continue;
+            }
+        };
+        let (flags, cas) = items.get(key, &value)?;
+        let data = value_to_bytes(value)?;
+        reply.extend(format!("VALUE {} {} {}", key, flags, data.len()).as_bytes());
+        if with_cas {
+            reply.extend(format!(" {}", cas).as_bytes());
+        }
+        reply.extend(b"\r\n");
+        reply.extend(data);
+        reply.extend(b"\r\n");
+    }
+    reply.extend(b"END\r\n");
+    return Ok(reply);
+}
+
+fn store(
+    engine: &mut dyn StorageEngine,
+    items: &mut MemcachedItems,
+    mode: StoreMode,
+    key: &str,
+    flags: u32,
+    exptime: i64,
+    data: &[u8],
+) -> Result<Vec<u8>, KvError> {
+    let ttl = get_ttl(exptime);
+    if ttl.is_some_and(|ttl| !ttl.is_zero()) {
+        check_ttl_support(engine)?;
+    }
+    let current = lookup(engine, key)?;
+    let exists = current.is_some();
+    let is_stored = match (mode, &current) {
+        (StoreMode::Set, _) => true,
+        (StoreMode::Add, _) => !exists,
+        (StoreMode::Replace, _) => exists,
+        (StoreMode::Cas(_), None) => return Ok(b"NOT_FOUND\r\n".to_vec()),
+        (StoreMode::Cas(cas), Some(value)) => items.get(key, value)?.1 == cas,
+    };
+    if !is_stored {
+        if let StoreMode::Cas(_) = mode {
+            return Ok(b"EXISTS\r\n".to_vec());
+        }
+        return Ok(b"NOT_STORED\r\n".to_vec());
+    }
+
+    match ttl {
+        // Items which are already expired are stored and removed right away
+        Some(ttl) if ttl.is_zero() => {
+            if exists {
+                engine.delete(key)?;
+            }
+            items.remove(key);
+        }
+        ttl => {
+            let value = value_from_bytes(data);
+            engine.put(key, value.clone())?;
+            if ttl.is_some() {
+                engine.expire(key, ttl)?;
+            }
+            items.update(key, &value, flags, ttl)?;
+        }
+    }
+    return Ok(b"STORED\r\n".to_vec());
+}
+
+// `None` for items which never expire
+fn get_ttl(exptime: i64) -> Option<Duration> {
+    if exptime == 0 {
+        return None;
+    }
+    if exptime < 0 {
+        return Some(Duration::ZERO);
+    }
+    if exptime <= MAX_RELATIVE_EXPTIME {
+        return Some(Duration::from_secs(exptime as u64));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    return Some(Duration::from_secs(exptime as u64).saturating_sub(now));
+}
+
+fn delete(
+    engine: &mut dyn StorageEngine,
+    items: &mut MemcachedItems,
+    key: &str,
+) -> Result<Vec<u8>, KvError> {
+    items.remove(key);
+    return match engine.delete(key) {
+        Ok(()) => Ok(b"DELETED\r\n".to_vec()),
+        Err(KvError::KeyDoesNotExist { .. }) => Ok(b"NOT_FOUND\r\n".to_vec()),
+        Err(error) => Err(error),
+    };
+}
+
+fn increment(
+    engine: &mut dyn StorageEngine,
+    items: &mut MemcachedItems,
+    key: &str,
+    amount: u64,
+    is_decrement: bool,
+) -> Result<Vec<u8>, KvError> {
+    let current = match lookup(engine, key)? {
+        Some(value) => value,
+        None => return Ok(b"NOT_FOUND\r\n".to_vec()),
+    };
+    let number = match &current {
+        Value::Integer(number) => Some(*number),
+        Value::String(text) => text.parse::<u64>().ok(),
+        _ => None,
+    };
+    let number = match number {
+        Some(number) => number,
+        None => {
+            return Ok(b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec())
+        }
+    };
+    // Increments wrap around while decrements stop at zero like in memcached
+    let number = if is_decrement {
+        number.saturating_sub(amount)
+    } else {
+        number.wrapping_add(amount)
+    };
+
+    let (flags, _) = items.get(key, &current)?;
+    let ttl = match engine.supports_ttl() {
+        true => engine.get_ttl(key)?,
+        false => None,
+    };
+    engine.put(key, Value::Integer(number))?;
+    if ttl.is_some() {
+        engine.expire(key, ttl)?;
+    }
+    items.update(key, &Value::Integer(number), flags, ttl)?;
+    return Ok(format!("{}\r\n", number).into_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, read_command, MemcachedCommand, MemcachedItems, StoreMode};
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{MemKvPage, MemoryEngine, StorageEngine, Value, KV_PAGE_SIZE};
+    use crate::server::errors::ProtocolError;
+    use std::path::Path;
+    use std::time::Duration;
+
+    async fn run(
+        engine: &mut dyn StorageEngine,
+        items: &mut MemcachedItems,
+        input: &str,
+    ) -> String {
+        let mut reader = input.as_bytes();
+        let command = read_command(&mut reader).await.unwrap().unwrap();
+        return String::from_utf8(execute(engine, items, command)).unwrap();
+    }
+
+    // Cas unique of the single item of a `gets` reply
+    fn get_cas(reply: &str) -> u64 {
+        let line = reply.lines().next().unwrap();
+        return line.rsplit(' ').next().unwrap().parse().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        const KEYSPACE: &str = "test_memcached_commands";
+        let _page_files = PageFilesGuard::new(Path::new(KEYSPACE)).unwrap();
+        let mut page = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+        let engine: &mut dyn StorageEngine = &mut page;
+        let mut items = MemcachedItems::new();
+        let items = &mut items;
+
+        assert_eq!(run(engine, items, "get a\r\n").await, "END\r\n");
+        assert_eq!(
+            run(engine, items, "set a 5 0 2\r\nhi\r\n").await,
+            "STORED\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "add a 0 0 2\r\nho\r\n").await,
+            "NOT_STORED\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "replace b 0 0 2\r\nho\r\n").await,
+            "NOT_STORED\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "add b 0 0 1\r\n7\r\n").await,
+            "STORED\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "get a b c\r\n").await,
+            "VALUE a 5 2\r\nhi\r\nVALUE b 0 1\r\n7\r\nEND\r\n"
+        );
+        let cas = get_cas(&run(engine, items, "gets a\r\n").await);
+        assert_eq!(
+            run(
+                engine,
+                items,
+                &format!("cas a 1 0 3 {}\r\nnew\r\n", cas + 1)
+            )
+            .await,
+            "EXISTS\r\n"
+        );
+        assert_eq!(
+            run(engine, items, &format!("cas a 1 0 3 {}\r\nnew\r\n", cas)).await,
+            "STORED\r\n"
+        );
+        assert_eq!(
+            run(engine, items, &format!("cas a 1 0 3 {}\r\nnew\r\n", cas)).await,
+            "EXISTS\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "cas c 1 0 3 1\r\nnew\r\n").await,
+            "NOT_FOUND\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "get a\r\n").await,
+            "VALUE a 1 3\r\nnew\r\nEND\r\n"
+        );
+
+        // Storing the same data again changes the cas unique
+        let cas = get_cas(&run(engine, items, "gets a\r\n").await);
+        assert_eq!(
+            run(engine, items, "set a 1 0 3\r\nnew\r\n").await,
+            "STORED\r\n"
+        );
+        assert_ne!(get_cas(&run(engine, items, "gets a\r\n").await), cas);
+
+        // Writes through other protocols change the cas unique and reset the flags
+        let cas = get_cas(&run(engine, items, "gets a\r\n").await);
+        engine.put("a", Value::String(String::from("old"))).unwrap();
+        assert_eq!(
+            run(engine, items, &format!("cas a 1 0 3 {}\r\nnew\r\n", cas)).await,
+            "EXISTS\r\n"
+        );
+        assert_eq!(
+            run(engine, items, "get a\r\n").await,
+            "VALUE a 0 3\r\nold\r\nEND\r\n"
+        );
+
+        // Cas uniques from before a restart don't match
+        let cas = get_cas(&run(engine, items, "gets a\r\n").await);
+        assert_eq!(
+            run(engine, items, &format!("cas a 1 0 3 {}\r\nnew\r\n", cas)).await,
+            "STORED\r\n"
+        );
+        let cas = get_cas(&run(engine, items, "gets a\r\n").await);
+        let mut restarted_items = MemcachedItems::new();
+        assert_eq!(
+            run(
+                engine,
+                &mut restarted_items,
+                &format!("cas a 1 0 3 {}\r\nnew\r\n", cas)
+            )
+            .await,
+            "EXISTS\r\n"
+        );
+
+        assert_eq!(run(engine, items, "incr b 5\r\n").await, "12\r\n");
+        assert_eq!(run(engine, items, "decr b 20\r\n").await, "0\r\n");
+        assert_eq!(run(engine, items, "incr c 1\r\n").await, "NOT_FOUND\r\n");
+        assert!(run(engine, items, "incr a 1\r\n")
+            .await
+            .starts_with("CLIENT_ERROR"));
+        assert_eq!(run(engine, items, "delete b noreply\r\n").await, "");
+        assert_eq!(run(engine, items, "delete b\r\n").await, "NOT_FOUND\r\n");
+
+        assert_eq!(
+            run(engine, items, "set t 0 100 1\r\n1\r\n").await,
+            "STORED\r\n"
+        );
+        assert_eq!(run(engine, items, "incr t 1\r\n").await, "2\r\n");
+        // Increments keep the expiry time of the item
+        assert!(engine.get_ttl("t").unwrap().is_some());
+        assert_eq!(
+            run(engine, items, "set t 0 -1 1\r\nx\r\n").await,
+            "STORED\r\n"
+        );
+        assert_eq!(run(engine, items, "get t\r\n").await, "END\r\n");
+
+        let mut reader: &[u8] = b"set a 0 0 2\r\nabc\r\n";
+        assert!(matches!(
+            read_command(&mut reader).await,
+            Err(ProtocolError::Malformed { .. })
+        ));
+        let mut reader: &[u8] = b"set a 0 0\r\n";
+        assert!(read_command(&mut reader).await.is_err());
+        let mut reader: &[u8] = b"flush_all\r\n";
+        assert!(matches!(
+            read_command(&mut reader).await,
+            Err(ProtocolError::UnknownCommand { .. })
+        ));
+        let mut reader: &[u8] = b"set a 1 2 0 noreply\r\n\r\n";
+        assert_eq!(
+            read_command(&mut reader).await.unwrap().unwrap(),
+            MemcachedCommand::Store {
+                mode: StoreMode::Set,
+                key: String::from("a"),
+                flags: 1,
+                exptime: 2,
+                data: vec![],
+                noreply: true,
+            }
+        );
+
+        drop(page);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_support() {
+        let engine = &mut MemoryEngine::new();
+        let items = &mut MemcachedItems::new();
+        assert_eq!(
+            run(engine, items, "set a 0 100 1\r\n1\r\n").await,
+            "SERVER_ERROR storage engine does not support expire\r\n"
+        );
+        assert_eq!(run(engine, items, "get a\r\n").await, "END\r\n");
+        assert_eq!(
+            run(engine, items, "set a 0 0 1\r\n1\r\n").await,
+            "STORED\r\n"
+        );
+        assert_eq!(run(engine, items, "incr a 1\r\n").await, "2\r\n");
+    }
+
+    #[test]
+    fn test_tracked_items() {
+        let mut items = MemcachedItems::with_max_items(10);
+        let value = Value::Integer(1);
+        items
+            .update("expired", &value, 1, Some(Duration::ZERO))
+            .unwrap();
+        for i in 0..10 {
+            items
+                .update(&format!("key-{}", i), &value, 1, None)
+                .unwrap();
+        }
+        items.update("key-0", &value, 2, None).unwrap();
+        items.update("key-10", &value, 1, None).unwrap();
+
+        // Expired items go first, then the least recently written ones
+        assert_eq!(items.items.len(), 9);
+        assert_eq!(items.keys.len(), 9);
+        assert_eq!(items.get("expired", &value).unwrap().0, 0);
+        assert_eq!(items.get("key-1", &value).unwrap().0, 0);
+        assert_eq!(items.get("key-0", &value).unwrap().0, 2);
+        assert_eq!(items.get("key-10", &value).unwrap().0, 1);
+    }
+}
@@ -0,0 +1,79 @@
+pub mod binary_protocol;
+pub mod errors;
+pub mod http;
+pub mod memcached;
+pub mod resp;
+pub mod resp_commands;
+pub mod tcp_server;
+pub use tcp_server::TcpServer;
+
+use crate::memkv::{KvError, StorageEngine, Value};
+use parking_lot::Mutex;
+use std::io;
+use std::sync::Arc;
+
+// Engine calls can block on disk io, compactions or backups and the lock can be held for as
+// long, so they only run on blocking threads, see `with_engine`
+pub type SharedEngine = Arc<Mutex<Box<dyn StorageEngine>>>;
+
+/// Runs `call` with the locked engine on a blocking thread, so waiting for the engine never
+/// stalls the async workers which serve the other connections
+pub async fn with_engine<T, F>(engine: &SharedEngine, call: F) -> Result<T, KvError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn StorageEngine) -> T + Send + 'static,
+{
+    let engine = engine.clone();
+    return tokio::task::spawn_blocking(move || call(engine.lock().as_mut()))
+        .await
+        .map_err(|error| KvError::Io(io::Error::other(error)));
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    // Length-prefixed frames, see `binary_protocol`
+    Binary,
+    // Redis serialization protocol, see `resp`
+    Resp,
+    // Memcached text protocol, see `memcached`
+    Memcached,
+    // REST api over HTTP/1.1, see `http`
+    Http,
+}
+
+// The text based protocols only know byte strings, which are stored as strings if possible
+// so they stay readable through the other protocols
+pub fn value_from_bytes(data: &[u8]) -> Value {
+    return match std::str::from_utf8(data) {
+        Ok(text) => Value::String(String::from(text)),
+        Err(_) => Value::Blob(data.to_vec()),
+    };
+}
+
+pub fn value_to_bytes(value: Value) -> Result<Vec<u8>, KvError> {
+    return match value {
+        Value::String(text) => Ok(text.into_bytes()),
+        Value::Integer(number) => Ok(number.to_string().into_bytes()),
+        Value::Blob(data) | Value::Typed(_, data) => Ok(data),
+        Value::Json(document) => Ok(serde_json::to_vec(&document)?),
+    };
+}
+
+/// Fails on engines without expiry, so values with a time to live are not stored without it
+pub fn check_ttl_support(engine: &dyn StorageEngine) -> Result<(), KvError> {
+    if engine.supports_ttl() {
+        return Ok(());
+    }
+    return Err(KvError::UnsupportedOperation {
+        operation: String::from("expire"),
+    });
+}
+
+/// Returns `None` for missing keys, which most text protocol commands don't treat as error
+pub fn lookup(engine: &dyn StorageEngine, key: &str) -> Result<Option<Value>, KvError> {
+    return match engine.get(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(KvError::KeyDoesNotExist { .. }) => Ok(None),
+        Err(error) => Err(error),
+    };
+}
@@ -0,0 +1,226 @@
+use super::errors::ProtocolError;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+// Bulk strings are limited like frames of the binary protocol, inline commands like in redis
+pub const MAX_BULK_SIZE: usize = 64 * 1024 * 1024; // 64 MB
+const MAX_INLINE_SIZE: u64 = 64 * 1024; // 64 KB
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+
+/// Reply of a command, encoded for the protocol version the client selected with `HELLO`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Vec<u8>),
+    Array(Vec<RespValue>),
+    // RESP2 has no map type, maps are sent as flat arrays of keys and values instead
+    Map(Vec<(RespValue, RespValue)>),
+    Null,
+}
+
+impl RespValue {
+    pub fn ok() -> RespValue {
+        return RespValue::SimpleString(String::from("OK"));
+    }
+
+    pub fn error(message: &str) -> RespValue {
+        return RespValue::Error(format!("ERR {}", message));
+    }
+
+    pub fn encode(self: &Self, version: u8, output: &mut Vec<u8>) {
+        match self {
+            RespValue::SimpleString(text) => {
+                output.push(b'+');
+                output.extend(text.as_bytes());
+            }
+            RespValue::Error(message) => {
+                output.push(b'-');
+                output.extend(message.replace(['\r', '\n'], " ").as_bytes());
+            }
+            RespValue::Integer(number) => {
+                output.push(b':');
+                output.extend(number.to_string().as_bytes());
+            }
+            RespValue::BulkString(data) => {
+                output.push(b'$');
+                output.extend(data.len().to_string().as_bytes());
+                output.extend(b"\r\n");
+                output.extend(data);
+            }
+            RespValue::Array(items) => {
+                output.push(b'*');
+                output.extend(items.len().to_string().as_bytes());
+                output.extend(b"\r\n");
+                for item in items {
+                    item.encode(version, output);
+                }
+                return;
+            }
+            RespValue::Map(entries) => {
+                if version >= 3 {
+                    output.push(b'%');
+                    output.extend(entries.len().to_string().as_bytes());
+                } else {
+                    output.push(b'*');
+                    output.extend((entries.len() * 2).to_string().as_bytes());
+                }
+                output.extend(b"\r\n");
+                for (key, value) in entries {
+                    key.encode(version, output);
+                    value.encode(version, output);
+                }
+                return;
+            }
+            RespValue::Null if version >= 3 => output.push(b'_'),
+            RespValue::Null => output.extend(b"$-1"),
+        }
+        output.extend(b"\r\n");
+    }
+}
+
+/// Reads the arguments of the next command, either sent as an array of bulk strings or as
+/// a plain inline line. Returns `None` once the client closed the connection.
+pub async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<Vec<u8>>>, ProtocolError> {
+    loop {
+        let line = match read_line(reader).await? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'*') {
+            let arguments: Vec<Vec<u8>> = line
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|argument| !argument.is_empty())
+                .map(|argument| argument.to_vec())
+                .collect();
+            // Empty lines are ignored like in redis
+            if arguments.is_empty() {
+                continue;
+            }
+            return Ok(Some(arguments));
+        }
+
+        let count = parse_size(&line[1..], MAX_ARGUMENTS)?;
+        let mut arguments = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            let header = match read_line(reader).await? {
+                Some(header) => header,
+                None => return Err(unexpected_end()),
+            };
+            if header.first() != Some(&b'$') {
+                return Err(ProtocolError::Malformed {
+                    reason: format!("expected '$', got '{}'", String::from_utf8_lossy(&header)),
+                });
+            }
+            let size = parse_size(&header[1..], MAX_BULK_SIZE)?;
+            let mut argument = vec![0; size + 2];
+            reader.read_exact(&mut argument).await?;
+            if !argument.ends_with(b"\r\n") {
+                return Err(ProtocolError::Malformed {
+                    reason: String::from("bulk string is not terminated by CRLF"),
+                });
+            }
+            argument.truncate(size);
+            arguments.push(argument);
+        }
+        if !arguments.is_empty() {
+            return Ok(Some(arguments));
+        }
+    }
+}
+
+/// Returns the line without its line break, `None` if the stream ended before the line started
+pub async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<u8>>, ProtocolError> {
+    let mut line = vec![];
+    reader
+        .take(MAX_INLINE_SIZE)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() as u64 + 1 >= MAX_INLINE_SIZE {
+            return Err(ProtocolError::Malformed {
+                reason: String::from("too big inline request"),
+            });
+        }
+        return Err(unexpected_end());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    return Ok(Some(line));
+}
+
+fn parse_size(text: &[u8], max_size: usize) -> Result<usize, ProtocolError> {
+    let size = std::str::from_utf8(text)
+        .ok()
+        .and_then(|text| text.parse::<usize>().ok());
+    return match size {
+        Some(size) if size <= max_size => Ok(size),
+        _ => Err(ProtocolError::Malformed {
+            reason: format!("invalid length '{}'", String::from_utf8_lossy(text)),
+        }),
+    };
+}
+
+fn unexpected_end() -> ProtocolError {
+    return ProtocolError::Malformed {
+        reason: String::from("unexpected end of stream"),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_command, RespValue};
+    use crate::server::errors::ProtocolError;
+
+    #[tokio::test]
+    async fn test_read_and_encode() {
+        let mut data: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4\r\nb\r\nc\r\n\r\nGET  a\r\n";
+        let command = read_command(&mut data).await.unwrap().unwrap();
+        assert_eq!(
+            command,
+            vec![b"SET".to_vec(), b"a".to_vec(), b"b\r\nc".to_vec()]
+        );
+        let command = read_command(&mut data).await.unwrap().unwrap();
+        assert_eq!(command, vec![b"GET".to_vec(), b"a".to_vec()]);
+        assert!(read_command(&mut data).await.unwrap().is_none());
+
+        let mut data: &[u8] = b"*2\r\n$4\r\nPING\r\n";
+        assert!(matches!(
+            read_command(&mut data).await,
+            Err(ProtocolError::Malformed { .. })
+        ));
+        let mut data: &[u8] = b"*1\r\n$-4\r\n";
+        assert!(read_command(&mut data).await.is_err());
+
+        let reply = RespValue::Array(vec![
+            RespValue::ok(),
+            RespValue::Integer(-2),
+            RespValue::BulkString(b"abc".to_vec()),
+            RespValue::Null,
+            RespValue::Map(vec![(
+                RespValue::BulkString(b"proto".to_vec()),
+                RespValue::Integer(3),
+            )]),
+        ]);
+        let mut output = vec![];
+        reply.encode(2, &mut output);
+        assert_eq!(
+            output,
+            b"*5\r\n+OK\r\n:-2\r\n$3\r\nabc\r\n$-1\r\n*2\r\n$5\r\nproto\r\n:3\r\n"
+        );
+        let mut output = vec![];
+        reply.encode(3, &mut output);
+        assert_eq!(
+            output,
+            b"*5\r\n+OK\r\n:-2\r\n$3\r\nabc\r\n_\r\n%1\r\n$5\r\nproto\r\n:3\r\n"
+        );
+    }
+}
@@ -0,0 +1,564 @@
+use super::resp::RespValue;
+use super::{check_ttl_support, lookup, value_from_bytes, value_to_bytes};
+use crate::memkv::{KvError, StorageEngine, Value};
+use std::time::Duration;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// State of a RESP connection, `HELLO` switches the protocol version of all later replies
+pub struct RespSession {
+    pub version: u8,
+    pub is_closing: bool,
+}
+
+impl RespSession {
+    pub fn new() -> RespSession {
+        return RespSession {
+            version: 2,
+            is_closing: false,
+        };
+    }
+}
+
+impl From<KvError> for RespValue {
+    fn from(error: KvError) -> Self {
+        return RespValue::error(&error.to_string());
+    }
+}
+
+// Error replies are returned as `Err` so commands can bail out with `?`
+type CommandResult = Result<RespValue, RespValue>;
+
+/// Runs a redis command against the engine, arguments include the command name
+pub fn execute(
+    engine: &mut dyn StorageEngine,
+    session: &mut RespSession,
+    arguments: &[Vec<u8>],
+) -> RespValue {
+    let name = String::from_utf8_lossy(&arguments[0]).to_ascii_lowercase();
+    let arguments = &arguments[1..];
+    let result = match name.as_str() {
+        "ping" => ping(arguments),
+        "echo" => echo(arguments),
+        "hello" => hello(session, arguments),
+        "quit" => {
+            session.is_closing = true;
+            Ok(RespValue::ok())
+        }
+        "get" => get(engine, arguments),
+        "set" => set(engine, arguments),
+        "del" => del(engine, arguments),
+        "exists" => exists(engine, arguments),
+        "incr" => incr(engine, arguments),
+        "keys" => keys(engine, arguments),
+        "scan" => scan(engine, arguments),
+        "expire" => expire(engine, arguments),
+        "ttl" => ttl(engine, arguments, Duration::from_secs(1)),
+        "pttl" => ttl(engine, arguments, Duration::from_millis(1)),
+        _ => {
+            let arguments: Vec<String> = arguments
+                .iter()
+                .map(|argument| format!("'{}'", String::from_utf8_lossy(argument)))
+                .collect();
+            Err(RespValue::error(&format!(
+                "unknown command '{}', with args beginning with: {}",
+                name,
+                arguments.join(" ")
+            )))
+        }
+    };
+    return result.unwrap_or_else(|error| error);
+}
+
+fn check_arity(
+    name: &str,
+    arguments: &[Vec<u8>],
+    min: usize,
+    max: Option<usize>,
+) -> Result<(), RespValue> {
+    if arguments.len() < min || max.is_some_and(|max| arguments.len() > max) {
+        return Err(RespValue::error(&format!(
+            "wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+    return Ok(());
+}
+
+// Keys of the store are strings while redis keys may be arbitrary bytes
+fn parse_key(argument: &[u8]) -> Result<String, RespValue> {
+    return match std::str::from_utf8(argument) {
+        Ok(key) => Ok(String::from(key)),
+        Err(_) => Err(RespValue::error("keys must be valid utf-8")),
+    };
+}
+
+fn parse_integer(argument: &[u8]) -> Result<i64, RespValue> {
+    return std::str::from_utf8(argument)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| RespValue::error("value is not an integer or out of range"));
+}
+
+fn ping(arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("ping", arguments, 0, Some(1))?;
+    return match arguments.first() {
+        Some(message) => Ok(RespValue::BulkString(message.clone())),
+        None => Ok(RespValue::SimpleString(String::from("PONG"))),
+    };
+}
+
+fn echo(arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("echo", arguments, 1, Some(1))?;
+    return Ok(RespValue::BulkString(arguments[0].clone()));
+}
+
+fn hello(session: &mut RespSession, arguments: &[Vec<u8>]) -> CommandResult {
+    if let Some(version) = arguments.first() {
+        session.version = match parse_integer(version) {
+            Ok(version @ 2..=3) => version as u8,
+            _ => {
+                return Err(RespValue::Error(String::from(
+                    "NOPROTO unsupported protocol version",
+                )))
+            }
+        };
+    }
+    let field = |name: &str| RespValue::BulkString(name.as_bytes().to_vec());
+    return Ok(RespValue::Map(vec![
+        (field("server"), field("rdkv")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), RespValue::Integer(session.version as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), RespValue::Array(vec![])),
+    ]));
+}
+
+fn get(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("get", arguments, 1, Some(1))?;
+    return match lookup(engine, &parse_key(&arguments[0])?)? {
+        Some(value) => Ok(RespValue::BulkString(value_to_bytes(value)?)),
+        None => Ok(RespValue::Null),
+    };
+}
+
+fn set(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("set", arguments, 2, None)?;
+    let key = parse_key(&arguments[0])?;
+    let mut ttl = None;
+    let mut only_missing = false;
+    let mut only_existing = false;
+    let mut options = arguments[2..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"nx" => only_missing = true,
+            b"xx" => only_existing = true,
+            unit @ (b"ex" | b"px") if ttl.is_none() => {
+                let amount = match options.next() {
+                    Some(amount) => parse_integer(amount)?,
+                    None => return Err(RespValue::error("syntax error")),
+                };
+                if amount <= 0 {
+                    return Err(RespValue::error("invalid expire time in 'set' command"));
+                }
+                ttl = Some(match unit {
+                    b"ex" => Duration::from_secs(amount as u64),
+                    _ => Duration::from_millis(amount as u64),
+                });
+            }
+            _ => return Err(RespValue::error("syntax error")),
+        }
+    }
+    if only_missing && only_existing {
+        return Err(RespValue::error("syntax error"));
+    }
+    if ttl.is_some() {
+        check_ttl_support(engine)?;
+    }
+
+    let exists = lookup(engine, &key)?.is_some();
+    if (only_missing && exists) || (only_existing && !exists) {
+        return Ok(RespValue::Null);
+    }
+    engine.put(&key, value_from_bytes(&arguments[1]))?;
+    if ttl.is_some() {
+        engine.expire(&key, ttl)?;
+    }
+    return Ok(RespValue::ok());
+}
+
+fn del(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("del", arguments, 1, None)?;
+    let mut deleted = 0;
+    for argument in arguments {
+        match engine.delete(&parse_key(argument)?) {
+            Ok(()) => deleted += 1,
+            Err(KvError::KeyDoesNotExist { .. }) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    return Ok(RespValue::Integer(deleted));
+}
+
+fn exists(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("exists", arguments, 1, None)?;
+    let mut count = 0;
+    for argument in arguments {
+        if lookup(engine, &parse_key(argument)?)?.is_some() {
+            count += 1;
+        }
+    }
+    return Ok(RespValue::Integer(count));
+}
+
+fn incr(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("incr", arguments, 1, Some(1))?;
+    let key = parse_key(&arguments[0])?;
+    let current = lookup(engine, &key)?;
+    let ttl = match current {
+        Some(_) if engine.supports_ttl() => engine.get_ttl(&key)?,
+        _ => None,
+    };
+    let number = match current {
+        None => 0,
+        Some(Value::Integer(number)) => i64::try_from(number).unwrap_or(i64::MAX),
+        Some(Value::String(text)) => parse_integer(text.as_bytes())?,
+        Some(_) => return Err(RespValue::error("value is not an integer or out of range")),
+    };
+    let number = number
+        .checked_add(1)
+        .ok_or_else(|| RespValue::error("increment or decrement would overflow"))?;
+
+    // Integer values are unsigned, negative counters are kept as strings
+    let value = match u64::try_from(number) {
+        Ok(number) => Value::Integer(number),
+        Err(_) => Value::String(number.to_string()),
+    };
+    engine.put(&key, value)?;
+    // Overwriting a key drops its expiry, but incrementing must keep it
+    if ttl.is_some() {
+        engine.expire(&key, ttl)?;
+    }
+    return Ok(RespValue::Integer(number));
+}
+
+fn keys(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("keys", arguments, 1, Some(1))?;
+    let keys = find_keys(engine, &arguments[0])?;
+    return Ok(RespValue::Array(
+        keys.into_iter()
+            .map(|key| RespValue::BulkString(key.into_bytes()))
+            .collect(),
+    ));
+}
+
+// The cursor is the position in the ordered list of matching keys
+fn scan(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("scan", arguments, 1, None)?;
+    let cursor = match usize::try_from(parse_integer(&arguments[0])?) {
+        Ok(cursor) => cursor,
+        Err(_) => return Err(RespValue::error("invalid cursor")),
+    };
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = arguments[1..].iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return Err(RespValue::error("syntax error")),
+        };
+        match option.to_ascii_lowercase().as_slice() {
+            b"match" => pattern = value,
+            b"count" => {
+                count = match usize::try_from(parse_integer(value)?) {
+                    Ok(count) if count > 0 => count,
+                    _ => return Err(RespValue::error("syntax error")),
+                }
+            }
+            _ => return Err(RespValue::error("syntax error")),
+        }
+    }
+
+    let keys = find_keys(engine, pattern)?;
+    let end = cursor.saturating_add(count).min(keys.len());
+    let next_cursor = if end < keys.len() { end } else { 0 };
+    let batch = keys
+        .into_iter()
+        .skip(cursor)
+        .take(end.saturating_sub(cursor))
+        .map(|key| RespValue::BulkString(key.into_bytes()))
+        .collect();
+    return Ok(RespValue::Array(vec![
+        RespValue::BulkString(next_cursor.to_string().into_bytes()),
+        RespValue::Array(batch),
+    ]));
+}
+
+fn expire(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>]) -> CommandResult {
+    check_arity("expire", arguments, 2, Some(2))?;
+    let key = parse_key(&arguments[0])?;
+    let seconds = parse_integer(&arguments[1])?;
+    // Like in redis a time in the past deletes the key right away
+    let result = if seconds <= 0 {
+        engine.delete(&key)
+    } else {
+        engine.expire(&key, Some(Duration::from_secs(seconds as u64)))
+    };
+    return match result {
+        Ok(()) => Ok(RespValue::Integer(1)),
+        Err(KvError::KeyDoesNotExist { .. }) => Ok(RespValue::Integer(0)),
+        Err(error) => Err(error.into()),
+    };
+}
+
+fn ttl(engine: &mut dyn StorageEngine, arguments: &[Vec<u8>], unit: Duration) -> CommandResult {
+    check_arity("ttl", arguments, 1, Some(1))?;
+    return match engine.get_ttl(&parse_key(&arguments[0])?) {
+        // Rounded like redis so a fresh `EX 10` reports 10 seconds
+        Ok(Some(ttl)) => Ok(RespValue::Integer(
+            ((ttl.as_millis() + unit.as_millis() / 2) / unit.as_millis()) as i64,
+        )),
+        Ok(None) => Ok(RespValue::Integer(-1)),
+        Err(KvError::KeyDoesNotExist { .. }) => Ok(RespValue::Integer(-2)),
+        Err(error) => Err(error.into()),
+    };
+}
+
+// Only keys sharing the literal start of the pattern are read from the engine
+fn find_keys(engine: &dyn StorageEngine, pattern: &[u8]) -> Result<Vec<String>, RespValue> {
+    let literal_size = pattern
+        .iter()
+        .position(|byte| b"*?[\\".contains(byte))
+        .unwrap_or(pattern.len());
+    let prefix = std::str::from_utf8(&pattern[..literal_size]).unwrap_or("");
+    return Ok(engine
+        .scan(prefix)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| matches_pattern(pattern, key.as_bytes()))
+        .collect());
+}
+
+/// Matches redis glob patterns with `*`, `?`, `[...]` character classes and `\` escapes.
+/// Only the last `*` is backtracked to, which is enough as any earlier one could only
+/// swallow what the last one can, so matching takes at most pattern times text steps.
+fn matches_pattern(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut pattern_position, mut text_position) = (0, 0);
+    // Pattern position after the last `*` and the text position it matches from
+    let mut last_star: Option<(usize, usize)> = None;
+    while text_position < text.len() {
+        if pattern.get(pattern_position) == Some(&b'*') {
+            pattern_position += 1;
+            last_star = Some((pattern_position, text_position));
+            continue;
+        }
+        if let Some(size) = match_token(&pattern[pattern_position..], text[text_position]) {
+            pattern_position += size;
+            text_position += 1;
+            continue;
+        }
+        match last_star {
+            // The last `*` swallows one more byte
+            Some((star_pattern_position, star_text_position)) => {
+                pattern_position = star_pattern_position;
+                text_position = star_text_position + 1;
+                last_star = Some((star_pattern_position, text_position));
+            }
+            None => return false,
+        }
+    }
+    return pattern[pattern_position..].iter().all(|byte| *byte == b'*');
+}
+
+// Size of the token at the start of `pattern` if it matches `byte`
+fn match_token(pattern: &[u8], byte: u8) -> Option<usize> {
+    return match pattern.first() {
+        None => None,
+        Some(b'?') => Some(1),
+        Some(b'[') => match pattern.iter().position(|byte| *byte == b']') {
+            Some(end) if end > 1 => matches_class(&pattern[1..end], byte).then_some(end + 1),
+            // Unterminated classes are matched literally
+            None => (byte == b'[').then_some(1),
+            _ => None,
+        },
+        Some(b'\\') if pattern.len() > 1 => (pattern[1] == byte).then_some(2),
+        Some(literal) => (*literal == byte).then_some(1),
+    };
+}
+
+fn matches_class(class: &[u8], byte: u8) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut position = 0;
+    while position < class.len() {
+        if position + 2 < class.len() && class[position + 1] == b'-' {
+            let (start, end) = (class[position], class[position + 2]);
+            matched |= start.min(end) <= byte && byte <= start.max(end);
+            position += 3;
+        } else {
+            matched |= class[position] == byte;
+            position += 1;
+        }
+    }
+    return matched != negated;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, matches_pattern, RespSession};
+    use crate::memkv::test_support::PageFilesGuard;
+    use crate::memkv::{MemKvPage, MemoryEngine, StorageEngine, KV_PAGE_SIZE};
+    use crate::server::resp::RespValue;
+    use std::path::Path;
+
+    fn run(engine: &mut dyn StorageEngine, session: &mut RespSession, command: &str) -> RespValue {
+        let arguments: Vec<Vec<u8>> = command
+            .split(' ')
+            .map(|argument| argument.as_bytes().to_vec())
+            .collect();
+        return execute(engine, session, &arguments);
+    }
+
+    fn bulk(text: &str) -> RespValue {
+        return RespValue::BulkString(text.as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_ttl_support() {
+        let mut memory = MemoryEngine::new();
+        let engine: &mut dyn StorageEngine = &mut memory;
+        let session = &mut RespSession::new();
+        assert_eq!(
+            run(engine, session, "SET a 1 EX 10"),
+            RespValue::error("storage engine does not support expire")
+        );
+        assert_eq!(run(engine, session, "GET a"), RespValue::Null);
+        assert_eq!(run(engine, session, "SET a 1"), RespValue::ok());
+        assert_eq!(run(engine, session, "INCR a"), RespValue::Integer(2));
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern(b"*", b""));
+        assert!(matches_pattern(b"user:*", b"user:42"));
+        assert!(!matches_pattern(b"user:*", b"group:1"));
+        assert!(matches_pattern(b"h?llo", b"hello"));
+        assert!(matches_pattern(b"h[ae]llo", b"hallo"));
+        assert!(!matches_pattern(b"h[^e]llo", b"hello"));
+        assert!(matches_pattern(b"key[0-9]", b"key7"));
+        assert!(matches_pattern(b"a\\*", b"a*"));
+        assert!(!matches_pattern(b"a\\*", b"ab"));
+        assert!(matches_pattern(b"*a*b*", b"xxaxxbxx"));
+        assert!(!matches_pattern(b"*a*b", b"xxaxxbxx"));
+        assert!(matches_pattern(b"[abc", b"[abc"));
+
+        // Would take exponential time with backtracking to every `*`
+        let pattern = format!("{}b", "a*".repeat(20));
+        assert!(!matches_pattern(pattern.as_bytes(), &[b'a'; 100]));
+    }
+
+    #[test]
+    fn test_commands() {
+        const KEYSPACE: &str = "test_resp_commands";
+        let _page_files = PageFilesGuard::new(Path::new(KEYSPACE)).unwrap();
+        let mut page = MemKvPage::new(Path::new(KEYSPACE), KV_PAGE_SIZE).unwrap();
+        let engine: &mut dyn StorageEngine = &mut page;
+        let mut session = RespSession::new();
+        let session = &mut session;
+
+        assert_eq!(
+            run(engine, session, "PING"),
+            RespValue::SimpleString(String::from("PONG"))
+        );
+        assert_eq!(run(engine, session, "GET a"), RespValue::Null);
+        assert_eq!(run(engine, session, "SET a 1"), RespValue::ok());
+        assert_eq!(run(engine, session, "SET a 2 NX"), RespValue::Null);
+        assert_eq!(run(engine, session, "SET b 2 XX"), RespValue::Null);
+        assert_eq!(run(engine, session, "get a"), bulk("1"));
+        assert_eq!(run(engine, session, "INCR a"), RespValue::Integer(2));
+        assert_eq!(run(engine, session, "INCR counter"), RespValue::Integer(1));
+        assert_eq!(run(engine, session, "GET a"), bulk("2"));
+        assert_eq!(run(engine, session, "SET text abc"), RespValue::ok());
+        assert!(matches!(
+            run(engine, session, "INCR text"),
+            RespValue::Error(_)
+        ));
+        assert_eq!(run(engine, session, "EXISTS a b a"), RespValue::Integer(2));
+
+        assert_eq!(run(engine, session, "TTL a"), RespValue::Integer(-1));
+        assert_eq!(run(engine, session, "TTL b"), RespValue::Integer(-2));
+        assert_eq!(run(engine, session, "SET a 5 EX 100"), RespValue::ok());
+        assert_eq!(run(engine, session, "TTL a"), RespValue::Integer(100));
+        assert_eq!(run(engine, session, "INCR a"), RespValue::Integer(6));
+        assert_eq!(run(engine, session, "TTL a"), RespValue::Integer(100));
+        assert_eq!(run(engine, session, "EXPIRE b 10"), RespValue::Integer(0));
+        assert_eq!(
+            run(engine, session, "EXPIRE counter 10"),
+            RespValue::Integer(1)
+        );
+        assert!(matches!(
+            run(engine, session, "PTTL counter"),
+            RespValue::Integer(ttl) if ttl > 9000 && ttl <= 10000
+        ));
+        assert_eq!(
+            run(engine, session, "EXPIRE counter 0"),
+            RespValue::Integer(1)
+        );
+        assert_eq!(
+            run(engine, session, "EXISTS counter"),
+            RespValue::Integer(0)
+        );
+
+        assert_eq!(run(engine, session, "SET user:1 a"), RespValue::ok());
+        assert_eq!(run(engine, session, "SET user:2 b"), RespValue::ok());
+        assert_eq!(run(engine, session, "SET user:3 c"), RespValue::ok());
+        assert_eq!(
+            run(engine, session, "KEYS user:[12]"),
+            RespValue::Array(vec![bulk("user:1"), bulk("user:2")])
+        );
+        assert_eq!(
+            run(engine, session, "SCAN 0 MATCH user:* COUNT 2"),
+            RespValue::Array(vec![
+                bulk("2"),
+                RespValue::Array(vec![bulk("user:1"), bulk("user:2")])
+            ])
+        );
+        assert_eq!(
+            run(engine, session, "SCAN 2 MATCH user:* COUNT 2"),
+            RespValue::Array(vec![bulk("0"), RespValue::Array(vec![bulk("user:3")])])
+        );
+        assert_eq!(
+            run(engine, session, "DEL user:1 user:9 user:2"),
+            RespValue::Integer(2)
+        );
+
+        assert_eq!(
+            run(engine, session, "SET a 1 NX XX"),
+            RespValue::error("syntax error")
+        );
+        assert_eq!(
+            run(engine, session, "SET a 1 EX 0"),
+            RespValue::error("invalid expire time in 'set' command")
+        );
+        assert_eq!(
+            run(engine, session, "GET"),
+            RespValue::error("wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            run(engine, session, "LPUSH list a"),
+            RespValue::error("unknown command 'lpush', with args beginning with: 'list' 'a'")
+        );
+        assert!(matches!(
+            run(engine, session, "HELLO 4"),
+            RespValue::Error(_)
+        ));
+        assert!(matches!(run(engine, session, "HELLO 3"), RespValue::Map(_)));
+        assert_eq!(session.version, 3);
+
+        drop(page);
+    }
+}
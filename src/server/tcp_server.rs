@@ -0,0 +1,498 @@
+use super::binary_protocol::{read_frame, write_frame, ErrorKind, Request, Response};
+use super::errors::ProtocolError;
+use super::http;
+use super::memcached::{self, MemcachedCommand, MemcachedItems};
+use super::resp::{read_command, RespValue};
+use super::resp_commands::{self, RespSession};
+use super::{lookup, with_engine, Protocol, SharedEngine};
+use crate::memkv::{KvError, StorageEngine, Value};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves one protocol, every connection gets its own task and shares the engine
+pub struct TcpServer {
+    listener: TcpListener,
+    protocol: Protocol,
+    engine: SharedEngine,
+    // Where `POST /backup` of the REST api writes backups
+    backup_directory: Option<PathBuf>,
+}
+
+impl TcpServer {
+    pub async fn bind(
+        address: &str,
+        protocol: Protocol,
+        engine: SharedEngine,
+    ) -> Result<TcpServer, io::Error> {
+        let listener = TcpListener::bind(address).await?;
+        return Ok(TcpServer {
+            listener,
+            protocol,
+            engine,
+            backup_directory: None,
+        });
+    }
+
+    pub fn set_backup_directory(self: &mut Self, directory: &Path) {
+        self.backup_directory = Some(PathBuf::from(directory));
+    }
+
+    pub fn local_addr(self: &Self) -> Result<SocketAddr, io::Error> {
+        return self.listener.local_addr();
+    }
+
+    pub async fn run(self: Self) -> Result<(), io::Error> {
+        info!(
+            "Serving {:?} protocol on {}",
+            self.protocol,
+            self.local_addr()?
+        );
+        // Memcached flags and cas uniques are shared by all connections of the listener
+        let items = Arc::new(Mutex::new(MemcachedItems::new()));
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            let engine = self.engine.clone();
+            let items = items.clone();
+            let protocol = self.protocol;
+            let backup_directory = self.backup_directory.clone();
+            tokio::spawn(async move {
+                let result = match protocol {
+                    Protocol::Binary => handle_connection(stream, engine).await,
+                    Protocol::Resp => handle_resp_connection(stream, engine).await,
+                    Protocol::Memcached => handle_memcached_connection(stream, engine, items).await,
+                    Protocol::Http => {
+                        handle_http_connection(stream, engine, backup_directory).await
+                    }
+                };
+                if let Err(error) = result {
+                    warn!("Connection to {} failed: {}", peer, error);
+                }
+            });
+        }
+    }
+}
+
+/// Runs a single request against the engine, failures become error responses
+pub fn execute(engine: &mut dyn StorageEngine, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get(&key).map(Response::Value),
+        Request::Put { key, value } => engine.put(&key, value).map(|_| Response::Done),
+        Request::Delete { key } => engine.delete(&key).map(|_| Response::Done),
+        Request::Scan { prefix } => engine.scan(&prefix).map(Response::Entries),
+        Request::Cas {
+            key,
+            expected,
+            value,
+        } => compare_and_swap(engine, &key, expected, value),
+    };
+    return result.unwrap_or_else(|error| Response::from_error(&error));
+}
+
+// The engine stays locked for the whole request, so nothing can write in between
+fn compare_and_swap(
+    engine: &mut dyn StorageEngine,
+    key: &str,
+    expected: Option<Value>,
+    value: Value,
+) -> Result<Response, KvError> {
+    if lookup(engine, key)? != expected {
+        return Ok(Response::Error(
+            ErrorKind::Conflict,
+            format!("value of key {:?} does not match the expected value", key),
+        ));
+    }
+    engine.put(key, value)?;
+    return Ok(Response::Done);
+}
+
+async fn handle_connection(stream: TcpStream, engine: SharedEngine) -> Result<(), ProtocolError> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let payload = match read_frame(&mut reader).await {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(ProtocolError::Io(error)) => return Err(error.into()),
+            Err(error) => {
+                // The stream cannot be resynchronized after an invalid frame header
+                let response = Response::Error(ErrorKind::BadRequest, error.to_string());
+                write_frame(&mut writer, &response.encode()?).await?;
+                break;
+            }
+        };
+        let response = match Request::decode(&payload) {
+            Ok(request) => with_engine(&engine, move |engine| execute(engine, request)).await?,
+            Err(error) => Response::Error(ErrorKind::BadRequest, error.to_string()),
+        };
+        let payload = match response.encode() {
+            Ok(payload) => payload,
+            Err(error) => Response::from_error(&error).encode()?,
+        };
+        write_frame(&mut writer, &payload).await?;
+
+        // Pipelined requests are answered with a single write once all of them were handled
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    return Ok(());
+}
+
+async fn handle_resp_connection(
+    stream: TcpStream,
+    engine: SharedEngine,
+) -> Result<(), ProtocolError> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = RespSession::new();
+    let mut output = vec![];
+    loop {
+        output.clear();
+        let arguments = match read_command(&mut reader).await {
+            Ok(Some(arguments)) => arguments,
+            Ok(None) => break,
+            Err(ProtocolError::Io(error)) => return Err(error.into()),
+            Err(error) => {
+                // Like redis the connection is closed after a protocol error
+                let reply = RespValue::error(&format!("Protocol error: {}", error));
+                reply.encode(session.version, &mut output);
+                writer.write_all(&output).await?;
+                break;
+            }
+        };
+        let (reply, returned_session) = with_engine(&engine, move |engine| {
+            let reply = resp_commands::execute(engine, &mut session, &arguments);
+            return (reply, session);
+        })
+        .await?;
+        session = returned_session;
+        reply.encode(session.version, &mut output);
+        writer.write_all(&output).await?;
+        if session.is_closing {
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    return Ok(());
+}
+
+async fn handle_memcached_connection(
+    stream: TcpStream,
+    engine: SharedEngine,
+    items: Arc<Mutex<MemcachedItems>>,
+) -> Result<(), ProtocolError> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let command = match memcached::read_command(&mut reader).await {
+            Ok(Some(MemcachedCommand::Quit)) | Ok(None) => break,
+            Ok(Some(command)) => command,
+            Err(ProtocolError::Io(error)) => return Err(error.into()),
+            Err(ProtocolError::UnknownCommand { .. }) => {
+                writer.write_all(b"ERROR\r\n").await?;
+                continue;
+            }
+            Err(ProtocolError::Malformed { reason }) => {
+                writer
+                    .write_all(format!("CLIENT_ERROR {}\r\n", reason).as_bytes())
+                    .await?;
+                continue;
+            }
+            Err(_) => {
+                // The data block of an oversized item is not read, so the stream is lost
+                writer
+                    .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                    .await?;
+                break;
+            }
+        };
+        // The engine is always locked before the items to avoid deadlocks
+        let items = items.clone();
+        let reply = with_engine(&engine, move |engine| {
+            return memcached::execute(engine, &mut items.lock(), command);
+        })
+        .await?;
+        writer.write_all(&reply).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    return Ok(());
+}
+
+async fn handle_http_connection(
+    stream: TcpStream,
+    engine: SharedEngine,
+    backup_directory: Option<PathBuf>,
+) -> Result<(), ProtocolError> {
+    let service = service_fn(move |request| {
+        http::handle_request(engine.clone(), backup_directory.clone(), request)
+    });
+    http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .await?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TcpServer;
+    use crate::memkv::{MemoryEngine, StorageEngine, Value};
+    use crate::server::binary_protocol::{read_frame, write_frame, ErrorKind, Request, Response};
+    use crate::server::Protocol;
+    use parking_lot::Mutex;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn start_server(protocol: Protocol) -> SocketAddr {
+        let engine: Box<dyn StorageEngine> = Box::new(MemoryEngine::new());
+        let server = TcpServer::bind("127.0.0.1:0", protocol, Arc::new(Mutex::new(engine)))
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        return address;
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let address = start_server(Protocol::Binary).await;
+
+        let requests = vec![
+            Request::Put {
+                key: String::from("user:1"),
+                value: Value::Integer(1),
+            },
+            Request::Put {
+                key: String::from("user:2"),
+                value: Value::String(String::from("peter")),
+            },
+            Request::Get {
+                key: String::from("user:1"),
+            },
+            Request::Delete {
+                key: String::from("user:1"),
+            },
+            Request::Get {
+                key: String::from("user:1"),
+            },
+            Request::Scan {
+                prefix: String::from("user:"),
+            },
+            Request::Cas {
+                key: String::from("user:2"),
+                expected: Some(Value::Integer(2)),
+                value: Value::Integer(3),
+            },
+            Request::Cas {
+                key: String::from("user:2"),
+                expected: Some(Value::String(String::from("peter"))),
+                value: Value::Integer(3),
+            },
+        ];
+        // Every request is sent before the first response is read
+        let mut data = vec![];
+        for request in &requests {
+            write_frame(&mut data, &request.encode().unwrap())
+                .await
+                .unwrap();
+        }
+        write_frame(&mut data, &[0x9]).await.unwrap();
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+
+        let mut responses = vec![];
+        for _ in 0..requests.len() + 1 {
+            let payload = read_frame(&mut stream).await.unwrap().unwrap();
+            responses.push(Response::decode(&payload).unwrap());
+        }
+        assert!(matches!(responses[0], Response::Done));
+        assert!(matches!(responses[1], Response::Done));
+        assert!(matches!(responses[2], Response::Value(Value::Integer(1))));
+        assert!(matches!(responses[3], Response::Done));
+        assert!(matches!(
+            responses[4],
+            Response::Error(ErrorKind::NotFound, _)
+        ));
+        match &responses[5] {
+            Response::Entries(entries) => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].0, "user:2");
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            responses[6],
+            Response::Error(ErrorKind::Conflict, _)
+        ));
+        assert!(matches!(responses[7], Response::Done));
+        // Invalid requests don't close the connection
+        assert!(matches!(
+            responses[8],
+            Response::Error(ErrorKind::BadRequest, _)
+        ));
+        write_frame(
+            &mut stream,
+            &Request::Get {
+                key: String::from("user:2"),
+            }
+            .encode()
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+        let payload = read_frame(&mut stream).await.unwrap().unwrap();
+        assert!(matches!(
+            Response::decode(&payload).unwrap(),
+            Response::Value(Value::Integer(3))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resp_connection() {
+        let address = start_server(Protocol::Resp).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$2\r\nhi\r\nGET a\r\nGET b\r\nHELLO 3\r\n")
+            .await
+            .unwrap();
+        stream.write_all(b"GET b\r\nQUIT\r\n").await.unwrap();
+        let mut replies = vec![];
+        stream.read_to_end(&mut replies).await.unwrap();
+        let replies = String::from_utf8(replies).unwrap();
+        assert!(replies.starts_with("+OK\r\n$2\r\nhi\r\n$-1\r\n%6\r\n"));
+        assert!(replies.ends_with("_\r\n+OK\r\n"));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"*1\r\n$x\r\n").await.unwrap();
+        let mut replies = vec![];
+        stream.read_to_end(&mut replies).await.unwrap();
+        assert!(replies.starts_with(b"-ERR Protocol error"));
+    }
+
+    #[tokio::test]
+    async fn test_memcached_connection() {
+        let address = start_server(Protocol::Memcached).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"set a 3 0 2\r\nhi\r\nset b 0 0 1 noreply\r\n1\r\nbogus\r\nincr b x\r\n")
+            .await
+            .unwrap();
+        stream.write_all(b"get a b\r\nquit\r\n").await.unwrap();
+        let mut replies = vec![];
+        stream.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(
+            String::from_utf8(replies).unwrap(),
+            "STORED\r\nERROR\r\nCLIENT_ERROR bad command line format\r\n\
+             VALUE a 3 2\r\nhi\r\nVALUE b 0 1\r\n1\r\nEND\r\n"
+        );
+
+        // Flags are shared by the connections of a listener
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"get a\r\nset c 0 0 999999999\r\n")
+            .await
+            .unwrap();
+        let mut replies = vec![];
+        stream.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(
+            String::from_utf8(replies).unwrap(),
+            "VALUE a 3 2\r\nhi\r\nEND\r\nSERVER_ERROR object too large for cache\r\n"
+        );
+    }
+
+    async fn send_http(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        return String::from_utf8(response).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_requests() {
+        let address = start_server(Protocol::Http).await;
+        let put = |key: &str, headers: &str, body: &str| {
+            format!(
+                "PUT /kv/{} HTTP/1.1\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+                key,
+                headers,
+                body.len(),
+                body
+            )
+        };
+        let response = send_http(address, &put("user%3A1", "", "peter")).await;
+        assert!(response.starts_with("HTTP/1.1 204"));
+        let body = r#"{"type": "Integer", "value": 7}"#;
+        let request = put("user:2", "Content-Type: application/json\r\n", body);
+        assert!(send_http(address, &request)
+            .await
+            .starts_with("HTTP/1.1 204"));
+        let request = put("user:2", "If-None-Match: *\r\n", "x");
+        assert!(send_http(address, &request)
+            .await
+            .starts_with("HTTP/1.1 409"));
+        let request = put("user:3", "Content-Type: application/json\r\n", "{}");
+        assert!(send_http(address, &request)
+            .await
+            .starts_with("HTTP/1.1 400"));
+
+        let response = send_http(
+            address,
+            "GET /kv/user:1 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("x-rdkv-type: String\r\n"));
+        assert!(response.ends_with("\r\n\r\npeter"));
+        let response = send_http(
+            address,
+            "GET /kv/user:2 HTTP/1.1\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.ends_with(r#"{"type":"Integer","value":7}"#));
+        let response = send_http(
+            address,
+            "GET /kv?prefix=user%3A HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let listing = response.split("\r\n\r\n").nth(1).unwrap();
+        let listing: serde_json::Value = serde_json::from_str(listing).unwrap();
+        assert_eq!(listing.as_array().unwrap().len(), 2);
+
+        let request = "DELETE /kv/user:1 HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(send_http(address, request)
+            .await
+            .starts_with("HTTP/1.1 204"));
+        assert!(send_http(address, request)
+            .await
+            .starts_with("HTTP/1.1 404"));
+        let request = "POST /kv HTTP/1.1\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+        assert!(send_http(address, request)
+            .await
+            .starts_with("HTTP/1.1 405"));
+        let request = "POST /backup HTTP/1.1\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+        assert!(send_http(address, request)
+            .await
+            .starts_with("HTTP/1.1 404"));
+    }
+}